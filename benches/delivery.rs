@@ -0,0 +1,64 @@
+//! Compare building a delivered message by streaming the input line by line
+//! (`write_message`) with reading it all in one go (`write_message_buffered`), for a
+//! typical small (2 KB) message.
+//!
+//! Input is read through a reader which returns at most a pipe-buffer-sized chunk per
+//! `read`, as stdin would.
+
+use std::io::{BufReader, Read};
+
+use chrono::{Local, TimeZone};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use rattomail::{write_message, write_message_buffered, Config, Envelope};
+
+/// a reader returning at most `chunk` bytes per `read`, like a pipe
+struct ChunkedReader<'a> {
+  data: &'a [u8],
+  chunk: usize,
+}
+
+impl Read for ChunkedReader<'_> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let n = buf.len().min(self.chunk).min(self.data.len());
+    buf[..n].copy_from_slice(&self.data[..n]);
+    self.data = &self.data[n..];
+    Ok(n)
+  }
+}
+
+fn small_message() -> Vec<u8> {
+  let mut message = b"Subject: cron job output\nTo: root\nX-Cron-Env: <SHELL=/bin/sh>\n\n".to_vec();
+  while message.len() < 2048 {
+    message.extend_from_slice(b"backup completed successfully -- /etc/backup.d/example.sys\n");
+  }
+  message
+}
+
+fn bench_delivery(c: &mut Criterion) {
+  let message = small_message();
+  let envelope = Envelope::new("root", "cron", Local.with_ymd_and_hms(2024, 12, 31, 10, 30, 0).unwrap());
+  let config = Config::default();
+
+  let mut group = c.benchmark_group("2KB message");
+  group.bench_function("streaming", |b| {
+    b.iter(|| {
+      let mut input = BufReader::new(ChunkedReader { data: &message, chunk: 4096 });
+      let mut output = Vec::new();
+      write_message(&mut input, &mut output, &envelope, &config).unwrap();
+      output
+    })
+  });
+  group.bench_function("buffered", |b| {
+    b.iter(|| {
+      let mut input = BufReader::new(ChunkedReader { data: &message, chunk: 4096 });
+      let mut output = Vec::new();
+      write_message_buffered(&mut input, &mut output, &envelope, &config).unwrap();
+      output
+    })
+  });
+  group.finish();
+}
+
+criterion_group!(benches, bench_delivery);
+criterion_main!(benches);
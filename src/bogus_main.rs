@@ -8,14 +8,17 @@ use rattomail::{CreateMaildirsOption, MainContext, MessageDestination, Privilege
 
 fn main() {
     // bizarrely, bsd-mailx sets argv[0] to "send-mail", for no good reason.
-    let valid_program_names = ["bogus_rattomail", "rattomail", "attomail", "sendmail", "send-mail"];
+    let valid_program_names = ["bogus_rattomail", "rattomail", "attomail", "sendmail", "send-mail", "lmtp"];
     let cli_args: Vec<String> = std::env::args().collect();
-    let config_path = env!("ATTOMAIL_CONFIG_PATH");
+    // since this executable is only for testing, the config file can also be chosen at run
+    // time (the real one only ever uses the path it was built with)
+    let config_path = std::env::var("ATTOMAIL_CONFIG_PATH")
+        .unwrap_or_else(|_| env!("ATTOMAIL_CONFIG_PATH").to_string());
     let now: chrono::DateTime<Local> = Local::now();
 
     let ctx = MainContext {
         args: cli_args,
-        config_path: config_path.to_string(),
+        config_path,
         should_drop_privs: PrivilegeOption::NoDropPrivileges,
         should_create_maildirs: CreateMaildirsOption::NoCreateMaildirs,
         message_destination: MessageDestination::OutputStream,
@@ -27,5 +30,7 @@ fn main() {
 
     let mut stdout = std::io::stdout();
 
-    rattomail::main(&valid_program_names, &ctx, &mut handle, Some(&mut stdout));
+    if let Err(e) = rattomail::main(&valid_program_names, &ctx, &mut handle, Some(&mut stdout)) {
+        e.exit();
+    }
 }
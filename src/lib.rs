@@ -1,25 +1,413 @@
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, Write};
+use std::io::{BufRead, BufReader, Read, Seek, Write};
+use std::os::fd::{FromRawFd, RawFd};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Context, Result};
-use chrono::Local;
-use clap::{command, Arg, ArgAction, Command};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{FixedOffset, Local};
+use chrono_tz::Tz;
+use clap::error::{ContextKind, ContextValue, ErrorKind};
+use clap::{command, Arg, ArgAction, ArgMatches, Command};
+use hmac::{Hmac, Mac};
 use ini::Ini;
-use maildir::Maildir;
-use nix::unistd::{Uid, User};
+use mail_parser::{HeaderName, MessageParser};
+use maildir::{Maildir, MaildirError};
+use nix::errno::Errno;
+use nix::unistd::{Gid, Group, Uid, User};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use sha2::Sha256;
 use simplelog::{LevelFilter, WriteLogger};
 
 /// Contents of a config file.
 ///
 /// - `mailDir` is a path to a Maildir/new directory.
 /// - `userName` is the name of the user we'll assume the privileges of while delivering mail
+///
+/// `mailDir` and `userName` may contain a `%u` placeholder, standing for the recipient's local
+/// part (see [`expand_recipient_templates`]).
+///
+/// They're the defaults: recipients can instead be given a Maildir and user of their own, in
+/// `userRoutes`, read from sections of the config file named after their local part (see
+/// [`UserRoute`]).
+/// - `maildirRelative` (optional): instead of `mailDir`, deliver to this path (e.g.
+///   `Maildir/new`) under `userName`'s home directory, as looked up in the passwd database
+///   (see [`resolve_maildir_relative`])
+/// - `deliveryGroup` (optional): the group to deliver mail as, instead of `userName`'s primary
+///   group (see [`delivery_group_id`])
+/// - `bounceOnQuotaFull` (optional, default `false`): if the user's disk quota is exceeded,
+///   report a permanent failure (so the message gets bounced) rather than a temporary one
+/// - `writeLastDelivery` (optional, default `false`): after each delivery, record its time,
+///   sender and message ID in a [`LAST_DELIVERY_FILE`] in the Maildir (see
+///   [`write_last_delivery_status`])
+/// - `setSenderHeader` (optional, default `false`): add a `Sender:` header giving the
+///   envelope sender, if it isn't the message's `From:` author (see [`sender_header_value`])
+/// - `dateAtDelivery` (optional, default `false`): stamp the `Received:` header (and any
+///   `Date:` we add) with the time the message was stored, once all the input has been read,
+///   rather than the time we were invoked
+/// - `fixQpMismatch` (optional, default `false`): if a message is declared quoted-printable but
+///   its body has raw 8-bit bytes, re-declare it `8bit` (see [`fix_qp_mismatch`]), rather than
+///   just logging a warning
+/// - `dedupeCriticalHeaders` (optional, default `false`): keep only the first of any duplicate
+///   `From:`, `Date:`, `Message-ID:` or `Subject:` headers (see [`dedupe_critical_headers`])
+/// - `normalizeMacLineEndings` (optional, default `false`): treat lone carriage returns (old
+///   Mac-style line endings) as line endings, converting them to `\n` (see [`MacLineEndings`])
+/// - `strictHeaders` (optional, default `false`): reject messages containing header lines
+///   that aren't syntactically valid
+/// - `warnRawSubject8bit` (optional, default `false`): what to do about a `Subject:` header
+///   containing raw 8-bit text (see [`RawSubjectAction`])
+/// - `requireFromHeader` (optional, default `false`): reject messages without a `From:`
+///   header, rather than adding one
+/// - `requireHeaders` (optional, default `false`): reject input without any valid header
+///   line before the first blank line, rather than treating it all as the body (see
+///   [`process_existing_headers`])
+/// - `maxHeaderBytes` (optional, default 1 MiB): reject messages whose header block is larger
+///   than this many bytes
+/// - `maxMessageSize` (optional): reject (with `EX_DATAERR`) messages which would be larger
+///   than this many bytes as delivered, headers and body together, without writing anything
+///   to the Maildir (see [`SizeLimitWriter`]). It can't be set together with `truncateAtBytes`
+/// - `inputLineLimit` (optional): reject (with `EX_DATAERR`) input of more than this many
+///   lines, headers and body together (see [`LineLimitReader`])
+/// - `deliveryTimeoutSeconds` (optional): give up (with `EX_TEMPFAIL`) on deliveries which
+///   take longer than this many seconds altogether, from reading the input to storing the
+///   message (see [`DeadlineReader`] and [`DeliveryAlarm`])
+/// - `truncateAtBytes` (optional): rather than delivering message bodies larger than this many
+///   bytes in full, deliver them truncated, with a note saying so (see [`TruncatingWriter`])
+/// - `deliverFlags` (optional): Maildir flags (any of `DFPRST`) to deliver messages with; if
+///   set, messages are delivered straight into `cur/`, with the flags in their filenames'
+///   `:2,` info section (see [`parse_maildir_flags`])
+/// - `messageMode` (optional): permissions (in octal, e.g. `0600`) to give each delivered
+///   message, whatever the umask; by default, they're left as created
+/// - `defaultReplyTo` (optional): add a `Reply-To:` header with this value to messages that
+///   lack one
+/// - `addLinesHeader` (optional, default `false`): add a `Lines:` header giving the number of
+///   lines in the message body (see [`add_lines_header`])
+/// - `normalizeDomainCase` (optional): lowercase the domain part of the sender and recipient
+///   addresses (see [`normalize_domain_case`])
+/// - `trustedUsers` (optional): users who may set the envelope sender with `-f` without an
+///   `X-Authentication-Warning:` header being added (see [`authentication_warning`])
+/// - `allowedLogfiles` (optional): logfile paths which may be given with `-X`, in addition to
+///   the [`DEFAULT_LOGFILES`] (see [`check_logfile_allowed`])
+/// - `dateTimezone` (optional): timezone to use for the timestamps in synthesized `Date:` and
+///   `Received:` headers, instead of the system's local timezone (see [`DateTimezone`])
+/// - `datePartition` (optional): a `chrono` format string (e.g. `%Y/%m`); if given, mail is
+///   delivered to a Maildir subfolder named after the message's received time, rather than
+///   to the top-level inbox (see [`date_partition_folder`])
+/// - `filenameRetries` (optional, default 5): how many times to retry, with a new name, if a
+///   message's filename collides with an existing one (see [`store_new_unique`])
+/// - `tmpfileStrategy` (optional, default `classic`): how a new message's file is created
+///   before it's moved into `new/` (see [`TmpfileStrategy`])
+/// - `nssRetries` (optional, default 3): how many times to retry looking up the delivery user
+///   (or the invoking user), if the lookup fails (e.g. because an LDAP server is briefly unavailable -- see
+///   [`retry_nss_lookup`])
+/// - `receivedHideProduct` (optional, default `false`): leave the `(rattomail)` product token
+///   out of the `Received:` header, so as not to advertise what delivered the message
+/// - `receivedFoldWidth` (optional): fold the `Received:` header so no line of it is longer
+///   than this many characters, where possible (see [`fold_received_header`])
+/// - `reportTls` (optional, default `false`): note the TLS cipher the message was received
+///   with, if the MTA passes one in the environment, in the `Received:` header (see
+///   [`tls_cipher_from_env`])
+/// - `rejectSelfAddressed` (optional, default `false`): what to do with messages whose
+///   recipient is the same as the envelope sender, which might indicate a mail loop (see
+///   [`SelfAddressedAction`])
+/// - `onEmptyInput` (optional, default `deliver`): what to do when the input is completely
+///   empty (see [`EmptyInputAction`])
+/// - `onInvalidSender` (optional, default `reject`): what to do when the envelope sender isn't
+///   a plausible address (see [`InvalidSenderAction`] and [`check_sender`])
+/// - `programNames` (optional): names the program may be invoked as, in addition to the
+///   built-in ones (e.g. `mailx`, for a site that symlinks it under that name); these behave
+///   as `sendmail` does, unless `programProfiles` says otherwise
+/// - `programProfiles` (optional): how to behave when invoked under each name, overriding
+///   [`DEFAULT_PROGRAM_PROFILES`] (see [`program_profile`])
+/// - `receiptKey` or `receiptKeyFile` (optional): a key, or a file containing one, with which
+///   to sign the receipts written with `--receipt-fd` (see [`DeliveryReceipt`])
+/// - `rejectPartial` (optional, default `false`): reject messages which are fragments of a
+///   larger message (`Content-Type: message/partial`), since we can't reassemble them
+/// - `validateRfc5322` (optional, default `false`): check that the message as delivered can be
+///   parsed as an RFC 5322 message, and reject it if not (see [`validate_rfc5322`])
+/// - `maxRecipients` (optional, default 100): reject deliveries with more than this many
+///   recipients (see [`check_recipient_count`])
+/// - `indexCommand` (optional): a shell command to run after each successful delivery, e.g.
+///   to update a search index (see [`run_index_command`])
+/// - `dbusNotify` (optional, default `false`): after each delivery, send a desktop
+///   notification over D-Bus (only if built with the `dbus` feature; see
+///   [`notify_delivered_messages`])
+/// - `indexTimeout` (optional, default 60): how many seconds `indexCommand` may run for,
+///   before it's killed
 #[derive(Debug, PartialEq, Eq)]
 #[allow(non_snake_case)]
 pub struct Config {
     pub mailDir: String,
     pub userName: String,
+    pub bounceOnQuotaFull: bool,
+    pub strictHeaders: bool,
+    pub maxHeaderBytes: u64,
+    pub defaultReplyTo: Option<String>,
+    pub addLinesHeader: bool,
+    pub normalizeDomainCase: bool,
+    pub trustedUsers: Vec<String>,
+    pub allowedLogfiles: Vec<String>,
+    pub dateTimezone: Option<DateTimezone>,
+    pub filenameRetries: u64,
+    pub nssRetries: u64,
+    pub indexCommand: Option<String>,
+    pub indexTimeout: u64,
+    pub maxRecipients: u64,
+    pub reportTls: bool,
+    pub rejectSelfAddressed: SelfAddressedAction,
+    pub rejectPartial: bool,
+    pub validateRfc5322: bool,
+    pub datePartition: Option<String>,
+    pub requireFromHeader: bool,
+    pub requireHeaders: bool,
+    pub dbusNotify: bool,
+    pub receivedHideProduct: bool,
+    pub warnRawSubject8bit: RawSubjectAction,
+    pub truncateAtBytes: Option<u64>,
+    pub maxMessageSize: Option<u64>,
+    pub receivedFoldWidth: Option<u64>,
+    pub inputLineLimit: Option<u64>,
+    pub deliveryTimeoutSeconds: Option<u64>,
+    pub messageMode: Option<u32>,
+    pub deliverFlags: Option<String>,
+    pub maildirRelative: Option<String>,
+    pub deliveryGroup: Option<String>,
+    pub normalizeMacLineEndings: bool,
+    pub onEmptyInput: EmptyInputAction,
+    pub onInvalidSender: InvalidSenderAction,
+    pub tmpfileStrategy: TmpfileStrategy,
+    pub receiptKey: Option<String>,
+    pub receiptKeyFile: Option<String>,
+    pub dedupeCriticalHeaders: bool,
+    pub programProfiles: Vec<(String, ProgramProfile)>,
+    pub programNames: Vec<String>,
+    pub fixQpMismatch: bool,
+    pub writeLastDelivery: bool,
+    pub setSenderHeader: bool,
+    pub dateAtDelivery: bool,
+    pub userRoutes: Vec<UserRoute>,
+}
+
+/// Where to deliver mail for one recipient, instead of the config's default `mailDir`, and as
+/// which user. Read from a section of the config file named after the recipient's local part,
+/// e.g.
+///
+/// ```text
+/// [alice]
+/// mailDir = /home/alice/Maildir/new
+/// userName = alice
+/// ```
+///
+/// See [`route_for_recipient`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(non_snake_case)]
+pub struct UserRoute {
+    /// the recipient local part this applies to
+    pub name: String,
+    pub mailDir: String,
+    pub userName: String,
+}
+
+/// What to do with a message whose recipient is the same as its envelope sender. Set in the
+/// config file with `rejectSelfAddressed = false` (the default), `drop` or `reject` (or
+/// `true`, which means the same as `reject`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SelfAddressedAction {
+    /// deliver it as normal
+    #[default]
+    Deliver,
+    /// silently discard it, reporting success
+    Drop,
+    /// reject it, with `EX_DATAERR`
+    Reject,
+}
+
+/// What to do when the input is completely empty (0 bytes) -- as when a cron job with no
+/// output still invokes sendmail. Set in the config file with `onEmptyInput = deliver` (the
+/// default), `reject` or `skip`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EmptyInputAction {
+    /// deliver a message with just the headers we add, and an empty body
+    #[default]
+    Deliver,
+    /// reject it, with `EX_NOINPUT`
+    Reject,
+    /// don't deliver anything, but report success
+    Skip,
+}
+
+/// What to do when the envelope sender (from `-f`, or the default) isn't a plausible address
+/// (see [`is_plausible_string`]) -- as some quirky MUAs pass. Set in the config file with
+/// `onInvalidSender = reject` (the default), `sanitize` or `fallback`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidSenderAction {
+    /// reject the message, with `EX_DATAERR`
+    #[default]
+    Reject,
+    /// remove the offending characters, and use what's left, if that's plausible
+    Sanitize,
+    /// use the invoking user's name instead
+    Fallback,
+}
+
+/// How a new message's file is created in the Maildir's `tmp/` directory, before being moved
+/// into `new/` (or `cur/`). Set in the config file with `tmpfileStrategy = classic` (the
+/// default) or `o_tmpfile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TmpfileStrategy {
+    /// write a named file in `tmp/`, then hard-link it into place and remove it
+    #[default]
+    Classic,
+    /// on Linux, write an unnamed `O_TMPFILE` file in `tmp/`, then `linkat` it into place, so
+    /// a partial message is never visible in `tmp/`, nor left there if delivery is
+    /// interrupted. Elsewhere, or on filesystems without `O_TMPFILE`, the same as `Classic`.
+    OTmpfile,
+}
+
+/// What to do with a `Subject:` header containing raw 8-bit (i.e., non-ASCII, and not RFC
+/// 2047-encoded) text. Set in the config file with `warnRawSubject8bit = false` (the default),
+/// `warn` (or `true`) or `encode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RawSubjectAction {
+    /// leave it as-is
+    #[default]
+    Ignore,
+    /// leave it as-is, but log a warning
+    Warn,
+    /// log a warning, and RFC 2047-encode it (if it's valid UTF-8)
+    Encode,
+}
+
+/// A timezone to format timestamps in: either a fixed offset from UTC (written like `+1000`
+/// or `-0530`), or an IANA timezone name (like `Australia/Sydney`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateTimezone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl std::str::FromStr for DateTimezone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(offset) = parse_fixed_offset(s) {
+            return Ok(DateTimezone::Fixed(offset));
+        }
+        s.parse::<Tz>()
+            .map(DateTimezone::Named)
+            .map_err(|_| anyhow!("'{}' is not a valid timezone name or UTC offset", s))
+    }
+}
+
+impl fmt::Display for DateTimezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DateTimezone::Fixed(offset) => {
+                let secs = offset.local_minus_utc();
+                let sign = if secs < 0 { '-' } else { '+' };
+                let mins = secs.abs() / 60;
+                write!(f, "{}{:02}{:02}", sign, mins / 60, mins % 60)
+            }
+            DateTimezone::Named(tz) => write!(f, "{}", tz.name()),
+        }
+    }
+}
+
+/// Parse a UTC offset of the form `+HHMM` or `-HHMM`.
+fn parse_fixed_offset(s: &str) -> Option<FixedOffset> {
+    let (sign, digits) = match s.as_bytes().first()? {
+        b'+' => (1, &s[1..]),
+        b'-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let mins: i32 = digits[2..].parse().ok()?;
+    if mins >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + mins * 60))
+}
+
+/// Default for `Config::maxHeaderBytes`.
+pub const DEFAULT_MAX_HEADER_BYTES: u64 = 1024 * 1024;
+
+/// Default for `Config::filenameRetries`.
+pub const DEFAULT_FILENAME_RETRIES: u64 = 5;
+
+/// Default for `Config::nssRetries`.
+pub const DEFAULT_NSS_RETRIES: u64 = 3;
+
+/// Default for `Config::indexTimeout`, in seconds.
+pub const DEFAULT_INDEX_TIMEOUT: u64 = 60;
+
+/// Default for `Config::maxRecipients`.
+pub const DEFAULT_MAX_RECIPIENTS: u64 = 100;
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mailDir: String::new(),
+            userName: String::new(),
+            bounceOnQuotaFull: false,
+            strictHeaders: false,
+            maxHeaderBytes: DEFAULT_MAX_HEADER_BYTES,
+            defaultReplyTo: None,
+            addLinesHeader: false,
+            normalizeDomainCase: false,
+            trustedUsers: Vec::new(),
+            allowedLogfiles: Vec::new(),
+            dateTimezone: None,
+            filenameRetries: DEFAULT_FILENAME_RETRIES,
+            nssRetries: DEFAULT_NSS_RETRIES,
+            indexCommand: None,
+            indexTimeout: DEFAULT_INDEX_TIMEOUT,
+            maxRecipients: DEFAULT_MAX_RECIPIENTS,
+            reportTls: false,
+            rejectSelfAddressed: SelfAddressedAction::Deliver,
+            rejectPartial: false,
+            validateRfc5322: false,
+            datePartition: None,
+            requireFromHeader: false,
+            requireHeaders: false,
+            dbusNotify: false,
+            receivedHideProduct: false,
+            warnRawSubject8bit: RawSubjectAction::Ignore,
+            truncateAtBytes: None,
+            maxMessageSize: None,
+            receivedFoldWidth: None,
+            inputLineLimit: None,
+            deliveryTimeoutSeconds: None,
+            messageMode: None,
+            deliverFlags: None,
+            maildirRelative: None,
+            deliveryGroup: None,
+            normalizeMacLineEndings: false,
+            onEmptyInput: EmptyInputAction::Deliver,
+            onInvalidSender: InvalidSenderAction::Reject,
+            tmpfileStrategy: TmpfileStrategy::Classic,
+            receiptKey: None,
+            receiptKeyFile: None,
+            dedupeCriticalHeaders: false,
+            programProfiles: Vec::new(),
+            programNames: Vec::new(),
+            fixQpMismatch: false,
+            writeLastDelivery: false,
+            setSenderHeader: false,
+            dateAtDelivery: false,
+            userRoutes: Vec::new(),
+        }
+    }
 }
 
 /// Whether to drop privileges (i.e., change to the user specified in the config file).
@@ -40,11 +428,246 @@ pub enum CreateMaildirsOption {
 
 /// Where to write the message to.
 /// In production, this should be `Maildir`; in testing, we might
-/// instead write to some `OutputStream`.
+/// instead write to some `OutputStream`, or `Discard` the message (after processing
+/// it as usual) -- which is also what the `--discard` flag does.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum MessageDestination {
     Maildir,
     OutputStream,
+    Discard,
+}
+
+/// Exit statuses from sysexits(3), for failures where it matters to the caller (normally an MTA)
+/// what sort of failure it was -- e.g. whether delivery is worth retrying later.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Sysexit {
+    /// `EX_USAGE`: the command was used incorrectly, e.g. with a bad argument
+    Usage = 64,
+    /// `EX_DATAERR`: the input data (i.e., the message) was incorrect in some way
+    DataErr = 65,
+    /// `EX_NOINPUT`: there was no input (i.e., the message was empty)
+    NoInput = 66,
+    /// `EX_NOUSER`: the user to deliver to doesn't exist
+    NoUser = 67,
+    /// `EX_UNAVAILABLE`: a requested service isn't available
+    Unavailable = 69,
+    /// `EX_NOPERM`: permanent failure, the message should be bounced
+    NoPerm = 77,
+    /// `EX_CANTCREAT`: an output file couldn't be created
+    CantCreat = 73,
+    /// `EX_TEMPFAIL`: temporary failure, delivery can be retried later
+    TempFail = 75,
+    /// `EX_CONFIG`: there's something wrong with the config file
+    Config = 78,
+}
+
+impl Sysexit {
+    /// The numeric exit code.
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+/// An error carrying the sysexits(3) status the program should exit with.
+#[derive(Debug)]
+pub struct SysexitError {
+    pub status: Sysexit,
+    pub message: String,
+}
+
+impl fmt::Display for SysexitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for SysexitError {}
+
+/// Make an `anyhow::Error` that will cause us to exit with `status`.
+fn sysexit_error(status: Sysexit, message: String) -> anyhow::Error {
+    anyhow::Error::new(SysexitError { status, message })
+}
+
+/// Exit code to use for an error: the status of the first `SysexitError` in its chain,
+/// or [`failure_exit_code`] if there isn't one.
+pub fn exit_code_for(err: &anyhow::Error) -> i32 {
+    err.chain()
+        .find_map(|e| e.downcast_ref::<SysexitError>())
+        .map_or_else(failure_exit_code, |e| e.status.code())
+}
+
+/// Make an error for a failure to read the input. If the I/O error carries a `SysexitError`
+/// (as [`LineLimitReader`]'s does), the result has the same status.
+fn input_error(e: std::io::Error) -> anyhow::Error {
+    let message = format!("Error reading input: {}", e);
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<SysexitError>())
+    {
+        Some(inner) => sysexit_error(inner.status, message),
+        None => anyhow!(message),
+    }
+}
+
+/// Why [`main`] stopped without delivering a message: a failure, or (for `--help` and
+/// `--version`) a request for information. The program reports it with [`MainError::print`],
+/// then exits with [`MainError::exit_code`] -- or does both with [`MainError::exit`].
+#[derive(Debug)]
+pub enum MainError {
+    /// the command line couldn't be parsed, or asked for `--help` or `--version` -- which clap
+    /// formats the output for
+    Cli {
+        error: clap::Error,
+        /// the status to exit with: normally clap's own, but `EX_USAGE` with `--compat-strict`
+        code: i32,
+    },
+    /// any other failure
+    Failed {
+        /// the status to exit with
+        code: i32,
+        /// what to print to stderr
+        message: String,
+        /// the error that caused the failure, if there was one
+        source: Option<anyhow::Error>,
+    },
+}
+
+impl MainError {
+    /// A failure exiting with `code`, and printing `message`.
+    pub fn new(code: i32, message: String) -> Self {
+        MainError::Failed {
+            code,
+            message,
+            source: None,
+        }
+    }
+
+    /// A failure caused by `source`, exiting with `code`, and printing `message`.
+    pub fn caused_by(code: i32, message: String, source: anyhow::Error) -> Self {
+        MainError::Failed {
+            code,
+            message,
+            source: Some(source),
+        }
+    }
+
+    /// The status the program should exit with.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MainError::Cli { code, .. } | MainError::Failed { code, .. } => *code,
+        }
+    }
+
+    /// Report the error as the program does: clap's own output (on stdout, for `--help` and
+    /// `--version`), or else the message, on stderr.
+    pub fn print(&self) {
+        match self {
+            MainError::Cli { error, .. } => {
+                let _ = error.print();
+            }
+            MainError::Failed { message, .. } => eprintln!("{}", message),
+        }
+    }
+
+    /// Report the error (see [`MainError::print`]), and exit with its status.
+    pub fn exit(&self) -> ! {
+        self.print();
+        let _ = std::io::stdout().flush();
+        std::process::exit(self.exit_code())
+    }
+}
+
+impl fmt::Display for MainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MainError::Cli { error, .. } => write!(f, "{}", error),
+            MainError::Failed { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for MainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MainError::Cli { error, .. } => Some(error),
+            MainError::Failed { source, .. } => source
+                .as_ref()
+                .map(|e| e.as_ref() as &(dyn std::error::Error + 'static)),
+        }
+    }
+}
+
+/// Most failures are reported as `Error: <error>`, exiting with the error's status (see
+/// [`exit_code_for`]).
+impl From<anyhow::Error> for MainError {
+    fn from(e: anyhow::Error) -> Self {
+        MainError::caused_by(exit_code_for(&e), format!("Error: {}", e), e)
+    }
+}
+
+/// Details of a delivery, other than the message itself, that headers get synthesized from.
+///
+/// - `to_addr`: recipient address
+/// - `from_addr`: envelope sender address
+/// - `received_time`: time the message was received, used for the `Received:` header
+///   (and `Date:`, if missing)
+/// - `extra_headers`: additional `(name, value)` headers to add, after `Received:`
+/// - `tls_cipher`: the TLS cipher the message was received over, if known, for noting
+///   in the `Received:` header
+/// - `reinject`: whether the message is being re-delivered, in which case any trace headers
+///   rattomail added last time are removed (see [`strip_rattomail_trace_headers`])
+/// - `message_id`: the `Message-ID:` to add, if the message lacks one (see
+///   [`make_message_id`])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub to_addr: String,
+    pub from_addr: String,
+    pub received_time: chrono::DateTime<Local>,
+    pub extra_headers: Vec<(String, String)>,
+    pub tls_cipher: Option<String>,
+    pub reinject: bool,
+    pub message_id: String,
+}
+
+impl Envelope {
+    /// Make an `Envelope` with no extra headers, and no TLS cipher, for a fresh delivery. Its
+    /// `message_id` is made from `received_time`, our PID, a random number and the hostname;
+    /// tests wanting a predictable one can just overwrite it.
+    pub fn new(to_addr: &str, from_addr: &str, received_time: chrono::DateTime<Local>) -> Self {
+        let message_id = make_message_id(
+            &received_time,
+            std::process::id(),
+            rand::random(),
+            &get_hostname(),
+        );
+        Envelope {
+            to_addr: to_addr.to_string(),
+            from_addr: from_addr.to_string(),
+            received_time,
+            extra_headers: Vec::new(),
+            tls_cipher: None,
+            reinject: false,
+            message_id,
+        }
+    }
+}
+
+/// Make a `Message-ID:` value, `<timestamp.pid.random@hostname>`, for a message received at
+/// `received_time` (as a Unix timestamp) by process `pid`, with `random` (in hex) making it
+/// unique among messages received by the same process in the same second.
+pub fn make_message_id(
+    received_time: &chrono::DateTime<Local>,
+    pid: u32,
+    random: u64,
+    hostname: &str,
+) -> String {
+    format!(
+        "<{}.{}.{:016x}@{}>",
+        received_time.timestamp(),
+        pid,
+        random,
+        hostname
+    )
 }
 
 /// Main context for the program. Represents values injected into main() for easy testing.
@@ -59,7 +682,7 @@ pub enum MessageDestination {
 ///   they don't exist)
 /// - `message_destination`: where to deliver mail to (maildirs or an output stream)
 /// - `received_time`: time the program was invoked. Used as the "Received" time in headers,
-///    and for the `Date:` header if we need to insert one.
+///   and for the `Date:` header if we need to insert one.
 #[derive(Debug)]
 pub struct MainContext {
     pub args: Vec<String>,
@@ -70,51 +693,195 @@ pub struct MainContext {
     pub received_time: chrono::DateTime<Local>,
 }
 
+/// Names the program recognizes being invoked as, by default. (Bizarrely, bsd-mailx sets
+/// argv[0] to "send-mail", for no good reason.)
+pub const PROGRAM_NAMES: [&str; 5] = ["rattomail", "attomail", "sendmail", "send-mail", "lmtp"];
+
+/// How the program behaves, selected by the name it's invoked as (see [`program_profile`]).
+/// Set in the config file's `programProfiles` with `full`, `sendmail` or `lmtp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramProfile {
+    /// rewrite headers and deliver, as `rattomail` (or `attomail`)
+    Full,
+    /// compatibility with traditional `sendmail`: as `Full`, but every failure exits with a
+    /// `sysexits.h` code (see [`main`])
+    Sendmail,
+    /// act as an LMTP server (not supported yet)
+    Lmtp,
+}
+
+impl std::str::FromStr for ProgramProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(ProgramProfile::Full),
+            "sendmail" => Ok(ProgramProfile::Sendmail),
+            "lmtp" => Ok(ProgramProfile::Lmtp),
+            _ => Err(anyhow!(
+                "'{}' is not a program profile (should be full, sendmail or lmtp)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProgramProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ProgramProfile::Full => "full",
+            ProgramProfile::Sendmail => "sendmail",
+            ProgramProfile::Lmtp => "lmtp",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The [`ProgramProfile`] selected by each of the [`PROGRAM_NAMES`], unless the config file's
+/// `programProfiles` says otherwise.
+pub const DEFAULT_PROGRAM_PROFILES: [(&str, ProgramProfile); 5] = [
+    ("rattomail", ProgramProfile::Full),
+    ("attomail", ProgramProfile::Full),
+    ("sendmail", ProgramProfile::Sendmail),
+    ("send-mail", ProgramProfile::Sendmail),
+    ("lmtp", ProgramProfile::Lmtp),
+];
+
+/// Get the [`ProgramProfile`] for being invoked as `prog_name` (as returned by
+/// `normalize_prog_name`): from `overrides` (normally `Config::programProfiles`) if it's
+/// listed there, or else from [`DEFAULT_PROGRAM_PROFILES`]. Any other name (such as one a
+/// library caller allows, or `bogus_rattomail`) gets [`ProgramProfile::Full`], since it's
+/// already been accepted as a name the program may be invoked as.
+pub fn program_profile(prog_name: &str, overrides: &[(String, ProgramProfile)]) -> ProgramProfile {
+    overrides
+        .iter()
+        .find(|(name, _)| name == prog_name)
+        .map(|&(_, profile)| profile)
+        .or_else(|| {
+            DEFAULT_PROGRAM_PROFILES
+                .iter()
+                .find(|(name, _)| *name == prog_name)
+                .map(|&(_, profile)| profile)
+        })
+        .unwrap_or(ProgramProfile::Full)
+}
+
+/// Information about this build of the program.
+///
+/// - `name`, `version`: the package name and version
+/// - `config_path`: the config file path baked in at build time (from `ATTOMAIL_CONFIG_PATH`)
+/// - `program_names`: names the program recognizes being invoked as
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BuildMetadata {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub config_path: &'static str,
+    pub program_names: Vec<String>,
+}
+
+/// Get the [`BuildMetadata`] for this build, with the default [`PROGRAM_NAMES`].
+pub fn build_metadata() -> BuildMetadata {
+    BuildMetadata {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        config_path: env!("ATTOMAIL_CONFIG_PATH"),
+        program_names: PROGRAM_NAMES.iter().map(|name| name.to_string()).collect(),
+    }
+}
+
+/// Quote a string as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl BuildMetadata {
+    /// Render as a single-line JSON object, e.g.
+    /// `{"name":"rattomail","version":"0.1.0","config_path":"/etc/attomail.conf","program_names":["rattomail"]}`.
+    pub fn to_json(&self) -> String {
+        let program_names: Vec<String> =
+            self.program_names.iter().map(|n| json_string(n)).collect();
+        format!(
+            "{{\"name\":{},\"version\":{},\"config_path\":{},\"program_names\":[{}]}}",
+            json_string(self.name),
+            json_string(self.version),
+            json_string(self.config_path),
+            program_names.join(",")
+        )
+    }
+}
+
 /// Normalize the program name to one of the names we expect to be invoked as:
-/// e.g. `rattomail`, `attomail`, or `sendmail`. If the name is not one of these, exit with an
-/// error message.
-fn normalize_prog_name(valid_names: &[&str], prog_name: &String) -> String {
+/// e.g. `rattomail`, `attomail`, or `sendmail` -- or any of the `extra_names` (normally the
+/// config file's `programNames`). If the name is not one of these, returns an error.
+fn normalize_prog_name(
+    valid_names: &[&str],
+    extra_names: &[String],
+    prog_name: &str,
+) -> Result<String> {
     // last component of program's path
     let last_component = Path::new(&prog_name)
         .file_name()
         .and_then(|name| name.to_str())
-        .unwrap_or_else(|| {
-            eprintln!("Error: Invalid program name '{}'.", prog_name);
-            std::process::exit(1);
-        });
+        .ok_or_else(|| anyhow!("Invalid program name '{}'.", prog_name))?;
 
     // Check if the last component matches any of the valid names
-    if valid_names.contains(&last_component) {
-        return last_component.to_string();
+    if valid_names.contains(&last_component) || extra_names.iter().any(|n| n == last_component) {
+        return Ok(last_component.to_string());
     }
 
     // invalid program name
-    eprintln!(
-        "Error: Invalid program name '{}'. Only {:?} are allowed.",
-        prog_name, valid_names
-    );
-    std::process::exit(1);
+    let allowed: Vec<&str> = valid_names
+        .iter()
+        .copied()
+        .chain(extra_names.iter().map(|name| name.as_str()))
+        .collect();
+    Err(anyhow!(
+        "Invalid program name '{}'. Only {:?} are allowed.",
+        prog_name,
+        allowed
+    ))
+}
+
+/// The config file's `programNames`, for checking the program name (see
+/// [`normalize_prog_name`]) before the config file is properly read. If it can't be read,
+/// there are none -- the error is reported once the config file is read for real.
+fn configured_program_names(config_path: &str) -> Vec<String> {
+    read_config_ini(config_path).map_or_else(|_| Vec::new(), |config| config.programNames)
 }
 
 /// Build a CLI parser for the program.
 /// Most of the arguments exist only for compatibility with sendmail, and are ignored.
-/// The only arguments we actually use are `-f`, `-bm`, and '-X', and (if present) a
-/// positional argument, the recipient address.
+/// The sendmail arguments we actually use are `-f`, `-bm`, `-X`, `-t` and `-N` (while `-i`,
+/// `-o` and `-O` are understood, but only logged), along with our own long options and (if
+/// present) a positional argument, the recipient address.
 ///
 /// The `-f` argument is used to specify the sender (from) envelope address. The address
 /// must not contain non-ASCII-graphical characters (see <https://doc.rust-lang.org/std/primitive.char.html#method.is_ascii_graphic>
 /// or libc isgraphic).
 /// If not specified, the program
-/// should use the current user's username (which is checked once privileges are dropped,
+/// should use the username in `$LOGNAME` or `$USER` if that's an existing user, or otherwise
+/// the current user's username (which is checked once privileges are dropped,
 /// and we've changed to the user specified in the config file).
 ///
 /// The `-bm` argument is used to specify the mode of operation. If `-bm` or `-b m` is supplied, the program
 /// will read the message from stdin (which is the default mode of operation anyway).
 /// Given any other argument to `-b`, the program should print an error message and exit.
 ///
-/// The `-X` argument is used to specify a logfile. The only permissible arguments for
-/// rattomail are `/dev/stderr` and '-' (which has the same meaning as `/dev/stderr`).
-/// Given any other argument, the program should print an error message and exit.
+/// The `-X` argument is used to specify a logfile. The permissible arguments for
+/// rattomail are `/dev/stderr` and '-' (which has the same meaning as `/dev/stderr`), plus
+/// any paths listed in the config file's `allowedLogfiles` (which can only be checked once
+/// the config file has been read). Given any other argument, the program should print an
+/// error message and exit.
 ///
 /// Polite user-mail agents will normally provide the recipient address, but because
 /// some don't (e.g. bsd-mailx), we don't mandate it.
@@ -127,29 +894,45 @@ pub fn build_cli() -> Command {
             .action(ArgAction::Version)
             .help("Print version")
     )
+    .arg(
+        Arg::new("version_json")
+            .long("version-json")
+            .action(ArgAction::SetTrue)
+            .help("Print version and build information as JSON")
+    )
 
-    // actual args we use - `-f sender`, `-bm`, and `-X logfile`
+    // actual args we use - `-f sender`, `-bm`, `-X logfile` (`/dev/stderr`, `-`, or one of
+    // the config file's allowedLogfiles), `-t`, and `-N conditions`
     .arg(Arg::new("sender_env").short('f').value_name("ADDRESS")
-        .help("Sender (from) envelope address. If not specified, the current user is used. Must not contain non-ASCII, whitespace or non-printable characters."))
+        .help("Sender (from) envelope address. If not specified, $LOGNAME or $USER (if an existing user) or the current user is used. Must not contain non-ASCII, whitespace or non-printable characters."))
     .arg(Arg::new("b").short('b').value_name("MODE")
         .help("-bm: Read input from stdin (default), everything else - error"))
     .arg(Arg::new("logfile").short('X').value_name("LOGFILE")
-        .help("Log debugging messages to a file. The only valid values are /dev/stderr and '-', which has the same meaning. (Originally: 'Log mailer traffic')"))
+        .help("Log debugging messages to a file. The only valid values are /dev/stderr and '-', which has the same meaning, plus any listed in the config file's allowedLogfiles. (Originally: 'Log mailer traffic')"))
+    .arg(Arg::new("t").short('t')
+        .action(ArgAction::SetTrue)
+        .conflicts_with("recipients_from_mailto")
+        .help("Deliver to the recipients in the message's To:, Cc: and Bcc: headers (and any RECIPIENT given as well). Bcc: headers are removed from the delivered message"))
+    .arg(Arg::new("N").short('N').value_name("CONDITIONS")
+        .help("Delivery status notification conditions: 'never', or any of 'success', 'failure' and 'delay', separated by commas. Since we deliver immediately, these are only recorded, in an X-DSN-Notify: header"))
 
-    // ignored args that take no argument - i, n, t
+    // args which are understood, but only logged - `-i`, `-o option`, and `-O option=value`
     .arg(Arg::new("i").short('i')
         .action(ArgAction::SetTrue)
-        .help("Ignored, used only for compatibility with sendmail. (Originally: 'Ignore dots alone on lines by themselves in incoming messages.')"))
+        .help("Mostly ignored, used only for compatibility with sendmail: the same as -oi. (Originally: 'Ignore dots alone on lines by themselves in incoming messages.')"))
+    .arg(Arg::new("o").short('o')
+        .action(ArgAction::Append)
+        .help("Mostly ignored, used only for compatibility with sendmail: only -oi and -od<MODE> are understood. (Originally: 'set an option')"))
+    .arg(Arg::new("O").short('O')
+        .action(ArgAction::Append)
+        .help("Mostly ignored, used only for compatibility with sendmail: only IgnoreDots and DeliveryMode are understood. (Originally: 'set an option')"))
+
+    // ignored args that take no argument - n
     .arg(Arg::new("n").short('n')
         .action(ArgAction::SetTrue)
         .help("Ignored, used only for compatibility with sendmail. (Originally: 'Don't do aliasing.')"))
-    .arg(Arg::new("t").short('t')
-        .action(ArgAction::SetTrue)
-        .help("Ignored, used only for compatibility with sendmail. (Originally: 'Read message to work out the recipients.')"))
 
-    // ignored args that do take an argument - o, p, q, r, v, B, C, F, N, O, R, U, V, X
-    .arg(Arg::new("o").short('o')
-        .help("Ignored, used only for compatibility with sendmail. (Originally: 'set an option')"))
+    // ignored args that do take an argument - p, q, r, v, B, C, F, R, U, V
     .arg(Arg::new("p").short('p')
         .help("Ignored, used only for compatibility with sendmail. (Originally: 'specify PROTOCOL')"))
     .arg(Arg::new("q").short('q')
@@ -164,10 +947,6 @@ pub fn build_cli() -> Command {
         .help("Ignored, used only for compatibility with sendmail. (Originally: 'use an alternate configuration file')"))
     .arg(Arg::new("F").short('F')
         .help("Ignored, used only for compatibility with sendmail. (Originally: 'set full name of sender')"))
-    .arg(Arg::new("N").short('N')
-        .help("Ignored, used only for compatibility with sendmail. (Originally: 'specify delivery status notification conditions')"))
-    .arg(Arg::new("O").short('O')
-        .help("Ignored, used only for compatibility with sendmail. (Originally: 'set an option')"))
     .arg(Arg::new("R").short('R')
         .help("Ignored, used only for compatibility with sendmail. (Originally: 'set amount of the message to be returned if the message bounces')"))
     .arg(Arg::new("U").short('U')
@@ -175,6 +954,100 @@ pub fn build_cli() -> Command {
     .arg(Arg::new("V").short('V')
         .help("Ignored, used only for compatibility with sendmail. (Originally: 'set envelope ID for notification')"))
 
+    .arg(Arg::new("quiet").long("quiet")
+        .action(ArgAction::SetTrue)
+        .help("Write nothing to stderr except errors: no warnings, and only error-level messages from -X"))
+
+    .arg(Arg::new("trace").long("trace")
+        .action(ArgAction::SetTrue)
+        .conflicts_with("quiet")
+        .help("For debugging: write the state after each stage of processing (headers, addresses, destination and message) to stderr"))
+
+    .arg(Arg::new("compat_strict").long("compat-strict")
+        .action(ArgAction::SetTrue)
+        .conflicts_with("trace")
+        .help("Behave exactly as sendmail is documented to: nothing written on success (as with --quiet), a sysexits code for every failure, and unknown options ignored"))
+
+    .arg(Arg::new("assume_user").long("assume-user").value_name("NAME")
+        .help("For testing only (when not dropping privileges): act as if invoked by NAME, for the default sender and recipient, and %u in the config file"))
+
+    .arg(Arg::new("maildir_root").long("maildir-root").value_name("PATH")
+        .help("Deliver to the Maildir at PATH, instead of the one given in the config file"))
+
+    .arg(Arg::new("chdir").long("chdir")
+        .action(ArgAction::SetTrue)
+        .help("Change to the Maildir's parent directory (after dropping privileges) before delivering"))
+
+    .arg(Arg::new("input_limit_lines").long("input-limit-lines").value_name("LINES")
+        .help("Reject input of more than LINES lines (headers and body together), overriding the config file's inputLineLimit"))
+
+    .arg(Arg::new("deliver_timeout").long("deliver-timeout").value_name("SECONDS")
+        .help("Give up on the delivery if it takes longer than SECONDS altogether, overriding the config file's deliveryTimeoutSeconds"))
+
+    .arg(Arg::new("headers_only").long("headers-only")
+        .action(ArgAction::SetTrue)
+        .help("When writing to an output stream (for testing), write only the message's headers"))
+
+    .arg(Arg::new("stdout_eml").long("stdout-eml")
+        .action(ArgAction::SetTrue)
+        .conflicts_with("headers_only")
+        .help("When writing to an output stream (for testing), write the message as a standalone .eml file, ending in a newline"))
+
+    .arg(Arg::new("verify_maildir_writable").long("verify-maildir-writable")
+        .action(ArgAction::SetTrue)
+        .help("Instead of delivering, check (as the delivery user) that a file can really be written, renamed and deleted in the Maildir's tmp directory, then exit"))
+
+    .arg(Arg::new("dump_message_to").long("dump-message-to").value_name("PATH")
+        .help("Also write a copy of the delivered message to PATH (created, or truncated, as the delivery user)"))
+
+    .arg(Arg::new("envelope_from_fd").long("envelope-from-fd").value_name("FD")
+        .conflicts_with("sender_env")
+        .help("Read the sender (from) envelope address from file descriptor FD (which must already be open), instead of -f, so it doesn't appear in the command line"))
+    .arg(Arg::new("recipient_fd").long("recipient-fd").value_name("FD")
+        .conflicts_with("to_address")
+        .help("Read the recipient address from file descriptor FD (which must already be open), instead of the command line, so it doesn't appear there"))
+    .arg(Arg::new("recipients_from_mailto").long("recipients-from-mailto")
+        .action(ArgAction::SetTrue)
+        .conflicts_with_all(["to_address", "recipient_fd"])
+        .help("Read the recipients from the MAILTO environment variable (comma-separated), as cron sets it. If MAILTO is set but empty, exit successfully without delivering anything"))
+
+    .arg(Arg::new("receipt_fd").long("receipt-fd").value_name("FD")
+        .help("After delivering, write a receipt for the delivered message to file descriptor FD (which must already be open)"))
+
+    .arg(Arg::new("pid_tag").long("pid-tag")
+        .action(ArgAction::SetTrue)
+        .help("Record the delivering process's PID (and its parent's) in an X-Rattomail-Pid: header, for correlating deliveries with system logs"))
+
+    .arg(Arg::new("reinject").long("reinject")
+        .action(ArgAction::SetTrue)
+        .help("The message is being re-delivered: remove the trace headers rattomail added last time, before adding new ones"))
+
+    .arg(Arg::new("discard").long("discard")
+        .action(ArgAction::SetTrue)
+        .help("Read and process the message as usual, but discard it instead of delivering it"))
+
+    // config file scaffolding
+    .arg(Arg::new("read_config_only").long("read-config-only")
+        .action(ArgAction::SetTrue)
+        .help("Only read and parse the config file, then exit: silently with status 0 if it's valid, or with EX_CONFIG (78) if not. Users and the Maildir aren't checked"))
+    .arg(Arg::new("generate_config").long("generate-config")
+        .action(ArgAction::SetTrue)
+        .requires("config_user")
+        .help("Write a minimal config file (for the user given with --user) instead of delivering mail, then exit"))
+    .arg(Arg::new("config_user").long("user").value_name("USER")
+        .requires("generate_config")
+        .help("With --generate-config: the user to deliver mail as"))
+    .arg(Arg::new("config_maildir").long("maildir").value_name("PATH")
+        .requires("generate_config")
+        .help("With --generate-config: the Maildir/new directory to deliver to (default: the user's ~/Maildir/new)"))
+    .arg(Arg::new("config_output").long("output").value_name("PATH")
+        .requires("generate_config")
+        .help("With --generate-config: where to write the config file (default: the standard config file path)"))
+    .arg(Arg::new("force").long("force")
+        .action(ArgAction::SetTrue)
+        .requires("generate_config")
+        .help("With --generate-config: overwrite any existing config file"))
+
     // positional arguments - to address
     .arg(Arg::new("to_address")
          .value_name("RECIPIENT")
@@ -182,19 +1055,175 @@ pub fn build_cli() -> Command {
          .required(false))
 }
 
+/// Header in which the delivery status notification conditions given with `-N` are recorded.
+pub const DSN_NOTIFY_HEADER: &str = "X-DSN-Notify";
+
+/// Header in which the delivering process's PID is recorded, with `--pid-tag`.
+pub const PID_HEADER: &str = "X-Rattomail-Pid";
+
+/// The value of the [`PID_HEADER`] for a delivery by process `pid`, invoked by process `ppid`:
+/// e.g. `1234 (parent 1200)`.
+pub fn pid_header_value(pid: u32, ppid: u32) -> String {
+    format!("{} (parent {})", pid, ppid)
+}
+
+/// Parse the delivery status notification conditions given with `-N`, as for the `NOTIFY`
+/// parameter of RFC 3461: either `never`, or a comma-separated list of any of `success`,
+/// `failure` and `delay` (ignoring case). Returns the conditions, lowercased, or an
+/// `EX_USAGE` error if they aren't valid -- including if `never` is combined with others.
+pub fn parse_dsn_notify(value: &str) -> Result<String> {
+    let invalid = |why: String| {
+        sysexit_error(
+            Sysexit::Usage,
+            format!("Invalid -N conditions '{}': {}", value, why),
+        )
+    };
+
+    let conditions: Vec<String> = value
+        .split(',')
+        .map(|condition| condition.trim().to_ascii_lowercase())
+        .collect();
+    for condition in &conditions {
+        if !["never", "success", "failure", "delay"].contains(&condition.as_str()) {
+            return Err(invalid(format!("unknown condition '{}'", condition)));
+        }
+    }
+    if conditions.len() > 1 && conditions.iter().any(|c| c == "never") {
+        return Err(invalid(
+            "'never' can't be combined with other conditions".to_string(),
+        ));
+    }
+
+    Ok(conditions.join(","))
+}
+
+/// Sendmail delivery modes, as set with `-od<MODE>` or `-O DeliveryMode=<MODE>`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DeliveryMode {
+    /// `b`: deliver in the background
+    Background,
+    /// `i`: deliver interactively (i.e., immediately, in the foreground)
+    Interactive,
+    /// `q`: just queue the message, to be delivered later
+    Queue,
+    /// `d`: defer delivery, including any lookups
+    Deferred,
+}
+
+/// Settings from sendmail's `-o`/`-O` options (plus `-i`) that we understand.
+///
+/// rattomail has no queue, and never treats a lone `.` as the end of a message -- it
+/// always behaves as if `-oi -odi` had been given. So these are just recorded for
+/// logging purposes.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SendmailOptions {
+    /// `-i`, `-oi` or `-O IgnoreDots`: a line consisting of a single dot doesn't end the message
+    pub ignore_dots: bool,
+    /// `-od<MODE>` or `-O DeliveryMode=<MODE>`
+    pub delivery_mode: Option<DeliveryMode>,
+}
+
+/// Parse a delivery mode, as used by `-od<MODE>`. Only the first letter is significant.
+fn parse_delivery_mode(mode: &str) -> Option<DeliveryMode> {
+    match mode.chars().next() {
+        Some('b') => Some(DeliveryMode::Background),
+        Some('i') => Some(DeliveryMode::Interactive),
+        Some('q') => Some(DeliveryMode::Queue),
+        Some('d') => Some(DeliveryMode::Deferred),
+        _ => None,
+    }
+}
+
+/// Parse the values given to sendmail's `-o` (short-form options, e.g. `i` for `-oi`) and `-O`
+/// (long-form options, e.g. `DeliveryMode=q`) into `SendmailOptions`. Options we don't
+/// understand are logged and otherwise ignored.
+pub fn parse_sendmail_options(short_opts: &[String], long_opts: &[String]) -> SendmailOptions {
+    let mut options = SendmailOptions::default();
+
+    for opt in short_opts {
+        let delivery_mode = opt.strip_prefix('d').and_then(parse_delivery_mode);
+        if opt == "i" {
+            options.ignore_dots = true;
+        } else if delivery_mode.is_some() {
+            options.delivery_mode = delivery_mode;
+        } else {
+            log::debug!("Ignoring unsupported option -o{}", opt);
+        }
+    }
+
+    for opt in long_opts {
+        let (name, value) = opt.split_once('=').unwrap_or((opt, ""));
+        match (name, value) {
+            ("IgnoreDots", "" | "true" | "True" | "T" | "t") => options.ignore_dots = true,
+            ("IgnoreDots", "false" | "False" | "F" | "f") => options.ignore_dots = false,
+            ("DeliveryMode", mode) if parse_delivery_mode(mode).is_some() => {
+                options.delivery_mode = parse_delivery_mode(mode)
+            }
+            _ => log::debug!("Ignoring unsupported option -O {}", opt),
+        }
+    }
+
+    options
+}
+
 /// Read a "key = value" style config file, and return the values as a Config struct.
 ///
 /// The file must contain a section with the following keys:
 ///   - mailDir: path to a subdir of a Maildir directory, where new mail will be stored
+///     (unless maildirRelative is given instead)
 ///   - userName: name of the user we expect the Maildir to be owned by. (When deliviering mail,
 ///     the program will attempt to drop privileges and run as this user.)
 ///
+/// and may optionally contain:
+///   - bounceOnQuotaFull, strictHeaders, addLinesHeader, normalizeDomainCase, reportTls,
+///     rejectPartial, validateRfc5322, requireFromHeader, requireHeaders, dbusNotify,
+///     receivedHideProduct, normalizeMacLineEndings, dedupeCriticalHeaders, fixQpMismatch,
+///     writeLastDelivery, setSenderHeader, dateAtDelivery: `true` or `false` (see [`Config`])
+///   - maxHeaderBytes, truncateAtBytes, maxMessageSize: a number of bytes
+///   - receivedFoldWidth: a number of characters
+///   - inputLineLimit: a number of lines
+///   - deliveryTimeoutSeconds: a number of seconds
+///   - messageMode: file permissions, in octal
+///   - deliverFlags: Maildir flags (see [`parse_maildir_flags`])
+///   - maildirRelative: a path relative to the delivery user's home directory
+///   - dateTimezone: a timezone name or UTC offset (see [`DateTimezone`])
+///   - datePartition: a date format string (see [`date_partition_folder`])
+///   - filenameRetries, nssRetries: a number of retries
+///   - indexCommand: a shell command
+///   - indexTimeout: a number of seconds
+///   - maxRecipients: a number of recipients
+///   - rejectSelfAddressed: `true`, `false`, `drop` or `reject` (see [`SelfAddressedAction`])
+///   - onEmptyInput: `deliver`, `reject` or `skip` (see [`EmptyInputAction`])
+///   - onInvalidSender: `reject`, `sanitize` or `fallback` (see [`InvalidSenderAction`])
+///   - tmpfileStrategy: `classic` or `o_tmpfile` (see [`TmpfileStrategy`])
+///   - warnRawSubject8bit: `true`, `false`, `warn` or `encode` (see [`RawSubjectAction`])
+///   - defaultReplyTo: a header value
+///   - deliveryGroup: a group name
+///   - receiptKey: a string; receiptKeyFile: a path
+///   - trustedUsers: a list of usernames, separated by commas and/or whitespace
+///   - allowedLogfiles: a list of paths, separated by commas and/or whitespace
+///   - programNames: a list of names, separated by commas and/or whitespace
+///   - programProfiles: a list of `name:profile` pairs, separated by commas and/or whitespace,
+///     where each profile is `full`, `sendmail` or `lmtp` (see [`ProgramProfile`])
+///
+/// Those all go in the unnamed section at the top of the file. It may be followed by any
+/// number of sections named after a recipient local part, each containing just a mailDir and
+/// userName, to deliver that recipient's mail with (see [`UserRoute`]).
+///
 pub fn read_config_ini<P>(file_path: P) -> Result<Config>
 where
     P: AsRef<Path>,
 {
     let file_path_ref = file_path.as_ref();
-    let conf = Ini::load_from_file(file_path_ref).map_err(|e| {
+    let bytes = std::fs::read(file_path_ref).map_err(|e| {
+        anyhow::anyhow!(
+            "Error reading config file {}: {}",
+            file_path_ref.display().to_string(),
+            e
+        )
+    })?;
+    let conts = config_text(&bytes, file_path_ref)?;
+    let conf = Ini::load_from_str(conts).map_err(|e| {
         anyhow::anyhow!(
             "Error reading config file {}: {}",
             file_path_ref.display().to_string(),
@@ -208,12 +1237,16 @@ where
             file_path_ref.display()
         )
     })?;
-    let mail_dir = section.get("mailDir").ok_or_else(|| {
-        anyhow!(
-            "Error reading config file {}: variable mailDir not found",
-            file_path_ref.display()
-        )
-    })?;
+    let maildir_relative = section.get("maildirRelative");
+    let mail_dir = section
+        .get("mailDir")
+        .or(maildir_relative.map(|_| ""))
+        .ok_or_else(|| {
+            anyhow!(
+                "Error reading config file {}: variable mailDir not found",
+                file_path_ref.display()
+            )
+        })?;
 
     let user_name = section.get("userName").ok_or_else(|| {
         anyhow!(
@@ -222,677 +1255,8739 @@ where
         )
     })?;
 
+    let getenv = |var: &str| env::var(var).ok();
+    let expand = |key: &str, val: &str| {
+        expand_env_vars(val, getenv).map_err(|e| {
+            anyhow!(
+                "Error reading config file {}: variable {}: {}",
+                file_path_ref.display(),
+                key,
+                e
+            )
+        })
+    };
+
+    let default_reply_to = section
+        .get("defaultReplyTo")
+        .map(|val| expand("defaultReplyTo", val))
+        .transpose()?;
+
+    let defaults = Config::default();
     let config = Config {
-        mailDir: mail_dir.to_string(),
-        userName: user_name.to_string(),
+        mailDir: expand("mailDir", mail_dir)?,
+        userName: expand("userName", user_name)?,
+        bounceOnQuotaFull: get_bool_key(section, "bounceOnQuotaFull", file_path_ref)?
+            .unwrap_or(defaults.bounceOnQuotaFull),
+        strictHeaders: get_bool_key(section, "strictHeaders", file_path_ref)?
+            .unwrap_or(defaults.strictHeaders),
+        maxHeaderBytes: get_u64_key(section, "maxHeaderBytes", file_path_ref)?
+            .unwrap_or(defaults.maxHeaderBytes),
+        defaultReplyTo: check_header_value(default_reply_to, "defaultReplyTo", file_path_ref)?,
+        addLinesHeader: get_bool_key(section, "addLinesHeader", file_path_ref)?
+            .unwrap_or(defaults.addLinesHeader),
+        normalizeDomainCase: get_bool_key(section, "normalizeDomainCase", file_path_ref)?
+            .unwrap_or(defaults.normalizeDomainCase),
+        trustedUsers: get_list_key(section, "trustedUsers").unwrap_or(defaults.trustedUsers),
+        allowedLogfiles: get_list_key(section, "allowedLogfiles")
+            .unwrap_or(defaults.allowedLogfiles),
+        programProfiles: get_program_profiles_key(section, "programProfiles", file_path_ref)?
+            .unwrap_or(defaults.programProfiles),
+        programNames: get_list_key(section, "programNames").unwrap_or(defaults.programNames),
+        dateTimezone: section
+            .get("dateTimezone")
+            .map(|val| {
+                val.parse::<DateTimezone>().map_err(|e| {
+                    anyhow!(
+                        "Error reading config file {}: variable dateTimezone: {}",
+                        file_path_ref.display(),
+                        e
+                    )
+                })
+            })
+            .transpose()?,
+        filenameRetries: get_u64_key(section, "filenameRetries", file_path_ref)?
+            .unwrap_or(defaults.filenameRetries),
+        nssRetries: get_u64_key(section, "nssRetries", file_path_ref)?
+            .unwrap_or(defaults.nssRetries),
+        indexCommand: section.get("indexCommand").map(|val| val.to_string()),
+        indexTimeout: get_u64_key(section, "indexTimeout", file_path_ref)?
+            .unwrap_or(defaults.indexTimeout),
+        maxRecipients: get_u64_key(section, "maxRecipients", file_path_ref)?
+            .unwrap_or(defaults.maxRecipients),
+        reportTls: get_bool_key(section, "reportTls", file_path_ref)?.unwrap_or(defaults.reportTls),
+        rejectSelfAddressed: get_self_addressed_key(section, "rejectSelfAddressed", file_path_ref)?
+            .unwrap_or(defaults.rejectSelfAddressed),
+        onEmptyInput: get_empty_input_key(section, "onEmptyInput", file_path_ref)?
+            .unwrap_or(defaults.onEmptyInput),
+        onInvalidSender: get_invalid_sender_key(section, "onInvalidSender", file_path_ref)?
+            .unwrap_or(defaults.onInvalidSender),
+        tmpfileStrategy: get_tmpfile_strategy_key(section, "tmpfileStrategy", file_path_ref)?
+            .unwrap_or(defaults.tmpfileStrategy),
+        rejectPartial: get_bool_key(section, "rejectPartial", file_path_ref)?
+            .unwrap_or(defaults.rejectPartial),
+        validateRfc5322: get_bool_key(section, "validateRfc5322", file_path_ref)?
+            .unwrap_or(defaults.validateRfc5322),
+        datePartition: section
+            .get("datePartition")
+            .map(|val| {
+                check_date_format(val)
+                    .map(|_| val.to_string())
+                    .map_err(|e| {
+                        anyhow!(
+                            "Error reading config file {}: variable datePartition: {}",
+                            file_path_ref.display(),
+                            e
+                        )
+                    })
+            })
+            .transpose()?,
+        requireFromHeader: get_bool_key(section, "requireFromHeader", file_path_ref)?
+            .unwrap_or(defaults.requireFromHeader),
+        requireHeaders: get_bool_key(section, "requireHeaders", file_path_ref)?
+            .unwrap_or(defaults.requireHeaders),
+        dbusNotify: get_bool_key(section, "dbusNotify", file_path_ref)?
+            .unwrap_or(defaults.dbusNotify),
+        receivedHideProduct: get_bool_key(section, "receivedHideProduct", file_path_ref)?
+            .unwrap_or(defaults.receivedHideProduct),
+        warnRawSubject8bit: get_raw_subject_key(section, "warnRawSubject8bit", file_path_ref)?
+            .unwrap_or(defaults.warnRawSubject8bit),
+        truncateAtBytes: get_u64_key(section, "truncateAtBytes", file_path_ref)?,
+        maxMessageSize: get_u64_key(section, "maxMessageSize", file_path_ref)?,
+        receivedFoldWidth: get_u64_key(section, "receivedFoldWidth", file_path_ref)?,
+        inputLineLimit: get_u64_key(section, "inputLineLimit", file_path_ref)?,
+        deliveryTimeoutSeconds: get_u64_key(section, "deliveryTimeoutSeconds", file_path_ref)?,
+        messageMode: get_mode_key(section, "messageMode", file_path_ref)?,
+        maildirRelative: maildir_relative.map(|val| val.to_string()),
+        deliverFlags: section
+            .get("deliverFlags")
+            .map(|val| {
+                parse_maildir_flags(val).map_err(|e| {
+                    anyhow!(
+                        "Error reading config file {}: {}",
+                        file_path_ref.display(),
+                        e
+                    )
+                })
+            })
+            .transpose()?,
+        deliveryGroup: section.get("deliveryGroup").map(|val| val.to_string()),
+        receiptKey: section.get("receiptKey").map(|val| val.to_string()),
+        receiptKeyFile: section.get("receiptKeyFile").map(|val| val.to_string()),
+        normalizeMacLineEndings: get_bool_key(section, "normalizeMacLineEndings", file_path_ref)?
+            .unwrap_or(defaults.normalizeMacLineEndings),
+        dedupeCriticalHeaders: get_bool_key(section, "dedupeCriticalHeaders", file_path_ref)?
+            .unwrap_or(defaults.dedupeCriticalHeaders),
+        fixQpMismatch: get_bool_key(section, "fixQpMismatch", file_path_ref)?
+            .unwrap_or(defaults.fixQpMismatch),
+        writeLastDelivery: get_bool_key(section, "writeLastDelivery", file_path_ref)?
+            .unwrap_or(defaults.writeLastDelivery),
+        setSenderHeader: get_bool_key(section, "setSenderHeader", file_path_ref)?
+            .unwrap_or(defaults.setSenderHeader),
+        dateAtDelivery: get_bool_key(section, "dateAtDelivery", file_path_ref)?
+            .unwrap_or(defaults.dateAtDelivery),
+        userRoutes: read_user_routes(&conf, file_path_ref)?,
     };
 
+    // a message would be truncated before its size was checked, so (silently) the limit would
+    // hardly ever be reached -- it's only one or the other
+    if config.truncateAtBytes.is_some() && config.maxMessageSize.is_some() {
+        return Err(sysexit_error(
+            Sysexit::Config,
+            format!(
+                "Error reading config file {}: truncateAtBytes and maxMessageSize can't both be set",
+                file_path_ref.display()
+            ),
+        ));
+    }
+
     Ok(config)
 }
 
-/// Return the username of the current user, or exit with an error message.
-/// Exits the program, with an error message, on failure.
-pub fn get_current_user() -> String {
-    // Getting the current user's username is basically infallible, unless
-    // something has gone terribly wrong; testing failure scenarios is
-    // tricky; and shifting error-handling logic into `main` has little benefit.
-    // So we just exit with an error message if it happens.
+/// Read the per-recipient sections of a config file (see [`UserRoute`]). Each must have a
+/// mailDir and a userName, and nothing else; and no recipient may have more than one.
+fn read_user_routes(conf: &Ini, file_path: &Path) -> Result<Vec<UserRoute>> {
+    let mut routes: Vec<UserRoute> = Vec::new();
 
-    let uid: Uid = Uid::current();
-    let user: User = User::from_uid(uid).map_or_else(
-        |err| {
-            let desc = err.desc();
-            eprintln!(
-                "Couldn't get username for uid {}: errno was {} ({})",
-                uid, err, desc
+    for (name, section) in conf.iter() {
+        let Some(name) = name else {
+            continue;
+        };
+        let location = format!(
+            "Error reading config file {}: section [{}]",
+            file_path.display(),
+            name
+        );
+        if routes.iter().any(|route| route.name == name) {
+            anyhow::bail!("{}: appears more than once", location);
+        }
+        if let Some((key, _)) = section
+            .iter()
+            .find(|(key, _)| !["mailDir", "userName"].contains(key))
+        {
+            anyhow::bail!(
+                "{}: variable {} can't be set per recipient (only mailDir and userName can)",
+                location,
+                key
             );
-            std::process::exit(1);
-        },
-        |opt| {
-            opt.unwrap_or_else(|| {
-                eprintln!("Couldn't get username for uid {}: no such user", uid);
-                std::process::exit(1);
-            })
-        },
-    );
-    user.name
-}
+        }
+        let get = |key: &str| {
+            let val = section
+                .get(key)
+                .ok_or_else(|| anyhow!("{}: variable {} not found", location, key))?;
+            expand_env_vars(val, |var| env::var(var).ok())
+                .map_err(|e| anyhow!("{}: variable {}: {}", location, key, e))
+        };
 
-/// set up logging for a given logfile path. The only permissible paths, however, are
-/// `/dev/stderr` and `-` (which is equivalent to `/dev/stderr`). Any other path will
-/// cause the program to exit with an error message.
-fn init_logfile(logfile_path: String) {
-    let valid_logfiles = ["-", "/dev/stderr"];
+        routes.push(UserRoute {
+            name: name.to_string(),
+            mailDir: get("mailDir")?,
+            userName: get("userName")?,
+        });
+    }
 
-    if !valid_logfiles.contains(&logfile_path.as_str()) {
-        eprintln!(
-            "Error: Invalid logfile path '{}'. Only {:?} are allowed.",
-            logfile_path, valid_logfiles
-        );
-        std::process::exit(1);
-    }
+    Ok(routes)
+}
 
-    let logfile_path = if logfile_path == "-" {
-        "/dev/stdout".to_string()
-    } else {
-        logfile_path
-    };
+/// Check that the config file at `file_path` can be read and parsed, as `--read-config-only`
+/// does -- without looking up the users it names, or touching its Maildir. Any error is an
+/// `EX_CONFIG` one (see [`exit_code_for`]).
+pub fn check_config_file<P>(file_path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    read_config_ini(file_path)
+        .map(|_config| ())
+        .map_err(|e| sysexit_error(Sysexit::Config, format!("{:#}", e)))
+}
 
-    let logfile = File::create(logfile_path.clone()).unwrap_or_else(|e| {
-        eprintln!("Error creating log file '{}': {}", logfile_path, e);
-        std::process::exit(1);
-    });
-    let _ = WriteLogger::init(LevelFilter::Trace, simplelog::Config::default(), logfile);
+/// Get the text of a config file from its contents, `bytes`, stripping any UTF-8 byte order
+/// mark. If the contents aren't valid UTF-8, returns an `EX_CONFIG` error naming the file,
+/// and the line (and key, if there is one) where the invalid UTF-8 is.
+fn config_text<'a>(bytes: &'a [u8], file_path: &Path) -> Result<&'a str> {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+
+    std::str::from_utf8(bytes).map_err(|e| {
+        let bad_line_start = bytes[..e.valid_up_to()]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |pos| pos + 1);
+        let bad_line = bytes[bad_line_start..]
+            .split(|&b| b == b'\n')
+            .next()
+            .unwrap_or(&[]);
+        let line_num = bytes[..bad_line_start]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+            + 1;
+
+        let location = match bad_line.iter().position(|&b| b == b'=') {
+            Some(eq_pos) => format!(
+                "line {}, variable {}",
+                line_num,
+                String::from_utf8_lossy(&bad_line[..eq_pos]).trim()
+            ),
+            None => format!("line {}", line_num),
+        };
+        sysexit_error(
+            Sysexit::Config,
+            format!(
+                "Error reading config file {}: invalid UTF-8 at {}",
+                file_path.display(),
+                location
+            ),
+        )
+    })
 }
 
-/// Drop privileges to the specified user. If the specified user is root, exit with an error message.
-/// If an error occurs while dropping privileges, exit with an error message.
-fn drop_privileges(new_user: User) {
-    // We attempt to follow the recipe laid out in Viega et al, Secure Programming Cookbook for C and C++
-    // (O'Reilly, 2003), recipe 1.3, "Dropping Privileges in setuid Programs".
-    // We drop all ancillary groups, then the group privileges, then the user privileges,
-    // and finally check that we can't regain them.
+/// Get an optional boolean value from a config file section. `true`/`false`, `yes`/`no`
+/// and `1`/`0` are accepted; anything else is an error.
+fn get_bool_key(section: &ini::Properties, key: &str, file_path: &Path) -> Result<Option<bool>> {
+    match section.get(key) {
+        None => Ok(None),
+        Some("true") | Some("yes") | Some("1") => Ok(Some(true)),
+        Some("false") | Some("no") | Some("0") => Ok(Some(false)),
+        Some(other) => Err(anyhow!(
+            "Error reading config file {}: variable {} should be true or false, not '{}'",
+            file_path.display(),
+            key,
+            other
+        )),
+    }
+}
 
-    let old_uid = nix::unistd::geteuid();
-    let old_gid = nix::unistd::getegid();
+/// Get an optional [`RawSubjectAction`] from a config file section: `warn` or `encode`, or a
+/// boolean, where `true` means `warn` and `false` means leave the subject alone.
+fn get_raw_subject_key(
+    section: &ini::Properties,
+    key: &str,
+    file_path: &Path,
+) -> Result<Option<RawSubjectAction>> {
+    match section.get(key) {
+        None => Ok(None),
+        Some("encode") => Ok(Some(RawSubjectAction::Encode)),
+        Some("warn") | Some("true") | Some("yes") | Some("1") => {
+            Ok(Some(RawSubjectAction::Warn))
+        }
+        Some("false") | Some("no") | Some("0") => Ok(Some(RawSubjectAction::Ignore)),
+        Some(other) => Err(anyhow!(
+            "Error reading config file {}: variable {} should be true, false, warn or encode, not '{}'",
+            file_path.display(),
+            key,
+            other
+        )),
+    }
+}
 
-    let new_uid = new_user.uid;
+/// Get an optional [`SelfAddressedAction`] from a config file section: `drop` or `reject`, or
+/// a boolean, where `true` means `reject` and `false` means deliver as normal.
+fn get_self_addressed_key(
+    section: &ini::Properties,
+    key: &str,
+    file_path: &Path,
+) -> Result<Option<SelfAddressedAction>> {
+    match section.get(key) {
+        None => Ok(None),
+        Some("drop") => Ok(Some(SelfAddressedAction::Drop)),
+        Some("reject") | Some("true") | Some("yes") | Some("1") => {
+            Ok(Some(SelfAddressedAction::Reject))
+        }
+        Some("false") | Some("no") | Some("0") => Ok(Some(SelfAddressedAction::Deliver)),
+        Some(other) => Err(anyhow!(
+            "Error reading config file {}: variable {} should be true, false, drop or reject, not '{}'",
+            file_path.display(),
+            key,
+            other
+        )),
+    }
+}
 
-    if new_uid.is_root() {
-        eprintln!("Error: Cannot run as root. Please specify a different user in the config file.");
-        std::process::exit(1);
+/// Get an optional [`EmptyInputAction`] from a config file section: `deliver`, `reject` or
+/// `skip`.
+fn get_empty_input_key(
+    section: &ini::Properties,
+    key: &str,
+    file_path: &Path,
+) -> Result<Option<EmptyInputAction>> {
+    match section.get(key) {
+        None => Ok(None),
+        Some("deliver") => Ok(Some(EmptyInputAction::Deliver)),
+        Some("reject") => Ok(Some(EmptyInputAction::Reject)),
+        Some("skip") => Ok(Some(EmptyInputAction::Skip)),
+        Some(other) => Err(anyhow!(
+            "Error reading config file {}: variable {} should be deliver, reject or skip, not '{}'",
+            file_path.display(),
+            key,
+            other
+        )),
     }
+}
 
-    let new_gid = new_user.gid;
+/// Get an optional [`InvalidSenderAction`] from a config file section: `reject`, `sanitize` or
+/// `fallback`.
+fn get_invalid_sender_key(
+    section: &ini::Properties,
+    key: &str,
+    file_path: &Path,
+) -> Result<Option<InvalidSenderAction>> {
+    match section.get(key) {
+        None => Ok(None),
+        Some("reject") => Ok(Some(InvalidSenderAction::Reject)),
+        Some("sanitize") => Ok(Some(InvalidSenderAction::Sanitize)),
+        Some("fallback") => Ok(Some(InvalidSenderAction::Fallback)),
+        Some(other) => Err(anyhow!(
+            "Error reading config file {}: variable {} should be reject, sanitize or fallback, not '{}'",
+            file_path.display(),
+            key,
+            other
+        )),
+    }
+}
 
-    // drop ancillary groups from process
-    nix::unistd::setgroups(&[new_gid]).unwrap_or_else(|e| {
-        eprintln!("Error: Couldn't drop ancillary groups: {}", e);
-        std::process::exit(1);
-    });
+/// Get an optional [`TmpfileStrategy`] from a config file section: `classic` or `o_tmpfile`.
+fn get_tmpfile_strategy_key(
+    section: &ini::Properties,
+    key: &str,
+    file_path: &Path,
+) -> Result<Option<TmpfileStrategy>> {
+    match section.get(key) {
+        None => Ok(None),
+        Some("classic") => Ok(Some(TmpfileStrategy::Classic)),
+        Some("o_tmpfile") => Ok(Some(TmpfileStrategy::OTmpfile)),
+        Some(other) => Err(anyhow!(
+            "Error reading config file {}: variable {} should be classic or o_tmpfile, not '{}'",
+            file_path.display(),
+            key,
+            other
+        )),
+    }
+}
 
-    nix::unistd::setresgid(new_gid, new_gid, new_gid).unwrap_or_else(|e| {
-        eprintln!("Error: Couldn't drop group privileges: {}", e);
-        std::process::exit(1);
-    });
+/// Get an optional non-negative integer value from a config file section.
+fn get_u64_key(section: &ini::Properties, key: &str, file_path: &Path) -> Result<Option<u64>> {
+    section
+        .get(key)
+        .map(|val| {
+            val.parse::<u64>().map_err(|_| {
+                anyhow!(
+                    "Error reading config file {}: variable {} should be a non-negative integer, not '{}'",
+                    file_path.display(),
+                    key,
+                    val
+                )
+            })
+        })
+        .transpose()
+}
 
-    nix::unistd::setresuid(new_uid, new_uid, new_uid).unwrap_or_else(|e| {
-        eprintln!("Error: Couldn't drop user privileges: {}", e);
-        std::process::exit(1);
-    });
+/// Get an optional file permission mode, in octal (e.g. `0600` or `600`), from a config file
+/// section.
+fn get_mode_key(section: &ini::Properties, key: &str, file_path: &Path) -> Result<Option<u32>> {
+    section
+        .get(key)
+        .map(|val| {
+            u32::from_str_radix(val, 8)
+                .ok()
+                .filter(|&mode| mode <= 0o7777)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Error reading config file {}: variable {} should be an octal file mode (e.g. 0600), not '{}'",
+                        file_path.display(),
+                        key,
+                        val
+                    )
+                })
+        })
+        .transpose()
+}
 
-    // check that privileges can't be regained
+/// Get an optional list of values, separated by commas and/or whitespace, from a config file
+/// section.
+fn get_list_key(section: &ini::Properties, key: &str) -> Option<Vec<String>> {
+    section.get(key).map(|val| {
+        val.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|item| !item.is_empty())
+            .map(|item| item.to_string())
+            .collect()
+    })
+}
 
-    if new_gid != old_gid {
-        let res = nix::unistd::setresgid(old_gid, old_gid, old_gid);
-        match res {
-            Ok(_) => {
-                eprintln!(
-                    "Error: Failed to drop group privileges: setresgid of old gid {} succeeded unexpectedly",
-                    old_gid
-                );
-                std::process::exit(1);
+/// Get an optional list of `name:profile` pairs (see [`ProgramProfile`]), separated by commas
+/// and/or whitespace, from a config file section.
+fn get_program_profiles_key(
+    section: &ini::Properties,
+    key: &str,
+    file_path: &Path,
+) -> Result<Option<Vec<(String, ProgramProfile)>>> {
+    let Some(items) = get_list_key(section, key) else {
+        return Ok(None);
+    };
+    items
+        .iter()
+        .map(|item| {
+            let (name, profile) = item
+                .split_once(':')
+                .ok_or_else(|| anyhow!("'{}' should be of the form name:profile", item))?;
+            Ok((name.to_string(), profile.parse::<ProgramProfile>()?))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(Some)
+        .map_err(|e| {
+            anyhow!(
+                "Error reading config file {}: variable {}: {}",
+                file_path.display(),
+                key,
+                e
+            )
+        })
+}
+
+/// Check an optional config value which is to be used as the value of a header.
+/// It must be non-empty, and contain only printable ASCII characters and spaces -- in
+/// particular, no newlines, which could be used to inject extra headers.
+fn check_header_value(
+    value: Option<String>,
+    key: &str,
+    file_path: &Path,
+) -> Result<Option<String>> {
+    value
+        .map(|val| {
+            if is_safe_header_value(&val) {
+                Ok(val)
+            } else {
+                Err(anyhow!(
+                    "Error reading config file {}: variable {} must be non-empty, and contain only printable ASCII characters and spaces",
+                    file_path.display(),
+                    key
+                ))
             }
-            Err(_e) => {}
-        }
+        })
+        .transpose()
+}
+
+/// Expand environment variable references in a config value, using `getenv` to look up
+/// variables. `${VAR}` is replaced with the value of `VAR`, and it's an error for `VAR`
+/// to be undefined; `${VAR:-default}` is replaced with `default` if `VAR` is undefined or
+/// empty. A `$` not followed by `{` is left as-is.
+pub fn expand_env_vars<F>(value: &str, getenv: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        result.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| anyhow!("unterminated '${{' in '{}'", value))?;
+        let reference = &after[..end];
+
+        let expansion = match reference.split_once(":-") {
+            Some((name, default)) => getenv(name)
+                .filter(|val| !val.is_empty())
+                .unwrap_or_else(|| default.to_string()),
+            None => getenv(reference)
+                .ok_or_else(|| anyhow!("environment variable {} is not defined", reference))?,
+        };
+        result.push_str(&expansion);
+        rest = &after[end + 1..];
     }
+    result.push_str(rest);
 
-    if new_uid != old_uid {
-        let res = nix::unistd::setresuid(old_uid, old_uid, old_uid);
-        match res {
-            Ok(_) => {
-                eprintln!(
-                    "Error: Failed to drop user privileges: setresuid of old uid {} succeeded unexpectedly",
-                    old_uid
-                );
-                std::process::exit(1);
-            }
-            Err(_e) => {}
-        }
+    Ok(result)
+}
+
+/// Check that a string is safe to use as a synthesized header value: non-empty, and printable
+/// ASCII or spaces only.
+pub fn is_safe_header_value(s: &str) -> bool {
+    !s.trim().is_empty() && s.chars().all(|c| c == ' ' || c.is_ascii_graphic())
+}
+
+/// Make a minimal `Config` for delivering mail as `user_name`, to `mail_dir` if given, or else
+/// the user's `~/Maildir/new`. The user must exist and not be root, and the mail directory
+/// must be a valid path to a Maildir's `new` directory (see [`parse_maildir_new_path`]).
+pub fn generate_config(user_name: &str, mail_dir: Option<&str>) -> Result<Config> {
+    let user = User::from_name(user_name)
+        .map_err(|e| anyhow!("Couldn't look up user '{}': errno was {}", user_name, e))?
+        .ok_or_else(|| anyhow!("User '{}' is not a valid user", user_name))?;
+    if user.uid.is_root() {
+        anyhow::bail!("Cannot deliver mail as root. Please specify a different user.");
     }
+
+    let mail_dir = match mail_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => user.dir.join("Maildir").join("new"),
+    };
+    parse_maildir_new_path(&mail_dir)?;
+
+    Ok(Config {
+        mailDir: mail_dir.display().to_string(),
+        userName: user.name,
+        ..Config::default()
+    })
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct HeaderStatus {
-    pub has_from: bool,
-    pub has_date: bool,
+/// Write a config file made by [`generate_config`] to `file_path` -- refusing to overwrite an
+/// existing file, unless `force` is set.
+pub fn write_new_config_ini<P>(config: &Config, file_path: P, force: bool) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let file_path_ref = file_path.as_ref();
+    if !force && file_path_ref.exists() {
+        anyhow::bail!(
+            "Config file {} already exists (use --force to overwrite it)",
+            file_path_ref.display()
+        );
+    }
+    write_config_ini(config, file_path_ref)
 }
 
-/// Read headers from an input stream, and write them to an output stream, recording whether
-/// we've seen the `From:` and `Date:` headers.
-///
-/// Should write all the header lines to the output stream, _except_ for the final newline
-/// indicating the end of the headers. (Because the caller will want to write additional
-/// headers after this function returns.)
-///
-/// So if `Foo: foo\nBar: bar\n\n` is read from the input, `Foo: foo\nBar: bar\n` should be
-/// written to the output.
+/// Write a `Config` to a file, in the "key = value" format [`read_config_ini`] expects.
 ///
-/// Returns a `HeaderStatus` struct indicating whether we've seen the `From:` and `Date:` headers.
-/// If an error occurs while reading or writing, returns an error.
+/// Optional keys are only written if their value differs from the default, so that
+/// the resulting file is as minimal as possible.
+pub fn write_config_ini<P>(config: &Config, file_path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let file_path_ref = file_path.as_ref();
+    let mut conf = Ini::new();
+    let defaults = Config::default();
+    let mut section = conf.with_general_section();
+    section
+        .set("mailDir", config.mailDir.as_str())
+        .set("userName", config.userName.as_str());
+    if config.bounceOnQuotaFull != defaults.bounceOnQuotaFull {
+        section.set("bounceOnQuotaFull", config.bounceOnQuotaFull.to_string());
+    }
+    if config.strictHeaders != defaults.strictHeaders {
+        section.set("strictHeaders", config.strictHeaders.to_string());
+    }
+    if config.maxHeaderBytes != defaults.maxHeaderBytes {
+        section.set("maxHeaderBytes", config.maxHeaderBytes.to_string());
+    }
+    if let Some(reply_to) = &config.defaultReplyTo {
+        section.set("defaultReplyTo", reply_to.as_str());
+    }
+    if config.addLinesHeader != defaults.addLinesHeader {
+        section.set("addLinesHeader", config.addLinesHeader.to_string());
+    }
+    if config.normalizeDomainCase != defaults.normalizeDomainCase {
+        section.set(
+            "normalizeDomainCase",
+            config.normalizeDomainCase.to_string(),
+        );
+    }
+    if config.trustedUsers != defaults.trustedUsers {
+        section.set("trustedUsers", config.trustedUsers.join(","));
+    }
+    if config.allowedLogfiles != defaults.allowedLogfiles {
+        section.set("allowedLogfiles", config.allowedLogfiles.join(","));
+    }
+    if config.programNames != defaults.programNames {
+        section.set("programNames", config.programNames.join(","));
+    }
+    if config.programProfiles != defaults.programProfiles {
+        let profiles: Vec<String> = config
+            .programProfiles
+            .iter()
+            .map(|(name, profile)| format!("{}:{}", name, profile))
+            .collect();
+        section.set("programProfiles", profiles.join(","));
+    }
+    if let Some(tz) = &config.dateTimezone {
+        section.set("dateTimezone", tz.to_string());
+    }
+    if config.filenameRetries != defaults.filenameRetries {
+        section.set("filenameRetries", config.filenameRetries.to_string());
+    }
+    if config.nssRetries != defaults.nssRetries {
+        section.set("nssRetries", config.nssRetries.to_string());
+    }
+    if let Some(command) = &config.indexCommand {
+        section.set("indexCommand", command.as_str());
+    }
+    if config.indexTimeout != defaults.indexTimeout {
+        section.set("indexTimeout", config.indexTimeout.to_string());
+    }
+    if config.maxRecipients != defaults.maxRecipients {
+        section.set("maxRecipients", config.maxRecipients.to_string());
+    }
+    if config.reportTls != defaults.reportTls {
+        section.set("reportTls", config.reportTls.to_string());
+    }
+    if config.rejectPartial != defaults.rejectPartial {
+        section.set("rejectPartial", config.rejectPartial.to_string());
+    }
+    if config.receivedHideProduct != defaults.receivedHideProduct {
+        section.set(
+            "receivedHideProduct",
+            config.receivedHideProduct.to_string(),
+        );
+    }
+    if let Some(width) = config.receivedFoldWidth {
+        section.set("receivedFoldWidth", width.to_string());
+    }
+    if config.dbusNotify != defaults.dbusNotify {
+        section.set("dbusNotify", config.dbusNotify.to_string());
+    }
+    if config.requireFromHeader != defaults.requireFromHeader {
+        section.set("requireFromHeader", config.requireFromHeader.to_string());
+    }
+    if config.requireHeaders != defaults.requireHeaders {
+        section.set("requireHeaders", config.requireHeaders.to_string());
+    }
+    if let Some(date_partition) = &config.datePartition {
+        section.set("datePartition", date_partition);
+    }
+    if config.validateRfc5322 != defaults.validateRfc5322 {
+        section.set("validateRfc5322", config.validateRfc5322.to_string());
+    }
+    if config.setSenderHeader != defaults.setSenderHeader {
+        section.set("setSenderHeader", config.setSenderHeader.to_string());
+    }
+    if config.dateAtDelivery != defaults.dateAtDelivery {
+        section.set("dateAtDelivery", config.dateAtDelivery.to_string());
+    }
+    if config.writeLastDelivery != defaults.writeLastDelivery {
+        section.set("writeLastDelivery", config.writeLastDelivery.to_string());
+    }
+    if config.fixQpMismatch != defaults.fixQpMismatch {
+        section.set("fixQpMismatch", config.fixQpMismatch.to_string());
+    }
+    if config.dedupeCriticalHeaders != defaults.dedupeCriticalHeaders {
+        section.set(
+            "dedupeCriticalHeaders",
+            config.dedupeCriticalHeaders.to_string(),
+        );
+    }
+    if config.normalizeMacLineEndings != defaults.normalizeMacLineEndings {
+        section.set(
+            "normalizeMacLineEndings",
+            config.normalizeMacLineEndings.to_string(),
+        );
+    }
+    if let Some(delivery_group) = &config.deliveryGroup {
+        section.set("deliveryGroup", delivery_group);
+    }
+    if let Some(receipt_key) = &config.receiptKey {
+        section.set("receiptKey", receipt_key);
+    }
+    if let Some(receipt_key_file) = &config.receiptKeyFile {
+        section.set("receiptKeyFile", receipt_key_file);
+    }
+    if let Some(truncate_at_bytes) = config.truncateAtBytes {
+        section.set("truncateAtBytes", truncate_at_bytes.to_string());
+    }
+    if let Some(limit) = config.maxMessageSize {
+        section.set("maxMessageSize", limit.to_string());
+    }
+    if let Some(limit) = config.inputLineLimit {
+        section.set("inputLineLimit", limit.to_string());
+    }
+    if let Some(timeout) = config.deliveryTimeoutSeconds {
+        section.set("deliveryTimeoutSeconds", timeout.to_string());
+    }
+    if let Some(relative) = &config.maildirRelative {
+        section.set("maildirRelative", relative);
+    }
+    if let Some(flags) = &config.deliverFlags {
+        section.set("deliverFlags", flags);
+    }
+    if let Some(mode) = config.messageMode {
+        section.set("messageMode", format!("{:04o}", mode));
+    }
+    match config.warnRawSubject8bit {
+        RawSubjectAction::Ignore => {}
+        RawSubjectAction::Warn => {
+            section.set("warnRawSubject8bit", "warn");
+        }
+        RawSubjectAction::Encode => {
+            section.set("warnRawSubject8bit", "encode");
+        }
+    }
+    match config.rejectSelfAddressed {
+        SelfAddressedAction::Deliver => {}
+        SelfAddressedAction::Drop => {
+            section.set("rejectSelfAddressed", "drop");
+        }
+        SelfAddressedAction::Reject => {
+            section.set("rejectSelfAddressed", "reject");
+        }
+    }
+    match config.onEmptyInput {
+        EmptyInputAction::Deliver => {}
+        EmptyInputAction::Reject => {
+            section.set("onEmptyInput", "reject");
+        }
+        EmptyInputAction::Skip => {
+            section.set("onEmptyInput", "skip");
+        }
+    }
+    match config.onInvalidSender {
+        InvalidSenderAction::Reject => {}
+        InvalidSenderAction::Sanitize => {
+            section.set("onInvalidSender", "sanitize");
+        }
+        InvalidSenderAction::Fallback => {
+            section.set("onInvalidSender", "fallback");
+        }
+    }
+    if config.tmpfileStrategy == TmpfileStrategy::OTmpfile {
+        section.set("tmpfileStrategy", "o_tmpfile");
+    }
+    for route in &config.userRoutes {
+        conf.with_section(Some(route.name.as_str()))
+            .set("mailDir", route.mailDir.as_str())
+            .set("userName", route.userName.as_str());
+    }
+
+    conf.write_to_file(file_path_ref).map_err(|e| {
+        anyhow!(
+            "Error writing config file {}: {}",
+            file_path_ref.display(),
+            e
+        )
+    })
+}
+
+/// Check that the local part of a recipient address is safe to substitute into a path
+/// or username: it must be non-empty, consist only of ASCII letters, digits, `.`, `_` and `-`,
+/// and not start with `.` or `-`. (So it can't escape a directory, or be taken as an option.)
+fn is_safe_local_part(local_part: &str) -> bool {
+    !local_part.is_empty()
+        && !local_part.starts_with(['.', '-'])
+        && local_part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// Substitute the local part of `recipient` (the part before any `@`) for every `%u` in
+/// `template`. Returns an error if the local part isn't safe to use in a path or username
+/// (see [`is_safe_local_part`]). A template without `%u` is returned unchanged.
 ///
 /// Example
 ///
 /// ```
-/// use std::io::Cursor;
-/// use rattomail::{process_existing_headers,HeaderStatus};
-///
-/// let input = b"Foo: foo\nBar: bar\n\n";
-/// let mut output = Vec::new();
-/// let result = process_existing_headers(&mut Cursor::new(input), &mut output).unwrap();
+/// use rattomail::expand_user_template;
 ///
-/// assert_eq!(result, HeaderStatus { has_from: false, has_date: false });
-/// assert_eq!(output, b"Foo: foo\nBar: bar\n");
+/// let path = expand_user_template("/home/%u/Maildir/new", "alice@example.com").unwrap();
+/// assert_eq!(path, "/home/alice/Maildir/new");
+/// assert!(expand_user_template("/home/%u/Maildir/new", "../bob").is_err());
 /// ```
-///
-pub fn process_existing_headers<R: BufRead, W: Write>(
-    input: &mut R,
-    output: &mut W,
-) -> Result<HeaderStatus> {
-    let mut buffer = Vec::new();
-    // record what headers we see
-    let mut header_status = HeaderStatus {
-        has_from: false,
-        has_date: false,
-        //reached_header_end: false,
-    };
+pub fn expand_user_template(template: &str, recipient: &str) -> Result<String> {
+    if !template.contains("%u") {
+        return Ok(template.to_string());
+    }
 
-    loop {
-        // read until newline or EOF
-        let bytes_read = input
-            .read_until(b'\n', &mut buffer)
-            .map_err(|e| anyhow!("Error reading input: {}", e))?;
+    let local_part = recipient
+        .rsplit_once('@')
+        .map_or(recipient, |(local_part, _domain)| local_part);
 
-        // check for headers
-        if buffer.starts_with(b"From: ") {
-            header_status.has_from = true;
-        } else if buffer.starts_with(b"Date: ") {
-            header_status.has_date = true;
-        } else if buffer == b"\n" || buffer == b"\r\n" {
-            // end of headers
-            break;
-        }
+    if !is_safe_local_part(local_part) {
+        anyhow::bail!(
+            "recipient '{}' can't be substituted for %u: local part must consist only of letters, digits, '.', '_' and '-', and not start with '.' or '-'",
+            recipient
+        );
+    }
 
-        if bytes_read == 0 {
-            break; // reached EOF
-        }
+    Ok(template.replace("%u", local_part))
+}
 
-        output
-            .write_all(&buffer)
-            .map_err(|e| anyhow!("Error writing output: {}", e))?;
+/// Whether a config's `mailDir` or `userName` uses the `%u` placeholder, and so depends on
+/// the recipient.
+pub fn uses_recipient_template(config: &Config) -> bool {
+    config.mailDir.contains("%u") || config.userName.contains("%u")
+}
 
-        // clear for next read
-        buffer.clear();
+/// Expand `%u` placeholders in the `mailDir` and `userName` of a config (see
+/// [`expand_user_template`]), given the recipient address (if any) from the command line.
+/// It's an error for the config to use `%u` if there's no recipient.
+pub fn expand_recipient_templates(config: Config, recipient: Option<&str>) -> Result<Config> {
+    match recipient {
+        None if uses_recipient_template(&config) => {
+            anyhow::bail!("config file uses %u, but no recipient address was given")
+        }
+        None => Ok(config),
+        Some(recipient) => Ok(Config {
+            mailDir: expand_user_template(&config.mailDir, recipient)?,
+            userName: expand_user_template(&config.userName, recipient)?,
+            ..config
+        }),
     }
+}
 
-    // ensure all buffered data is written
-    output
-        .flush()
-        .map_err(|e| anyhow!("Error flushing output: {}", e))?;
+/// The [`UserRoute`] in `config` for `recipient`, if there is one: the one named after its
+/// local part.
+pub fn find_user_route<'a>(config: &'a Config, recipient: &str) -> Option<&'a UserRoute> {
+    let local_part = recipient
+        .rsplit_once('@')
+        .map_or(recipient, |(local_part, _domain)| local_part);
 
-    Ok(header_status)
+    config
+        .userRoutes
+        .iter()
+        .find(|route| route.name == local_part)
 }
 
-/// Make a `Received:` header for a given `to_addr`, `from_addr`, and `time`.
-pub fn make_received_header(
-    to_addr: &str,
-    from_addr: &str,
-    time: &chrono::DateTime<Local>,
-) -> String {
-    let date_str = time.to_rfc2822();
-    format!(
-        "Received: for {} with local (rattomail) (envelope-from {}); {}\n",
-        to_addr, from_addr, date_str
-    )
+/// Apply a config's per-recipient routing, given the recipient address (if any) from the
+/// command line: if the recipient has a [`UserRoute`], its `mailDir` and `userName` replace
+/// the config's defaults (and `maildirRelative`, which would otherwise override `mailDir`,
+/// is dropped). Otherwise, the config is returned unchanged.
+pub fn route_for_recipient(config: Config, recipient: Option<&str>) -> Config {
+    match recipient.and_then(|recipient| find_user_route(&config, recipient)) {
+        None => config,
+        Some(route) => {
+            let route = route.clone();
+            log::debug!("Routing recipient to section [{}]", route.name);
+            Config {
+                mailDir: route.mailDir,
+                userName: route.userName,
+                maildirRelative: None,
+                ..config
+            }
+        }
+    }
 }
 
-/// Write a `Received:` header to the output stream, then existing headers
-/// (read from input stream), plus `Date:` and `From:` headers if missing,
-/// then a blank line terminator to indicate end of headers.
-///
-/// The current time is used to get a date-time for the `Received` header.
+/// How long [`UserCache`] entries are kept by default. Short, since the passwd database can
+/// change underneath us, and the point is only to avoid repeated lookups of the same user
+/// within one delivery (or a short burst of them).
+pub const USER_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// A thread-safe cache of user lookups by name, so that looking up the same user repeatedly
+/// (e.g. once per recipient) doesn't mean repeated NSS lookups.
 ///
-/// Arguments:
+/// Entries expire after a TTL, so changes to the passwd database are picked up. Failed
+/// lookups aren't cached.
 ///
-/// - `input`: input stream to read existing headers from
-/// - `output`: output stream to write headers to
-/// - `to_addr`: recipient address
-/// - `from_addr`: sender address
-pub fn write_headers<R: BufRead, W: Write>(
-    input: &mut R,
-    output: &mut W,
-    to_addr: &str,
-    from_addr: &str,
-    received_time: &chrono::DateTime<Local>,
-) -> Result<()> {
-    let received_header = make_received_header(to_addr, from_addr, received_time);
-    let received_header = received_header.as_bytes();
-    output
-        .write_all(received_header)
-        .map_err(|e| anyhow!("Error writing output: {}", e))?;
-
-    let res = process_existing_headers(input, output)?;
+/// By default, lookups by name are done with [`User::from_name`] (which uses the reentrant
+/// `getpwnam_r`); a different resolver can be supplied with [`UserCache::with_resolver`].
+/// Lookups by uid, with [`UserCache::from_uid`], always use [`User::from_uid`] (which uses
+/// `getpwuid_r`).
+pub struct UserCache<F> {
+    resolve: F,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, (Instant, Option<User>)>>,
+    uid_entries: Mutex<HashMap<Uid, (Instant, Option<User>)>>,
+}
 
-    if res.has_date == false {
-        let date_str = received_time.to_rfc2822();
-        output
-            .write_all(format!("Date: {}\n", date_str).as_bytes())
-            .map_err(|e| anyhow!("Error writing output: {}", e))?;
+impl UserCache<fn(&str) -> nix::Result<Option<User>>> {
+    /// Make a cache which looks up users with [`User::from_name`].
+    pub fn new(ttl: Duration) -> Self {
+        UserCache::with_resolver(ttl, User::from_name)
     }
+}
 
-    if res.has_from == false {
-        output
-            .write_all(format!("From: {}\n", from_addr).as_bytes())
-            .map_err(|e| anyhow!("Error writing output: {}", e))?;
+impl<F> UserCache<F>
+where
+    F: Fn(&str) -> nix::Result<Option<User>>,
+{
+    /// Make a cache which looks up users with `resolve`.
+    pub fn with_resolver(ttl: Duration, resolve: F) -> Self {
+        UserCache {
+            resolve,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            uid_entries: Mutex::new(HashMap::new()),
+        }
     }
 
-    // write end-of-headers newline
-    output
-        .write_all(b"\n")
-        .map_err(|e| anyhow!("Error writing output: {}", e))?;
+    /// Look up a user by name, using a cached result if there's one less than the TTL old.
+    pub fn from_name(&self, name: &str) -> nix::Result<Option<User>> {
+        cached_lookup(&self.entries, self.ttl, name.to_string(), || {
+            (self.resolve)(name)
+        })
+    }
 
-    Ok(())
+    /// Look up a user by uid, using a cached result if there's one less than the TTL old.
+    pub fn from_uid(&self, uid: Uid) -> nix::Result<Option<User>> {
+        cached_lookup(&self.uid_entries, self.ttl, uid, || User::from_uid(uid))
+    }
 }
 
-/// Just reads lines from input and writes to output.
-pub fn write_body<R: BufRead, W: Write>(input: &mut R, output: &mut W) -> Result<()> {
-    let mut buffer = Vec::new();
-
-    loop {
-        // read until newline or EOF
-        let bytes_read = input
-            .read_until(b'\n', &mut buffer)
-            .map_err(|e| anyhow!("Error reading input: {}", e))?;
+/// Look `key` up in a [`UserCache`]'s `entries`, falling back to `resolve` (and caching what
+/// it finds, if it succeeds) if there's no entry less than `ttl` old.
+fn cached_lookup<K: std::hash::Hash + Eq>(
+    entries: &Mutex<HashMap<K, (Instant, Option<User>)>>,
+    ttl: Duration,
+    key: K,
+    resolve: impl FnOnce() -> nix::Result<Option<User>>,
+) -> nix::Result<Option<User>> {
+    let mut entries = entries.lock().unwrap_or_else(|e| e.into_inner());
 
-        if bytes_read == 0 {
-            break; // reached EOF
+    if let Some((looked_up_at, user)) = entries.get(&key) {
+        if looked_up_at.elapsed() < ttl {
+            return Ok(user.clone());
         }
+    }
 
-        output
-            .write_all(&buffer)
-            .map_err(|e| anyhow!("Error writing output: {}", e))?;
+    let user = resolve()?;
+    entries.insert(key, (Instant::now(), user.clone()));
+    Ok(user)
+}
 
-        // clear for next read
-        buffer.clear();
+/// How long to wait before the first retry of a failed user lookup (see [`retry_nss_lookup`]).
+pub const NSS_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Do an NSS `lookup` (e.g. with [`User::from_name`]), retrying it up to `retries` times if it
+/// fails -- since directory backends such as LDAP or SSSD can be briefly unavailable -- after
+/// waiting `backoff`, doubling the wait each time. A lookup which succeeds, but finds no such
+/// user, isn't retried. If every attempt fails, the last error is returned.
+pub fn retry_nss_lookup<T>(
+    retries: u64,
+    backoff: Duration,
+    mut lookup: impl FnMut() -> nix::Result<T>,
+) -> nix::Result<T> {
+    let mut delay = backoff;
+    for _ in 0..retries {
+        match lookup() {
+            Err(e) => {
+                log::debug!("User lookup failed ({}), retrying in {:?}", e, delay);
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+            res => return res,
+        }
     }
+    lookup()
+}
 
-    // flush all buffered data
-    output
-        .flush()
-        .map_err(|e| anyhow!("Error flushing output: {}", e))?;
+/// Look up the user `name` (given in the config file) to deliver as, with `cache`, retrying
+/// failed lookups as for [`retry_nss_lookup`]. It's an `EX_NOUSER` error if there's no such
+/// user, and an `EX_TEMPFAIL` error if the lookup still fails after `retries` retries.
+pub fn lookup_delivery_user<F>(
+    cache: &UserCache<F>,
+    name: &str,
+    retries: u64,
+    backoff: Duration,
+) -> Result<User>
+where
+    F: Fn(&str) -> nix::Result<Option<User>>,
+{
+    match retry_nss_lookup(retries, backoff, || cache.from_name(name)) {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(sysexit_error(
+            Sysexit::NoUser,
+            format!(
+                "User '{}' specified in config file is not a valid user",
+                name
+            ),
+        )),
+        Err(err) => Err(sysexit_error(
+            Sysexit::TempFail,
+            format!(
+                "Couldn't get user '{}' specified in config file: errno was {}",
+                name, err
+            ),
+        )),
+    }
+}
 
-    Ok(())
+/// Return the username of the current user, looked up with `cache`, and retrying failed lookups
+/// up to `retries` times as for [`retry_nss_lookup`]. (Which is basically infallible, unless something has gone
+/// terribly wrong -- or the passwd database is served by a directory backend that's briefly
+/// unavailable, in which case it's an `EX_TEMPFAIL` error if every attempt fails.)
+pub fn get_current_user<F>(cache: &UserCache<F>, retries: u64) -> Result<String>
+where
+    F: Fn(&str) -> nix::Result<Option<User>>,
+{
+    let uid: Uid = Uid::current();
+    let lookup = || cache.from_uid(uid);
+    let user: User = retry_nss_lookup(retries, NSS_RETRY_BACKOFF, lookup)
+        .map_err(|err| {
+            sysexit_error(
+                Sysexit::TempFail,
+                format!(
+                    "Couldn't get username for uid {}: errno was {} ({})",
+                    uid,
+                    err,
+                    err.desc()
+                ),
+            )
+        })?
+        .ok_or_else(|| anyhow!("Couldn't get username for uid {}: no such user", uid))?;
+    Ok(user.name)
 }
 
-/// Read headers from input stream, and write a "delivered" version of the
-/// message to the output stream (adding appropriate headers).
+/// Look for a username in the environment (`$LOGNAME`, then `$USER`, as traditional sendmail
+/// does), using `getenv` to look up variables. A value is only used if it's plausible (see
+/// [`is_plausible_string`]) and is the name of an existing user (looked up with `cache`).
+pub fn get_env_user<F, G>(cache: &UserCache<G>, getenv: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+    G: Fn(&str) -> nix::Result<Option<User>>,
+{
+    ["LOGNAME", "USER"]
+        .iter()
+        .filter_map(|var| getenv(var))
+        .find(|name| is_plausible_string(name) && matches!(cache.from_name(name), Ok(Some(_))))
+}
+
+/// Work out the default envelope sender, used when `-f` isn't given: the username from the
+/// environment, if there's a valid one there (see [`get_env_user`]), otherwise the current
+/// user's username (see [`get_current_user`], which is given `retries`). Users are looked up
+/// with `cache`.
+pub fn get_default_sender<F>(cache: &UserCache<F>, retries: u64) -> Result<String>
+where
+    F: Fn(&str) -> nix::Result<Option<User>>,
+{
+    match get_env_user(cache, |var| env::var(var).ok()) {
+        Some(user) => Ok(user),
+        None => get_current_user(cache, retries),
+    }
+}
+
+/// Lowercase the domain part of an address (the part after the last `@`), leaving the local
+/// part as-is, since that's (technically) case-sensitive. Addresses without an `@` are
+/// returned unchanged.
+pub fn normalize_domain_case(addr: &str) -> String {
+    match addr.rsplit_once('@') {
+        Some((local, domain)) => format!("{}@{}", local, domain.to_ascii_lowercase()),
+        None => addr.to_string(),
+    }
+}
+
+/// If `addr` is in the `Name <addr-spec>` form some MUAs pass as a recipient (e.g.
+/// `"Alice" <alice@example.com>`), return the bare `addr-spec` inside the angle brackets.
+/// Returns `None` for anything else, including a bare address.
+pub fn extract_addr_spec(addr: &str) -> Option<&str> {
+    let inner = addr.trim().strip_suffix('>')?;
+    let (_name, spec) = inner.rsplit_once('<')?;
+    Some(spec.trim())
+}
+
+/// Header in which a recipient given in `Name <addr-spec>` form is recorded in full (see
+/// [`extract_addr_spec`]).
+pub const ORIGINAL_TO_HEADER: &str = "X-Original-To";
+
+/// Environment variable cron sets to the recipients of a job's output (see
+/// [`parse_mailto`]).
+pub const MAILTO_VAR: &str = "MAILTO";
+
+/// Split a `MAILTO`-style comma-separated list of recipients into addresses, trimming
+/// whitespace around each. Empty entries are skipped, so an empty (or all-blank) value gives
+/// no recipients at all -- which cron takes to mean "don't send mail".
+pub fn parse_mailto(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Get the hostname of this machine, falling back to `localhost` if it can't be determined.
+pub fn get_hostname() -> String {
+    nix::unistd::gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+/// Make an `X-Authentication-Warning:` header, as traditional sendmail does, if
+/// `invoking_user` has set the envelope sender (with `-f`) to something other than
+/// their own username, and isn't trusted to do so. root, and the users in
+/// `config.trustedUsers`, are trusted.
 ///
-/// The current time is used to get a date-time for the `Received` header.
-fn write_message<R: BufRead, W: Write>(
-    input: &mut R,
-    output: &mut W,
-    to_addr: &str,
-    from_addr: &str,
-    received_time: &chrono::DateTime<Local>,
-) -> Result<()> {
-    write_headers(input, output, &to_addr, &from_addr, &received_time)
-        .context("Failed to write headers")?;
+/// Returns a `(name, value)` header pair, or `None` if no warning is needed.
+pub fn authentication_warning(
+    invoking_user: &str,
+    sender: &str,
+    config: &Config,
+    hostname: &str,
+) -> Option<(String, String)> {
+    let is_trusted =
+        invoking_user == "root" || config.trustedUsers.iter().any(|u| u == invoking_user);
 
-    write_body(input, output).context("Failed to write message body")?;
+    if is_trusted || sender == invoking_user {
+        return None;
+    }
+
+    Some((
+        "X-Authentication-Warning".to_string(),
+        format!(
+            "{}: {} set sender to {} using -f",
+            hostname, invoking_user, sender
+        ),
+    ))
+}
+
+/// Whether `--quiet` was given, in which case only errors are written to stderr.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Set whether only errors should be written to stderr (as with `--quiet`): warnings printed
+/// with [`print_warning`], and any debug logging below error level, are suppressed.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Print a (non-error) warning to stderr, unless `--quiet` was given (see [`set_quiet`]).
+pub fn print_warning(message: &str) {
+    if !QUIET.load(Ordering::Relaxed) {
+        let _ = writeln!(std::io::stderr(), "Warning: {}", message);
+    }
+}
 
+/// Whether `--compat-strict` was given (see [`set_compat_strict`]).
+static COMPAT_STRICT: AtomicBool = AtomicBool::new(false);
+
+/// Set whether to stick strictly to the documented `sendmail` contract (as with
+/// `--compat-strict`). Apart from implying `--quiet` (see [`set_quiet`]), this means every
+/// failure exits with a `sysexits.h` code (see [`failure_exit_code`]), and unknown options are
+/// ignored (see [`parse_args_tolerantly`]).
+pub fn set_compat_strict(strict: bool) {
+    COMPAT_STRICT.store(strict, Ordering::Relaxed);
+}
+
+/// The exit code for a failure that doesn't have a more specific [`Sysexit`] code: normally 1,
+/// but with `--compat-strict`, `EX_TEMPFAIL` -- so the invoking MTA retries the message later,
+/// rather than bouncing it.
+pub fn failure_exit_code() -> i32 {
+    if COMPAT_STRICT.load(Ordering::Relaxed) {
+        Sysexit::TempFail.code()
+    } else {
+        1
+    }
+}
+
+/// Parse the command-line `args` with `cli`, ignoring any options it doesn't recognize (as
+/// traditional `sendmail` does, and as `--compat-strict` requires) rather than treating them
+/// as an error. An unknown option is only ignored if it's a command-line argument of its own
+/// (possibly with an `=value`), not if it's bundled with other short options.
+pub fn parse_args_tolerantly(cli: &Command, args: &[String]) -> Result<ArgMatches, clap::Error> {
+    let mut args = args.to_vec();
+    loop {
+        let err = match cli.clone().try_get_matches_from(&args) {
+            Err(err) if err.kind() == ErrorKind::UnknownArgument => err,
+            res => return res,
+        };
+        let Some(ContextValue::String(unknown)) = err.get(ContextKind::InvalidArg) else {
+            return Err(err);
+        };
+        let with_value = format!("{}=", unknown);
+        let Some(pos) = args
+            .iter()
+            .skip(1)
+            .position(|arg| arg == unknown || arg.starts_with(&with_value))
+        else {
+            return Err(err);
+        };
+        args.remove(pos + 1);
+    }
+}
+
+/// Whether `--trace` was given, in which case the state after each stage of processing is
+/// written to stderr (see [`trace_stage`]).
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// Set whether to trace each stage of processing (as with `--trace`).
+pub fn set_trace(trace: bool) {
+    TRACE.store(trace, Ordering::Relaxed);
+}
+
+/// If `--trace` was given (see [`set_trace`]), write the state after a stage of processing to
+/// stderr: a `rattomail trace: [<stage>]` marker line, followed by whatever `details` returns,
+/// indented. (`details` isn't called otherwise, so needn't be cheap.)
+pub fn trace_stage<F: FnOnce() -> String>(stage: &str, details: F) {
+    if !TRACE.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut trace = format!("rattomail trace: [{}]\n", stage);
+    for line in details().lines() {
+        trace.push_str("    ");
+        trace.push_str(line);
+        trace.push('\n');
+    }
+    let _ = std::io::stderr().write_all(trace.as_bytes());
+}
+
+/// Format `data` as a hex dump, 16 bytes per line, each line giving the offset (starting from
+/// `offset`), the bytes in hex, and the bytes as ASCII (with `.` for anything unprintable).
+pub fn hexdump(data: &[u8], offset: u64) -> String {
+    let mut dump = String::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump.push_str(&format!(
+            "{:08x}  {:<47}  |{}|\n",
+            offset + 16 * i as u64,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    dump
+}
+
+/// How many bytes from each end of the delivered message to include in the `--trace` output.
+const TRACE_DUMP_BYTES: usize = 64;
+
+/// Trace the delivered message (see [`trace_stage`]): its size, and hex dumps of its first and
+/// last [`TRACE_DUMP_BYTES`] bytes (`head` and `tail`).
+fn trace_message(size: u64, head: &[u8], tail: &[u8]) {
+    trace_stage("message", || {
+        let mut details = format!("{} bytes\nfirst {} bytes:\n", size, head.len());
+        details.push_str(&hexdump(head, 0));
+        details.push_str(&format!("last {} bytes:\n", tail.len()));
+        details.push_str(&hexdump(tail, size - tail.len() as u64));
+        details
+    });
+}
+
+/// A writer which passes everything through to another writer, keeping track of how much was
+/// written and the first and last few bytes of it, so the message written can be traced (see
+/// [`trace_message`]) by [`MessageTracer::finish`].
+pub struct MessageTracer<W> {
+    inner: W,
+    size: u64,
+    head: Vec<u8>,
+    tail: Vec<u8>,
+}
+
+impl<W: Write> MessageTracer<W> {
+    pub fn new(inner: W) -> Self {
+        MessageTracer {
+            inner,
+            size: 0,
+            head: Vec::new(),
+            tail: Vec::new(),
+        }
+    }
+
+    /// Trace the message that was written.
+    pub fn finish(self) {
+        trace_message(self.size, &self.head, &self.tail);
+    }
+}
+
+impl<W: Write> Write for MessageTracer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let written = &buf[..n];
+        self.size += n as u64;
+
+        let head_room = TRACE_DUMP_BYTES - self.head.len();
+        self.head
+            .extend_from_slice(&written[..head_room.min(written.len())]);
+        self.tail
+            .extend_from_slice(&written[written.len().saturating_sub(TRACE_DUMP_BYTES)..]);
+        if self.tail.len() > TRACE_DUMP_BYTES {
+            self.tail.drain(..self.tail.len() - TRACE_DUMP_BYTES);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Logfile paths which may always be given with `-X`: `/dev/stderr`, and `-` (which is
+/// equivalent to `/dev/stderr`).
+pub const DEFAULT_LOGFILES: [&str; 2] = ["-", "/dev/stderr"];
+
+/// Check that `logfile_path` may be given with `-X`: that it's one of the
+/// [`DEFAULT_LOGFILES`], or one of the `extra` paths allowed by the config file's
+/// `allowedLogfiles` (e.g. `/dev/fd/2`). Since the logfile is opened before privileges are
+/// dropped, arbitrary paths aren't allowed.
+pub fn check_logfile_allowed(logfile_path: &str, extra: &[String]) -> Result<()> {
+    if DEFAULT_LOGFILES.contains(&logfile_path) || extra.iter().any(|path| path == logfile_path) {
+        return Ok(());
+    }
+    let allowed: Vec<&str> = DEFAULT_LOGFILES
+        .iter()
+        .copied()
+        .chain(extra.iter().map(|path| path.as_str()))
+        .collect();
+    Err(anyhow!(
+        "Invalid logfile path '{}'. Only {:?} are allowed.",
+        logfile_path,
+        allowed
+    ))
+}
+
+/// set up logging for a given logfile path, which must be allowed by
+/// [`check_logfile_allowed`]. Any other path is an error.
+fn init_logfile(logfile_path: String, extra_logfiles: &[String]) -> Result<(), MainError> {
+    check_logfile_allowed(&logfile_path, extra_logfiles)?;
+
+    let logfile_path = if logfile_path == "-" {
+        "/dev/stdout".to_string()
+    } else {
+        logfile_path
+    };
+
+    let logfile = File::create(logfile_path.clone()).map_err(|e| {
+        MainError::caused_by(
+            failure_exit_code(),
+            format!("Error creating log file '{}': {}", logfile_path, e),
+            e.into(),
+        )
+    })?;
+    let level = if QUIET.load(Ordering::Relaxed) {
+        LevelFilter::Error
+    } else {
+        LevelFilter::Trace
+    };
+    let _ = WriteLogger::init(level, simplelog::Config::default(), logfile);
     Ok(())
 }
 
-/// validate that a path to a Maildir/new
+/// Get the group ID to deliver mail to `user` as: that of `config.deliveryGroup`, if set (e.g.
+/// for delivering into a group-writable shared Maildir), or else the user's primary group.
+/// If the group doesn't exist, returns an `EX_CONFIG` error.
+pub fn delivery_group_id(config: &Config, user: &User) -> Result<Gid> {
+    let Some(group_name) = &config.deliveryGroup else {
+        return Ok(user.gid);
+    };
+    match Group::from_name(group_name) {
+        Ok(Some(group)) => Ok(group.gid),
+        Ok(None) => Err(sysexit_error(
+            Sysexit::Config,
+            format!(
+                "Group '{}' specified in config file is not a valid group",
+                group_name
+            ),
+        )),
+        Err(err) => Err(sysexit_error(
+            Sysexit::Config,
+            format!(
+                "Couldn't get group '{}' specified in config file: errno was {}",
+                group_name, err
+            ),
+        )),
+    }
+}
+
+/// Check whether a process with real, effective and saved user IDs `uids`, and group IDs
+/// `gids`, is already running as exactly `new_uid` and `new_gid` -- so that there are no
+/// privileges left to drop.
+pub fn already_running_as(
+    uids: nix::unistd::ResUid,
+    gids: nix::unistd::ResGid,
+    new_uid: Uid,
+    new_gid: Gid,
+) -> bool {
+    [uids.real, uids.effective, uids.saved]
+        .iter()
+        .all(|&uid| uid == new_uid)
+        && [gids.real, gids.effective, gids.saved]
+            .iter()
+            .all(|&gid| gid == new_gid)
+}
+
+/// Permanently change to `new_user`'s UID, and the GID `new_gid` (normally the user's primary
+/// group -- see [`delivery_group_id`]).
 ///
-/// - is an absolute path
-/// - has `new` as the last component
-/// - has `Maildir` as the second-to-last component
+/// If the process is already running as that UID and GID (see [`already_running_as`]) --
+/// e.g. in a rootless container, where `setgroups` and `setresuid` aren't permitted -- nothing
+/// needs doing, so the privilege-dropping calls are skipped.
 ///
-/// Return Maildir path if valid, or an error message if not.
-pub fn parse_maildir_new_path(maildir_new_path: &Path) -> Result<PathBuf> {
-    if !maildir_new_path.is_absolute() {
+/// It's an error if `new_user` is root, or if the privileges can't be dropped (or could be
+/// regained afterwards).
+fn drop_privileges(new_user: User, new_gid: Gid) -> Result<()> {
+    // We attempt to follow the recipe laid out in Viega et al, Secure Programming Cookbook for C and C++
+    // (O'Reilly, 2003), recipe 1.3, "Dropping Privileges in setuid Programs".
+    // We drop all ancillary groups, then the group privileges, then the user privileges,
+    // and finally check that we can't regain them.
+
+    let old_uid = nix::unistd::geteuid();
+    let old_gid = nix::unistd::getegid();
+
+    let new_uid = new_user.uid;
+
+    if new_uid.is_root() {
+        anyhow::bail!("Cannot run as root. Please specify a different user in the config file.");
+    }
+
+    if let (Ok(uids), Ok(gids)) = (nix::unistd::getresuid(), nix::unistd::getresgid()) {
+        if already_running_as(uids, gids, new_uid, new_gid) {
+            log::debug!(
+                "Already running as uid {} and gid {}, so no privileges to drop",
+                new_uid,
+                new_gid
+            );
+            return Ok(());
+        }
+    }
+
+    // drop ancillary groups from process
+    nix::unistd::setgroups(&[new_gid])
+        .map_err(|e| anyhow!("Couldn't drop ancillary groups: {}", e))?;
+
+    nix::unistd::setresgid(new_gid, new_gid, new_gid)
+        .map_err(|e| anyhow!("Couldn't drop group privileges: {}", e))?;
+
+    nix::unistd::setresuid(new_uid, new_uid, new_uid)
+        .map_err(|e| anyhow!("Couldn't drop user privileges: {}", e))?;
+
+    // check that privileges can't be regained
+
+    if new_gid != old_gid && nix::unistd::setresgid(old_gid, old_gid, old_gid).is_ok() {
         anyhow::bail!(
-            "mailDir path '{:?}' is not an absolute path",
-            maildir_new_path
+            "Failed to drop group privileges: setresgid of old gid {} succeeded unexpectedly",
+            old_gid
         );
     }
 
-    let components = maildir_new_path.components().collect::<Vec<_>>();
+    if new_uid != old_uid && nix::unistd::setresuid(old_uid, old_uid, old_uid).is_ok() {
+        anyhow::bail!(
+            "Failed to drop user privileges: setresuid of old uid {} succeeded unexpectedly",
+            old_uid
+        );
+    }
 
-    match components.as_slice() {
-        [.., second_to_last, last] => {
-            if last.as_os_str() != "new" {
-                anyhow::bail!(
-                    "mailDir path '{:?}' does not end in 'new'",
-                    maildir_new_path
-                );
+    Ok(())
+}
+
+/// Which of the headers we care about were present in a message.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HeaderStatus {
+    pub has_from: bool,
+    pub has_date: bool,
+    pub has_reply_to: bool,
+    pub has_message_id: bool,
+    /// whether the message declares `Content-Transfer-Encoding: quoted-printable`
+    pub quoted_printable: bool,
+    /// whether the message already had a `Return-Path:` header (which is dropped, since we
+    /// add our own)
+    pub had_return_path: bool,
+    /// the values of any `Delivered-To:` headers, unfolded and trimmed, in order
+    pub delivered_to: Vec<String>,
+    /// Input that was read while looking for headers, but turned out to be the start of the
+    /// body -- because the message had no headers at all (see [`process_existing_headers`]).
+    pub body_prefix: Vec<u8>,
+}
+
+/// Read headers from an input stream, and write them to an output stream, recording whether
+/// we've seen the `From:`, `Date:` and `Message-ID:` headers (matching their names ignoring
+/// case, since e.g. `Message-Id:` is just as common).
+///
+/// Should write all the header lines to the output stream, _except_ for the final newline
+/// indicating the end of the headers. (Because the caller will want to write additional
+/// headers after this function returns.)
+///
+/// So if `Foo: foo\nBar: bar\n\n` is read from the input, `Foo: foo\nBar: bar\n` should be
+/// written to the output.
+///
+/// A `From:` or `Date:` header with an empty (or whitespace-only) value doesn't count as present,
+/// and is dropped from the output. So is any `Return-Path:` header, since [`write_headers`]
+/// adds a fresh one, from the envelope sender. The values of any `Delivered-To:` headers are
+/// collected, for [`write_headers`] to check for a mail loop (see [`check_mail_loop`]).
+///
+/// Lines starting with a space or tab are continuations of a folded header (RFC 5322 section
+/// 2.2.3): they're written out unchanged, but only the first line of each header is looked at
+/// to see what header it is.
+///
+/// If `config.rejectPartial` is set, a message with a `Content-Type: message/partial` header
+/// is rejected with an `EX_DATAERR` error.
+///
+/// If `config.strictHeaders` is set, every header line must be syntactically valid
+/// (see [`is_valid_header_line`]), or an `EX_DATAERR` error is returned. Likewise if the
+/// headers add up to more than `config.maxHeaderBytes` bytes.
+///
+/// If the first line of input doesn't look like a header at all (there's no `field-name:`),
+/// the message is taken to have no headers: nothing is written, and the line is returned in
+/// the `body_prefix` of the `HeaderStatus`, for the caller to write as the start of the body.
+/// Unless `config.requireHeaders` is set, that is: then input without at least one valid
+/// header line (see [`is_valid_header_line`]) before the first blank line is rejected with an
+/// `EX_DATAERR` error. (Completely empty input is left to `config.onEmptyInput`.)
+///
+/// Returns a `HeaderStatus` struct indicating whether we've seen the `From:`, `Date:`,
+/// `Message-ID:` and `Reply-To:` headers.
+/// If an error occurs while reading or writing, returns an error.
+///
+/// Example
+///
+/// ```
+/// use std::io::Cursor;
+/// use rattomail::{process_existing_headers,Config,HeaderStatus};
+///
+/// let input = b"Foo: foo\nBar: bar\n\n";
+/// let mut output = Vec::new();
+/// let config = Config::default();
+/// let result = process_existing_headers(&mut Cursor::new(input), &mut output, &config).unwrap();
+///
+/// assert_eq!(result, HeaderStatus::default());
+/// assert_eq!(output, b"Foo: foo\nBar: bar\n");
+/// ```
+///
+pub fn process_existing_headers<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    config: &Config,
+) -> Result<HeaderStatus> {
+    let mut buffer = Vec::new();
+    // record what headers we see
+    let mut header_status = HeaderStatus::default();
+    // whether there's been a previous header line, that a continuation line could continue
+    let mut seen_header = false;
+    // running total of header bytes read
+    let mut header_bytes: u64 = 0;
+    // whether any input was read at all, and whether any of it was a valid header line
+    let mut read_any = false;
+    let mut seen_valid_header = false;
+    // whether the current header (including any continuation lines) is a `Return-Path:`, or
+    // a `Delivered-To:`
+    let mut in_return_path = false;
+    let mut in_delivered_to = false;
+    // the current header, if it's a `Content-Type:` or a `Content-Transfer-Encoding:`,
+    // unfolded -- it can only be checked once all its continuation lines have been read
+    let mut content_type: Option<Vec<u8>> = None;
+    let mut transfer_encoding: Option<Vec<u8>> = None;
+
+    loop {
+        // read until newline or EOF
+        let bytes_read = input.read_until(b'\n', &mut buffer).map_err(input_error)?;
+        read_any |= bytes_read > 0;
+
+        // if the very first line doesn't look like a header (or the blank line ending the
+        // headers, or a - misplaced - continuation line), there are no headers at all, and the
+        // whole input is body
+        let is_blank = buffer == b"\n" || buffer == b"\r\n";
+        let is_indented = buffer.starts_with(b" ") || buffer.starts_with(b"\t");
+        // a continuation of a folded header, whose first line has already been looked at
+        let is_continuation = seen_header && is_indented;
+        if !seen_header
+            && bytes_read > 0
+            && !is_blank
+            && !is_indented
+            && !starts_with_field_name(&buffer)
+        {
+            log::debug!("Message has no headers, treating all input as body");
+            header_status.body_prefix = buffer;
+            break;
+        }
+
+        // an empty `From:` or `Date:` is as good as missing - we drop it, and
+        // leave it to the caller to synthesize another
+        let is_empty_from_or_date = (is_empty_header(&buffer, b"From:")
+            || is_empty_header(&buffer, b"Date:"))
+            && !next_line_is_continuation(input)?;
+
+        // check for headers. Only the first line of a folded header says what header it is:
+        // a continuation line that happens to look like `Date: ...` is still part of the
+        // previous header's value.
+        if !is_continuation {
+            if let Some(field) = content_type.take() {
+                if config.rejectPartial && is_message_partial(&field) {
+                    return Err(sysexit_error(
+                        Sysexit::DataErr,
+                        "Message is a fragment (Content-Type: message/partial), and \
+                         reassembling fragments isn't supported"
+                            .to_string(),
+                    ));
+                }
             }
-            if second_to_last.as_os_str() != "Maildir" {
-                anyhow::bail!(
-                    "mailDir path '{:?}' does not have 'Maildir' as the second-to-last component",
-                    maildir_new_path
-                );
+            if let Some(field) = transfer_encoding.take() {
+                header_status.quoted_printable |= is_quoted_printable_cte(&field);
+            }
+            in_return_path = has_header_name(&buffer, "Return-Path");
+            in_delivered_to = has_header_name(&buffer, "Delivered-To");
+            if has_header_name(&buffer, "Content-Type") {
+                content_type = Some(Vec::new());
+            }
+            if has_header_name(&buffer, "Content-Transfer-Encoding") {
+                transfer_encoding = Some(Vec::new());
             }
         }
-        _ => {
-            anyhow::bail!(
-                "mailDir path '{:?}' does not end in /Maildir/new",
-                maildir_new_path
+        for field in [content_type.as_mut(), transfer_encoding.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            field.extend(buffer.iter().filter(|&&b| b != b'\r' && b != b'\n'));
+        }
+        if is_continuation {
+            if let (true, Some(value)) = (in_delivered_to, header_status.delivered_to.last_mut()) {
+                *value = format!("{} {}", value, unfolded_value(&buffer))
+                    .trim()
+                    .to_string();
+            }
+        } else if in_delivered_to {
+            let value = unfolded_value(&buffer["Delivered-To:".len()..]);
+            header_status.delivered_to.push(value);
+        } else if in_return_path {
+            header_status.had_return_path = true;
+            log::debug!(
+                "Dropping existing header: {:?}",
+                String::from_utf8_lossy(&buffer)
             );
+        } else if is_empty_from_or_date {
+            log::debug!(
+                "Dropping empty header: {:?}",
+                String::from_utf8_lossy(&buffer)
+            );
+        } else if has_header_name(&buffer, "From") {
+            header_status.has_from = true;
+        } else if has_header_name(&buffer, "Date") {
+            header_status.has_date = true;
+        } else if has_header_name(&buffer, "Message-ID") {
+            header_status.has_message_id = true;
+        } else if has_header_name(&buffer, "Reply-To") {
+            header_status.has_reply_to = true;
+        } else if buffer == b"\n" || buffer == b"\r\n" {
+            // end of headers
+            break;
+        }
+
+        if bytes_read == 0 {
+            break; // reached EOF
+        }
+
+        header_bytes += bytes_read as u64;
+        if header_bytes > config.maxHeaderBytes {
+            return Err(sysexit_error(
+                Sysexit::DataErr,
+                format!(
+                    "Message headers exceed maximum size of {} bytes",
+                    config.maxHeaderBytes
+                ),
+            ));
+        }
+
+        let is_valid = is_valid_header_line(&buffer);
+        seen_valid_header |= !is_continuation && is_valid;
+        if config.strictHeaders && !is_continuation && !is_valid {
+            return Err(sysexit_error(
+                Sysexit::DataErr,
+                format!(
+                    "Malformed header line: {:?}",
+                    String::from_utf8_lossy(&buffer)
+                ),
+            ));
+        }
+        seen_header = true;
+
+        if !is_empty_from_or_date && !in_return_path {
+            // input that ends in the middle of a header line still needs that line ending,
+            // or whatever is written after the headers would be run into it
+            if !buffer.ends_with(b"\n") {
+                buffer.push(b'\n');
+            }
+            output
+                .write_all(&buffer)
+                .map_err(|e| anyhow!("Error writing output: {}", e))?;
+        }
+
+        // clear for next read
+        buffer.clear();
+    }
+
+    if config.requireHeaders && read_any && !seen_valid_header {
+        return Err(sysexit_error(
+            Sysexit::DataErr,
+            "Message has no headers".to_string(),
+        ));
+    }
+
+    // ensure all buffered data is written
+    output
+        .flush()
+        .map_err(|e| anyhow!("Error flushing output: {}", e))?;
+
+    Ok(header_status)
+}
+
+/// Remove the trace headers rattomail adds from a block of `headers` (as written by
+/// [`process_existing_headers`]), so that a re-delivered message doesn't accumulate them:
+/// `Received:` headers containing the `(rattomail)` product token, the `Delivered-To:` header
+/// just before each of those, and any `X-Rattomail-*` headers. Folded headers are removed
+/// along with all their continuation lines. All other headers -- including other programs'
+/// `Received:` headers -- are kept.
+///
+/// (A `Received:` header added with `receivedHideProduct` set can't be told apart from
+/// anyone else's, so is kept -- as is the `Delivered-To:` before it.)
+pub fn strip_rattomail_trace_headers(headers: &[u8]) -> Vec<u8> {
+    let is_rattomail_received =
+        |field: &[u8]| has_header_name(field, "Received") && contains_bytes(field, b"(rattomail)");
+    let fields = header_fields(headers);
+    let mut output = Vec::with_capacity(headers.len());
+    for (i, &field) in fields.iter().enumerate() {
+        let is_rattomail_delivered_to = has_header_name(field, "Delivered-To")
+            && fields
+                .get(i + 1)
+                .is_some_and(|&next| is_rattomail_received(next));
+        let is_rattomail_x_header = field.len() > "X-Rattomail-".len()
+            && field[.."X-Rattomail-".len()].eq_ignore_ascii_case(b"X-Rattomail-");
+        if is_rattomail_received(field) || is_rattomail_delivered_to || is_rattomail_x_header {
+            log::debug!(
+                "Removing previous trace header: {:?}",
+                String::from_utf8_lossy(field)
+            );
+        } else {
+            output.extend_from_slice(field);
+        }
+    }
+    output
+}
+
+/// Headers which a message should have at most one of (see [`dedupe_critical_headers`]).
+const CRITICAL_HEADERS: [&str; 4] = ["From", "Date", "Message-ID", "Subject"];
+
+/// Remove any duplicates of the [`CRITICAL_HEADERS`] from a block of `headers` (as written by
+/// [`process_existing_headers`]): only the first `From:`, `Date:`, `Message-ID:` and `Subject:`
+/// are kept, since mail clients differ on which of several to believe. Folded headers are
+/// removed along with all their continuation lines.
+pub fn dedupe_critical_headers(headers: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(headers.len());
+    let mut seen = [false; CRITICAL_HEADERS.len()];
+    for field in header_fields(headers) {
+        let critical = CRITICAL_HEADERS
+            .iter()
+            .position(|name| has_header_name(field, name));
+        match critical {
+            Some(i) if seen[i] => {
+                log::debug!(
+                    "Removing duplicate header: {:?}",
+                    String::from_utf8_lossy(field)
+                );
+            }
+            _ => {
+                if let Some(i) = critical {
+                    seen[i] = true;
+                }
+                output.extend_from_slice(field);
+            }
+        }
+    }
+    output
+}
+
+/// Split a block of `headers` (as written by [`process_existing_headers`]) into fields: each
+/// header line, together with any continuation lines following it.
+fn header_fields(headers: &[u8]) -> Vec<&[u8]> {
+    let mut fields = Vec::new();
+    let mut field_start = 0;
+    let mut line_start = 0;
+    for line in headers.split_inclusive(|&b| b == b'\n') {
+        let is_continuation = line.starts_with(b" ") || line.starts_with(b"\t");
+        if !is_continuation && line_start > field_start {
+            fields.push(&headers[field_start..line_start]);
+            field_start = line_start;
+        }
+        line_start += line.len();
+    }
+    if line_start > field_start {
+        fields.push(&headers[field_start..line_start]);
+    }
+    fields
+}
+
+/// Check whether a header `field` contains raw 8-bit text -- i.e., any non-ASCII bytes, which
+/// (unlike RFC 2047 encoded-words, `=?charset?encoding?text?=`) many mail clients will
+/// display as mojibake.
+pub fn has_raw_8bit(field: &[u8]) -> bool {
+    !field.is_ascii()
+}
+
+/// Handle any `Subject:` headers containing raw 8-bit text (see [`has_raw_8bit`]) in a block of
+/// `headers`, according to `action` (see [`RawSubjectAction`]): log a warning, and, when
+/// encoding, replace the subject with RFC 2047 encoded-words (see [`encode_subject_field`]).
+pub fn check_raw_subject(headers: &[u8], action: RawSubjectAction) -> Vec<u8> {
+    if action == RawSubjectAction::Ignore {
+        return headers.to_vec();
+    }
+
+    let mut output = Vec::with_capacity(headers.len());
+    for field in header_fields(headers) {
+        if !(has_header_name(field, "Subject") && has_raw_8bit(field)) {
+            output.extend_from_slice(field);
+            continue;
+        }
+        log::warn!(
+            "Subject: header contains raw 8-bit text, which may not display correctly: {:?}",
+            String::from_utf8_lossy(field)
+        );
+        match (action, encode_subject_field(field)) {
+            (RawSubjectAction::Encode, Some(encoded)) => output.extend_from_slice(&encoded),
+            (RawSubjectAction::Encode, None) => {
+                log::warn!("Subject: header isn't valid UTF-8, so can't be encoded");
+                output.extend_from_slice(field);
+            }
+            _ => output.extend_from_slice(field),
+        }
+    }
+    output
+}
+
+/// Maximum number of bytes of text to put in each encoded-word made by
+/// [`encode_subject_field`]: once base64-encoded, and with the `=?UTF-8?B?...?=` around it, this
+/// keeps each encoded-word within RFC 2047's limit of 75 characters -- and each line,
+/// including the `Subject: ` before the first word, within RFC 5322's recommended 78.
+const ENCODED_WORD_TEXT_BYTES: usize = 42;
+
+/// Re-encode a (possibly folded) `Subject:` header `field` containing UTF-8 text as RFC
+/// 2047 `B`-encoded words, folded onto separate lines as needed. The field's line ending is
+/// kept. Returns `None` if the subject isn't valid UTF-8 (so we don't know what charset it's
+/// in).
+pub fn encode_subject_field(field: &[u8]) -> Option<Vec<u8>> {
+    let (body, eol) = if let Some(body) = field.strip_suffix(b"\r\n") {
+        (body, "\r\n")
+    } else if let Some(body) = field.strip_suffix(b"\n") {
+        (body, "\n")
+    } else {
+        (field, "")
+    };
+    // unfold, by removing the line breaks before continuation lines
+    let value: Vec<u8> = body["Subject:".len()..]
+        .iter()
+        .copied()
+        .filter(|&b| b != b'\r' && b != b'\n')
+        .collect();
+    let text = std::str::from_utf8(&value).ok()?.trim();
+
+    // split the text into chunks, without splitting any characters
+    let mut words = Vec::new();
+    let mut chunk_start = 0;
+    for (pos, c) in text.char_indices() {
+        if pos + c.len_utf8() - chunk_start > ENCODED_WORD_TEXT_BYTES {
+            words.push(&text[chunk_start..pos]);
+            chunk_start = pos;
+        }
+    }
+    words.push(&text[chunk_start..]);
+
+    let fold = if eol.is_empty() { "\n" } else { eol };
+    let encoded_words: Vec<String> = words
+        .iter()
+        .map(|word| format!("=?UTF-8?B?{}?=", BASE64_STANDARD.encode(word)))
+        .collect();
+    Some(
+        format!(
+            "Subject: {}{}",
+            encoded_words.join(&format!("{} ", fold)),
+            eol
+        )
+        .into_bytes(),
+    )
+}
+
+/// Check whether `needle` occurs anywhere in `haystack`.
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Check whether `line` starts with something that looks like a header field-name followed by a
+/// colon, i.e., one or more printable ASCII characters other than colon, then a colon.
+fn starts_with_field_name(line: &[u8]) -> bool {
+    match line.iter().position(|&b| b == b':') {
+        Some(colon_pos) => colon_pos > 0 && line[..colon_pos].iter().all(|b| b.is_ascii_graphic()),
+        None => false,
+    }
+}
+
+/// The value of a header field (after the colon), with any line breaks removed, and leading
+/// and trailing whitespace trimmed.
+fn unfolded_value(value: &[u8]) -> String {
+    let value: String = String::from_utf8_lossy(value).split(['\r', '\n']).collect();
+    value.trim().to_string()
+}
+
+/// Check whether `line` is a header called `name` (not including the colon), ignoring case.
+fn has_header_name(line: &[u8], name: &str) -> bool {
+    line.len() > name.len()
+        && line[name.len()] == b':'
+        && line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+}
+
+/// The author address of the `From:` header in a block of `headers` (as written by
+/// [`process_existing_headers`]): its `addr-spec`, if it's in `Name <addr-spec>` form (see
+/// [`extract_addr_spec`]), or else the whole (unfolded) value.
+fn from_author_address(headers: &[u8]) -> Option<String> {
+    let field = header_fields(headers)
+        .into_iter()
+        .find(|field| has_header_name(field, "From"))?;
+    let value: String = String::from_utf8_lossy(&field["From:".len()..])
+        .split(['\r', '\n'])
+        .collect();
+    let value = value.trim();
+    Some(extract_addr_spec(value).unwrap_or(value).to_string())
+}
+
+/// The `Sender:` header to add, with `config.setSenderHeader`, to a message with the given
+/// existing `headers`, sent by `envelope_from`: the envelope sender itself -- but only if it
+/// differs from the `From:` author (ignoring the case of domains; see
+/// [`normalize_domain_case`]), and the message doesn't already have a `Sender:`.
+/// Returns `None` if no `Sender:` is needed, including if there's no `From:` at all (since
+/// the one we synthesize is the envelope sender).
+pub fn sender_header_value<'a>(headers: &[u8], envelope_from: &'a str) -> Option<&'a str> {
+    let has_sender = header_fields(headers)
+        .into_iter()
+        .any(|field| has_header_name(field, "Sender"));
+    let author = from_author_address(headers)?;
+    if has_sender || normalize_domain_case(&author) == normalize_domain_case(envelope_from) {
+        None
+    } else {
+        Some(envelope_from)
+    }
+}
+
+/// Split the value of an address header (e.g. `To:`) into the addresses it lists, for `-t`:
+/// each is separated from the next by a comma, and may be in `Name <addr-spec>` form (in
+/// which case only its `addr-spec` is kept -- see [`extract_addr_spec`]). Commas inside quoted
+/// strings or angle brackets don't separate addresses, and the name of an RFC 5322 group
+/// (`name: addr, addr;`) is skipped, so `undisclosed-recipients:;` lists no addresses at all.
+///
+/// Returns an `EX_DATAERR` error if any address is empty (e.g. `a, , b`) or isn't plausible
+/// (see [`is_plausible_string`]).
+pub fn parse_address_list(value: &str) -> Result<Vec<String>> {
+    fn finish(current: &mut String, addrs: &mut Vec<String>, allow_empty: bool) -> Result<()> {
+        let element = current.trim();
+        if element.is_empty() {
+            if !allow_empty {
+                return Err(sysexit_error(
+                    Sysexit::DataErr,
+                    "Empty address in address list".to_string(),
+                ));
+            }
+        } else {
+            let addr = extract_addr_spec(element).unwrap_or(element);
+            if !is_plausible_string(addr) {
+                return Err(sysexit_error(
+                    Sysexit::DataErr,
+                    format!(
+                        "Recipient address '{}' contains non-ASCII, non-printable or whitespace characters",
+                        addr
+                    ),
+                ));
+            }
+            addrs.push(addr.to_string());
+        }
+        current.clear();
+        Ok(())
+    }
+
+    let mut addrs = Vec::new();
+    let mut current = String::new();
+    let (mut in_quotes, mut in_angle, mut escaped) = (false, false, false);
+    // whether the last separator ended a group, after which nothing more is needed
+    let mut after_group = false;
+    for c in value.chars() {
+        let is_special = !in_quotes && !in_angle;
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' if !in_angle => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_angle = true,
+            '>' if !in_quotes => in_angle = false,
+            ',' if is_special => {
+                finish(&mut current, &mut addrs, after_group)?;
+                after_group = false;
+                continue;
+            }
+            ':' if is_special => {
+                // a group's name
+                current.clear();
+                continue;
+            }
+            ';' if is_special => {
+                finish(&mut current, &mut addrs, true)?;
+                after_group = true;
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    if !value.trim().is_empty() {
+        finish(&mut current, &mut addrs, after_group)?;
+    }
+    Ok(addrs)
+}
+
+/// Read the header block of a message from `input`, for `-t`, and return it along with the
+/// addresses listed in its `To:`, `Cc:` and `Bcc:` headers (see [`parse_address_list`]), in
+/// the order they appear. Folded headers are unfolded first.
+///
+/// The returned block has any `Bcc:` headers (and their continuation lines) removed, but is
+/// otherwise exactly what was read -- including the blank line ending the headers, or the
+/// first line of the body, if the message turns out to have no headers -- so the caller can
+/// put it back in front of the rest of `input`.
+///
+/// Returns an `EX_DATAERR` error if any address is malformed, or the headers add up to more
+/// than `max_header_bytes` bytes.
+pub fn take_header_recipients<R: BufRead>(
+    input: &mut R,
+    max_header_bytes: u64,
+) -> Result<(Vec<u8>, Vec<String>)> {
+    let mut headers = Vec::new();
+    let mut rest = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        let bytes_read = input.read_until(b'\n', &mut line).map_err(input_error)?;
+        let is_continuation =
+            !headers.is_empty() && (line.starts_with(b" ") || line.starts_with(b"\t"));
+        if bytes_read == 0 {
+            break;
+        }
+        if !is_continuation && !starts_with_field_name(&line) {
+            // the blank line ending the headers, or the start of the body
+            rest = line;
+            break;
+        }
+        headers.extend_from_slice(&line);
+        if headers.len() as u64 > max_header_bytes {
+            return Err(sysexit_error(
+                Sysexit::DataErr,
+                format!(
+                    "Message headers exceed maximum size of {} bytes",
+                    max_header_bytes
+                ),
+            ));
+        }
+    }
+
+    let mut block = Vec::with_capacity(headers.len() + rest.len());
+    let mut recipients = Vec::new();
+    for field in header_fields(&headers) {
+        let name = ["To", "Cc", "Bcc"]
+            .into_iter()
+            .find(|name| has_header_name(field, name));
+        if let Some(name) = name {
+            let value: String = String::from_utf8_lossy(&field[name.len() + 1..])
+                .split(['\r', '\n'])
+                .collect();
+            recipients.extend(parse_address_list(&value)?);
+        }
+        if name == Some("Bcc") {
+            log::debug!("Removing header: {:?}", String::from_utf8_lossy(field));
+        } else {
+            block.extend_from_slice(field);
+        }
+    }
+    block.extend_from_slice(&rest);
+
+    Ok((block, recipients))
+}
+
+/// Check whether `field` is a `Content-Transfer-Encoding: quoted-printable` header (ignoring
+/// case).
+fn is_quoted_printable_cte(field: &[u8]) -> bool {
+    let name = "Content-Transfer-Encoding";
+    has_header_name(field, name)
+        && field[name.len() + 1..]
+            .trim_ascii()
+            .eq_ignore_ascii_case(b"quoted-printable")
+}
+
+/// Check whether `line` is a `Content-Type:` header for a `message/partial` fragment
+/// (ignoring case). A folded header has to be unfolded first.
+fn is_message_partial(line: &[u8]) -> bool {
+    const PARTIAL: &[u8] = b"message/partial";
+    if !has_header_name(line, "Content-Type") {
+        return false;
+    }
+    let value = line["Content-Type:".len()..].trim_ascii_start();
+    value.len() >= PARTIAL.len()
+        && value[..PARTIAL.len()].eq_ignore_ascii_case(PARTIAL)
+        && value[PARTIAL.len()..]
+            .first()
+            .is_none_or(|&b| b == b';' || b.is_ascii_whitespace())
+}
+
+/// Check whether `line` is a header called `name` (which should include the colon, e.g.
+/// `b"Date:"`, and is matched ignoring case) with an empty or whitespace-only value.
+fn is_empty_header(line: &[u8], name: &[u8]) -> bool {
+    line.len() >= name.len()
+        && line[..name.len()].eq_ignore_ascii_case(name)
+        && line[name.len()..].iter().all(|b| b" \t\r\n".contains(b))
+}
+
+/// Peek at the input to see whether the next line is a continuation of the current header
+/// (i.e., starts with whitespace).
+fn next_line_is_continuation<R: BufRead>(input: &mut R) -> Result<bool> {
+    let next = input.fill_buf().map_err(input_error)?;
+    Ok(matches!(next.first(), Some(b' ') | Some(b'\t')))
+}
+
+/// Check whether a line (including any trailing newline) is a syntactically valid header
+/// line, according to RFC 5322: `field-name ":" field-body`, where the field-name
+/// consists of printable ASCII characters other than colon, and the field-body contains no
+/// control characters other than tab.
+///
+/// (Continuation lines of folded headers aren't valid header lines by themselves, so will
+/// be rejected.)
+pub fn is_valid_header_line(line: &[u8]) -> bool {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+    let Some(colon_pos) = line.iter().position(|&b| b == b':') else {
+        return false;
+    };
+    let (field_name, field_body) = (&line[..colon_pos], &line[colon_pos + 1..]);
+
+    !field_name.is_empty()
+        && field_name.iter().all(|b| b.is_ascii_graphic())
+        && field_body
+            .iter()
+            .all(|&b| b == b'\t' || !b.is_ascii_control())
+}
+
+/// Format `time` as an RFC 5322 date-time, for use in a header -- in the timezone `tz`,
+/// if given, or else the local timezone.
+pub fn format_mail_date(time: &chrono::DateTime<Local>, tz: Option<&DateTimezone>) -> String {
+    match tz {
+        None => time.to_rfc2822(),
+        Some(DateTimezone::Fixed(offset)) => time.with_timezone(offset).to_rfc2822(),
+        Some(DateTimezone::Named(tz)) => time.with_timezone(tz).to_rfc2822(),
+    }
+}
+
+/// Parse `format` as a `chrono` format string, returning an error if it contains any invalid
+/// format specifiers.
+fn check_date_format(format: &str) -> Result<Vec<Item<'_>>> {
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        anyhow::bail!("'{}' is not a valid date format", format);
+    }
+    Ok(items)
+}
+
+/// Get the name of the Maildir subfolder that a message received at `time` should be
+/// delivered to, given a `datePartition` format string, `pattern`. The time is formatted
+/// in the timezone `tz`, if given, or else the local timezone.
+///
+/// The formatted time is turned into a Maildir++-style folder name: `/` and `.` both
+/// separate levels of the folder hierarchy (and become `.`), and any characters other than
+/// ASCII letters, digits, `-` and `_` are replaced with `_`. So `%Y/%m` gives e.g.
+/// `.2024.01`.
+pub fn date_partition_folder(
+    pattern: &str,
+    time: &chrono::DateTime<Local>,
+    tz: Option<&DateTimezone>,
+) -> Result<String> {
+    let items = check_date_format(pattern)?;
+    let formatted = match tz {
+        None => time.format_with_items(items.iter()).to_string(),
+        Some(DateTimezone::Fixed(offset)) => time
+            .with_timezone(offset)
+            .format_with_items(items.iter())
+            .to_string(),
+        Some(DateTimezone::Named(tz)) => time
+            .with_timezone(tz)
+            .format_with_items(items.iter())
+            .to_string(),
+    };
+
+    let levels: Vec<String> = formatted
+        .split(['/', '.'])
+        .filter(|level| !level.is_empty())
+        .map(|level| {
+            level
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect()
+        })
+        .collect();
+    if levels.is_empty() {
+        anyhow::bail!("date format '{}' gives an empty folder name", pattern);
+    }
+
+    Ok(format!(".{}", levels.join(".")))
+}
+
+/// Make a `Received:` header for a given `to_addr`, `from_addr`, and `time` (formatted in the
+/// local timezone).
+pub fn make_received_header(
+    to_addr: &str,
+    from_addr: &str,
+    time: &chrono::DateTime<Local>,
+) -> String {
+    received_header(
+        to_addr,
+        from_addr,
+        None,
+        false,
+        &format_mail_date(time, None),
+    )
+    .concat()
+}
+
+/// Make a `Received:` header, folded so that no line is longer than `width` characters (not
+/// counting the line ending), given `to_addr`, `from_addr` and `time` as for
+/// [`make_received_header`].
+///
+/// The header is only folded at whitespace: after `for <to_addr>`, around the
+/// `(envelope-from ...)` clause, and before the date. A part that's longer than `width` by
+/// itself can't be split up, so gets a line of its own, however long.
+pub fn fold_received_header(
+    to_addr: &str,
+    from_addr: &str,
+    time: &chrono::DateTime<Local>,
+    width: u64,
+) -> String {
+    fold_header_parts(
+        &received_header(
+            to_addr,
+            from_addr,
+            None,
+            false,
+            &format_mail_date(time, None),
+        ),
+        width,
+    )
+}
+
+/// Make the parts of a `Received:` header for a given `to_addr`, `from_addr`, TLS cipher (if
+/// any), and already-formatted date. If `hide_product` is set, the `(rattomail)` product token
+/// is left out.
+///
+/// Every part after the first starts with a space, and the header may be folded before any
+/// of them (see [`fold_header_parts`]); concatenated, they make the unfolded header.
+fn received_header(
+    to_addr: &str,
+    from_addr: &str,
+    tls_cipher: Option<&str>,
+    hide_product: bool,
+    date_str: &str,
+) -> [String; 4] {
+    let product = if hide_product { "" } else { " (rattomail)" };
+    let tls_note = tls_cipher.map_or_else(String::new, |cipher| format!(" (TLS {})", cipher));
+    [
+        format!("Received: for {}", to_addr),
+        format!(" with local{}{}", product, tls_note),
+        format!(" (envelope-from {});", from_addr),
+        format!(" {}\n", date_str),
+    ]
+}
+
+/// Join the `parts` of a header (see [`received_header`]), starting a new continuation line
+/// before any part that would take the current line past `width` characters.
+fn fold_header_parts(parts: &[String], width: u64) -> String {
+    let mut header = String::new();
+    let mut line_len = 0;
+    for part in parts {
+        let part_len = part.trim_end_matches('\n').len();
+        if line_len > 0 && (line_len + part_len) as u64 > width {
+            header.push('\n');
+            line_len = 0;
+        }
+        header.push_str(part);
+        line_len += part_len;
+    }
+    header
+}
+
+/// Environment variable in which an MTA can tell us the TLS cipher it received a message with.
+pub const TLS_CIPHER_VAR: &str = "RATTOMAIL_TLS_CIPHER";
+
+/// Get the TLS cipher a message was received with from the environment (the variable named
+/// by [`TLS_CIPHER_VAR`]), using `getenv` to look up variables. Values which aren't safe to
+/// put in a header comment -- anything other than printable ASCII and spaces, or containing
+/// parentheses or backslashes -- are ignored.
+pub fn tls_cipher_from_env<F>(getenv: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    getenv(TLS_CIPHER_VAR).filter(|cipher| {
+        let is_safe = is_safe_header_value(cipher) && !cipher.contains(['(', ')', '\\']);
+        if !is_safe {
+            log::debug!("Ignoring unsafe TLS cipher value: {:?}", cipher);
+        }
+        is_safe
+    })
+}
+
+/// Write `Return-Path:`, `Delivered-To:` and `Received:` headers to the output stream, then
+/// `Date:`, `From:` and `Message-ID:` headers if missing (and `Reply-To:`, if missing and
+/// `config.defaultReplyTo` is set, and `Sender:`, if `config.setSenderHeader` is set and
+/// [`sender_header_value`] gives one), then existing headers (read from input stream), then a
+/// blank line terminator to indicate end of headers.
+///
+/// The order of the header block is guaranteed to be:
+///
+/// 1. a `Return-Path:` header, from the envelope sender (see [`return_path_value`]) -- any the
+///    message already had are dropped;
+/// 2. a `Delivered-To:` header, for the recipient -- so that if the message comes back here,
+///    the loop can be detected (see [`check_mail_loop`]);
+/// 3. our `Received:` header (trace headers should be topmost);
+/// 4. any `extra_headers` from the envelope, in order;
+/// 5. synthesized `Date:`, `From:`, `Message-ID:`, `Reply-To:` and `Sender:` headers, if the
+///    message lacked them;
+/// 6. the message's existing headers, in their original order and byte-for-byte unchanged;
+/// 7. the blank line ending the headers.
+///
+/// Since we only know whether `Date:`, `From:` and `Message-ID:` are missing once we've seen
+/// all the existing headers, those are buffered before being written.
+///
+/// Arguments:
+///
+/// - `input`: input stream to read existing headers from
+/// - `output`: output stream to write headers to
+/// - `envelope`: recipient and sender addresses, received time, and any extra headers to add
+/// - `config`: config settings affecting how headers are processed
+///
+/// Returns the `HeaderStatus` from [`process_existing_headers`] -- in particular, any
+/// `body_prefix` that was read, which the caller should write before the rest of the body.
+pub fn write_headers<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    envelope: &Envelope,
+    config: &Config,
+) -> Result<HeaderStatus> {
+    let Envelope {
+        to_addr,
+        from_addr,
+        received_time,
+        extra_headers,
+        tls_cipher,
+        reinject,
+        message_id,
+    } = envelope;
+
+    let mut existing_headers = Vec::<u8>::new();
+    let res = process_existing_headers(input, &mut existing_headers, config)?;
+    trace_stage("headers", || {
+        let mut details = format!(
+            "has_from: {}, has_date: {}, has_message_id: {}, has_reply_to: {}\n",
+            res.has_from, res.has_date, res.has_message_id, res.has_reply_to
+        );
+        for field in header_fields(&existing_headers) {
+            details.push_str(String::from_utf8_lossy(field).trim_end());
+            details.push('\n');
+        }
+        details
+    });
+    // when reinjecting, the message has been delivered here before, but not in a loop: the
+    // `Delivered-To:` added then is removed, and mustn't count
+    let mut delivered_to = res.delivered_to.clone();
+    if *reinject {
+        existing_headers = strip_rattomail_trace_headers(&existing_headers);
+        delivered_to = header_fields(&existing_headers)
+            .into_iter()
+            .filter(|field| has_header_name(field, "Delivered-To"))
+            .map(|field| unfolded_value(&field["Delivered-To:".len()..]))
+            .collect();
+    }
+    if config.dedupeCriticalHeaders {
+        existing_headers = dedupe_critical_headers(&existing_headers);
+    }
+    if config.warnRawSubject8bit != RawSubjectAction::Ignore {
+        existing_headers = check_raw_subject(&existing_headers, config.warnRawSubject8bit);
+    }
+
+    if config.requireFromHeader && !res.has_from {
+        return Err(sysexit_error(
+            Sysexit::DataErr,
+            "Message has no From: header".to_string(),
+        ));
+    }
+    check_mail_loop(&delivered_to, to_addr)?;
+
+    write_header(output, "Return-Path", &return_path_value(from_addr))?;
+    write_header(output, "Delivered-To", to_addr)?;
+
+    let date_str = format_mail_date(received_time, config.dateTimezone.as_ref());
+    let received_parts = received_header(
+        to_addr,
+        from_addr,
+        tls_cipher.as_deref(),
+        config.receivedHideProduct,
+        &date_str,
+    );
+    let received_header = match config.receivedFoldWidth {
+        Some(width) => fold_header_parts(&received_parts, width),
+        None => received_parts.concat(),
+    };
+    let received_header = received_header.as_bytes();
+    output
+        .write_all(received_header)
+        .map_err(|e| anyhow!("Error writing output: {}", e))?;
+
+    for (name, value) in extra_headers {
+        write_header(output, name, value)?;
+    }
+
+    if !res.has_date {
+        write_header(output, "Date", &date_str)?;
+    }
+
+    if !res.has_from {
+        write_header(output, "From", from_addr)?;
+    }
+
+    if !res.has_message_id {
+        write_header(output, "Message-ID", message_id)?;
+    }
+
+    if let (false, Some(reply_to)) = (res.has_reply_to, &config.defaultReplyTo) {
+        write_header(output, "Reply-To", reply_to)?;
+    }
+
+    if config.setSenderHeader {
+        if let Some(sender) = sender_header_value(&existing_headers, from_addr) {
+            write_header(output, "Sender", sender)?;
+        }
+    }
+
+    output
+        .write_all(&existing_headers)
+        .map_err(|e| anyhow!("Error writing output: {}", e))?;
+
+    // write end-of-headers newline
+    output
+        .write_all(b"\n")
+        .map_err(|e| anyhow!("Error writing output: {}", e))?;
+
+    Ok(res)
+}
+
+/// The value of the `Return-Path:` header for envelope sender `from_addr`: the address in
+/// angle brackets, adding them if it doesn't already have them. An empty sender (as for a
+/// bounce) gives `<>`.
+///
+/// Example
+///
+/// ```
+/// use rattomail::return_path_value;
+///
+/// assert_eq!(return_path_value("alice@example.com"), "<alice@example.com>");
+/// assert_eq!(return_path_value("<alice@example.com>"), "<alice@example.com>");
+/// assert_eq!(return_path_value("<>"), "<>");
+/// assert_eq!(return_path_value(""), "<>");
+/// ```
+pub fn return_path_value(from_addr: &str) -> String {
+    let from_addr = from_addr.trim();
+    if from_addr.starts_with('<') && from_addr.ends_with('>') {
+        from_addr.to_string()
+    } else {
+        format!("<{}>", from_addr)
+    }
+}
+
+/// Check that a message isn't in a mail loop: that none of its existing `Delivered-To:`
+/// headers (as collected by [`process_existing_headers`]) is for `to_addr` -- comparing the
+/// addresses ignoring case, and any angle brackets. If one is, the message has been delivered
+/// here before, and would only go round again, so an `EX_NOPERM` error is returned, for the
+/// sender to bounce it.
+///
+/// Example
+///
+/// ```
+/// use rattomail::check_mail_loop;
+///
+/// let delivered_to = vec!["carol@example.com".to_string(), "<Bob@Example.COM>".to_string()];
+/// assert!(check_mail_loop(&delivered_to, "alice@example.com").is_ok());
+/// assert!(check_mail_loop(&delivered_to, "bob@example.com").is_err());
+/// ```
+pub fn check_mail_loop(delivered_to: &[String], to_addr: &str) -> Result<()> {
+    let bare = |addr: &str| {
+        let addr = addr.trim();
+        addr.strip_prefix('<')
+            .and_then(|addr| addr.strip_suffix('>'))
+            .unwrap_or(addr)
+            .trim()
+            .to_string()
+    };
+    let to_addr = bare(to_addr);
+
+    if delivered_to
+        .iter()
+        .any(|value| bare(value).eq_ignore_ascii_case(&to_addr))
+    {
+        return Err(sysexit_error(
+            Sysexit::NoPerm,
+            format!(
+                "Mail loop detected: message has already been delivered to {}",
+                to_addr
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The canonical capitalization of the header names rattomail synthesizes (see
+/// [`canonical_header_name`]).
+pub const CANONICAL_HEADER_NAMES: [&str; 16] = [
+    "Return-Path",
+    "Delivered-To",
+    "Received",
+    "Date",
+    "From",
+    "Sender",
+    "Reply-To",
+    "Message-ID",
+    "MIME-Version",
+    "Content-Type",
+    "Content-Transfer-Encoding",
+    "X-Original-To",
+    "X-DSN-Notify",
+    "X-Authentication-Warning",
+    "X-Rattomail-Pid",
+    "Lines",
+];
+
+/// The canonical capitalization of header `name`, if it's one of the
+/// [`CANONICAL_HEADER_NAMES`] (matching it ignoring case); otherwise, `name` as given.
+///
+/// Example
+///
+/// ```
+/// use rattomail::canonical_header_name;
+///
+/// assert_eq!(canonical_header_name("message-id"), "Message-ID");
+/// assert_eq!(canonical_header_name("MIME-VERSION"), "MIME-Version");
+/// assert_eq!(canonical_header_name("x-Custom"), "x-Custom");
+/// ```
+pub fn canonical_header_name(name: &str) -> &str {
+    CANONICAL_HEADER_NAMES
+        .iter()
+        .find(|canonical| canonical.eq_ignore_ascii_case(name))
+        .copied()
+        .unwrap_or(name)
+}
+
+/// Write a synthesized `name: value` header line to `output`, with the name capitalized
+/// canonically (see [`canonical_header_name`]). Headers passed through from the message are
+/// written as they are, and never come through here.
+fn write_header<W: Write>(output: &mut W, name: &str, value: &str) -> Result<()> {
+    output
+        .write_all(format!("{}: {}\n", canonical_header_name(name), value).as_bytes())
+        .map_err(|e| anyhow!("Error writing output: {}", e))
+}
+
+/// Just reads lines from input and writes to output.
+pub fn write_body<R: BufRead, W: Write>(input: &mut R, output: &mut W) -> Result<()> {
+    let mut buffer = Vec::new();
+
+    loop {
+        // read until newline or EOF
+        let bytes_read = input.read_until(b'\n', &mut buffer).map_err(input_error)?;
+
+        if bytes_read == 0 {
+            break; // reached EOF
+        }
+
+        output
+            .write_all(&buffer)
+            .map_err(|e| anyhow!("Error writing output: {}", e))?;
+
+        // clear for next read
+        buffer.clear();
+    }
+
+    // flush all buffered data
+    output
+        .flush()
+        .map_err(|e| anyhow!("Error flushing output: {}", e))?;
+
+    Ok(())
+}
+
+/// Read headers from input stream, and write a "delivered" version of the
+/// message to the output stream (adding appropriate headers).
+///
+/// The envelope's received time is used as the date-time for the `Received` header.
+///
+/// With `fixQpMismatch` or `addLinesHeader`, the message is buffered in full before being
+/// written; `maxMessageSize` is applied as it's buffered, so a runaway message is abandoned as
+/// soon as it's known to be too big.
+pub fn write_message<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    envelope: &Envelope,
+    config: &Config,
+) -> Result<()> {
+    if config.fixQpMismatch || config.addLinesHeader {
+        // the headers can only be fixed once we've seen the body, so it all has to be buffered
+        let mut message = Vec::new();
+        let mut limited = SizeLimitWriter::new(&mut message, config.maxMessageSize);
+        let res = write_unfixed_message(input, &mut limited, envelope, config);
+        if let (true, Some(limit)) = (limited.exceeded(), config.maxMessageSize) {
+            return Err(message_too_large(limit));
+        }
+        res?;
+        if config.fixQpMismatch {
+            if let Some(fixed) = fix_qp_mismatch(&message) {
+                message = fixed;
+            }
+        }
+        if config.addLinesHeader {
+            if let Some(with_lines) = add_lines_header(&message) {
+                message = with_lines;
+            }
+        }
+        return output
+            .write_all(&message)
+            .map_err(|e| anyhow!("Error writing output: {}", e));
+    }
+    write_unfixed_message(input, output, envelope, config)
+}
+
+/// [`write_message`], apart from `fixQpMismatch` and `addLinesHeader`.
+fn write_unfixed_message<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    envelope: &Envelope,
+    config: &Config,
+) -> Result<()> {
+    if config.normalizeMacLineEndings {
+        let mut input = BufReader::new(MacLineEndings::new(input));
+        return write_input_message(&mut input, output, envelope, config);
+    }
+    write_input_message(input, output, envelope, config)
+}
+
+/// Log a warning that a message declared as quoted-printable has raw 8-bit bytes in its body,
+/// which strict mail clients will decode wrongly.
+fn warn_qp_mismatch(fixing: bool) {
+    log::warn!(
+        "Message is declared quoted-printable, but its body contains raw 8-bit bytes{}",
+        if fixing { "; re-declaring it 8bit" } else { "" }
+    );
+}
+
+/// A writer which passes everything through to another writer, noting whether any of it was
+/// 8-bit (see [`has_raw_8bit`]). Used to check the bodies of quoted-printable messages.
+struct EightBitDetector<W> {
+    inner: W,
+    seen_8bit: bool,
+}
+
+impl<W: Write> Write for EightBitDetector<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.seen_8bit = self.seen_8bit || has_raw_8bit(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The length of the header block at the start of `message`, including the blank line which
+/// ends it -- or `None`, if there's no blank line.
+fn header_block_len(message: &[u8]) -> Option<usize> {
+    let mut len = 0;
+    for line in message.split_inclusive(|&b| b == b'\n') {
+        len += line.len();
+        if line == b"\n" || line == b"\r\n" {
+            return Some(len);
+        }
+    }
+    None
+}
+
+/// Return a copy of a complete `message` with a `Lines:` header, giving the number of lines in
+/// its body, added at the end of its header block. (A final line lacking a line ending still
+/// counts.) Returns `None` if the message has no blank line ending its headers.
+pub fn add_lines_header(message: &[u8]) -> Option<Vec<u8>> {
+    let headers_len = header_block_len(message)?;
+    let body = &message[headers_len..];
+    let lines = body.split_inclusive(|&b| b == b'\n').count();
+    // the blank line is `\n` or `\r\n`; the new header goes just before it, using the same
+    // line ending
+    let blank_len = if message[..headers_len].ends_with(b"\r\n") {
+        2
+    } else {
+        1
+    };
+    let blank_start = headers_len - blank_len;
+    let mut result = Vec::with_capacity(message.len() + 16);
+    result.extend_from_slice(&message[..blank_start]);
+    result.extend_from_slice(format!("{}: {}", canonical_header_name("Lines"), lines).as_bytes());
+    result.extend_from_slice(&message[blank_start..headers_len]);
+    result.extend_from_slice(&message[blank_start..]);
+    Some(result)
+}
+
+/// If a complete `message` declares `Content-Transfer-Encoding: quoted-printable`, but has raw
+/// 8-bit bytes in its body -- a common bug in broken encoders, which strict mail clients will
+/// decode wrongly -- return a copy of it with the header changed to
+/// `Content-Transfer-Encoding: 8bit`. Otherwise, returns `None`.
+pub fn fix_qp_mismatch(message: &[u8]) -> Option<Vec<u8>> {
+    let headers_len = header_block_len(message)?;
+    let (headers, body) = message.split_at(headers_len);
+    if !has_raw_8bit(body) {
+        return None;
+    }
+    let fields = header_fields(headers);
+    let cte = fields
+        .iter()
+        .position(|field| is_quoted_printable_cte(field))?;
+
+    let mut fixed = Vec::with_capacity(message.len());
+    for (i, field) in fields.iter().enumerate() {
+        if i == cte {
+            let eol: &[u8] = if field.ends_with(b"\r\n") {
+                b"\r\n"
+            } else {
+                b"\n"
+            };
+            let name = canonical_header_name("Content-Transfer-Encoding");
+            fixed.extend_from_slice(format!("{}: 8bit", name).as_bytes());
+            fixed.extend_from_slice(eol);
+        } else {
+            fixed.extend_from_slice(field);
+        }
+    }
+    fixed.extend_from_slice(body);
+    Some(fixed)
+}
+
+/// [`write_message`], once the input's line endings have been dealt with.
+fn write_input_message<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    envelope: &Envelope,
+    config: &Config,
+) -> Result<()> {
+    let res = write_headers(input, output, envelope, config).context("Failed to write headers")?;
+
+    if res.quoted_printable {
+        let mut detector = EightBitDetector {
+            inner: &mut *output,
+            seen_8bit: false,
+        };
+        write_input_body(input, &mut detector, &res.body_prefix, config)?;
+        if detector.seen_8bit {
+            warn_qp_mismatch(config.fixQpMismatch);
+        }
+        return Ok(());
+    }
+    write_input_body(input, output, &res.body_prefix, config)
+}
+
+/// Write the body of a message -- `body_prefix` (see [`HeaderStatus`]), then the rest of
+/// `input` -- to `output`, truncating it if `config.truncateAtBytes` says to.
+fn write_input_body<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    body_prefix: &[u8],
+    config: &Config,
+) -> Result<()> {
+    if let Some(limit) = config.truncateAtBytes {
+        let mut truncating = TruncatingWriter::new(&mut *output, limit);
+        truncating
+            .write_all(body_prefix)
+            .map_err(|e| anyhow!("Error writing output: {}", e))
+            .context("Failed to write message body")?;
+        write_body(input, &mut truncating).context("Failed to write message body")?;
+        return truncating.finish().context("Failed to write message body");
+    }
+
+    output
+        .write_all(body_prefix)
+        .map_err(|e| anyhow!("Error writing output: {}", e))
+        .context("Failed to write message body")?;
+    write_body(input, output).context("Failed to write message body")?;
+
+    Ok(())
+}
+
+/// A reader which converts old Mac-style line endings -- carriage returns not followed by a
+/// line feed -- in the underlying reader to line feeds (`\n`). CRLF line endings are left
+/// alone. Used for `Config::normalizeMacLineEndings`, since otherwise, when reading line by
+/// line, a whole message with `\r`-only line endings would look like one enormous line.
+pub struct MacLineEndings<R> {
+    inner: R,
+    /// converted data not yet returned to the reader
+    converted: Vec<u8>,
+    converted_pos: usize,
+    /// whether the last byte read from `inner` was a `\r`, which we can't convert until we
+    /// see what comes after it
+    pending_cr: bool,
+}
+
+impl<R: Read> MacLineEndings<R> {
+    pub fn new(inner: R) -> Self {
+        MacLineEndings {
+            inner,
+            converted: Vec::new(),
+            converted_pos: 0,
+            pending_cr: false,
+        }
+    }
+
+    /// Read some more of `inner`, and convert it. Returns `false` at EOF.
+    fn fill_converted(&mut self) -> std::io::Result<bool> {
+        let mut chunk = [0u8; 8192];
+        let bytes_read = self.inner.read(&mut chunk)?;
+        self.converted.clear();
+        self.converted_pos = 0;
+
+        if bytes_read == 0 {
+            if self.pending_cr {
+                self.pending_cr = false;
+                self.converted.push(b'\n');
+                return Ok(true);
+            }
+            return Ok(false);
+        }
+
+        for &b in &chunk[..bytes_read] {
+            if self.pending_cr {
+                self.pending_cr = false;
+                if b == b'\n' {
+                    self.converted.extend_from_slice(b"\r\n");
+                    continue;
+                }
+                self.converted.push(b'\n');
+            }
+            if b == b'\r' {
+                self.pending_cr = true;
+            } else {
+                self.converted.push(b);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for MacLineEndings<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.converted_pos == self.converted.len() {
+            if !self.fill_converted()? {
+                return Ok(0);
+            }
+        }
+        let available = &self.converted[self.converted_pos..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.converted_pos += n;
+        Ok(n)
+    }
+}
+
+/// A reader which fails, with an `EX_DATAERR` error, once more than `limit` lines have been
+/// read through it -- a guard against runaway producers which emit endless lines (see
+/// `Config::inputLineLimit`). A final line counts whether or not it ends in a newline. With no
+/// limit, everything is passed through as-is.
+pub struct LineLimitReader<R> {
+    inner: R,
+    limit: Option<u64>,
+    lines: u64,
+    at_line_start: bool,
+}
+
+impl<R: BufRead> LineLimitReader<R> {
+    pub fn new(inner: R, limit: Option<u64>) -> Self {
+        LineLimitReader {
+            inner,
+            limit,
+            lines: 0,
+            at_line_start: true,
+        }
+    }
+}
+
+impl<R: BufRead> Read for LineLimitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for LineLimitReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if let Some(limit) = self.limit.filter(|&limit| self.lines > limit) {
+            let err = SysexitError {
+                status: Sysexit::DataErr,
+                message: format!("input is longer than the limit of {} lines", limit),
+            };
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+        }
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if self.limit.is_some() {
+            // the bytes being consumed are still in the inner reader's buffer, so this
+            // doesn't read anything
+            if let Ok(buf) = self.inner.fill_buf() {
+                for &byte in &buf[..amt.min(buf.len())] {
+                    if self.at_line_start {
+                        self.lines += 1;
+                    }
+                    self.at_line_start = byte == b'\n';
+                }
+            }
+        }
+        self.inner.consume(amt)
+    }
+}
+
+/// Set by the `SIGALRM` handler which [`DeliveryAlarm`] installs, when the alarm goes off.
+static DELIVERY_ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_delivery_alarm(_signal: nix::libc::c_int) {
+    DELIVERY_ALARM_FIRED.store(true, Ordering::SeqCst);
+}
+
+/// An `alarm(2)`, set to go off when a delivery's time is up (see
+/// `Config::deliveryTimeoutSeconds`). The `SIGALRM` interrupts any read which is blocked
+/// waiting for input, so that a [`DeadlineReader`] can fail it -- a deadline alone is only
+/// noticed once a read returns, which for a hung producer might be never. Dropping it
+/// cancels the alarm, and puts back whatever `SIGALRM` action was there before.
+pub struct DeliveryAlarm {
+    previous_action: nix::sys::signal::SigAction,
+}
+
+impl DeliveryAlarm {
+    pub fn arm(timeout: Duration) -> Result<Self> {
+        use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+        DELIVERY_ALARM_FIRED.store(false, Ordering::SeqCst);
+        // no SA_RESTART, so that blocked reads fail with EINTR rather than being resumed
+        let action = SigAction::new(
+            SigHandler::Handler(on_delivery_alarm),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        let previous_action = unsafe { sigaction(Signal::SIGALRM, &action) }
+            .map_err(|e| anyhow!("Couldn't install SIGALRM handler: {}", e))?;
+        let secs = timeout.as_secs().clamp(1, nix::libc::c_uint::MAX as u64);
+        nix::unistd::alarm::set(secs as nix::libc::c_uint);
+        Ok(DeliveryAlarm { previous_action })
+    }
+}
+
+impl Drop for DeliveryAlarm {
+    fn drop(&mut self) {
+        use nix::sys::signal::{sigaction, Signal};
+
+        nix::unistd::alarm::cancel();
+        // SAFETY: this is the action which was installed before, so restoring it can't be any
+        // less safe than it was
+        if let Err(e) = unsafe { sigaction(Signal::SIGALRM, &self.previous_action) } {
+            log::warn!("Couldn't restore previous SIGALRM action: {}", e);
+        }
+    }
+}
+
+/// Whether a delivery's `deadline` (if it has one) has passed.
+pub fn deadline_passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// A reader which fails, with an `EX_TEMPFAIL` error, once `deadline` has passed -- so that a
+/// producer which stalls, or trickles its input out too slowly, can't hold a delivery up
+/// forever (see `Config::deliveryTimeoutSeconds`). A read interrupted by a [`DeliveryAlarm`]
+/// fails the same way. With no deadline, everything is passed through as-is.
+pub struct DeadlineReader<R> {
+    inner: R,
+    deadline: Option<Instant>,
+}
+
+impl<R: BufRead> DeadlineReader<R> {
+    pub fn new(inner: R, deadline: Option<Instant>) -> Self {
+        DeadlineReader { inner, deadline }
+    }
+}
+
+/// The error a [`DeadlineReader`] fails with.
+fn deadline_error() -> std::io::Error {
+    let err = SysexitError {
+        status: Sysexit::TempFail,
+        message: "delivery timed out waiting for input".to_string(),
+    };
+    std::io::Error::new(std::io::ErrorKind::TimedOut, err)
+}
+
+impl<R: BufRead> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for DeadlineReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        let deadline = self.deadline;
+        let expired = || {
+            deadline.is_some()
+                && (deadline_passed(deadline) || DELIVERY_ALARM_FIRED.load(Ordering::SeqCst))
+        };
+        if expired() {
+            return Err(deadline_error());
+        }
+        match self.inner.fill_buf() {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted && expired() => {
+                Err(deadline_error())
+            }
+            res => res,
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.inner.consume(amt)
+    }
+}
+
+/// A writer which passes through only the first `limit` bytes written to it, and discards
+/// the rest. Used for `Config::truncateAtBytes`: once the whole body has been written,
+/// [`TruncatingWriter::finish`] adds a note to say if it was truncated.
+pub struct TruncatingWriter<W> {
+    inner: W,
+    limit: u64,
+    written: u64,
+    truncated: bool,
+}
+
+impl<W: Write> TruncatingWriter<W> {
+    pub fn new(inner: W, limit: u64) -> Self {
+        TruncatingWriter {
+            inner,
+            limit,
+            written: 0,
+            truncated: false,
+        }
+    }
+
+    /// If anything was discarded, write a note saying the message was truncated (and at what
+    /// size) to the underlying writer.
+    pub fn finish(mut self) -> Result<()> {
+        if self.truncated {
+            log::debug!("Message body truncated at {} bytes", self.limit);
+            write!(
+                self.inner,
+                "\n\n[message truncated by rattomail at {} bytes]\n",
+                self.limit
+            )
+            .and_then(|_| self.inner.flush())
+            .map_err(|e| anyhow!("Error writing output: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for TruncatingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let remaining = (self.limit - self.written).min(buf.len() as u64) as usize;
+        self.inner.write_all(&buf[..remaining])?;
+        self.written += remaining as u64;
+        if remaining < buf.len() {
+            self.truncated = true;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer which fails, with an `EX_DATAERR` error, rather than let more than `limit` bytes
+/// be written through it -- so that a runaway producer's message is abandoned as soon as it's
+/// known to be too big, instead of being buffered in full (see `Config::maxMessageSize`).
+/// With no limit, everything is passed through as-is.
+///
+/// Since the error may have been wrapped in others by the time it reaches the caller,
+/// [`SizeLimitWriter::exceeded`] tells whether the limit was hit.
+pub struct SizeLimitWriter<W> {
+    inner: W,
+    limit: Option<u64>,
+    written: u64,
+    exceeded: bool,
+}
+
+impl<W: Write> SizeLimitWriter<W> {
+    pub fn new(inner: W, limit: Option<u64>) -> Self {
+        SizeLimitWriter {
+            inner,
+            limit,
+            written: 0,
+            exceeded: false,
+        }
+    }
+
+    /// Whether a write was refused for going over the limit.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<W: Write> Write for SizeLimitWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(limit) = self.limit {
+            if self.written + buf.len() as u64 > limit {
+                self.exceeded = true;
+                let err = SysexitError {
+                    status: Sysexit::DataErr,
+                    message: format!("message is larger than the limit of {} bytes", limit),
+                };
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err));
+            }
+        }
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The `EX_DATAERR` error for a message found (by a [`SizeLimitWriter`]) to be over the
+/// `maxMessageSize` limit.
+fn message_too_large(limit: u64) -> anyhow::Error {
+    sysexit_error(
+        Sysexit::DataErr,
+        format!(
+            "Message is larger than the limit of {} bytes (maxMessageSize)",
+            limit
+        ),
+    )
+}
+
+/// Messages up to this size (which covers most local mail -- cron output, alerts and the
+/// like) are read in one go by [`write_message_buffered`], rather than streamed.
+pub const SMALL_MESSAGE_THRESHOLD: usize = 64 * 1024;
+
+/// Like [`write_message`], but optimized for small messages: if the whole message is no
+/// bigger than [`SMALL_MESSAGE_THRESHOLD`], it's read in a single pass into one buffer; the
+/// headers are processed from there, and the body written as a single slice. Otherwise,
+/// what's been read so far is streamed through, followed by the rest of the input. Either way,
+/// the output is exactly the same as [`write_message`]'s.
+pub fn write_message_buffered<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    envelope: &Envelope,
+    config: &Config,
+) -> Result<()> {
+    let mut buffer = Vec::new();
+    input
+        .take(SMALL_MESSAGE_THRESHOLD as u64 + 1)
+        .read_to_end(&mut buffer)
+        .map_err(input_error)?;
+
+    // truncation, line ending conversion, encoding fixes and line counts are rare enough that they're not
+    // worth a fast path
+    if buffer.len() <= SMALL_MESSAGE_THRESHOLD
+        && config.truncateAtBytes.is_none()
+        && !config.normalizeMacLineEndings
+        && !config.fixQpMismatch
+        && !config.addLinesHeader
+    {
+        // once the headers are processed, what's left of the buffer is the body, which can
+        // be written in one go rather than line by line
+        let mut rest = buffer.as_slice();
+        let res = write_headers(&mut rest, output, envelope, config)
+            .context("Failed to write headers")?;
+        if res.quoted_printable && (has_raw_8bit(&res.body_prefix) || has_raw_8bit(rest)) {
+            warn_qp_mismatch(false);
+        }
+        output
+            .write_all(&res.body_prefix)
+            .and_then(|_| output.write_all(rest))
+            .map_err(|e| anyhow!("Error writing output: {}", e))
+            .context("Failed to write message body")
+    } else {
+        write_message(
+            &mut buffer.as_slice().chain(input),
+            output,
+            envelope,
+            config,
+        )
+    }
+}
+
+/// Check that `message` (a complete message, as it will be delivered) parses as an RFC 5322
+/// message. The parser used is a forgiving one, so as well as checking it parses at all, we
+/// check that it found every field in the header block (rather than skipping ones it couldn't
+/// make sense of), and that any `Date:` header holds an actual date.
+///
+/// Returns an `EX_DATAERR` error if not.
+pub fn validate_rfc5322(message: &[u8]) -> Result<()> {
+    let invalid = |why: &str| {
+        sysexit_error(
+            Sysexit::DataErr,
+            format!("Message is not a valid RFC 5322 message: {}", why),
+        )
+    };
+
+    let parsed = MessageParser::default()
+        .parse_headers(message)
+        .ok_or_else(|| invalid("couldn't parse message"))?;
+
+    let field_count = message
+        .split(|&b| b == b'\n')
+        .take_while(|line| !line.is_empty() && *line != b"\r")
+        .filter(|line| !line.starts_with(b" ") && !line.starts_with(b"\t"))
+        .count();
+    if parsed.headers().len() != field_count {
+        return Err(invalid(
+            "header block contains lines which aren't header fields",
+        ));
+    }
+
+    if parsed.header(HeaderName::Date).is_some() && parsed.date().is_none() {
+        return Err(invalid("malformed Date header"));
+    }
+
+    Ok(())
+}
+
+/// A writer which makes sure that what's written to it ends in a newline, as a standalone
+/// `.eml` file should: once everything's been written, [`EmlWriter::finish`] adds a `\n` if
+/// the last byte written wasn't one (or if nothing was written at all).
+pub struct EmlWriter<W> {
+    inner: W,
+    last_byte: Option<u8>,
+}
+
+impl<W: Write> EmlWriter<W> {
+    pub fn new(inner: W) -> Self {
+        EmlWriter {
+            inner,
+            last_byte: None,
+        }
+    }
+
+    /// Add a final newline, if needed, and flush the underlying writer.
+    pub fn finish(mut self) -> Result<()> {
+        if self.last_byte != Some(b'\n') {
+            self.inner
+                .write_all(b"\n")
+                .map_err(|e| anyhow!("Error writing output: {}", e))?;
+        }
+        self.inner
+            .flush()
+            .map_err(|e| anyhow!("Error writing output: {}", e))
+    }
+}
+
+impl<W: Write> Write for EmlWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if n > 0 {
+            self.last_byte = Some(buf[n - 1]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Like [`write_message`], but the output is guaranteed to end in a newline (see
+/// [`EmlWriter`]), so it can be used as a standalone `.eml` file.
+pub fn write_eml_message<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    envelope: &Envelope,
+    config: &Config,
+) -> Result<()> {
+    let mut eml = EmlWriter::new(output);
+    write_message(input, &mut eml, envelope, config)?;
+    eml.finish()
+}
+
+/// Like [`write_message`], but only writes the header block (up to and including the blank
+/// line ending it). The body is still read, but discarded.
+fn write_message_headers<R: BufRead, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    envelope: &Envelope,
+    config: &Config,
+) -> Result<()> {
+    if config.fixQpMismatch || config.addLinesHeader {
+        // which headers are written depends on the body, so write the whole message first
+        let mut message = Vec::new();
+        write_message(input, &mut message, envelope, config)?;
+        let headers_len = header_block_len(&message).unwrap_or(message.len());
+        return output
+            .write_all(&message[..headers_len])
+            .map_err(|e| anyhow!("Error writing output: {}", e));
+    }
+
+    if config.normalizeMacLineEndings {
+        let mut input = BufReader::new(MacLineEndings::new(input));
+        write_headers(&mut input, output, envelope, config).context("Failed to write headers")?;
+        write_body(&mut input, &mut std::io::sink()).context("Failed to read message body")?;
+        return Ok(());
+    }
+
+    write_headers(input, output, envelope, config).context("Failed to write headers")?;
+
+    write_body(input, &mut std::io::sink()).context("Failed to read message body")?;
+
+    Ok(())
+}
+
+/// validate that a path to the root of a Maildir (as given with `--maildir-root`)
+///
+/// - is an absolute path
+/// - has a parent directory
+///
+/// Unlike [`parse_maildir_new_path`], the Maildir needn't be called `Maildir`. A path to the
+/// Maildir's `new` directory is accepted too. If the path doesn't exist yet, it's taken to be
+/// the Maildir's `new` directory if it's called `new`, and the Maildir itself otherwise. If it
+/// does exist, we look at what's there instead (see [`is_maildir`]): a directory containing
+/// `cur`, `new` and `tmp` is a Maildir, and a directory called `new` whose parent is a Maildir
+/// is its `new` directory. Anything else which exists is an error.
+///
+/// Return Maildir path if valid, or an error message if not.
+pub fn parse_maildir_root_path(maildir_path: &Path) -> Result<PathBuf> {
+    if !maildir_path.is_absolute() {
+        anyhow::bail!("Maildir path '{:?}' is not an absolute path", maildir_path);
+    }
+
+    let is_new_dir_name = maildir_path.file_name().is_some_and(|name| name == "new");
+    let maildir_path = if !maildir_path.exists() {
+        match maildir_path.parent() {
+            Some(parent) if is_new_dir_name => parent,
+            _ => maildir_path,
+        }
+    } else if is_maildir(maildir_path) {
+        maildir_path
+    } else {
+        match maildir_path.parent() {
+            Some(parent) if is_new_dir_name && is_maildir(parent) => parent,
+            _ => anyhow::bail!(
+                "Maildir path '{:?}' exists, but is neither a Maildir nor a Maildir's 'new' directory",
+                maildir_path
+            ),
+        }
+    };
+
+    if maildir_path.parent().is_none() {
+        anyhow::bail!("Maildir path '{:?}' has no parent", maildir_path);
+    }
+
+    Ok(PathBuf::from(maildir_path))
+}
+
+/// Whether `path` is an existing directory that looks like a Maildir -- i.e., it contains all
+/// of the Maildir subdirectories `cur`, `new` and `tmp`.
+pub fn is_maildir(path: &Path) -> bool {
+    ["cur", "new", "tmp"]
+        .iter()
+        .all(|subdir| path.join(subdir).is_dir())
+}
+
+/// The `mailDir` to use for a config with `maildirRelative` set: `relative` (e.g.
+/// `Maildir/new`) under `home`, the delivery user's home directory, as the passwd database
+/// gives it -- which is more robust than a `/home/%u/Maildir/new` template, since home
+/// directories can be anywhere. `relative` must be a relative path, without any `..`
+/// components; and the result still has to pass [`parse_maildir_new_path`].
+pub fn resolve_maildir_relative(home: &Path, relative: &str) -> Result<PathBuf> {
+    let relative_path = Path::new(relative);
+    let escapes = relative_path.components().any(|component| {
+        !matches!(
+            component,
+            std::path::Component::Normal(_) | std::path::Component::CurDir
+        )
+    });
+    if relative.is_empty() || escapes {
+        return Err(sysexit_error(
+            Sysexit::Config,
+            format!(
+                "maildirRelative '{}' must be a relative path, without '..' components",
+                relative
+            ),
+        ));
+    }
+    Ok(home.join(relative_path))
+}
+
+/// validate that a path to a Maildir/new
+///
+/// - is an absolute path
+/// - has `new` as the last component
+/// - has `Maildir` as the second-to-last component
+///
+/// Return Maildir path if valid, or an error message if not.
+pub fn parse_maildir_new_path(maildir_new_path: &Path) -> Result<PathBuf> {
+    if !maildir_new_path.is_absolute() {
+        anyhow::bail!(
+            "mailDir path '{:?}' is not an absolute path",
+            maildir_new_path
+        );
+    }
+
+    let components = maildir_new_path.components().collect::<Vec<_>>();
+
+    match components.as_slice() {
+        [.., second_to_last, last] => {
+            if last.as_os_str() != "new" {
+                anyhow::bail!(
+                    "mailDir path '{:?}' does not end in 'new'",
+                    maildir_new_path
+                );
+            }
+            if second_to_last.as_os_str() != "Maildir" {
+                anyhow::bail!(
+                    "mailDir path '{:?}' does not have 'Maildir' as the second-to-last component",
+                    maildir_new_path
+                );
+            }
+        }
+        _ => {
+            anyhow::bail!(
+                "mailDir path '{:?}' does not end in /Maildir/new",
+                maildir_new_path
+            );
+        }
+    }
+
+    let maildir = maildir_new_path.parent().ok_or_else(||
+        // actually, if we are here, there is necessarily a parent, but the compiler doesn't
+        // know that
+        anyhow::anyhow!("mailDir path '{:?}' has no parent", maildir_new_path))?;
+
+    Ok(PathBuf::from(maildir))
+}
+
+/// Work out how to report a failure to store a message in a Maildir.
+///
+/// Running out of disk space (`ENOSPC`) and exceeding a disk quota (`EDQUOT`) are both
+/// reported as temporary failures (`EX_TEMPFAIL`), so the MTA will retry delivery later.
+/// But a full disk affects the whole system and will hopefully be fixed soon, whereas a full
+/// mailbox is specific to one user and may never be -- so if `bounce_on_quota_full` is set, an
+/// exceeded quota is instead reported as a permanent failure (`EX_NOPERM`), and the MTA
+/// should bounce the message.
+fn store_error(err: MaildirError, bounce_on_quota_full: bool) -> anyhow::Error {
+    let errno = match &err {
+        MaildirError::Io(io_err) => io_err.raw_os_error().map(Errno::from_raw),
+        _ => None,
+    };
+
+    match errno {
+        Some(Errno::ENOSPC) => {
+            log::warn!("No space left on device while storing message: {}", err);
+            sysexit_error(
+                Sysexit::TempFail,
+                format!(
+                    "Couldn't store message in maildir, no space left on device: {}",
+                    err
+                ),
+            )
+        }
+        Some(Errno::EDQUOT) => {
+            log::warn!(
+                "Mailbox full (disk quota exceeded) while storing message: {}",
+                err
+            );
+            let status = if bounce_on_quota_full {
+                Sysexit::NoPerm
+            } else {
+                Sysexit::TempFail
+            };
+            sysexit_error(
+                status,
+                format!("Couldn't store message in maildir, mailbox full: {}", err),
+            )
+        }
+        _ => anyhow::anyhow!("Couldn't store message in maildir: {}", err),
+    }
+}
+
+/// Make the time-and-process part of a Maildir filename for a message delivered at `time`:
+/// `<secs>.M<micros>P<pid>`. (See [`store_new_unique`] for the rest of the name.)
+pub fn maildir_name_base(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!(
+        "{}.M{}P{}",
+        since_epoch.as_secs(),
+        since_epoch.subsec_micros(),
+        std::process::id()
+    )
+}
+
+/// Make a candidate Maildir filename, `<base>R<random>.<host>`, from the `base` made by
+/// [`maildir_name_base`], a random number from `rng`, and this machine's hostname (with `/`
+/// and `:` encoded, as the Maildir spec requires).
+pub fn maildir_candidate_name<G: RngCore>(base: &str, rng: &mut G) -> String {
+    let host = get_hostname().replace('/', "\\057").replace(':', "\\072");
+    format!("{}R{:08x}.{}", base, rng.next_u32(), host)
+}
+
+/// The Maildir flags a message can be delivered with (see `Config::deliverFlags`), in the
+/// alphabetical order the Maildir spec requires: draft, flagged, passed, replied, seen and
+/// trashed.
+pub const MAILDIR_FLAGS: &str = "DFPRST";
+
+/// Parse a set of Maildir flags (any of [`MAILDIR_FLAGS`], e.g. `SD`), returning them in
+/// alphabetical order, without duplicates (e.g. `DS`) -- as they need to appear in a filename's
+/// `:2,` info section. Returns an error naming any other character.
+pub fn parse_maildir_flags(flags: &str) -> Result<String> {
+    if let Some(bad) = flags.chars().find(|&flag| !MAILDIR_FLAGS.contains(flag)) {
+        anyhow::bail!(
+            "invalid Maildir flag '{}' in '{}' (only {} are allowed)",
+            bad,
+            flags,
+            MAILDIR_FLAGS
+        );
+    }
+    Ok(MAILDIR_FLAGS
+        .chars()
+        .filter(|&flag| flags.contains(flag))
+        .collect())
+}
+
+/// The `:2,` info section for a message with `flags`: the flag characters in ASCII order,
+/// without duplicates (e.g. `:2,DS` for `SD`), since the Maildir spec requires that, and
+/// stricter clients (e.g. Dovecot) complain about anything else.
+pub fn maildir_info_suffix(flags: &str) -> String {
+    let mut flags: Vec<char> = flags.chars().collect();
+    flags.sort_unstable();
+    flags.dedup();
+    format!(":2,{}", flags.into_iter().collect::<String>())
+}
+
+/// The path of the message with id `id` in the Maildir at `maildir_path`. Ids with a `:2,`
+/// info section (see `Config::deliverFlags`) are in `cur/`; others are still in `new/`.
+pub fn message_path(maildir_path: &Path, id: &str) -> PathBuf {
+    let subdir = if id.contains(":2,") { "cur" } else { "new" };
+    maildir_path.join(subdir).join(id)
+}
+
+/// Somewhere messages can be delivered to: normally a [`Maildir`], but abstracted so that
+/// delivery logic (filename collisions, retries and so on) can be tested without a real
+/// filesystem, using an [`InMemoryTarget`].
+pub trait DeliveryTarget {
+    /// The path reported for deliveries to this target (see [`DeliveryReport`]).
+    fn path(&self) -> &Path;
+
+    /// Create whatever the target needs (for a Maildir, its `cur`, `new` and `tmp`
+    /// directories), if it doesn't already exist.
+    fn ensure_dirs(&self) -> std::io::Result<()>;
+
+    /// Whether a message with id `id` has been delivered to the target.
+    fn exists(&self, id: &str) -> bool;
+
+    /// Store `data` as a new message with id `id`, returning the id it was actually stored
+    /// with (which should be `id`). If the id is already taken, this must fail with an
+    /// [`std::io::ErrorKind::AlreadyExists`] error, and leave the existing message alone.
+    fn store(&self, id: &str, data: &[u8]) -> std::result::Result<String, MaildirError>;
+
+    /// Like [`DeliveryTarget::store`], but creating the message's file as `strategy` says, for
+    /// targets where that means anything. By default, just the same as `store`.
+    fn store_with_strategy(
+        &self,
+        id: &str,
+        data: &[u8],
+        strategy: TmpfileStrategy,
+    ) -> std::result::Result<String, MaildirError> {
+        let _ = strategy;
+        self.store(id, data)
+    }
+
+    /// Remove the message with id `id`.
+    fn remove(&self, id: &str) -> std::io::Result<()>;
+
+    /// Set the permissions of the stored message with id `id` to `mode` (as for `chmod`).
+    fn set_mode(&self, id: &str, mode: u32) -> std::io::Result<()>;
+}
+
+impl DeliveryTarget for Maildir {
+    fn path(&self) -> &Path {
+        Maildir::path(self)
+    }
+
+    fn ensure_dirs(&self) -> std::io::Result<()> {
+        self.create_dirs()
+    }
+
+    fn exists(&self, id: &str) -> bool {
+        message_path(self.path(), id).exists()
+    }
+
+    /// The message is written to a file in `tmp/`, which is then hard-linked into `new/` (or
+    /// `cur/`, if the id has an info section -- see [`message_path`]).
+    fn store(&self, id: &str, data: &[u8]) -> std::result::Result<String, MaildirError> {
+        let tmp_path = self.path().join("tmp").join(id);
+        let new_path = message_path(self.path(), id);
+
+        let mut file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        let written = file.write_all(data).and_then(|_| file.sync_all());
+        let linked = written.and_then(|_| std::fs::hard_link(&tmp_path, &new_path));
+        let _ = std::fs::remove_file(&tmp_path);
+        linked?;
+        Ok(id.to_string())
+    }
+
+    /// With [`TmpfileStrategy::OTmpfile`] on Linux, the message is written with
+    /// [`store_o_tmpfile`] -- unless the filesystem doesn't support `O_TMPFILE`, in which case
+    /// (as with [`TmpfileStrategy::Classic`]) it's stored as for [`DeliveryTarget::store`].
+    fn store_with_strategy(
+        &self,
+        id: &str,
+        data: &[u8],
+        strategy: TmpfileStrategy,
+    ) -> std::result::Result<String, MaildirError> {
+        #[cfg(target_os = "linux")]
+        if strategy == TmpfileStrategy::OTmpfile {
+            match store_o_tmpfile(self.path(), id, data) {
+                Ok(()) => return Ok(id.to_string()),
+                Err(e)
+                    if matches!(
+                        Errno::from_raw(e.raw_os_error().unwrap_or(0)),
+                        Errno::EOPNOTSUPP | Errno::EISDIR
+                    ) =>
+                {
+                    log::debug!("O_TMPFILE not supported ({}), using a named tmp file", e);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = strategy;
+        self.store(id, data)
+    }
+
+    fn remove(&self, id: &str) -> std::io::Result<()> {
+        std::fs::remove_file(message_path(self.path(), id))
+    }
+
+    fn set_mode(&self, id: &str, mode: u32) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(
+            message_path(self.path(), id),
+            std::fs::Permissions::from_mode(mode),
+        )
+    }
+}
+
+/// Store `data` as the message with id `id` in the Maildir at `maildir_path`, by writing it to
+/// an unnamed `O_TMPFILE` file in `tmp/`, then linking that into `new/` (or `cur/` -- see
+/// [`message_path`]) with `linkat`. If the id is already taken, this fails with an
+/// [`std::io::ErrorKind::AlreadyExists`] error, as [`DeliveryTarget::store`] must.
+///
+/// The file is linked via its `/proc/self/fd` entry, since `linkat`'s alternative,
+/// `AT_EMPTY_PATH`, needs privileges we won't have after dropping them.
+#[cfg(target_os = "linux")]
+pub fn store_o_tmpfile(maildir_path: &Path, id: &str, data: &[u8]) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = File::options()
+        .write(true)
+        .custom_flags(nix::libc::O_TMPFILE)
+        .open(maildir_path.join("tmp"))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    let fd_path = PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()));
+    nix::unistd::linkat(
+        None,
+        fd_path.as_path(),
+        None,
+        message_path(maildir_path, id).as_path(),
+        nix::fcntl::AtFlags::AT_SYMLINK_FOLLOW,
+    )?;
+    Ok(())
+}
+
+/// A [`DeliveryTarget`] which just keeps delivered messages in memory, for testing.
+#[derive(Debug, Default)]
+pub struct InMemoryTarget {
+    path: PathBuf,
+    messages: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl InMemoryTarget {
+    /// Make an empty target, which reports deliveries as being to `path`.
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        InMemoryTarget {
+            path: path.into(),
+            messages: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a copy of the message with id `id`, if there is one.
+    pub fn message(&self, id: &str) -> Option<Vec<u8>> {
+        self.messages.lock().unwrap().get(id).cloned()
+    }
+
+    /// The ids of all messages stored, in no particular order.
+    pub fn ids(&self) -> Vec<String> {
+        self.messages.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+impl DeliveryTarget for InMemoryTarget {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn ensure_dirs(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn exists(&self, id: &str) -> bool {
+        self.messages.lock().unwrap().contains_key(id)
+    }
+
+    fn store(&self, id: &str, data: &[u8]) -> std::result::Result<String, MaildirError> {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.contains_key(id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("message {} already exists", id),
+            )
+            .into());
+        }
+        messages.insert(id.to_string(), data.to_vec());
+        Ok(id.to_string())
+    }
+
+    fn remove(&self, id: &str) -> std::io::Result<()> {
+        match self.messages.lock().unwrap().remove(id) {
+            Some(_) => Ok(()),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no message {}", id),
+            )),
+        }
+    }
+
+    /// Messages in memory have no permissions, so this only checks the message exists.
+    fn set_mode(&self, id: &str, _mode: u32) -> std::io::Result<()> {
+        if self.exists(id) {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no message {}", id),
+            ))
+        }
+    }
+}
+
+/// Store `data` as a new message in `target`, returning its id (i.e., filename). If `flags`
+/// are given (see [`parse_maildir_flags`]), they're added to the id in a `:2,` info section
+/// (see [`maildir_info_suffix`]), so the message is stored in `cur/` (see [`message_path`]).
+///
+/// If the id we try is already taken -- which can happen, rarely, under heavy concurrent
+/// delivery -- we try again with a new random component in the name (see
+/// [`maildir_candidate_name`]), up to `retries` more times.
+///
+/// The message's file is created as `strategy` says (see [`TmpfileStrategy`]).
+pub fn store_new_unique<T: DeliveryTarget + ?Sized, G: RngCore>(
+    target: &T,
+    data: &[u8],
+    base: &str,
+    flags: Option<&str>,
+    retries: u64,
+    strategy: TmpfileStrategy,
+    rng: &mut G,
+) -> std::result::Result<String, MaildirError> {
+    for attempt in 0..=retries {
+        let mut name = maildir_candidate_name(base, rng);
+        if let Some(flags) = flags {
+            name.push_str(&maildir_info_suffix(flags));
+        }
+        match target.store_with_strategy(&name, data, strategy) {
+            Ok(id) => return Ok(id),
+            Err(MaildirError::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                log::debug!("Filename {} already in use (attempt {})", name, attempt);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        format!("couldn't find an unused filename after {} retries", retries),
+    )
+    .into())
+}
+
+/// Whether `id` looks like an id made by [`maildir_candidate_name`] --
+/// `<secs>.M<micros>P<pid>R<random>.<host>`, possibly followed by a `:2,<flags>` info section
+/// (see [`store_new_unique`]) -- and so is safe to use as a filename.
+pub fn is_valid_maildir_id(id: &str) -> bool {
+    fn is_digits(s: &str) -> bool {
+        !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+    }
+
+    let id = match id.split_once(":2,") {
+        Some((id, flags)) if flags.chars().all(|flag| MAILDIR_FLAGS.contains(flag)) => id,
+        Some(_) => return false,
+        None => id,
+    };
+
+    let Some((secs, rest)) = id.split_once(".M") else {
+        return false;
+    };
+    let Some((micros, rest)) = rest.split_once('P') else {
+        return false;
+    };
+    let Some((pid, rest)) = rest.split_once('R') else {
+        return false;
+    };
+    let Some((random, host)) = rest.split_once('.') else {
+        return false;
+    };
+    is_digits(secs)
+        && is_digits(micros)
+        && is_digits(pid)
+        && !random.is_empty()
+        && random.bytes().all(|b| b.is_ascii_hexdigit())
+        && !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && b != b'/' && b != b':')
+}
+
+/// Check that `id`, the id a message was stored in `target` with, is valid (see
+/// [`is_valid_maildir_id`]). If it isn't, we can't trust that the message really was
+/// delivered, or report where to: so try to remove the message (if the id is at least safe
+/// to use as a filename), and return an `EX_TEMPFAIL` error, so the delivery is retried.
+fn check_stored_id<T: DeliveryTarget>(target: &T, id: &str) -> Result<()> {
+    if is_valid_maildir_id(id) {
+        return Ok(());
+    }
+    log::warn!("Message was stored with invalid id {:?}", id);
+
+    let safe_filename = !id.is_empty() && id != "." && id != ".." && !id.contains('/');
+    if safe_filename {
+        if let Err(e) = target.remove(id) {
+            log::warn!(
+                "Couldn't remove message stored with invalid id {:?}: {}",
+                id,
+                e
+            );
+        }
+    }
+
+    Err(sysexit_error(
+        Sysexit::TempFail,
+        format!("Message was stored with invalid id {:?}", id),
+    ))
+}
+
+/// Store a delivered message using `store` (normally [`store_new_unique`]), returning the
+/// new message's id.
+fn store_message<F>(store: F, message: &[u8], config: &Config) -> Result<String>
+where
+    F: FnOnce(&[u8]) -> std::result::Result<String, MaildirError>,
+{
+    store(message).map_err(|e| store_error(e, config.bounceOnQuotaFull))
+}
+
+/// Check that messages can really be delivered to the Maildir at `maildir_path` -- catching
+/// read-only mounts, full disks, ACLs and the like, which looking at permission bits
+/// wouldn't -- by writing a small probe file in its `tmp/` directory, renaming it, and deleting
+/// it again. If a step fails, the error says which, and gives the errno; the underlying
+/// [`std::io::Error`] is its source.
+pub fn probe_maildir_writable(maildir_path: &Path) -> Result<()> {
+    let name = maildir_candidate_name(
+        &maildir_name_base(SystemTime::now()),
+        &mut StdRng::from_entropy(),
+    );
+    let probe_path = maildir_path.join("tmp").join(format!("{}.probe", name));
+    let renamed_path = maildir_path
+        .join("tmp")
+        .join(format!("{}.probe-renamed", name));
+
+    let step_error = |step: &str, path: &Path, e: std::io::Error| {
+        let errno = e
+            .raw_os_error()
+            .map_or("unknown errno".to_string(), |code| {
+                format!("{:?}", Errno::from_raw(code))
+            });
+        anyhow::Error::new(e).context(format!(
+            "Couldn't {} probe file {:?} ({})",
+            step, path, errno
+        ))
+    };
+
+    File::options()
+        .write(true)
+        .create_new(true)
+        .open(&probe_path)
+        .and_then(|mut file| {
+            file.write_all(b"rattomail probe\n")
+                .and_then(|_| file.sync_all())
+        })
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&probe_path);
+            step_error("write", &probe_path, e)
+        })?;
+    std::fs::rename(&probe_path, &renamed_path).map_err(|e| {
+        let _ = std::fs::remove_file(&probe_path);
+        step_error("rename", &probe_path, e)
+    })?;
+    std::fs::remove_file(&renamed_path).map_err(|e| step_error("delete", &renamed_path, e))
+}
+
+/// Change the current directory to the parent of `path`, and return the path relative to it
+/// (i.e., just its last component).
+fn chdir_to_parent(path: &Path) -> Result<PathBuf> {
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        anyhow::bail!("path '{:?}' has no parent directory", path);
+    };
+    env::set_current_dir(parent)
+        .with_context(|| format!("Couldn't change directory to {:?}", parent))?;
+    log::debug!("Changed directory to {:?}", parent);
+    Ok(PathBuf::from(name))
+}
+
+/// The outcome of a successful delivery: for each maildir delivered to, its path and the
+/// ID the message was stored with.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DeliveryReport {
+    pub deliveries: Vec<(PathBuf, String)>,
+}
+
+/// Deliver a message to `maildir`, returning the ID it was stored with. If `dump` is given,
+/// the delivered message is also written to it -- but only once it's been stored, so a
+/// message that's rejected, or fails to be delivered, leaves nothing in the dump.
+///
+/// With `config.dateAtDelivery`, the input is first spooled (see [`spool_input`]), and the
+/// headers are then stamped with the time `clock` gives once it's all been read, instead of
+/// the envelope's received time.
+fn deliver_to_maildir<R: BufRead, T: DeliveryTarget>(
+    input: &mut R,
+    envelope: &Envelope,
+    maildir: &T,
+    config: &Config,
+    dump: Option<&mut dyn Write>,
+    clock: &dyn Fn() -> chrono::DateTime<Local>,
+) -> Result<String> {
+    let mut spool;
+    let delivery_envelope;
+    let (mut input, envelope): (&mut dyn BufRead, &Envelope) = if config.dateAtDelivery {
+        spool = spool_input(input)?;
+        delivery_envelope = Envelope {
+            received_time: clock(),
+            ..envelope.clone()
+        };
+        (&mut spool, &delivery_envelope)
+    } else {
+        (input, envelope)
+    };
+
+    // the message is only stored once it's complete, so an oversized one never gets as far as
+    // the Maildir
+    let mut mail_mesg_bytes = Vec::<u8>::new();
+    let mut limited = SizeLimitWriter::new(&mut mail_mesg_bytes, config.maxMessageSize);
+    let res = write_message_buffered(&mut input, &mut limited, envelope, config);
+    if let (true, Some(limit)) = (limited.exceeded(), config.maxMessageSize) {
+        return Err(message_too_large(limit));
+    }
+    res.context("Couldn't construct delivered message")?;
+
+    let size = mail_mesg_bytes.len();
+    trace_message(
+        size as u64,
+        &mail_mesg_bytes[..size.min(TRACE_DUMP_BYTES)],
+        &mail_mesg_bytes[size.saturating_sub(TRACE_DUMP_BYTES)..],
+    );
+
+    if config.validateRfc5322 {
+        validate_rfc5322(&mail_mesg_bytes)?;
+    }
+
+    let base = maildir_name_base(SystemTime::now());
+    let message_id = store_message(
+        |bytes| {
+            store_new_unique(
+                maildir,
+                bytes,
+                &base,
+                config.deliverFlags.as_deref(),
+                config.filenameRetries,
+                config.tmpfileStrategy,
+                &mut StdRng::from_entropy(),
+            )
+        },
+        &mail_mesg_bytes,
+        config,
+    )?;
+    check_stored_id(maildir, &message_id)?;
+
+    if let Some(mode) = config.messageMode {
+        // a message left with the wrong permissions might be readable by the wrong people, so
+        // it's better not delivered at all
+        if let Err(e) = maildir.set_mode(&message_id, mode) {
+            let _ = maildir.remove(&message_id);
+            return Err(sysexit_error(
+                Sysexit::TempFail,
+                format!(
+                    "Couldn't set mode {:04o} on delivered message {}: {}",
+                    mode, message_id, e
+                ),
+            ));
+        }
+    }
+
+    log::debug!("Message successfully delivered, with id: {}", message_id);
+
+    if let Some(dump) = dump {
+        // the message has been delivered regardless, so this isn't a delivery failure
+        if let Err(e) = dump.write_all(&mail_mesg_bytes).and_then(|()| dump.flush()) {
+            print_warning(&format!("couldn't write message dump: {}", e));
+        }
+    }
+
+    Ok(message_id)
+}
+
+/// Environment variable in which [`run_index_command`] passes the delivered message's path.
+pub const INDEX_MESSAGE_PATH_VAR: &str = "RATTOMAIL_MESSAGE_PATH";
+
+/// Run `command` (with `sh -c`) to index a newly delivered message, passing the message's
+/// path in the environment variable named by [`INDEX_MESSAGE_PATH_VAR`]. The command runs
+/// as whatever user we're running as -- normally, the delivery user, since privileges have
+/// been dropped by then.
+///
+/// If the command runs for longer than `timeout`, it's killed. Returns an error if the command
+/// can't be run, times out, or exits unsuccessfully.
+pub fn run_index_command(command: &str, message_path: &Path, timeout: Duration) -> Result<()> {
+    let mut child = std::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env(INDEX_MESSAGE_PATH_VAR, message_path)
+        .stdin(std::process::Stdio::null())
+        .spawn()
+        .with_context(|| format!("Couldn't run index command '{}'", command))?;
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .context("Couldn't wait for index command")?
+        {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            anyhow::bail!(
+                "index command '{}' timed out after {} seconds",
+                command,
+                timeout.as_secs()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    if !status.success() {
+        anyhow::bail!("index command '{}' failed: {}", command, status);
+    }
+    Ok(())
+}
+
+/// If `config.indexCommand` is set, run it (see [`run_index_command`]) for each of the messages
+/// in a delivery report. Failures are only logged, since the message has been delivered
+/// regardless.
+pub fn index_delivered_messages(report: &DeliveryReport, config: &Config) {
+    let Some(command) = &config.indexCommand else {
+        return;
+    };
+    let timeout = Duration::from_secs(config.indexTimeout);
+
+    for (maildir_path, message_id) in &report.deliveries {
+        let message_path = message_path(maildir_path, message_id);
+        match run_index_command(command, &message_path, timeout) {
+            Ok(()) => log::debug!("Indexed message {:?}", message_path),
+            Err(e) => {
+                log::warn!("Couldn't index message {:?}: {}", message_path, e);
+                print_warning(&format!("couldn't index message {:?}: {}", message_path, e));
+            }
+        }
+    }
+}
+
+/// Get the sender and subject of a delivered `message`, for a new mail notification. The
+/// sender is the `From:` header's display name (or else its address); if the message has no
+/// usable `From:`, `fallback_sender` (normally the envelope sender) is used instead. A message
+/// without a subject gives an empty one.
+pub fn notification_summary(message: &[u8], fallback_sender: &str) -> (String, String) {
+    let parsed = MessageParser::default().parse_headers(message);
+    let sender = parsed
+        .as_ref()
+        .and_then(|msg| msg.from())
+        .and_then(|from| from.first())
+        .and_then(|addr| addr.name().or(addr.address()))
+        .unwrap_or(fallback_sender)
+        .to_string();
+    let subject = parsed
+        .as_ref()
+        .and_then(|msg| msg.subject())
+        .unwrap_or("")
+        .to_string();
+    (sender, subject)
+}
+
+/// Ask the desktop's notification server (`org.freedesktop.Notifications`), on the D-Bus
+/// `connection`, to show a "New mail from ..." notification for a message.
+#[cfg(feature = "dbus")]
+pub fn send_delivery_notification(
+    connection: &zbus::blocking::Connection,
+    sender: &str,
+    subject: &str,
+) -> Result<()> {
+    let hints: HashMap<&str, zbus::zvariant::Value> = HashMap::new();
+    connection
+        .call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.freedesktop.Notifications"),
+            "Notify",
+            &(
+                "rattomail",
+                0u32,
+                "mail-unread",
+                format!("New mail from {}", sender),
+                subject,
+                Vec::<&str>::new(),
+                hints,
+                -1i32,
+            ),
+        )
+        .context("Couldn't send notification")?;
+    Ok(())
+}
+
+/// If `config.dbusNotify` is set, send a desktop notification (see
+/// [`send_delivery_notification`]) on the D-Bus session bus for each of the messages in a
+/// delivery report. As with [`index_delivered_messages`], failures -- including there being
+/// no session bus to connect to, which is usual when we're invoked by an MTA -- are only
+/// logged.
+///
+/// Without the `dbus` feature, this just warns that notifications aren't supported.
+pub fn notify_delivered_messages(report: &DeliveryReport, envelope: &Envelope, config: &Config) {
+    if !config.dbusNotify {
+        return;
+    }
+
+    #[cfg(feature = "dbus")]
+    {
+        let connection = match zbus::blocking::Connection::session() {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::warn!("Couldn't connect to D-Bus session bus: {}", e);
+                return;
+            }
+        };
+        for (maildir_path, message_id) in &report.deliveries {
+            let message_path = message_path(maildir_path, message_id);
+            let res = std::fs::read(&message_path)
+                .context("Couldn't read delivered message")
+                .and_then(|message| {
+                    let (sender, subject) = notification_summary(&message, &envelope.from_addr);
+                    send_delivery_notification(&connection, &sender, &subject)
+                });
+            match res {
+                Ok(()) => log::debug!("Sent notification for message {:?}", message_path),
+                Err(e) => log::warn!("Couldn't notify for message {:?}: {:#}", message_path, e),
+            }
+        }
+    }
+
+    #[cfg(not(feature = "dbus"))]
+    {
+        let _ = (report, envelope);
+        log::warn!("dbusNotify is set, but rattomail was built without D-Bus support");
+    }
+}
+
+/// A receipt for a delivered message, written to the file descriptor given with
+/// `--receipt-fd`, so that whoever invoked us can confirm delivery without having to parse
+/// stdout or stderr.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReceipt {
+    pub message_id: String,
+    pub recipient: String,
+    /// size of the delivered message, in bytes
+    pub size: u64,
+    /// when the message was received, in RFC 3339 format
+    pub timestamp: String,
+}
+
+impl DeliveryReceipt {
+    /// Format the receipt as one `name: value` line per field (`message-id`, `recipient`,
+    /// `size` and `timestamp`, in that order). If `key` is given, the receipt is signed: a
+    /// final `hmac-sha256` line gives the HMAC (see [`receipt_hmac`]) of all the lines before
+    /// it, including their newlines.
+    pub fn format(&self, key: Option<&[u8]>) -> String {
+        let mut receipt = format!(
+            "message-id: {}\nrecipient: {}\nsize: {}\ntimestamp: {}\n",
+            self.message_id, self.recipient, self.size, self.timestamp
+        );
+        if let Some(key) = key {
+            let hmac = receipt_hmac(key, receipt.as_bytes());
+            receipt.push_str(&format!("hmac-sha256: {}\n", hmac));
+        }
+        receipt
+    }
+}
+
+/// The HMAC-SHA256 of `data` using `key`, as lowercase hex.
+pub fn receipt_hmac(key: &[u8], data: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Get the key to sign delivery receipts with, from `config.receiptKey` or the file named by
+/// `config.receiptKeyFile` (less any trailing newline). It's an `EX_CONFIG` error if both are
+/// set, or the file can't be read. This should be done before dropping privileges, so the key
+/// file needn't be readable by the delivery user.
+pub fn read_receipt_key(config: &Config) -> Result<Option<Vec<u8>>> {
+    match (&config.receiptKey, &config.receiptKeyFile) {
+        (None, None) => Ok(None),
+        (Some(key), None) => Ok(Some(key.as_bytes().to_vec())),
+        (None, Some(path)) => {
+            let mut key = std::fs::read(path).map_err(|e| {
+                sysexit_error(
+                    Sysexit::Config,
+                    format!("Couldn't read receipt key file '{}': {}", path, e),
+                )
+            })?;
+            while key.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+                key.pop();
+            }
+            Ok(Some(key))
+        }
+        (Some(_), Some(_)) => Err(sysexit_error(
+            Sysexit::Config,
+            "Only one of receiptKey and receiptKeyFile may be set".to_string(),
+        )),
+    }
+}
+
+/// Take ownership of the already-open file descriptor given (as a decimal number) with the
+/// command-line option `option`. It's an `EX_USAGE` error if `fd_arg` isn't a number, or isn't
+/// an open file descriptor.
+fn open_fd_arg(option: &str, fd_arg: &str) -> Result<File> {
+    let fd: RawFd = fd_arg
+        .parse()
+        .ok()
+        .filter(|&fd: &RawFd| fd >= 0)
+        .ok_or_else(|| {
+            sysexit_error(
+                Sysexit::Usage,
+                format!("{}: '{}' is not a file descriptor", option, fd_arg),
+            )
+        })?;
+    nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD).map_err(|e| {
+        sysexit_error(
+            Sysexit::Usage,
+            format!("{}: file descriptor {} isn't open: {}", option, fd, e),
+        )
+    })?;
+    // SAFETY: the descriptor is open, and nothing else in the program uses it
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Take ownership of the already-open file descriptor given with `--receipt-fd` (as a decimal
+/// number), to write delivery receipts to. It's an `EX_USAGE` error if `fd_arg` isn't a
+/// number, or isn't an open file descriptor.
+pub fn open_receipt_fd(fd_arg: &str) -> Result<File> {
+    open_fd_arg("--receipt-fd", fd_arg)
+}
+
+/// Read an address from the already-open file descriptor given with `option`
+/// (`--envelope-from-fd` or `--recipient-fd`) -- which lets the invoking program pass
+/// addresses without them being visible in our command line. Everything up to end-of-file is
+/// read, and the descriptor closed; a trailing newline is removed. The address is then
+/// validated just like one given on the command line.
+///
+/// It's an `EX_USAGE` error if `fd_arg` isn't an open file descriptor (as for
+/// [`open_receipt_fd`]), or what's read from it isn't UTF-8.
+pub fn read_address_fd(option: &str, fd_arg: &str) -> Result<String> {
+    let mut file = open_fd_arg(option, fd_arg)?;
+    let mut address = String::new();
+    file.read_to_string(&mut address).map_err(|e| {
+        sysexit_error(
+            Sysexit::Usage,
+            format!(
+                "{}: couldn't read an address from file descriptor {}: {}",
+                option, fd_arg, e
+            ),
+        )
+    })?;
+    let len = address.trim_end_matches(['\r', '\n']).len();
+    address.truncate(len);
+    Ok(address)
+}
+
+/// Write a receipt (see [`DeliveryReceipt`]), signed with `key` if there is one, to `output`
+/// for each of the messages in a delivery report.
+pub fn write_delivery_receipts<W: Write>(
+    report: &DeliveryReport,
+    envelope: &Envelope,
+    key: Option<&[u8]>,
+    output: &mut W,
+) -> Result<()> {
+    for (maildir_path, message_id) in &report.deliveries {
+        let message_path = message_path(maildir_path, message_id);
+        let size = std::fs::metadata(&message_path)
+            .with_context(|| format!("Couldn't get size of message {:?}", message_path))?
+            .len();
+        let receipt = DeliveryReceipt {
+            message_id: message_id.clone(),
+            recipient: envelope.to_addr.clone(),
+            size,
+            timestamp: envelope.received_time.to_rfc3339(),
+        };
+        output
+            .write_all(receipt.format(key).as_bytes())
+            .context("Couldn't write receipt")?;
+    }
+    output.flush().context("Couldn't write receipt")
+}
+
+/// Name of the file, in the top directory of a Maildir, in which the most recent delivery is
+/// recorded (see [`write_last_delivery_status`]).
+pub const LAST_DELIVERY_FILE: &str = ".rattomail-last-delivery";
+
+/// For each maildir in a delivery report, replace its [`LAST_DELIVERY_FILE`] with a record of
+/// this delivery -- `timestamp:`, `sender:` and `id:` lines -- so that monitoring scripts can
+/// easily check when mail last arrived. The file is replaced atomically (by writing a temporary
+/// file, then renaming it), so is never seen half-written.
+pub fn write_last_delivery_status(report: &DeliveryReport, envelope: &Envelope) -> Result<()> {
+    let status = |message_id: &str| {
+        format!(
+            "timestamp: {}\nsender: {}\nid: {}\n",
+            envelope.received_time.to_rfc3339(),
+            envelope.from_addr,
+            message_id
+        )
+    };
+    for (maildir_path, message_id) in &report.deliveries {
+        let path = maildir_path.join(LAST_DELIVERY_FILE);
+        let mut temp_file = tempfile::Builder::new()
+            .prefix(LAST_DELIVERY_FILE)
+            .tempfile_in(maildir_path)
+            .with_context(|| format!("Couldn't create temporary file in {:?}", maildir_path))?;
+        temp_file
+            .write_all(status(message_id).as_bytes())
+            .with_context(|| format!("Couldn't write {:?}", temp_file.path()))?;
+        temp_file
+            .persist(&path)
+            .with_context(|| format!("Couldn't replace {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Read all of `input` into an anonymous temporary "spool" file, and return a reader
+/// for the file, rewound to the start.
+///
+/// Used when the input needs to be read more than once -- which we can't do with stdin,
+/// since it isn't seekable.
+pub fn spool_input<R: BufRead>(input: &mut R) -> Result<BufReader<File>> {
+    let mut spool = tempfile::tempfile().context("Couldn't create temporary spool file")?;
+    std::io::copy(input, &mut spool).context("Couldn't write input to spool file")?;
+    spool
+        .rewind()
+        .context("Couldn't rewind spool file for reading")?;
+    Ok(BufReader::new(spool))
+}
+
+/// Deliver a message to each of `maildirs`. If `dump` is given, a copy of the delivered
+/// message (just one, however many maildirs there are) is also written to it -- e.g. for
+/// archiving or debugging.
+///
+/// Input is only ever read once. With a single maildir, it's streamed straight into the
+/// delivery; with more than one, it's first spooled to a temporary file (see
+/// [`spool_input`]), and each delivery re-reads it from there.
+pub fn deliver_to_maildirs<R: BufRead, T: DeliveryTarget>(
+    input: &mut R,
+    envelope: &Envelope,
+    maildirs: &[T],
+    config: &Config,
+    mut dump: Option<&mut dyn Write>,
+) -> Result<DeliveryReport> {
+    let mut report = DeliveryReport::default();
+
+    if let [maildir] = maildirs {
+        let message_id = deliver_to_maildir(input, envelope, maildir, config, dump, &Local::now)?;
+        report
+            .deliveries
+            .push((maildir.path().to_path_buf(), message_id));
+        return Ok(report);
+    }
+
+    let mut spool = spool_input(input)?;
+    for maildir in maildirs {
+        spool
+            .rewind()
+            .context("Couldn't rewind spool file for reading")?;
+        let message_id = deliver_to_maildir(
+            &mut spool,
+            envelope,
+            maildir,
+            config,
+            dump.take(),
+            &Local::now,
+        )
+        .with_context(|| format!("Couldn't deliver to maildir {:?}", maildir.path()))?;
+        report
+            .deliveries
+            .push((maildir.path().to_path_buf(), message_id));
+    }
+
+    Ok(report)
+}
+
+/// Async version of [`deliver_to_maildirs`], for use from within a tokio runtime: the
+/// (blocking) delivery is run on tokio's blocking thread pool, so as not to block the
+/// runtime's worker threads.
+#[cfg(feature = "async")]
+pub async fn deliver_async(
+    message: Vec<u8>,
+    envelope: Envelope,
+    maildirs: Vec<Maildir>,
+    config: Config,
+) -> Result<DeliveryReport> {
+    tokio::task::spawn_blocking(move || {
+        deliver_to_maildirs(
+            &mut std::io::Cursor::new(message),
+            &envelope,
+            &maildirs,
+            &config,
+            None,
+        )
+    })
+    .await
+    .context("Delivery task failed")?
+}
+
+/// Check whether `--assume-user` may be used: only when privileges aren't being dropped
+/// (i.e., in testing). Otherwise, it would let anyone have mail delivered as if it came from,
+/// or was addressed to, some other user.
+pub fn check_assume_user(
+    assume_user: Option<&str>,
+    should_drop_privs: PrivilegeOption,
+) -> Result<()> {
+    match (assume_user, should_drop_privs) {
+        (Some(name), PrivilegeOption::DropPrivileges) => anyhow::bail!(
+            "--assume-user {} can only be used for testing, when privileges aren't dropped",
+            name
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Check that a delivery to `recipients` doesn't exceed `config.maxRecipients`, so that a
+/// message with a huge number of recipients can't be used to trigger a huge number of
+/// deliveries. If it does, the whole delivery is rejected with `EX_DATAERR`.
+pub fn check_recipient_count(recipients: &[String], config: &Config) -> Result<()> {
+    if recipients.len() as u64 > config.maxRecipients {
+        return Err(sysexit_error(
+            Sysexit::DataErr,
+            format!(
+                "Too many recipients: {} given, but the maximum is {}",
+                recipients.len(),
+                config.maxRecipients
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Check whether a message from `from_addr` to `to_addr` should be delivered, given
+/// `config.rejectSelfAddressed`: returns `Ok(true)` if it should, `Ok(false)` if it should be
+/// dropped, and an `EX_DATAERR` error if it should be rejected.
+pub fn check_self_addressed(to_addr: &str, from_addr: &str, config: &Config) -> Result<bool> {
+    if to_addr != from_addr {
+        return Ok(true);
+    }
+    match config.rejectSelfAddressed {
+        SelfAddressedAction::Deliver => Ok(true),
+        SelfAddressedAction::Drop => {
+            log::info!("Dropping message addressed to its own sender, {}", to_addr);
+            Ok(false)
+        }
+        SelfAddressedAction::Reject => {
+            log::info!("Rejecting message addressed to its own sender, {}", to_addr);
+            Err(sysexit_error(
+                Sysexit::DataErr,
+                format!("Message is addressed to its own sender, {}", to_addr),
+            ))
+        }
+    }
+}
+
+/// Check whether a message read from `input` should be delivered, given `config.onEmptyInput`:
+/// returns `Ok(true)` if it should, `Ok(false)` if it's empty and should be skipped, and an
+/// `EX_NOINPUT` error if it's empty and should be rejected. Nothing is consumed from `input`.
+pub fn check_empty_input<R: BufRead>(input: &mut R, config: &Config) -> Result<bool> {
+    if config.onEmptyInput == EmptyInputAction::Deliver {
+        return Ok(true);
+    }
+    if !input.fill_buf().context("Couldn't read input")?.is_empty() {
+        return Ok(true);
+    }
+    match config.onEmptyInput {
+        EmptyInputAction::Deliver => Ok(true),
+        EmptyInputAction::Skip => {
+            log::info!("Skipping delivery of empty message");
+            Ok(false)
+        }
+        EmptyInputAction::Reject => {
+            log::info!("Rejecting empty message");
+            Err(sysexit_error(
+                Sysexit::NoInput,
+                "Message is empty".to_string(),
+            ))
+        }
+    }
+}
+
+/// Check if a string is plausible as an email address, in the very loosest sense.
+/// We require only that it (a) not be empty and (b) consist only of "graphical" ASCII characters
+/// (basically, all letters and digits and punctuation, but not whitespace or control
+/// characters).
+pub fn is_plausible_string(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_graphic())
+}
+
+/// Check the envelope sender `from_address`, returning the address to use: `from_address`
+/// itself if it's plausible (see [`is_plausible_string`]), and otherwise, according to
+/// `action`, an `EX_DATAERR` error, `from_address` with all whitespace, non-printable and
+/// non-ASCII characters removed, or `invoking_user`. (A warning is given when the sender is
+/// replaced.) If the replacement isn't plausible either, that's an `EX_DATAERR` error too.
+pub fn check_sender(
+    from_address: &str,
+    action: InvalidSenderAction,
+    invoking_user: &str,
+) -> Result<String> {
+    let implausible = |addr: &str| {
+        sysexit_error(
+            Sysexit::DataErr,
+            format!(
+                "From address '{}' contains non-ASCII, non-printable or whitespace characters, or is zero-length",
+                addr
+            ),
+        )
+    };
+    if is_plausible_string(from_address) {
+        return Ok(from_address.to_string());
+    }
+    let replacement = match action {
+        InvalidSenderAction::Reject => return Err(implausible(from_address)),
+        InvalidSenderAction::Sanitize => from_address
+            .chars()
+            .filter(|c| c.is_ascii_graphic())
+            .collect(),
+        InvalidSenderAction::Fallback => invoking_user.to_string(),
+    };
+    if !is_plausible_string(&replacement) {
+        return Err(implausible(&replacement));
+    }
+    print_warning(&format!(
+        "From address {:?} isn't plausible, using '{}' instead",
+        from_address, replacement
+    ));
+    Ok(replacement)
+}
+
+/// Main logic for the program. Various I/O-type values get injected here as arguments,
+/// for easy testing.
+///
+/// Arguments:
+/// - `allowable_program_names`: list of program names we expect to be invoked as (e.g.
+///   `sendmail`). It's an error if the program name is not one of these.
+/// - `ctx`: main context, containing arguments, config path, whether to drop privileges,
+///   time we were invoked, etc.
+/// - `input`: input stream to read from (stdin, in production)
+/// - `output`: optional output stream to write to. Should be `None` in production, but
+///   can be used for testing.
+///
+/// In production, we should _always_ drop privileges; for testing purposes,
+/// we might not.
+///
+/// Returns `Ok` once the message has been delivered (or, for options like `--version-json`,
+/// once their output has been written); or else a [`MainError`], which the caller should
+/// report and exit with (see [`MainError::exit`]). Under the [`ProgramProfile::Sendmail`]
+/// profile, a failure without a more specific [`Sysexit`] code exits with `EX_TEMPFAIL`, as
+/// with `--compat-strict` (see [`failure_exit_code`]), so the invoking MTA retries the message
+/// rather than bouncing it.
+pub fn main<R: BufRead, W: Write>(
+    allowable_program_names: &[&str],
+    ctx: &MainContext,
+    input: &mut R,
+    output_opt: Option<&mut W>,
+) -> Result<(), MainError> {
+    let mut profile = None;
+    match run_main(
+        allowable_program_names,
+        ctx,
+        input,
+        output_opt,
+        &mut profile,
+    ) {
+        Err(MainError::Failed {
+            code: 1,
+            message,
+            source,
+        }) if profile == Some(ProgramProfile::Sendmail) => Err(MainError::Failed {
+            code: Sysexit::TempFail.code(),
+            message,
+            source,
+        }),
+        res => res,
+    }
+}
+
+/// [`main`], recording in `profile` the [`ProgramProfile`] it runs with, once that's known.
+fn run_main<R: BufRead, W: Write>(
+    allowable_program_names: &[&str],
+    ctx: &MainContext,
+    input: &mut R,
+    output_opt: Option<&mut W>,
+    profile_out: &mut Option<ProgramProfile>,
+) -> Result<(), MainError> {
+    // needed before the arguments are parsed, since it changes how they're parsed
+    let compat_strict = ctx.args.iter().skip(1).any(|arg| arg == "--compat-strict");
+    set_compat_strict(compat_strict);
+
+    let prog_name = match ctx.args.as_slice() {
+        [prog_name, ..] => prog_name,
+        _ => {
+            return Err(MainError::new(
+                failure_exit_code(),
+                "No program name provided.".to_string(),
+            ));
+        }
+    };
+
+    // fail if not one of the expected program names. Only if it isn't one of the built-in
+    // ones does the config file need reading this early, for any it adds.
+    let is_built_in = Path::new(prog_name)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| allowable_program_names.contains(&name));
+    let extra_names = if is_built_in {
+        Vec::new()
+    } else {
+        configured_program_names(&ctx.config_path)
+    };
+    let prog_name = normalize_prog_name(allowable_program_names, &extra_names, prog_name)?;
+
+    let cli_options: Command = build_cli();
+
+    let cli_matches = if compat_strict {
+        parse_args_tolerantly(&cli_options, &ctx.args).map_err(|error| {
+            // --help and --version aren't failures
+            let code = if error.use_stderr() {
+                Sysexit::Usage.code()
+            } else {
+                error.exit_code()
+            };
+            MainError::Cli { error, code }
+        })?
+    } else {
+        cli_options
+            .try_get_matches_from(ctx.args.iter())
+            .map_err(|error| MainError::Cli {
+                code: error.exit_code(),
+                error,
+            })?
+    };
+
+    if cli_matches.get_flag("version_json") {
+        let metadata = BuildMetadata {
+            program_names: allowable_program_names
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            ..build_metadata()
+        };
+        println!("{}", metadata.to_json());
+        return Ok(());
+    }
+
+    if cli_matches.get_flag("generate_config") {
+        let output_path = cli_matches
+            .get_one::<String>("config_output")
+            .unwrap_or(&ctx.config_path);
+        let res = generate_config(
+            cli_matches
+                .get_one::<String>("config_user")
+                .map_or("", |s| s.as_str()),
+            cli_matches
+                .get_one::<String>("config_maildir")
+                .map(|s| s.as_str()),
+        )
+        .and_then(|config| {
+            write_new_config_ini(&config, output_path, cli_matches.get_flag("force"))
+        });
+        match res {
+            Ok(()) => {
+                println!("Wrote config file {}", output_path);
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(MainError::caused_by(
+                    failure_exit_code(),
+                    format!("Error generating config file: {}", e),
+                    e,
+                ));
+            }
+        }
+    }
+
+    if cli_matches.get_flag("read_config_only") {
+        check_config_file(&ctx.config_path)?;
+        return Ok(());
+    }
+
+    set_quiet(cli_matches.get_flag("quiet") || compat_strict);
+    set_trace(cli_matches.get_flag("trace"));
+
+    // set up logging. Any logfile other than the defaults can only be checked against the
+    // config file's allowedLogfiles once it's been read.
+    let mut pending_logfile = None;
+    if let Some(logfile_path) = cli_matches.get_one::<String>("logfile").cloned() {
+        if DEFAULT_LOGFILES.contains(&logfile_path.as_str()) {
+            init_logfile(logfile_path, &[])?;
+        } else {
+            pending_logfile = Some(logfile_path);
+        }
+    }
+
+    let sendmail_options = {
+        let get_all = |id: &str| -> Vec<String> {
+            cli_matches
+                .get_many::<String>(id)
+                .map_or_else(Vec::new, |vals| vals.cloned().collect())
+        };
+        let mut options = parse_sendmail_options(&get_all("o"), &get_all("O"));
+        options.ignore_dots |= cli_matches.get_flag("i");
+        options
+    };
+    log::debug!("Sendmail options: {:?}", sendmail_options);
+    if let Some(mode) = sendmail_options.delivery_mode {
+        if mode != DeliveryMode::Interactive {
+            log::debug!(
+                "Delivery mode {:?} requested, but we only support immediate delivery",
+                mode
+            );
+        }
+    }
+
+    let dsn_notify = cli_matches
+        .get_one::<String>("N")
+        .map(|conditions| parse_dsn_notify(conditions))
+        .transpose()?;
+
+    // addresses passed out-of-band take the place of the command-line ones
+    let read_fd_or_arg =
+        |fd_id: &str, option: &str, arg_id: &str| match cli_matches.get_one::<String>(fd_id) {
+            Some(fd_arg) => read_address_fd(option, fd_arg).map(Some),
+            None => Ok(cli_matches.get_one::<String>(arg_id).cloned()),
+        };
+    let sender_arg = read_fd_or_arg("envelope_from_fd", "--envelope-from-fd", "sender_env")?;
+    let recipient_arg = read_fd_or_arg("recipient_fd", "--recipient-fd", "to_address")?;
+
+    // a recipient given as `Name <addr-spec>` is routed by its bare address, but the full form
+    // is kept, to record in an X-Original-To: header
+    let original_recipient = recipient_arg
+        .clone()
+        .filter(|recipient| extract_addr_spec(recipient).is_some());
+    let recipient_arg = recipient_arg.map(|recipient| match extract_addr_spec(&recipient) {
+        Some(spec) => spec.to_string(),
+        None => recipient,
+    });
+
+    // likewise, recipients from MAILTO (as cron sets it). Empty means cron's "don't send mail".
+    let mailto_recipients = if cli_matches.get_flag("recipients_from_mailto") {
+        env::var(MAILTO_VAR).ok().map(|value| parse_mailto(&value))
+    } else {
+        None
+    };
+    if mailto_recipients
+        .as_ref()
+        .is_some_and(|recipients| recipients.is_empty())
+    {
+        log::info!("{} is empty, so not delivering anything", MAILTO_VAR);
+        return Ok(());
+    }
+
+    let assume_user = cli_matches.get_one::<String>("assume_user");
+    check_assume_user(assume_user.map(|s| s.as_str()), ctx.should_drop_privs)
+        .map_err(|e| MainError::caused_by(failure_exit_code(), format!("Error: {}", e), e))?;
+
+    // read config file to get maildir and user name to run as.
+    // We never run as root; permanently drop privileges to that user, and if the user
+    // _is_ root, fail with an error.
+    // Later on - if the specified user can't operate on the Maildir, we'll fail with an
+    // error then.
+
+    let config_path = &ctx.config_path;
+
+    log::debug!("Using config file: {:#?}", config_path);
+
+    let config = read_config_ini(config_path).map_err(|e| {
+        MainError::caused_by(
+            exit_code_for(&e),
+            format!("Error reading config file '{}': {}", config_path, e),
+            e,
+        )
+    })?;
+
+    if let Some(logfile_path) = pending_logfile {
+        init_logfile(logfile_path, &config.allowedLogfiles)?;
+    }
+
+    log::debug!("Read config: {:?}", config);
+
+    // names added with programNames behave as sendmail, unless programProfiles says otherwise
+    let profiles: Vec<(String, ProgramProfile)> = config
+        .programProfiles
+        .iter()
+        .cloned()
+        .chain(
+            config
+                .programNames
+                .iter()
+                .map(|name| (name.clone(), ProgramProfile::Sendmail)),
+        )
+        .collect();
+    let profile = program_profile(&prog_name, &profiles);
+    *profile_out = Some(profile);
+    log::debug!("Invoked as {}, using profile {}", prog_name, profile);
+    if profile == ProgramProfile::Lmtp {
+        return Err(MainError::new(
+            Sysexit::Unavailable.code(),
+            "Error: LMTP mode is not supported yet".to_string(),
+        ));
+    }
+
+    // the timeout covers everything from here on: reading the input, and storing the message
+    let delivery_timeout = match cli_matches.get_one::<String>("deliver_timeout") {
+        Some(secs) => Some(secs.parse::<u64>().map_err(|_| {
+            MainError::new(
+                Sysexit::Usage.code(),
+                format!(
+                    "Error: --deliver-timeout: '{}' is not a number of seconds",
+                    secs
+                ),
+            )
+        })?),
+        None => config.deliveryTimeoutSeconds,
+    }
+    .map(Duration::from_secs);
+    let deadline = delivery_timeout.map(|timeout| Instant::now() + timeout);
+    let _alarm = delivery_timeout
+        .map(DeliveryAlarm::arm)
+        .transpose()
+        .map_err(|e| MainError::caused_by(failure_exit_code(), format!("Error: {}", e), e))?;
+    let mut timed_input = DeadlineReader::new(input, deadline);
+    let input = &mut timed_input;
+
+    // with -t, the recipients are those listed in the message's headers, followed by any given
+    // as an argument. The headers are read now, and afterwards put back in front of the rest of
+    // the input (less any Bcc: headers).
+    let (header_block, listed_recipients) = if cli_matches.get_flag("t") {
+        let (block, mut recipients) = take_header_recipients(input, config.maxHeaderBytes)?;
+        recipients.extend(recipient_arg.clone());
+        if recipients.is_empty() {
+            return Err(MainError::new(
+                Sysexit::Usage.code(),
+                "Error: -t was given, but no recipients were found in the message's headers"
+                    .to_string(),
+            ));
+        }
+        (block, Some(recipients))
+    } else {
+        (Vec::new(), mailto_recipients)
+    };
+    let mut input = std::io::Cursor::new(header_block).chain(input);
+    let input = &mut input;
+    let recipient_arg = match &listed_recipients {
+        Some(recipients) => Some(recipients[0].clone()),
+        None => recipient_arg,
+    };
+
+    // the delivery user and Maildir are the same for every recipient, so can't depend on them
+    if listed_recipients
+        .as_ref()
+        .is_some_and(|recipients| recipients.len() > 1)
+        && uses_recipient_template(&config)
+    {
+        return Err(MainError::new(
+            failure_exit_code(),
+            format!(
+                "Error in config file '{}': config file uses %u, so mail can't be delivered to more than one recipient",
+                config_path
+            ),
+        ));
+    }
+
+    // likewise, they must all be routed to the same place
+    if let Some(recipients) = &listed_recipients {
+        let first_route = find_user_route(&config, &recipients[0]);
+        if let Some(other) = recipients
+            .iter()
+            .find(|recipient| find_user_route(&config, recipient) != first_route)
+        {
+            return Err(MainError::new(
+                failure_exit_code(),
+                format!(
+                    "Error in config file '{}': recipients '{}' and '{}' are routed to different Maildirs, so can't be delivered to together",
+                    config_path, recipients[0], other
+                ),
+            ));
+        }
+    }
+
+    let config = route_for_recipient(
+        config,
+        recipient_arg.as_ref().or(assume_user).map(|s| s.as_str()),
+    );
+
+    let config = expand_recipient_templates(
+        config,
+        recipient_arg.as_ref().or(assume_user).map(|s| s.as_str()),
+    )
+    .map_err(|e| {
+        MainError::caused_by(
+            failure_exit_code(),
+            format!("Error in config file '{}': {}", config_path, e),
+            e,
+        )
+    })?;
+
+    if config.userName == "root" {
+        return Err(MainError::new(
+            failure_exit_code(),
+            "Error: Cannot run as root. Please specify a different user in the config file."
+                .to_string(),
+        ));
+    }
+
+    // drop privileges to the user specified in the config file
+
+    let user_cache = UserCache::new(USER_CACHE_TTL);
+
+    let new_user = lookup_delivery_user(
+        &user_cache,
+        &config.userName,
+        config.nssRetries,
+        NSS_RETRY_BACKOFF,
+    )?;
+
+    // with maildirRelative, the Maildir is found from the delivery user's home directory
+    let config = match config.maildirRelative.clone() {
+        Some(relative) => {
+            let mail_dir = resolve_maildir_relative(&new_user.dir, &relative).map_err(|e| {
+                MainError::caused_by(
+                    exit_code_for(&e),
+                    format!("Error in config file '{}': {}", config_path, e),
+                    e,
+                )
+            })?;
+            Config {
+                mailDir: mail_dir.to_string_lossy().into_owned(),
+                ..config
+            }
+        }
+        None => config,
+    };
+
+    let delivery_gid = delivery_group_id(&config, &new_user)?;
+
+    // who invoked us - must be checked before dropping privileges
+    let invoking_user = get_current_user(&user_cache, config.nssRetries)
+        .map_err(|e| MainError::caused_by(exit_code_for(&e), e.to_string(), e))?;
+
+    // likewise, the key file may only be readable by root
+    let mut receipt = cli_matches
+        .get_one::<String>("receipt_fd")
+        .map(|fd_arg| {
+            open_receipt_fd(fd_arg).and_then(|file| Ok((file, read_receipt_key(&config)?)))
+        })
+        .transpose()?;
+
+    match ctx.should_drop_privs {
+        PrivilegeOption::NoDropPrivileges => {}
+        PrivilegeOption::DropPrivileges => {
+            drop_privileges(new_user, delivery_gid)?;
+        }
+    }
+
+    let from_address = match sender_arg.as_ref().or(assume_user) {
+        Some(sender) => sender.clone(),
+        None => get_default_sender(&user_cache, config.nssRetries)
+            .map_err(|e| MainError::caused_by(exit_code_for(&e), e.to_string(), e))?,
+    };
+
+    let from_address = check_sender(&from_address, config.onInvalidSender, &invoking_user)
+        .map_err(|e| MainError::caused_by(exit_code_for(&e), e.to_string(), e))?;
+
+    log::debug!("Using from_address: {:#?}", from_address);
+
+    // if no recipient address is provided, we'll use the name from the config file
+    let to_address = recipient_arg
+        .as_ref()
+        .or(assume_user)
+        .cloned()
+        .unwrap_or_else(|| config.userName.clone());
+
+    let to_addresses = listed_recipients.unwrap_or_else(|| vec![to_address]);
+
+    check_recipient_count(&to_addresses, &config)?;
+
+    for to_address in &to_addresses {
+        if !is_plausible_string(to_address) {
+            return Err(MainError::new(
+                failure_exit_code(),
+                format!(
+                    "Recipient address '{}' contains non-ASCII, non-printable or whitespace characters, or is zero-length",
+                    to_address
+                ),
+            ));
+        }
+    }
+
+    let (from_address, to_addresses) = if config.normalizeDomainCase {
+        (
+            normalize_domain_case(&from_address),
+            to_addresses
+                .iter()
+                .map(|to_address| normalize_domain_case(to_address))
+                .collect(),
+        )
+    } else {
+        (from_address, to_addresses)
+    };
+
+    log::debug!("Using to_addresses: {:#?}", to_addresses);
+    trace_stage("addresses", || {
+        format!("from: {}\nto: {}", from_address, to_addresses.join(", "))
+    });
+
+    // a Maildir given on the command line overrides the config file's. Since that would let
+    // anyone write files wherever the delivery user can, only root and the delivery user
+    // themselves may do so.
+    let (maildir_new_path, maildir_path) = match cli_matches.get_one::<String>("maildir_root") {
+        Some(_) if invoking_user != "root" && invoking_user != config.userName => {
+            return Err(MainError::new(
+                failure_exit_code(),
+                format!(
+                    "Error: --maildir-root can only be used by root or the delivery user ({})",
+                    config.userName
+                ),
+            ));
+        }
+        Some(root) => {
+            let maildir_path = parse_maildir_root_path(Path::new(root)).map_err(|err| {
+                MainError::caused_by(
+                    failure_exit_code(),
+                    format!("Error getting path to maildir: {}", err),
+                    err,
+                )
+            })?;
+            (maildir_path.join("new"), maildir_path)
+        }
+        None => {
+            let maildir_new_path = PathBuf::from(&config.mailDir);
+            let maildir_path = parse_maildir_new_path(&maildir_new_path).map_err(|err| {
+                MainError::caused_by(
+                    failure_exit_code(),
+                    format!("Error getting path to maildir: {}", err),
+                    err,
+                )
+            })?;
+            (maildir_new_path, maildir_path)
+        }
+    };
+
+    // if partitioning by date, deliver to a subfolder of the Maildir instead
+    let (maildir_new_path, maildir_path) = match &config.datePartition {
+        Some(pattern) => {
+            let folder =
+                date_partition_folder(pattern, &ctx.received_time, config.dateTimezone.as_ref())
+                    .map_err(|err| {
+                        MainError::caused_by(
+                            failure_exit_code(),
+                            format!("Error getting date-partitioned Maildir folder: {}", err),
+                            err,
+                        )
+                    })?;
+            let maildir_path = maildir_path.join(folder);
+            (maildir_path.join("new"), maildir_path)
+        }
+        None => (maildir_new_path, maildir_path),
+    };
+
+    // if requested, chdir to the maildir's parent, and use a relative path from then on.
+    // Done after dropping privileges, so it's the delivery user who needs access.
+    let maildir_path = if cli_matches.get_flag("chdir") {
+        chdir_to_parent(&maildir_path).map_err(|err| {
+            MainError::caused_by(
+                failure_exit_code(),
+                format!("Error changing to maildir's parent directory: {}", err),
+                err,
+            )
+        })?
+    } else {
+        maildir_path
+    };
+
+    let maildir = Maildir::from(maildir_path.clone());
+
+    if cli_matches.get_flag("verify_maildir_writable") {
+        match probe_maildir_writable(&maildir_path) {
+            Ok(()) => {
+                println!("Maildir {:?} is writable", maildir_path);
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(MainError::caused_by(
+                    Sysexit::CantCreat.code(),
+                    format!("Error: Maildir {:?} isn't writable: {:#}", maildir_path, e),
+                    e,
+                ));
+            }
+        }
+    }
+
+    let message_destination = if cli_matches.get_flag("discard") {
+        MessageDestination::Discard
+    } else {
+        ctx.message_destination
+    };
+
+    // opened after dropping privileges, so the file belongs to the delivery user
+    let mut dump_file = match cli_matches.get_one::<String>("dump_message_to") {
+        Some(_) if message_destination != MessageDestination::Maildir => {
+            return Err(MainError::new(
+                failure_exit_code(),
+                "Error: --dump-message-to can only be used when delivering to a Maildir"
+                    .to_string(),
+            ));
+        }
+        Some(dump_path) => Some(File::create(dump_path).map_err(|e| {
+            MainError::caused_by(
+                failure_exit_code(),
+                format!("Error creating message dump file '{}': {}", dump_path, e),
+                e.into(),
+            )
+        })?),
+        None => None,
+    };
+
+    if receipt.is_some() && message_destination != MessageDestination::Maildir {
+        return Err(MainError::new(
+            failure_exit_code(),
+            "Error: --receipt-fd can only be used when delivering to a Maildir".to_string(),
+        ));
+    }
+
+    if cli_matches.get_flag("headers_only")
+        && message_destination != MessageDestination::OutputStream
+    {
+        return Err(MainError::new(
+            failure_exit_code(),
+            "Error: --headers-only can only be used when writing to an output stream".to_string(),
+        ));
+    }
+
+    if cli_matches.get_flag("stdout_eml") && message_destination != MessageDestination::OutputStream
+    {
+        return Err(MainError::new(
+            failure_exit_code(),
+            "Error: --stdout-eml can only be used when writing to an output stream".to_string(),
+        ));
+    }
+
+    match ctx.should_create_maildirs {
+        // if discarding, nothing should be written to disk
+        _ if message_destination == MessageDestination::Discard => {}
+        CreateMaildirsOption::CreateMaildirs => {
+            maildir.ensure_dirs().map_err(|e| {
+                MainError::caused_by(
+                    failure_exit_code(),
+                    format!(
+                        "Error creating Maildir directories at '{:?}': {}",
+                        maildir_path, e
+                    ),
+                    e.into(),
+                )
+            })?;
+        }
+        CreateMaildirsOption::NoCreateMaildirs => {}
+    }
+
+    let mut envelope = Envelope::new(&to_addresses[0], &from_address, ctx.received_time);
+    envelope.reinject = cli_matches.get_flag("reinject");
+    if let Some(original) = original_recipient {
+        envelope
+            .extra_headers
+            .push((ORIGINAL_TO_HEADER.to_string(), original));
+    }
+    if config.reportTls {
+        envelope.tls_cipher = tls_cipher_from_env(|var| env::var(var).ok());
+    }
+    if let Some(sender) = &sender_arg {
+        let hostname = get_hostname();
+        envelope.extra_headers.extend(authentication_warning(
+            &invoking_user,
+            sender,
+            &config,
+            &hostname,
+        ));
+    }
+    if let Some(conditions) = dsn_notify {
+        envelope
+            .extra_headers
+            .push((DSN_NOTIFY_HEADER.to_string(), conditions));
+    }
+    if cli_matches.get_flag("pid_tag") {
+        let ppid = std::os::unix::process::parent_id();
+        envelope.extra_headers.push((
+            PID_HEADER.to_string(),
+            pid_header_value(std::process::id(), ppid),
+        ));
+    }
+
+    // each recipient gets a delivery of their own, unless it's to the message's own sender
+    let mut envelopes = Vec::new();
+    for to_address in &to_addresses {
+        let should_deliver = check_self_addressed(to_address, &from_address, &config)?;
+        if should_deliver {
+            envelopes.push(Envelope {
+                to_addr: to_address.clone(),
+                ..envelope.clone()
+            });
+        }
+    }
+    let should_deliver = !envelopes.is_empty();
+    let line_limit = match cli_matches.get_one::<String>("input_limit_lines") {
+        Some(lines) => Some(lines.parse::<u64>().map_err(|_| {
+            MainError::new(
+                Sysexit::Usage.code(),
+                format!(
+                    "Error: --input-limit-lines: '{}' is not a number of lines",
+                    lines
+                ),
+            )
+        })?),
+        None => config.inputLineLimit,
+    };
+    let mut limited_input = LineLimitReader::new(input, line_limit);
+    let input = &mut limited_input;
+
+    let should_deliver = should_deliver && check_empty_input(input, &config)?;
+    let message_destination = if should_deliver {
+        message_destination
+    } else {
+        MessageDestination::Discard
+    };
+
+    trace_stage("destination", || match message_destination {
+        MessageDestination::Maildir => format!("Maildir {:?}", maildir_path),
+        MessageDestination::OutputStream => "output stream".to_string(),
+        MessageDestination::Discard => "discard".to_string(),
+    });
+
+    match (message_destination, output_opt) {
+        (MessageDestination::Maildir, None) => {
+            // with more than one recipient, the input has to be re-read for each delivery
+            let mut spool = (envelopes.len() > 1)
+                .then(|| spool_input(input))
+                .transpose()
+                .map_err(|e| {
+                    MainError::caused_by(exit_code_for(&e), format!("Error: {:#}", e), e)
+                })?;
+            let mut dump = dump_file.as_mut().map(|f| f as &mut dyn Write);
+            // the IDs of every message delivered so far, for all recipients -- if time runs
+            // out, the sender will try again, so none of them should be left behind
+            let mut delivered_ids: Vec<String> = Vec::new();
+            let remove_delivered = |delivered_ids: &[String]| {
+                for message_id in delivered_ids {
+                    let _ = maildir.remove(message_id);
+                }
+            };
+            for envelope in &envelopes {
+                let mut input: &mut dyn BufRead = match spool.as_mut() {
+                    Some(spool) => {
+                        spool.rewind().map_err(|e| {
+                            MainError::caused_by(
+                                failure_exit_code(),
+                                format!("Error: Couldn't rewind spool file for reading: {}", e),
+                                e.into(),
+                            )
+                        })?;
+                        spool
+                    }
+                    None => &mut *input,
+                };
+                let report = deliver_to_maildirs(
+                    &mut input,
+                    envelope,
+                    std::slice::from_ref(&maildir),
+                    &config,
+                    dump.take(),
+                )
+                .map_err(|e| {
+                    if deadline_passed(deadline) {
+                        remove_delivered(&delivered_ids);
+                    }
+                    MainError::caused_by(
+                        exit_code_for(&e),
+                        format!(
+                            "Error delivering message to maildir 'new' directiory {:?}: {:#}",
+                            maildir_new_path, e
+                        ),
+                        e,
+                    )
+                })?;
+                delivered_ids.extend(report.deliveries.iter().map(|(_path, id)| id.clone()));
+                // a delivery which finished late is still a failure, so the messages delivered
+                // for this and any earlier recipients are removed again
+                if deadline_passed(deadline) {
+                    remove_delivered(&delivered_ids);
+                    return Err(MainError::new(
+                        Sysexit::TempFail.code(),
+                        format!(
+                            "Error: delivery took longer than the limit of {} seconds, so the messages were removed again",
+                            delivery_timeout.unwrap_or_default().as_secs()
+                        ),
+                    ));
+                }
+                log::debug!("Message successfully delivered to maildir");
+                if let Some((receipt_file, key)) = receipt.as_mut() {
+                    // the message has been delivered regardless, so this isn't a delivery failure
+                    if let Err(e) =
+                        write_delivery_receipts(&report, envelope, key.as_deref(), receipt_file)
+                    {
+                        print_warning(&format!("couldn't write delivery receipt: {:#}", e));
+                    }
+                }
+                if config.writeLastDelivery {
+                    if let Err(e) = write_last_delivery_status(&report, envelope) {
+                        print_warning(&format!("couldn't write last delivery status: {:#}", e));
+                    }
+                }
+                index_delivered_messages(&report, &config);
+                notify_delivered_messages(&report, envelope, &config);
+            }
+        }
+        (MessageDestination::OutputStream, Some(output)) => {
+            let mut output = MessageTracer::new(output);
+            let res = if cli_matches.get_flag("headers_only") {
+                write_message_headers(input, &mut output, &envelope, &config)
+            } else if cli_matches.get_flag("stdout_eml") {
+                write_eml_message(input, &mut output, &envelope, &config)
+            } else {
+                write_message(input, &mut output, &envelope, &config)
+            };
+            res.map_err(|e| {
+                MainError::caused_by(
+                    exit_code_for(&e),
+                    format!("Error writing message: {:#}", e),
+                    e,
+                )
+            })?;
+            output.finish();
+            log::debug!("Message successfully delivered to output stream");
+        }
+        (MessageDestination::Discard, _) => {
+            let mut output = MessageTracer::new(std::io::sink());
+            write_message(input, &mut output, &envelope, &config).map_err(|e| {
+                MainError::caused_by(
+                    exit_code_for(&e),
+                    format!("Error processing message: {:#}", e),
+                    e,
+                )
+            })?;
+            output.finish();
+            log::debug!("Message successfully processed, and discarded");
+        }
+        _ => {
+            return Err(MainError::new(
+                failure_exit_code(),
+                "Error: Invalid combination of message destination and output stream".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+//pub fn bogus_main() {
+//    let input = br#"Subject: backupninja: ubuntu2004.localdomain
+//To: ggg
+//X-Mailer: mail (GNU Mailutils 3.7)
+//
+//success -- /etc/backup.d/example.sys
+//"#;
+//
+//    let message = MessageParser::default().parse(input).unwrap();
+//
+//    println!("message: {:#?}", message);
+//
+//    let new_message = message.clone();
+//}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// `Message-ID:` added by [`test_envelope`]s' deliveries
+    const TEST_MESSAGE_ID: &str = "<1735605000.1234.00000000deadbeef@test.example>";
+
+    /// an `Envelope`, as from [`Envelope::new`], but with a predictable `message_id`
+    fn test_envelope(
+        to_addr: &str,
+        from_addr: &str,
+        received_time: chrono::DateTime<Local>,
+    ) -> Envelope {
+        Envelope {
+            message_id: TEST_MESSAGE_ID.to_string(),
+            ..Envelope::new(to_addr, from_addr, received_time)
+        }
+    }
+
+    /// helper func - standard control flow for all test cases with
+    /// `process_existing_headers` as subject under test.
+    fn test_headers_helper(input: &[u8], expected_status: HeaderStatus, expected_output: &str) {
+        let mut output = Vec::new();
+        let config = Config::default();
+        let result =
+            process_existing_headers(&mut Cursor::new(input), &mut output, &config).unwrap();
+
+        assert_eq!(result, expected_status);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output, expected_output);
+    }
+
+    /// plausible-looking `From:` and `Date:`
+    #[test]
+    fn test_process_headers_with_from_and_date() {
+        let input = b"From: sender@example.com\nDate: Wed, 21 Oct 2020 07:28:00 GMT\n\nBody";
+        let expected_status = HeaderStatus {
+            has_from: true,
+            has_date: true,
+            ..HeaderStatus::default()
+        };
+        let expected_output = "From: sender@example.com\nDate: Wed, 21 Oct 2020 07:28:00 GMT\n";
+        test_headers_helper(input, expected_status, expected_output);
+    }
+
+    /// implausible-looking `From:` and `Date:`
+    #[test]
+    fn test_process_headers_with_implausible_from_and_date() {
+        let input = b"From: :?\nDate: ,\n\nBody";
+        let expected_status = HeaderStatus {
+            has_from: true,
+            has_date: true,
+            ..HeaderStatus::default()
+        };
+        let expected_output = "From: :?\nDate: ,\n";
+        test_headers_helper(input, expected_status, expected_output);
+    }
+
+    /// `Date:` only
+    #[test]
+    fn test_process_headers_without_from() {
+        let input = b"Date: 21 Oct 2020\n\nBody";
+        let expected_status = HeaderStatus {
+            has_from: false,
+            has_date: true,
+            ..HeaderStatus::default()
+        };
+        let expected_output = "Date: 21 Oct 2020\n";
+        test_headers_helper(input, expected_status, expected_output);
+    }
+
+    /// `From:` only
+    #[test]
+    fn test_process_headers_without_date() {
+        let input = b"From: sender@example.com\n\nBody";
+        let expected_status = HeaderStatus {
+            has_from: true,
+            has_date: false,
+            ..HeaderStatus::default()
+        };
+        let expected_output = "From: sender@example.com\n";
+        test_headers_helper(input, expected_status, expected_output);
+    }
+
+    /// empty headers
+    #[test]
+    fn test_process_headers_empty() {
+        let input = b"\nBody";
+        let expected_status = HeaderStatus {
+            has_from: false,
+            has_date: false,
+            ..HeaderStatus::default()
+        };
+        let expected_output = "";
+        test_headers_helper(input, expected_status, expected_output);
+    }
+
+    /// fixed time to use for `received_time` in tests
+    fn test_received_time() -> chrono::DateTime<Local> {
+        use chrono::TimeZone;
+        Local.with_ymd_and_hms(2024, 12, 31, 10, 30, 0).unwrap()
+    }
+
+    /// `Received:` comes first, then synthesized `Date:` and `From:`, then the
+    /// original headers in their original order.
+    #[test]
+    fn test_write_headers_order_with_synthesized_headers() {
+        let time = test_received_time();
+        let config = Config::default();
+        let input = b"Subject: hi\nTo: bob\nX-Foo: foo\n\nBody\n";
+        let mut output = Vec::new();
+        write_headers(
+            &mut Cursor::new(input),
+            &mut output,
+            &test_envelope("bob", "alice", time),
+            &config,
+        )
+        .unwrap();
+
+        let expected_output = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nMessage-ID: {TEST_MESSAGE_ID}\nSubject: hi\nTo: bob\nX-Foo: foo\n\n",
+            make_received_header("bob", "alice", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+    }
+
+    /// when nothing needs synthesizing, only `Received:` is added, at the top, and
+    /// the original headers keep their order.
+    #[test]
+    fn test_write_headers_order_with_existing_headers() {
+        let time = test_received_time();
+        let config = Config::default();
+        let input = b"Subject: hi\nDate: 21 Oct 2020\nTo: bob\nFrom: carol\n\nBody\n";
+        let mut output = Vec::new();
+        write_headers(
+            &mut Cursor::new(input),
+            &mut output,
+            &test_envelope("bob", "alice", time),
+            &config,
+        )
+        .unwrap();
+
+        let expected_output = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Message-ID: {TEST_MESSAGE_ID}\nSubject: hi\nDate: 21 Oct 2020\nTo: bob\nFrom: carol\n\n",
+            make_received_header("bob", "alice", &time),
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+    }
+
+    /// `Return-Path:` comes first of all, from the envelope sender, replacing any the message
+    /// already had
+    #[test]
+    fn test_write_headers_return_path() {
+        let time = test_received_time();
+        let write = |input: &[u8], from_addr: &str| {
+            let mut output = Vec::new();
+            let status = write_headers(
+                &mut Cursor::new(input),
+                &mut output,
+                &test_envelope("bob", from_addr, time),
+                &Config::default(),
+            )
+            .unwrap();
+            (status, String::from_utf8(output).unwrap())
+        };
+
+        let input = b"Return-Path: <old@example.com>\nFrom: carol\nreturn-path: <older>\n\tfolded\nSubject: hi\n\nBody\n";
+        let (status, output) = write(input, "alice@example.com");
+        assert!(status.had_return_path);
+        let expected_output = format!(
+            "Return-Path: <alice@example.com>\nDelivered-To: bob\n{}Date: {}\nMessage-ID: {TEST_MESSAGE_ID}\nFrom: carol\nSubject: hi\n\n",
+            make_received_header("bob", "alice@example.com", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(output, expected_output);
+
+        let (status, output) = write(b"Subject: hi\n\nBody\n", "<>");
+        assert!(!status.had_return_path);
+        assert!(output.starts_with("Return-Path: <>\nDelivered-To: bob\nReceived: "));
+        assert_eq!(output.matches("Return-Path:").count(), 1);
+    }
+
+    /// a message with a `Delivered-To:` for the recipient is rejected as a loop; otherwise,
+    /// `Delivered-To:` headers are kept, and one for the recipient added
+    #[test]
+    fn test_write_headers_mail_loop() {
+        let time = test_received_time();
+        let write = |input: &[u8], to_addr: &str| {
+            let mut output = Vec::new();
+            write_headers(
+                &mut Cursor::new(input),
+                &mut output,
+                &test_envelope(to_addr, "alice", time),
+                &Config::default(),
+            )
+            .map(|_status| String::from_utf8(output).unwrap())
+        };
+
+        let input =
+            b"delivered-to: carol@example.com\nDelivered-To:\n <Bob@Example.COM>\nSubject: hi\n\n";
+        for to_addr in ["bob@example.com", "BOB@example.com"] {
+            let err = write(input, to_addr).unwrap_err();
+            assert_eq!(exit_code_for(&err), Sysexit::NoPerm.code());
+            assert!(err.to_string().contains("Mail loop detected"), "{}", err);
+        }
+
+        let output = write(input, "dave@example.com").unwrap();
+        assert!(
+            output.starts_with("Return-Path: <alice>\nDelivered-To: dave@example.com\nReceived: ")
+        );
+        assert!(output.ends_with(std::str::from_utf8(input).unwrap()));
+
+        let mut status_output = Vec::new();
+        let status = process_existing_headers(
+            &mut Cursor::new(input),
+            &mut status_output,
+            &Config::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            status.delivered_to,
+            ["carol@example.com", "<Bob@Example.COM>"]
+        );
+    }
+
+    /// a "store" that always fails with the given errno
+    fn failing_store(
+        errno: Errno,
+    ) -> impl FnOnce(&[u8]) -> std::result::Result<String, MaildirError> {
+        move |_bytes| Err(MaildirError::Io(std::io::Error::from(errno)))
+    }
+
+    #[test]
+    fn test_store_message_enospc_is_tempfail() {
+        for bounce in [false, true] {
+            let config = Config {
+                bounceOnQuotaFull: bounce,
+                ..Config::default()
+            };
+            let err = store_message(failing_store(Errno::ENOSPC), b"msg", &config).unwrap_err();
+            assert_eq!(exit_code_for(&err), Sysexit::TempFail.code());
+            assert!(
+                err.to_string().contains("no space left on device"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_store_message_edquot_is_mailbox_full() {
+        let config = Config::default();
+        let err = store_message(failing_store(Errno::EDQUOT), b"msg", &config).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::TempFail.code());
+        assert!(err.to_string().contains("mailbox full"), "{}", err);
+
+        let config = Config {
+            bounceOnQuotaFull: true,
+            ..Config::default()
+        };
+        let err = store_message(failing_store(Errno::EDQUOT), b"msg", &config).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::NoPerm.code());
+        assert!(err.to_string().contains("mailbox full"), "{}", err);
+    }
+
+    /// other errors get the generic exit code
+    #[test]
+    fn test_store_message_other_error() {
+        let config = Config::default();
+        let err = store_message(failing_store(Errno::EACCES), b"msg", &config).unwrap_err();
+        assert_eq!(exit_code_for(&err), 1);
+    }
+
+    /// delivering to two maildirs gives both identical copies of the message, even
+    /// though the input can only be read once
+    #[test]
+    fn test_deliver_to_maildirs_fan_out() {
+        let time = test_received_time();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildirs: Vec<Maildir> = ["one", "two"]
+            .iter()
+            .map(|name| {
+                let maildir = Maildir::from(temp_dir.path().join(name).join("Maildir"));
+                maildir.create_dirs().unwrap();
+                maildir
+            })
+            .collect();
+
+        /// a reader that panics if read from after reaching EOF
+        struct ReadOnce<R>(R, bool);
+        impl<R: std::io::Read> std::io::Read for ReadOnce<R> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                assert!(!self.1, "input was read again after EOF");
+                let n = self.0.read(buf)?;
+                self.1 = n == 0 && !buf.is_empty();
+                Ok(n)
+            }
+        }
+
+        let input = b"Subject: hi\n\nsome body\n";
+        let mut input = BufReader::new(ReadOnce(Cursor::new(input), false));
+        let config = Config::default();
+        let envelope = test_envelope("bob", "alice", time);
+        deliver_to_maildirs(&mut input, &envelope, &maildirs, &config, None).unwrap();
+
+        let contents: Vec<Vec<u8>> = maildirs
+            .iter()
+            .map(|maildir| {
+                let entries: Vec<_> = maildir.list_new().collect();
+                assert_eq!(
+                    entries.len(),
+                    1,
+                    "expected one message in {:?}",
+                    maildir.path()
+                );
+                std::fs::read(entries[0].as_ref().unwrap().path()).unwrap()
+            })
+            .collect();
+
+        assert_eq!(contents[0], contents[1]);
+        let mut expected = Vec::new();
+        write_message(
+            &mut Cursor::new(b"Subject: hi\n\nsome body\n"),
+            &mut expected,
+            &test_envelope("bob", "alice", time),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(contents[0], expected);
+    }
+
+    fn strict_config() -> Config {
+        Config {
+            strictHeaders: true,
+            ..Config::default()
+        }
+    }
+
+    /// in strict mode, well-formed headers (including folded ones) are accepted
+    #[test]
+    fn test_strict_headers_well_formed() {
+        let input =
+            b"From: sender@example.com\nSubject: a long\n\tsubject\nX-Empty:\r\n\nnot a header\n";
+        let mut output = Vec::new();
+        let result =
+            process_existing_headers(&mut Cursor::new(input), &mut output, &strict_config());
+        assert!(result.is_ok(), "expected success, got {:?}", result);
+        assert_eq!(
+            output,
+            b"From: sender@example.com\nSubject: a long\n\tsubject\nX-Empty:\r\n"
+        );
+    }
+
+    /// in strict mode, a non-header line in the header block is rejected with `EX_DATAERR`
+    #[test]
+    fn test_strict_headers_malformed() {
+        let input = b"Subject: hi\nnot a header\n\nBody";
+        let mut output = Vec::new();
+        let err = process_existing_headers(&mut Cursor::new(input), &mut output, &strict_config())
+            .unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+
+        // but in lenient mode, it's passed through
+        let mut output = Vec::new();
+        let config = Config::default();
+        process_existing_headers(&mut Cursor::new(input), &mut output, &config).unwrap();
+        assert_eq!(output, b"Subject: hi\nnot a header\n");
+    }
+
+    #[test]
+    fn test_is_valid_header_line() {
+        assert!(is_valid_header_line(b"Subject: hi\n"));
+        assert!(is_valid_header_line(b"X-Empty:\r\n"));
+        assert!(!is_valid_header_line(b"not a header\n"));
+        assert!(!is_valid_header_line(b": no name\n"));
+        assert!(!is_valid_header_line(b"Bad Name: x\n"));
+        assert!(!is_valid_header_line(b"Subject: \x00binary\n"));
+        assert!(!is_valid_header_line(b" continued\n"));
+    }
+
+    /// a continuation line can't come first
+    #[test]
+    fn test_strict_headers_leading_continuation() {
+        let input = b"\tSubject: hi\n\nBody";
+        let mut output = Vec::new();
+        let err = process_existing_headers(&mut Cursor::new(input), &mut output, &strict_config())
+            .unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+    }
+
+    /// a `getenv` looking up variables in a fixed list
+    fn fake_env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: Vec<(String, String)> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |var| vars.iter().find(|(k, _)| k == var).map(|(_, v)| v.clone())
+    }
+
+    #[test]
+    fn test_get_env_user_logname_honoured() {
+        let getenv = fake_env(&[("LOGNAME", "root"), ("USER", "nobody")]);
+        assert_eq!(
+            get_env_user(&UserCache::new(USER_CACHE_TTL), getenv),
+            Some("root".to_string())
+        );
+
+        let getenv = fake_env(&[("USER", "root")]);
+        assert_eq!(
+            get_env_user(&UserCache::new(USER_CACHE_TTL), getenv),
+            Some("root".to_string())
+        );
+    }
+
+    /// nonexistent or implausible users in the environment are ignored
+    #[test]
+    fn test_get_env_user_invalid() {
+        let getenv = fake_env(&[("LOGNAME", "no-such-user-rattomail"), ("USER", "root")]);
+        assert_eq!(
+            get_env_user(&UserCache::new(USER_CACHE_TTL), getenv),
+            Some("root".to_string())
+        );
+
+        let getenv = fake_env(&[
+            ("LOGNAME", "root\nBcc: x"),
+            ("USER", "no-such-user-rattomail"),
+        ]);
+        assert_eq!(get_env_user(&UserCache::new(USER_CACHE_TTL), getenv), None);
+
+        assert_eq!(
+            get_env_user(&UserCache::new(USER_CACHE_TTL), fake_env(&[])),
+            None
+        );
+    }
+
+    /// headers totalling more than `maxHeaderBytes` are rejected, even though each is small
+    #[test]
+    fn test_max_header_bytes() {
+        let config = Config {
+            maxHeaderBytes: 100,
+            ..Config::default()
+        };
+        let header_line = b"X-Foo: 123456789\n";
+
+        // 5 * 17 bytes = 85 bytes, plus a blank line
+        let input = [header_line.repeat(5), b"\nBody".to_vec()].concat();
+        let mut output = Vec::new();
+        process_existing_headers(&mut Cursor::new(input), &mut output, &config).unwrap();
+        assert_eq!(output, header_line.repeat(5));
+
+        // 6 * 17 bytes = 102 bytes
+        let input = [header_line.repeat(6), b"\nBody".to_vec()].concat();
+        let mut output = Vec::new();
+        let err =
+            process_existing_headers(&mut Cursor::new(input), &mut output, &config).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+    }
+
+    #[test]
+    fn test_expand_user_template() {
+        let path = expand_user_template("/home/%u/Maildir/new", "alice").unwrap();
+        assert_eq!(path, "/home/alice/Maildir/new");
+        assert_eq!(
+            parse_maildir_new_path(Path::new(&path)).unwrap(),
+            PathBuf::from("/home/alice/Maildir")
+        );
+
+        let path = expand_user_template("/home/%u/Maildir/new", "alice@example.com").unwrap();
+        assert_eq!(path, "/home/alice/Maildir/new");
+
+        let path = expand_user_template("/var/mail/Maildir/new", "../bob").unwrap();
+        assert_eq!(path, "/var/mail/Maildir/new");
+    }
+
+    /// recipients that could escape the template are rejected
+    #[test]
+    fn test_expand_user_template_escapes() {
+        for recipient in [
+            "../bob",
+            "..",
+            "a/b@example.com",
+            "",
+            "-rf",
+            "bob smith",
+            "@x",
+        ] {
+            let res = expand_user_template("/home/%u/Maildir/new", recipient);
+            assert!(
+                res.is_err(),
+                "expected error for {:?}, got {:?}",
+                recipient,
+                res
+            );
+        }
+    }
+
+    #[test]
+    fn test_expand_recipient_templates() {
+        let config = Config {
+            mailDir: "/home/%u/Maildir/new".to_string(),
+            userName: "%u".to_string(),
+            ..Config::default()
+        };
+        let expanded = expand_recipient_templates(config, Some("alice@example.com")).unwrap();
+        assert_eq!(expanded.mailDir, "/home/alice/Maildir/new");
+        assert_eq!(expanded.userName, "alice");
+
+        let config = Config {
+            mailDir: "/home/%u/Maildir/new".to_string(),
+            userName: "%u".to_string(),
+            ..Config::default()
+        };
+        assert!(expand_recipient_templates(config, None).is_err());
+    }
+
+    #[test]
+    fn test_build_metadata_json() {
+        let metadata = build_metadata();
+        let json: serde_json::Value = serde_json::from_str(&metadata.to_json()).unwrap();
+
+        assert_eq!(json["name"], "rattomail");
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["config_path"], env!("ATTOMAIL_CONFIG_PATH"));
+        assert_eq!(json["program_names"], serde_json::json!(PROGRAM_NAMES));
+    }
+
+    #[test]
+    fn test_json_string_escaping() {
+        let metadata = BuildMetadata {
+            config_path: "/odd \"path\"\\\n",
+            ..build_metadata()
+        };
+        let json: serde_json::Value = serde_json::from_str(&metadata.to_json()).unwrap();
+        assert_eq!(json["config_path"], "/odd \"path\"\\\n");
+    }
+
+    /// empty `Date:` and `From:` headers are treated as missing, and dropped
+    #[test]
+    fn test_process_headers_empty_date_and_from() {
+        let input = b"Date:\nSubject: hi\nFrom:  \t\r\n\nBody";
+        let expected_status = HeaderStatus {
+            has_from: false,
+            has_date: false,
+            ..HeaderStatus::default()
+        };
+        test_headers_helper(input, expected_status, "Subject: hi\n");
+    }
+
+    /// an "empty" header that's actually folded onto the next line isn't empty
+    #[test]
+    fn test_process_headers_folded_date_not_empty() {
+        let input = b"Date:\n Wed, 21 Oct 2020 07:28:00 GMT\n\nBody";
+        let mut output = Vec::new();
+        let config = Config::default();
+        process_existing_headers(&mut Cursor::new(input), &mut output, &config).unwrap();
+        assert_eq!(output, b"Date:\n Wed, 21 Oct 2020 07:28:00 GMT\n");
+    }
+
+    /// a valid `Date:` gets synthesized in place of an empty one
+    #[test]
+    fn test_write_headers_replaces_empty_date() {
+        let time = test_received_time();
+        let config = Config::default();
+        let input = b"Date:\nFrom: carol\n\nBody\n";
+        let mut output = Vec::new();
+        write_headers(
+            &mut Cursor::new(input),
+            &mut output,
+            &test_envelope("bob", "alice", time),
+            &config,
+        )
+        .unwrap();
+
+        let expected_output = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nMessage-ID: {TEST_MESSAGE_ID}\nFrom: carol\n\n",
+            make_received_header("bob", "alice", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+    }
+
+    fn reply_to_config() -> Config {
+        Config {
+            defaultReplyTo: Some("Tickets <tickets@example.com>".to_string()),
+            ..Config::default()
+        }
+    }
+
+    /// `defaultReplyTo` is used if there's no `Reply-To:`
+    #[test]
+    fn test_write_headers_default_reply_to() {
+        let time = test_received_time();
+        let config = reply_to_config();
+        let input = b"Subject: hi\n\nBody\n";
+        let mut output = Vec::new();
+        write_headers(
+            &mut Cursor::new(input),
+            &mut output,
+            &test_envelope("bob", "alice", time),
+            &config,
+        )
+        .unwrap();
+
+        let expected_output = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nMessage-ID: {TEST_MESSAGE_ID}\nReply-To: Tickets <tickets@example.com>\nSubject: hi\n\n",
+            make_received_header("bob", "alice", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+    }
+
+    /// with `setSenderHeader`, a `Sender:` is added when the envelope sender isn't the `From:`
+    /// author, and not when it is
+    #[test]
+    fn test_write_headers_set_sender_header() {
+        let time = test_received_time();
+        let config = Config {
+            setSenderHeader: true,
+            ..Config::default()
+        };
+        let write = |input: &[u8], from_addr: &str| {
+            let mut output = Vec::new();
+            write_headers(
+                &mut Cursor::new(input),
+                &mut output,
+                &test_envelope("bob", from_addr, time),
+                &config,
+            )
+            .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        let header = make_received_header("bob", "daemon@example.com", &time);
+
+        let output = write(
+            b"From: Alice <alice@example.com>\n\nBody\n",
+            "daemon@example.com",
+        );
+        let expected_output = format!(
+            "Return-Path: <daemon@example.com>\nDelivered-To: bob\n{}Date: {}\nMessage-ID: {TEST_MESSAGE_ID}\nSender: daemon@example.com\nFrom: Alice <alice@example.com>\n\n",
+            header,
+            time.to_rfc2822()
+        );
+        assert_eq!(output, expected_output);
+
+        // the same address (bar the domain's case), folded or not, doesn't need a Sender:
+        for input in [
+            &b"From: Daemon <daemon@Example.COM>\n\nBody\n"[..],
+            b"From: daemon@example.com\n\nBody\n",
+            b"From: Daemon\n <daemon@example.com>\n\nBody\n",
+        ] {
+            let output = write(input, "daemon@example.com");
+            assert!(!output.contains("Sender:"), "for {:?}", output);
+        }
+
+        // and neither does a synthesized From:, or a message with a Sender: already
+        assert!(!write(b"Subject: hi\n\nBody\n", "daemon@example.com").contains("Sender:"));
+        let output = write(
+            b"From: alice@example.com\nSender: carol@example.com\n\nBody\n",
+            "daemon@example.com",
+        );
+        assert_eq!(output.matches("Sender:").count(), 1);
+    }
+
+    /// an existing `Reply-To:` (in any case) is preserved, and no other added
+    #[test]
+    fn test_write_headers_existing_reply_to() {
+        let time = test_received_time();
+        let config = reply_to_config();
+        let input = b"Date: 21 Oct 2020\nFrom: carol\nreply-to: dave\n\nBody\n";
+        let mut output = Vec::new();
+        write_headers(
+            &mut Cursor::new(input),
+            &mut output,
+            &test_envelope("bob", "alice", time),
+            &config,
+        )
+        .unwrap();
+
+        let expected_output = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Message-ID: {TEST_MESSAGE_ID}\nDate: 21 Oct 2020\nFrom: carol\nreply-to: dave\n\n",
+            make_received_header("bob", "alice", &time),
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+    }
+
+    fn strings(vals: &[&str]) -> Vec<String> {
+        vals.iter().map(|val| val.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_sendmail_options_ignore_dots() {
+        let options = parse_sendmail_options(&strings(&["i"]), &[]);
+        assert!(options.ignore_dots);
+        assert_eq!(options.delivery_mode, None);
+
+        let options = parse_sendmail_options(&[], &strings(&["IgnoreDots"]));
+        assert!(options.ignore_dots);
+
+        let options = parse_sendmail_options(&[], &[]);
+        assert!(!options.ignore_dots);
+    }
+
+    #[test]
+    fn test_parse_sendmail_options_delivery_mode() {
+        let options = parse_sendmail_options(&strings(&["dq"]), &[]);
+        assert_eq!(options.delivery_mode, Some(DeliveryMode::Queue));
+        assert!(!options.ignore_dots);
+
+        let options = parse_sendmail_options(&strings(&["i", "db"]), &[]);
+        assert_eq!(
+            options,
+            SendmailOptions {
+                ignore_dots: true,
+                delivery_mode: Some(DeliveryMode::Background)
+            }
+        );
+
+        let options = parse_sendmail_options(&[], &strings(&["DeliveryMode=queue"]));
+        assert_eq!(options.delivery_mode, Some(DeliveryMode::Queue));
+    }
+
+    /// meaningless options are ignored
+    #[test]
+    fn test_parse_dsn_notify() {
+        assert_eq!(
+            parse_dsn_notify("success,failure").unwrap(),
+            "success,failure"
+        );
+        assert_eq!(parse_dsn_notify("Delay, FAILURE").unwrap(), "delay,failure");
+        assert_eq!(parse_dsn_notify("never").unwrap(), "never");
+
+        for invalid in ["never,success", "success,bogus", "", "success,"] {
+            let err = parse_dsn_notify(invalid).unwrap_err();
+            assert_eq!(
+                exit_code_for(&err),
+                Sysexit::Usage.code(),
+                "for {:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_sendmail_options_unknown() {
+        let options = parse_sendmail_options(
+            &strings(&["em", "dz", "", "é"]),
+            &strings(&["Timeout=5", "X"]),
+        );
+        assert_eq!(options, SendmailOptions::default());
+    }
+
+    #[test]
+    fn test_authentication_warning_untrusted() {
+        let config = Config {
+            trustedUsers: vec!["daemon".to_string()],
+            ..Config::default()
+        };
+        let warning = authentication_warning("mallory", "alice@example.com", &config, "myhost");
+        assert_eq!(
+            warning,
+            Some((
+                "X-Authentication-Warning".to_string(),
+                "myhost: mallory set sender to alice@example.com using -f".to_string()
+            ))
+        );
+
+        // and it ends up in the headers
+        let time = test_received_time();
+        let mut envelope = test_envelope("bob", "alice@example.com", time);
+        envelope.extra_headers.extend(warning);
+        let mut output = Vec::new();
+        let input = b"Subject: hi\n\nBody\n";
+        write_headers(&mut Cursor::new(input), &mut output, &envelope, &config).unwrap();
+        let expected_output = format!(
+            "Return-Path: <alice@example.com>\nDelivered-To: bob\n{}X-Authentication-Warning: myhost: mallory set sender to alice@example.com using -f\nDate: {}\nFrom: alice@example.com\nMessage-ID: {TEST_MESSAGE_ID}\nSubject: hi\n\n",
+            make_received_header("bob", "alice@example.com", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+    }
+
+    /// trusted users, root, and users setting their own name get no warning
+    #[test]
+    fn test_authentication_warning_trusted() {
+        let config = Config {
+            trustedUsers: vec!["daemon".to_string(), "www-data".to_string()],
+            ..Config::default()
+        };
+        assert_eq!(
+            authentication_warning("daemon", "alice", &config, "myhost"),
+            None
+        );
+        assert_eq!(
+            authentication_warning("www-data", "alice", &config, "myhost"),
+            None
+        );
+        assert_eq!(
+            authentication_warning("root", "alice", &config, "myhost"),
+            None
+        );
+        assert_eq!(
+            authentication_warning("alice", "alice", &config, "myhost"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_expand_env_vars_defined() {
+        let getenv = fake_env(&[("MAILROOT", "/srv/mail"), ("WHO", "alice")]);
+        assert_eq!(
+            expand_env_vars("${MAILROOT}/${WHO}/Maildir/new", &getenv).unwrap(),
+            "/srv/mail/alice/Maildir/new"
+        );
+        // no references, or a bare `$`, left alone
+        assert_eq!(
+            expand_env_vars("/home/$USER/%u", &getenv).unwrap(),
+            "/home/$USER/%u"
+        );
+    }
+
+    #[test]
+    fn test_expand_env_vars_undefined_is_error() {
+        let getenv = fake_env(&[]);
+        let err = expand_env_vars("${MAILROOT}/Maildir/new", &getenv).unwrap_err();
+        assert!(err.to_string().contains("MAILROOT"));
+        assert!(expand_env_vars("${MAILROOT/Maildir/new", &getenv).is_err());
+    }
+
+    #[test]
+    fn test_expand_env_vars_default() {
+        let getenv = fake_env(&[("MAILROOT", "/srv/mail"), ("EMPTY", "")]);
+        assert_eq!(
+            expand_env_vars("${UNSET:-/var/mail}/new", &getenv).unwrap(),
+            "/var/mail/new"
+        );
+        assert_eq!(expand_env_vars("${EMPTY:-x}", &getenv).unwrap(), "x");
+        assert_eq!(
+            expand_env_vars("${MAILROOT:-/var/mail}", &getenv).unwrap(),
+            "/srv/mail"
+        );
+    }
+
+    #[test]
+    fn test_normalize_domain_case() {
+        assert_eq!(
+            normalize_domain_case("User@Example.COM"),
+            "User@example.com"
+        );
+        assert_eq!(
+            normalize_domain_case("user@example.com"),
+            "user@example.com"
+        );
+        assert_eq!(normalize_domain_case("User"), "User");
+    }
+
+    #[test]
+    fn test_extract_addr_spec() {
+        assert_eq!(
+            extract_addr_spec(r#""Alice" <alice@example.com>"#),
+            Some("alice@example.com")
+        );
+        assert_eq!(extract_addr_spec("Alice <alice@x>"), Some("alice@x"));
+        assert_eq!(extract_addr_spec("<alice@x>"), Some("alice@x"));
+        assert_eq!(extract_addr_spec("alice@x"), None);
+        assert_eq!(extract_addr_spec("alice"), None);
+    }
+
+    #[test]
+    fn test_parse_mailto() {
+        assert_eq!(
+            parse_mailto("bob@example.com, carol@example.com"),
+            vec!["bob@example.com", "carol@example.com"]
+        );
+        assert_eq!(parse_mailto("bob"), vec!["bob"]);
+        assert_eq!(parse_mailto(" bob ,, carol,"), vec!["bob", "carol"]);
+        assert!(parse_mailto("").is_empty());
+        assert!(parse_mailto(" , ").is_empty());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_deliver_async() {
+        let time = test_received_time();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let report = runtime
+            .block_on(deliver_async(
+                b"Subject: hi\n\nsome body\n".to_vec(),
+                test_envelope("bob", "alice", time),
+                vec![Maildir::from(maildir.path().to_path_buf())],
+                Config::default(),
+            ))
+            .unwrap();
+
+        assert_eq!(report.deliveries.len(), 1);
+        let (path, id) = &report.deliveries[0];
+        assert_eq!(path, maildir.path());
+
+        let entry = maildir.find(id).expect("delivered message should be found");
+        let expected = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nMessage-ID: {TEST_MESSAGE_ID}\nSubject: hi\n\nsome body\n",
+            make_received_header("bob", "alice", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(std::fs::read(entry.path()).unwrap(), expected.as_bytes());
+    }
+
+    /// input with no headers at all is all body, and gets a full synthesized header block
+    #[test]
+    fn test_write_headers_headerless_input() {
+        let time = test_received_time();
+        let input = b"just some text\nKey: value\n\nmore text\n";
+        let mut input = Cursor::new(input);
+        let mut output = Vec::new();
+        let config = Config::default();
+        let envelope = test_envelope("bob", "alice", time);
+        write_message(&mut input, &mut output, &envelope, &config).unwrap();
+
+        let expected_output = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nMessage-ID: {TEST_MESSAGE_ID}\n\njust some text\nKey: value\n\nmore text\n",
+            make_received_header("bob", "alice", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+    }
+
+    /// a resolver which counts how many lookups it does, and always finds root
+    fn counting_resolver(
+        count: &std::cell::Cell<usize>,
+    ) -> impl Fn(&str) -> nix::Result<Option<User>> + '_ {
+        move |_name| {
+            count.set(count.get() + 1);
+            User::from_uid(Uid::from_raw(0))
+        }
+    }
+
+    #[test]
+    fn test_user_cache_hit() {
+        let count = std::cell::Cell::new(0);
+        let cache = UserCache::with_resolver(USER_CACHE_TTL, counting_resolver(&count));
+
+        let first = cache.from_name("root").unwrap();
+        let second = cache.from_name("root").unwrap();
+        assert_eq!(count.get(), 1, "second lookup should come from the cache");
+        assert_eq!(first, second);
+
+        cache.from_name("daemon").unwrap();
+        assert_eq!(count.get(), 2, "a different name should be looked up");
+    }
+
+    #[test]
+    fn test_user_cache_expiry() {
+        let count = std::cell::Cell::new(0);
+        let cache = UserCache::with_resolver(Duration::ZERO, counting_resolver(&count));
+
+        cache.from_name("root").unwrap();
+        cache.from_name("root").unwrap();
+        assert_eq!(count.get(), 2, "expired entries should be looked up again");
+    }
+
+    /// users named in the environment are looked up through the cache, as are uids
+    #[test]
+    fn test_user_cache_env_and_uid() {
+        let count = std::cell::Cell::new(0);
+        let cache = UserCache::with_resolver(USER_CACHE_TTL, counting_resolver(&count));
+        let getenv = fake_env(&[("LOGNAME", "root")]);
+        assert_eq!(get_env_user(&cache, &getenv), Some("root".to_string()));
+        assert_eq!(get_env_user(&cache, &getenv), Some("root".to_string()));
+        assert_eq!(count.get(), 1, "second lookup should come from the cache");
+
+        let root = cache.from_uid(Uid::from_raw(0)).unwrap().unwrap();
+        assert_eq!(root.name, "root");
+        assert_eq!(cache.from_uid(Uid::from_raw(0)).unwrap(), Some(root));
+        assert_eq!(
+            count.get(),
+            1,
+            "uids aren't looked up with the name resolver"
+        );
+    }
+
+    /// a transient lookup failure is retried, and the retry recovers; a missing user isn't
+    /// retried; and failures that persist past the retries are temporary failures
+    #[test]
+    fn test_lookup_delivery_user_retry() {
+        let failures_left = std::cell::Cell::new(1);
+        let count = std::cell::Cell::new(0);
+        let flaky_resolver = |name: &str| {
+            count.set(count.get() + 1);
+            if failures_left.get() > 0 {
+                failures_left.set(failures_left.get() - 1);
+                return Err(Errno::EIO);
+            }
+            User::from_name(name)
+        };
+        let cache = UserCache::with_resolver(USER_CACHE_TTL, flaky_resolver);
+
+        let user = lookup_delivery_user(&cache, "root", 3, Duration::ZERO).unwrap();
+        assert!(user.uid.is_root());
+        assert_eq!(count.get(), 2);
+
+        count.set(0);
+        let err =
+            lookup_delivery_user(&cache, "no-such-user-xyzzy", 3, Duration::ZERO).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::NoUser.code());
+        assert_eq!(count.get(), 1);
+
+        count.set(0);
+        failures_left.set(10);
+        let err = lookup_delivery_user(&cache, "daemon", 3, Duration::ZERO).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::TempFail.code());
+        assert_eq!(count.get(), 4);
+    }
+
+    /// if the first candidate filename is taken, another one is used
+    #[test]
+    fn test_store_new_unique_collision() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let base = maildir_name_base(SystemTime::now());
+
+        // work out what the first two candidates will be, and take the first
+        let mut rng = StdRng::seed_from_u64(42);
+        let first = maildir_candidate_name(&base, &mut rng);
+        let second = maildir_candidate_name(&base, &mut rng);
+        let first_path = maildir.path().join("new").join(&first);
+        std::fs::write(&first_path, b"already here").unwrap();
+
+        let id = store_new_unique(
+            &maildir,
+            b"message",
+            &base,
+            None,
+            1,
+            TmpfileStrategy::Classic,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .unwrap();
+        assert_eq!(id, second);
+        assert_eq!(
+            std::fs::read(maildir.path().join("new").join(&id)).unwrap(),
+            b"message"
+        );
+        assert_eq!(std::fs::read(&first_path).unwrap(), b"already here");
+        assert_eq!(
+            std::fs::read_dir(maildir.path().join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+
+        // with no retries, the collision is an error
+        let err = store_new_unique(
+            &maildir,
+            b"message",
+            &base,
+            None,
+            0,
+            TmpfileStrategy::Classic,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, MaildirError::Io(e) if e.kind() == std::io::ErrorKind::AlreadyExists)
+        );
+    }
+
+    /// with `O_TMPFILE`, the message lands in `new/` with nothing left in `tmp/`, and a taken
+    /// filename is still retried
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_store_new_unique_o_tmpfile() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let base = maildir_name_base(SystemTime::now());
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let first = maildir_candidate_name(&base, &mut rng);
+        let second = maildir_candidate_name(&base, &mut rng);
+        store_o_tmpfile(maildir.path(), &first, b"already here").unwrap();
+
+        let id = store_new_unique(
+            &maildir,
+            b"message",
+            &base,
+            None,
+            1,
+            TmpfileStrategy::OTmpfile,
+            &mut StdRng::seed_from_u64(42),
+        )
+        .unwrap();
+        assert_eq!(id, second);
+        assert_eq!(
+            std::fs::read(maildir.path().join("new").join(&id)).unwrap(),
+            b"message"
+        );
+        assert_eq!(
+            std::fs::read(maildir.path().join("new").join(&first)).unwrap(),
+            b"already here"
+        );
+        assert_eq!(
+            std::fs::read_dir(maildir.path().join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_format_mail_date_fixed_offset() {
+        use chrono::TimeZone;
+        let time = chrono::Utc
+            .with_ymd_and_hms(2024, 12, 31, 10, 30, 0)
+            .unwrap()
+            .with_timezone(&Local);
+        let tz: DateTimezone = "+1000".parse().unwrap();
+        assert_eq!(
+            format_mail_date(&time, Some(&tz)),
+            "Tue, 31 Dec 2024 20:30:00 +1000"
+        );
+        let tz: DateTimezone = "-0530".parse().unwrap();
+        assert_eq!(
+            format_mail_date(&time, Some(&tz)),
+            "Tue, 31 Dec 2024 05:00:00 -0530"
+        );
+        assert_eq!(tz.to_string(), "-0530");
+    }
+
+    #[test]
+    fn test_format_mail_date_named_zone() {
+        use chrono::TimeZone;
+        let time = chrono::Utc
+            .with_ymd_and_hms(2024, 12, 31, 10, 30, 0)
+            .unwrap()
+            .with_timezone(&Local);
+        // daylight saving time, in December
+        let tz: DateTimezone = "Australia/Sydney".parse().unwrap();
+        assert_eq!(
+            format_mail_date(&time, Some(&tz)),
+            "Tue, 31 Dec 2024 21:30:00 +1100"
+        );
+        assert_eq!(tz.to_string(), "Australia/Sydney");
+    }
+
+    #[test]
+    fn test_date_timezone_invalid() {
+        assert!("Mars/Olympus_Mons".parse::<DateTimezone>().is_err());
+        assert!("+10".parse::<DateTimezone>().is_err());
+        assert!("+1075".parse::<DateTimezone>().is_err());
+    }
+
+    /// the index command is passed the path of the stored message
+    #[test]
+    fn test_index_delivered_messages() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let record = temp_dir.path().join("indexed");
+
+        let config = Config {
+            indexCommand: Some(format!(
+                "printf '%s' \"${}\" > '{}'",
+                INDEX_MESSAGE_PATH_VAR,
+                record.display()
+            )),
+            ..Config::default()
+        };
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let input = b"Subject: hi\n\nsome body\n";
+        let report = deliver_to_maildirs(
+            &mut Cursor::new(input),
+            &envelope,
+            std::slice::from_ref(&maildir),
+            &config,
+            None,
+        )
+        .unwrap();
+        index_delivered_messages(&report, &config);
+
+        let entry = maildir.find(&report.deliveries[0].1).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&record).unwrap(),
+            entry.path().to_str().unwrap()
+        );
+    }
+
+    /// an index command that runs too long is killed, and reported as an error
+    #[test]
+    fn test_run_index_command_timeout() {
+        let started = Instant::now();
+        let err = run_index_command(
+            "sleep 5",
+            Path::new("/dev/null"),
+            Duration::from_millis(100),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        assert!(
+            run_index_command("exit 1", Path::new("/dev/null"), Duration::from_secs(5)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_check_recipient_count() {
+        let config = Config {
+            maxRecipients: 3,
+            ..Config::default()
+        };
+        let recipients =
+            |n: usize| -> Vec<String> { (0..n).map(|i| format!("user{}", i)).collect() };
+
+        assert!(check_recipient_count(&recipients(3), &config).is_ok());
+
+        let err = check_recipient_count(&recipients(4), &config).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+    }
+
+    #[test]
+    fn test_tls_cipher_from_env() {
+        let getenv = fake_env(&[(TLS_CIPHER_VAR, "TLS_AES_256_GCM_SHA384")]);
+        assert_eq!(
+            tls_cipher_from_env(&getenv),
+            Some("TLS_AES_256_GCM_SHA384".to_string())
+        );
+
+        assert_eq!(tls_cipher_from_env(fake_env(&[])), None);
+        let getenv = fake_env(&[(TLS_CIPHER_VAR, "x) (envelope-from mallory")]);
+        assert_eq!(tls_cipher_from_env(&getenv), None);
+    }
+
+    /// the dumped copy of a delivered message is identical to the stored one
+    #[test]
+    fn test_deliver_to_maildirs_dump() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let dump_path = temp_dir.path().join("dump.eml");
+        let mut dump = File::create(&dump_path).unwrap();
+
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let input = b"Subject: hi\n\nsome body\n";
+        let report = deliver_to_maildirs(
+            &mut Cursor::new(input),
+            &envelope,
+            std::slice::from_ref(&maildir),
+            &Config::default(),
+            Some(&mut dump),
+        )
+        .unwrap();
+
+        let entry = maildir.find(&report.deliveries[0].1).unwrap();
+        let stored = std::fs::read(entry.path()).unwrap();
+        assert!(stored.ends_with(b"Subject: hi\n\nsome body\n"));
+        assert_eq!(std::fs::read(&dump_path).unwrap(), stored);
+    }
+
+    #[test]
+    fn test_check_self_addressed() {
+        let config = |action| Config {
+            rejectSelfAddressed: action,
+            ..Config::default()
+        };
+
+        // by default, delivered
+        assert!(check_self_addressed("alice", "alice", &Config::default()).unwrap());
+
+        assert!(
+            !check_self_addressed("alice", "alice", &config(SelfAddressedAction::Drop)).unwrap()
+        );
+        let err = check_self_addressed("alice", "alice", &config(SelfAddressedAction::Reject))
+            .unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+
+        // other messages are unaffected
+        assert!(
+            check_self_addressed("bob", "alice", &config(SelfAddressedAction::Reject)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_parse_maildir_root_path() {
+        assert_eq!(
+            parse_maildir_root_path(Path::new("/tmp/scratch")).unwrap(),
+            PathBuf::from("/tmp/scratch")
+        );
+        assert_eq!(
+            parse_maildir_root_path(Path::new("/tmp/scratch/new")).unwrap(),
+            PathBuf::from("/tmp/scratch")
+        );
+        assert!(parse_maildir_root_path(Path::new("scratch")).is_err());
+        assert!(parse_maildir_root_path(Path::new("/")).is_err());
+    }
+
+    /// existing directories are recognized as a Maildir or its `new` directory by their contents
+    #[test]
+    fn test_parse_maildir_root_path_existing() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = temp_dir.path().join("mail");
+        Maildir::from(maildir.clone()).create_dirs().unwrap();
+
+        assert_eq!(parse_maildir_root_path(&maildir).unwrap(), maildir);
+        assert_eq!(
+            parse_maildir_root_path(&maildir.join("new")).unwrap(),
+            maildir
+        );
+
+        // a Maildir which happens to be called `new`
+        let new_maildir = maildir.join("cur").join("new");
+        Maildir::from(new_maildir.clone()).create_dirs().unwrap();
+        assert_eq!(parse_maildir_root_path(&new_maildir).unwrap(), new_maildir);
+
+        // a plain directory, even if called `new`, is neither
+        let plain = temp_dir.path().join("plain");
+        std::fs::create_dir_all(plain.join("new")).unwrap();
+        std::fs::create_dir_all(plain.join("other")).unwrap();
+        assert!(parse_maildir_root_path(&plain.join("other")).is_err());
+        assert!(parse_maildir_root_path(&plain.join("new")).is_err());
+        assert!(parse_maildir_root_path(&plain.join("new").join("x")).is_ok());
+    }
+
+    /// `message/partial` fragments are rejected with `rejectPartial`, and delivered otherwise
+    #[test]
+    fn test_reject_partial() {
+        let input =
+            b"Subject: part 1\nContent-Type: Message/Partial; number=1; total=2; id=\"abc\"\n\nBody";
+        let config = Config {
+            rejectPartial: true,
+            ..Config::default()
+        };
+        let mut output = Vec::new();
+        let err =
+            process_existing_headers(&mut Cursor::new(input), &mut output, &config).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+
+        let mut output = Vec::new();
+        process_existing_headers(&mut Cursor::new(input), &mut output, &Config::default()).unwrap();
+        assert_eq!(
+            output,
+            b"Subject: part 1\nContent-Type: Message/Partial; number=1; total=2; id=\"abc\"\n"
+        );
+
+        // a folded header is checked as a whole, whether or not the headers end with a
+        // blank line
+        for input in [
+            &b"Content-Type:\n message/partial;\n\tnumber=1; total=2; id=\"abc\"\nSubject: hi\n\nBody"[..],
+            b"Subject: hi\nContent-Type:\r\n\tMessage/Partial; number=1\r\n",
+        ] {
+            let mut output = Vec::new();
+            let err = process_existing_headers(&mut Cursor::new(input), &mut output, &config)
+                .unwrap_err();
+            assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+        }
+
+        // other content types are fine, folded or not
+        for input in [
+            &b"Content-Type: message/partialish\n\nBody"[..],
+            b"Content-Type: multipart/mixed;\n boundary=\"message/partial\"\n\nBody",
+        ] {
+            let mut output = Vec::new();
+            process_existing_headers(&mut Cursor::new(input), &mut output, &config).unwrap();
+        }
+    }
+
+    /// the fast path for small messages gives exactly the same output as streaming
+    #[test]
+    fn test_build_message_matches_streaming() {
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let config = Config::default();
+        let big_body = "a line of body text\n".repeat(SMALL_MESSAGE_THRESHOLD / 10);
+        let inputs: Vec<Vec<u8>> = vec![
+            b"Subject: hi\n\nsome body\n".to_vec(),
+            b"Subject: hi\r\nFrom: carol\r\n\r\nsome body\r\n".to_vec(),
+            b"just text, no headers\n".to_vec(),
+            b"".to_vec(),
+            format!("Subject: big\n\n{}", big_body).into_bytes(),
+        ];
+
+        for input in inputs {
+            let mut streamed = Vec::new();
+            write_message(&mut Cursor::new(&input), &mut streamed, &envelope, &config).unwrap();
+            let mut buffered = Vec::new();
+            write_message_buffered(&mut Cursor::new(&input), &mut buffered, &envelope, &config)
+                .unwrap();
+            assert_eq!(
+                buffered,
+                streamed,
+                "for input {:?}",
+                String::from_utf8_lossy(&input)
+            );
+        }
+    }
+
+    /// with `validateRfc5322`, well-formed messages are delivered, and malformed ones rejected
+    #[test]
+    fn test_validate_rfc5322() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let config = Config {
+            validateRfc5322: true,
+            ..Config::default()
+        };
+        let deliver = |input: &[u8]| {
+            deliver_to_maildirs(
+                &mut Cursor::new(input),
+                &envelope,
+                std::slice::from_ref(&maildir),
+                &config,
+                None,
+            )
+        };
+
+        let report = deliver(b"Subject: hi\r\nX-Folded: a\r\n b\r\n\r\nsome body\r\n").unwrap();
+        assert_eq!(report.deliveries.len(), 1);
+        deliver(b"just text, no headers\n").unwrap();
+
+        for input in [
+            &b"Subject: hi\nthis isn't a header\n\nsome body\n"[..],
+            b"Subject: hi\nDate: the day before yesterday\n\nsome body\n",
+        ] {
+            let err = deliver(input).unwrap_err();
+            assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+        }
+        assert_eq!(maildir.count_new(), 2);
+
+        // without the option, malformed messages are delivered as-is
+        deliver_to_maildirs(
+            &mut Cursor::new(b"Subject: hi\nthis isn't a header\n\nsome body\n"),
+            &envelope,
+            std::slice::from_ref(&maildir),
+            &Config::default(),
+            None,
+        )
+        .unwrap();
+    }
+
+    /// `datePartition` patterns are formatted, and sanitized into Maildir folder names
+    #[test]
+    fn test_date_partition_folder() {
+        let time = test_received_time();
+        let folder = |pattern| date_partition_folder(pattern, &time, None);
+
+        assert_eq!(folder("%Y/%m").unwrap(), ".2024.12");
+        assert_eq!(folder("%Y.%m").unwrap(), ".2024.12");
+        assert_eq!(folder("/%Y//%m-%d/").unwrap(), ".2024.12-31");
+        assert_eq!(folder("%Y week %V").unwrap(), ".2024_week_01");
+        assert_eq!(folder("../%Y").unwrap(), ".2024");
+        assert!(folder("/").is_err());
+        assert!(folder("%Y/%Q").is_err());
+    }
+
+    /// with `requireFromHeader`, messages without a `From:` are rejected rather than given one
+    #[test]
+    fn test_require_from_header() {
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let config = Config {
+            requireFromHeader: true,
+            ..Config::default()
+        };
+
+        let mut output = Vec::new();
+        let res = write_headers(
+            &mut Cursor::new(b"From: carol\nSubject: hi\n\nBody\n"),
+            &mut output,
+            &envelope,
+            &config,
+        )
+        .unwrap();
+        assert!(res.has_from);
+        assert!(!String::from_utf8(output).unwrap().contains("From: alice"));
+
+        for input in [
+            &b"Subject: hi\n\nBody\n"[..],
+            b"From:\nSubject: hi\n\nBody\n",
+        ] {
+            let err = write_headers(&mut Cursor::new(input), &mut Vec::new(), &envelope, &config)
+                .unwrap_err();
+            assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+        }
+    }
+
+    /// notifications use the `From:` header's name or address, falling back to the sender
+    #[test]
+    fn test_notification_summary() {
+        let summary = |message: &[u8]| notification_summary(message, "alice");
+        assert_eq!(
+            summary(b"From: Carol Smith <carol@example.com>\nSubject: hi\n\nBody\n"),
+            ("Carol Smith".to_string(), "hi".to_string())
+        );
+        assert_eq!(
+            summary(b"From: carol@example.com\nSubject: hi\n\nBody\n"),
+            ("carol@example.com".to_string(), "hi".to_string())
+        );
+        assert_eq!(
+            summary(b"Date: Wed, 21 Oct 2020 07:28:00 GMT\n\nBody\n"),
+            ("alice".to_string(), String::new())
+        );
+    }
+
+    /// A mock `org.freedesktop.Notifications` server, recording the notifications it's sent.
+    #[cfg(feature = "dbus")]
+    struct MockNotifications(std::sync::Arc<Mutex<Vec<(String, String, String)>>>);
+
+    #[cfg(feature = "dbus")]
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl MockNotifications {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            app_name: &str,
+            _replaces_id: u32,
+            _app_icon: &str,
+            summary: &str,
+            body: &str,
+            _actions: Vec<&str>,
+            _hints: HashMap<&str, zbus::zvariant::Value<'_>>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let notification = (app_name.to_string(), summary.to_string(), body.to_string());
+            self.0.lock().unwrap().push(notification);
+            1
+        }
+    }
+
+    /// the notification carries the sender and subject
+    #[cfg(feature = "dbus")]
+    #[test]
+    fn test_send_delivery_notification() {
+        use zbus::blocking::connection::Builder;
+
+        let (server_stream, client_stream) = std::os::unix::net::UnixStream::pair().unwrap();
+        let notifications = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let mock = MockNotifications(notifications.clone());
+        let server = std::thread::spawn(move || {
+            Builder::async_io_unix_stream(server_stream)
+                .server(zbus::Guid::generate())
+                .unwrap()
+                .p2p()
+                .serve_at("/org/freedesktop/Notifications", mock)
+                .unwrap()
+                .build()
+                .unwrap()
+        });
+        let client = Builder::async_io_unix_stream(client_stream)
+            .p2p()
+            .build()
+            .unwrap();
+        let _server = server.join().unwrap();
+
+        send_delivery_notification(&client, "Carol Smith", "hi").unwrap();
+
+        assert_eq!(
+            *notifications.lock().unwrap(),
+            vec![(
+                "rattomail".to_string(),
+                "New mail from Carol Smith".to_string(),
+                "hi".to_string()
+            )]
+        );
+    }
+
+    /// with `receivedHideProduct`, the `Received:` header doesn't mention rattomail
+    #[test]
+    fn test_received_hide_product() {
+        let time = test_received_time();
+        let config = Config {
+            receivedHideProduct: true,
+            ..Config::default()
+        };
+        let mut output = Vec::new();
+        write_headers(
+            &mut Cursor::new(b"Subject: hi\n\nBody\n"),
+            &mut output,
+            &test_envelope("bob", "alice", time),
+            &config,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with(&format!(
+            "Return-Path: <alice>\nDelivered-To: bob\nReceived: for bob with local (envelope-from alice); {}\n",
+            time.to_rfc2822()
+        )));
+        assert!(!output.contains("rattomail"));
+    }
+
+    /// with `receivedFoldWidth`, a long `Received:` header is folded into continuation lines
+    /// no longer than the width, which unfold to the usual header
+    #[test]
+    fn test_received_fold_width() {
+        let time = test_received_time();
+        let to_addr = format!("{}@example.com", "bob".repeat(10));
+        let from_addr = format!("{}@example.org", "alice".repeat(8));
+        let config = Config {
+            receivedFoldWidth: Some(78),
+            ..Config::default()
+        };
+        let mut output = Vec::new();
+        write_headers(
+            &mut Cursor::new(b"Subject: hi\n\nBody\n"),
+            &mut output,
+            &test_envelope(&to_addr, &from_addr, time),
+            &config,
+        )
+        .unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let folded = fold_received_header(&to_addr, &from_addr, &time, 78);
+        assert!(output.starts_with(&format!(
+            "Return-Path: <{}>\nDelivered-To: {}\n{}",
+            from_addr, to_addr, folded
+        )));
+        let lines: Vec<&str> = folded.lines().collect();
+        assert!(lines.len() > 1, "expected folding: {:?}", folded);
+        assert!(lines[0].starts_with("Received: for "));
+        for line in &lines {
+            assert!(line.len() <= 78, "line too long: {:?}", line);
+        }
+        for line in &lines[1..] {
+            assert!(line.starts_with(' '), "not a continuation line: {:?}", line);
+        }
+        assert_eq!(
+            folded.replace("\n ", " "),
+            make_received_header(&to_addr, &from_addr, &time)
+        );
+
+        // a short header isn't folded at all
+        assert_eq!(
+            fold_received_header("bob", "alice", &time, 998),
+            make_received_header("bob", "alice", &time)
+        );
+    }
+
+    /// when reinjecting, rattomail's own trace headers are replaced, and others kept
+    #[test]
+    fn test_reinject_strips_trace_headers() {
+        let time = test_received_time();
+        let input = b"Delivered-To: bob\n\
+            Received: for bob with local (rattomail) (envelope-from alice);\n \
+            Mon, 30 Dec 2024 10:30:00 +0000\n\
+            Received: from mx.example.com by example.com; Mon, 30 Dec 2024 10:29:00 +0000\n\
+            X-Rattomail-Version: 0.1.0\n\
+            x-rattomail-note: folded\n\tcontinuation\n\
+            Subject: hi\n\nBody\n";
+        let mut envelope = test_envelope("bob", "alice", time);
+        envelope.reinject = true;
+
+        let mut output = Vec::new();
+        write_message(
+            &mut Cursor::new(&input[..]),
+            &mut output,
+            &envelope,
+            &Config::default(),
+        )
+        .unwrap();
+
+        let expected_output = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nMessage-ID: {TEST_MESSAGE_ID}\n\
+            Received: from mx.example.com by example.com; Mon, 30 Dec 2024 10:29:00 +0000\n\
+            Subject: hi\n\nBody\n",
+            make_received_header("bob", "alice", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+
+        // without --reinject, they're left alone (and for the same recipient, they'd be a loop)
+        let mut output = Vec::new();
+        write_message(
+            &mut Cursor::new(&input[..]),
+            &mut output,
+            &test_envelope("carol", "alice", time),
+            &Config::default(),
+        )
+        .unwrap();
+        assert!(output.ends_with(&input[..]));
+    }
+
+    /// `--assume-user` is only allowed when privileges aren't dropped
+    #[test]
+    fn test_check_assume_user() {
+        check_assume_user(Some("carol"), PrivilegeOption::NoDropPrivileges).unwrap();
+        check_assume_user(None, PrivilegeOption::DropPrivileges).unwrap();
+        assert!(check_assume_user(Some("carol"), PrivilegeOption::DropPrivileges).is_err());
+    }
+
+    /// raw 8-bit subjects are left alone when warning, and encoded when encoding
+    #[test]
+    fn test_check_raw_subject() {
+        let headers = "To: bob\nSubject: Café au lait\nX-Other: ç\n".as_bytes();
+        assert!(has_raw_8bit(b"Subject: Caf\xc3\xa9\n"));
+        assert!(!has_raw_8bit(b"Subject: =?UTF-8?B?Q2Fmw6k=?=\n"));
+
+        assert_eq!(check_raw_subject(headers, RawSubjectAction::Warn), headers);
+        assert_eq!(
+            String::from_utf8(check_raw_subject(headers, RawSubjectAction::Encode)).unwrap(),
+            "To: bob\nSubject: =?UTF-8?B?Q2Fmw6kgYXUgbGFpdA==?=\nX-Other: ç\n"
+        );
+
+        // ASCII and non-UTF-8 subjects are left as-is
+        let ascii = b"Subject: =?UTF-8?B?Q2Fmw6k=?=\n";
+        assert_eq!(check_raw_subject(ascii, RawSubjectAction::Encode), ascii);
+        let latin1 = b"Subject: Caf\xe9\r\n";
+        assert_eq!(check_raw_subject(latin1, RawSubjectAction::Encode), latin1);
+    }
+
+    /// long (and folded) subjects are split into several encoded-words, which decode to the
+    /// original text
+    #[test]
+    fn test_encode_subject_field_long() {
+        let subject = "Ünïcödé ".repeat(10);
+        let field = format!("Subject: {}\r\n {}\r\n", subject.trim(), subject.trim());
+        let encoded = encode_subject_field(field.as_bytes()).unwrap();
+
+        let encoded_str = String::from_utf8(encoded.clone()).unwrap();
+        assert!(encoded_str.is_ascii());
+        assert!(encoded_str.ends_with("?=\r\n"));
+        for line in encoded_str.split("\r\n") {
+            assert!(line.len() <= 78, "line too long: {:?}", line);
+        }
+
+        let mut message = encoded;
+        message.extend_from_slice(b"\r\nBody\r\n");
+        let parsed = MessageParser::default().parse(&message).unwrap();
+        assert_eq!(
+            parsed.subject().unwrap(),
+            format!("{} {}", subject.trim(), subject.trim())
+        );
+    }
+
+    /// with `truncateAtBytes`, long bodies are cut off, with a note saying so
+    #[test]
+    fn test_truncate_at_bytes() {
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let config = Config {
+            truncateAtBytes: Some(10),
+            ..Config::default()
+        };
+        let deliver = |input: &[u8]| {
+            let mut output = Vec::new();
+            write_message_buffered(&mut Cursor::new(input), &mut output, &envelope, &config)
+                .unwrap();
+            let body_start = output.windows(2).position(|w| w == b"\n\n").unwrap() + 2;
+            String::from_utf8(output[body_start..].to_vec()).unwrap()
+        };
+
+        assert_eq!(
+            deliver(b"Subject: hi\n\n0123456789abcdef\nmore\n"),
+            "0123456789\n\n[message truncated by rattomail at 10 bytes]\n"
+        );
+        // headerless input counts towards the body, too
+        assert_eq!(
+            deliver(b"just text, no headers\n"),
+            "just text,\n\n[message truncated by rattomail at 10 bytes]\n"
+        );
+        // bodies within the limit are untouched
+        assert_eq!(deliver(b"Subject: hi\n\n012345678\n"), "012345678\n");
+    }
+
+    /// `deliveryGroup` overrides the user's primary group, and must exist
+    #[test]
+    fn test_delivery_group_id() {
+        let user = User::from_uid(nix::unistd::getuid()).unwrap().unwrap();
+        assert_eq!(
+            delivery_group_id(&Config::default(), &user).unwrap(),
+            user.gid
+        );
+
+        let root_group = Group::from_gid(Gid::from_raw(0)).unwrap().unwrap();
+        let config = Config {
+            deliveryGroup: Some(root_group.name),
+            ..Config::default()
+        };
+        assert_eq!(delivery_group_id(&config, &user).unwrap(), Gid::from_raw(0));
+
+        let config = Config {
+            deliveryGroup: Some("no-such-group-rattomail".to_string()),
+            ..Config::default()
+        };
+        let err = delivery_group_id(&config, &user).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::Config.code());
+    }
+
+    /// with `normalizeMacLineEndings`, `\r`-only line endings become `\n`
+    #[test]
+    fn test_normalize_mac_line_endings() {
+        let time = test_received_time();
+        let envelope = test_envelope("bob", "alice", time);
+        let config = Config {
+            normalizeMacLineEndings: true,
+            ..Config::default()
+        };
+        let input = b"From: carol\rSubject: hi\r\rline one\rline two\r\r";
+        let mut output = Vec::new();
+        write_message_buffered(&mut Cursor::new(input), &mut output, &envelope, &config).unwrap();
+
+        let expected_output = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nMessage-ID: {TEST_MESSAGE_ID}\nFrom: carol\nSubject: hi\n\nline one\nline two\n\n",
+            make_received_header("bob", "alice", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+    }
+
+    /// CRLFs are left alone, and a `\r` at the end of one read is still converted correctly
+    #[test]
+    fn test_mac_line_endings_reader() {
+        let convert = |input: &[u8], chunk_size: usize| {
+            let reader = ChunkedReader {
+                data: input.to_vec(),
+                pos: 0,
+                chunk_size,
+            };
+            let mut output = Vec::new();
+            MacLineEndings::new(reader)
+                .read_to_end(&mut output)
+                .unwrap();
+            output
+        };
+        for chunk_size in [1, 2, 3, 100] {
+            assert_eq!(
+                convert(b"a\rb\r\nc\r\rd\r", chunk_size),
+                b"a\nb\r\nc\n\nd\n"
+            );
+            assert_eq!(convert(b"", chunk_size), b"");
+            assert_eq!(convert(b"\r", chunk_size), b"\n");
+        }
+    }
+
+    /// a reader which returns at most `chunk_size` bytes per read
+    struct ChunkedReader {
+        data: Vec<u8>,
+        pos: usize,
+        chunk_size: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = (self.data.len() - self.pos)
+                .min(self.chunk_size)
+                .min(buf.len());
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    /// flags are stored sorted and deduplicated, however they're given
+    #[test]
+    fn test_store_new_unique_sorts_flags() {
+        assert_eq!(maildir_info_suffix("SD"), ":2,DS");
+        assert_eq!(maildir_info_suffix("TSSRD"), ":2,DRST");
+        assert_eq!(maildir_info_suffix(""), ":2,");
+
+        let target = InMemoryTarget::new("/nonexistent/Maildir");
+        let base = maildir_name_base(SystemTime::now());
+        let id = store_new_unique(
+            &target,
+            b"message",
+            &base,
+            Some("SFS"),
+            0,
+            TmpfileStrategy::Classic,
+            &mut StdRng::seed_from_u64(7),
+        )
+        .unwrap();
+        assert!(id.ends_with(":2,FS"), "unexpected id {}", id);
+        assert!(is_valid_maildir_id(&id));
+    }
+
+    /// the retry logic, against an in-memory target: taken ids are skipped, until we run out
+    /// of retries
+    #[test]
+    fn test_store_new_unique_in_memory() {
+        let target = InMemoryTarget::new("/nonexistent/Maildir");
+        let base = maildir_name_base(SystemTime::now());
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let candidates: Vec<String> = (0..3)
+            .map(|_| maildir_candidate_name(&base, &mut rng))
+            .collect();
+        target.store(&candidates[0], b"first").unwrap();
+        target.store(&candidates[1], b"second").unwrap();
+
+        // with only one retry, we can't get past both taken ids
+        assert!(store_new_unique(
+            &target,
+            b"message",
+            &base,
+            None,
+            1,
+            TmpfileStrategy::Classic,
+            &mut StdRng::seed_from_u64(7)
+        )
+        .is_err());
+        assert_eq!(target.ids().len(), 2);
+
+        let id = store_new_unique(
+            &target,
+            b"message",
+            &base,
+            None,
+            2,
+            TmpfileStrategy::Classic,
+            &mut StdRng::seed_from_u64(7),
+        )
+        .unwrap();
+        assert_eq!(id, candidates[2]);
+        assert!(target.exists(&id));
+        assert_eq!(target.message(&id).unwrap(), b"message");
+        assert_eq!(target.message(&candidates[0]).unwrap(), b"first");
+    }
+
+    /// delivering to in-memory targets gives each a copy of the full message, and reports
+    /// the targets' paths
+    #[test]
+    fn test_deliver_to_in_memory_targets() {
+        let targets = [InMemoryTarget::new("/one"), InMemoryTarget::new("/two")];
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let config = Config::default();
+        let mut input = Cursor::new(b"Subject: hi\n\nbody\n".to_vec());
+
+        let report = deliver_to_maildirs(&mut input, &envelope, &targets, &config, None).unwrap();
+
+        assert_eq!(report.deliveries.len(), 2);
+        for ((path, id), target) in report.deliveries.iter().zip(&targets) {
+            assert_eq!(path, target.path());
+            let message = String::from_utf8(target.message(id).unwrap()).unwrap();
+            assert!(message.starts_with("Return-Path: <alice>\nDelivered-To: bob\nReceived: "));
+            assert!(message.ends_with("Subject: hi\n\nbody\n"));
+        }
+    }
+
+    /// an `EmlWriter` adds a newline only when the output doesn't already end in one
+    #[test]
+    fn test_eml_writer() {
+        let write_eml = |chunks: &[&[u8]]| {
+            let mut output = Vec::new();
+            let mut eml = EmlWriter::new(&mut output);
+            for chunk in chunks {
+                eml.write_all(chunk).unwrap();
+            }
+            eml.finish().unwrap();
+            output
+        };
+        assert_eq!(write_eml(&[b"a: b\n\nbody"]), b"a: b\n\nbody\n");
+        assert_eq!(write_eml(&[b"a: b\n\nbody\n"]), b"a: b\n\nbody\n");
+        assert_eq!(write_eml(&[b"a: b\n\nbody\n", b""]), b"a: b\n\nbody\n");
+        assert_eq!(write_eml(&[]), b"\n");
+    }
+
+    /// empty input is only treated specially if `onEmptyInput` says so, and non-empty input
+    /// never is
+    #[test]
+    fn test_check_empty_input() {
+        let config = |on_empty_input| Config {
+            onEmptyInput: on_empty_input,
+            ..Config::default()
+        };
+        let check =
+            |input: &[u8], action| check_empty_input(&mut Cursor::new(input), &config(action));
+
+        assert!(check(b"", EmptyInputAction::Deliver).unwrap());
+        assert!(!check(b"", EmptyInputAction::Skip).unwrap());
+        let err = check(b"", EmptyInputAction::Reject).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::NoInput.code());
+
+        for action in [
+            EmptyInputAction::Deliver,
+            EmptyInputAction::Skip,
+            EmptyInputAction::Reject,
+        ] {
+            assert!(check(b"\n", action).unwrap());
+        }
+    }
+
+    /// a signed receipt's last line is the HMAC of the rest; an unsigned one has no HMAC
+    #[test]
+    fn test_delivery_receipt_format() {
+        let receipt = DeliveryReceipt {
+            message_id: "1735601400.M0P1R1.host".to_string(),
+            recipient: "bob".to_string(),
+            size: 123,
+            timestamp: "2024-12-31T10:30:00+00:00".to_string(),
+        };
+        let fields = "message-id: 1735601400.M0P1R1.host\nrecipient: bob\nsize: 123\ntimestamp: 2024-12-31T10:30:00+00:00\n";
+        assert_eq!(receipt.format(None), fields);
+
+        // test vector from RFC 4231, test case 2
+        assert_eq!(
+            receipt_hmac(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            receipt.format(Some(b"secret")),
+            format!(
+                "{}hmac-sha256: {}\n",
+                fields,
+                receipt_hmac(b"secret", fields.as_bytes())
+            )
+        );
+    }
+
+    /// the receipt key can come from the config file or a key file, but not both
+    #[test]
+    fn test_read_receipt_key() {
+        assert_eq!(read_receipt_key(&Config::default()).unwrap(), None);
+
+        let config = Config {
+            receiptKey: Some("secret".to_string()),
+            ..Config::default()
+        };
+        assert_eq!(read_receipt_key(&config).unwrap(), Some(b"secret".to_vec()));
+
+        let key_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(key_file.path(), "from file\n").unwrap();
+        let config = Config {
+            receiptKeyFile: Some(key_file.path().to_str().unwrap().to_string()),
+            ..Config::default()
+        };
+        assert_eq!(
+            read_receipt_key(&config).unwrap(),
+            Some(b"from file".to_vec())
+        );
+
+        let config = Config {
+            receiptKey: Some("secret".to_string()),
+            ..config
+        };
+        let err = read_receipt_key(&config).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::Config.code());
+    }
+
+    #[test]
+    fn test_open_receipt_fd() {
+        for fd_arg in ["", "x", "-1", "999999"] {
+            let err = open_receipt_fd(fd_arg).unwrap_err();
+            assert_eq!(
+                exit_code_for(&err),
+                Sysexit::Usage.code(),
+                "for {:?}",
+                fd_arg
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_valid_maildir_id() {
+        let base = maildir_name_base(SystemTime::now());
+        let id = maildir_candidate_name(&base, &mut StdRng::seed_from_u64(1));
+        assert!(is_valid_maildir_id(&id), "{}", id);
+        assert!(is_valid_maildir_id(
+            "1735601400.M123P45R0badf00d.host\\057name"
+        ));
+        assert!(is_valid_maildir_id("1735601400.M123P45R0badf00d.host:2,DS"));
+        assert!(is_valid_maildir_id("1735601400.M123P45R0badf00d.host:2,"));
+
+        for id in [
+            "",
+            "1735601400",
+            "1735601400.M123P45R0badf00d.",
+            "1735601400.M123P45Rxyz.host",
+            "1735601400.M123P45R0badf00d.host/../x",
+            "1735601400.M123P45R0badf00d.host:2,s",
+            "1735601400.M123P45R0badf00d.host:2,S/x",
+            "1735601400.M123P45R0badf00d.host:1,S",
+            "x1735601400.M123P45R0badf00d.host",
+            "1735601400.M123P45R0badf00d.hö",
+        ] {
+            assert!(!is_valid_maildir_id(id), "{:?} should be invalid", id);
+        }
+    }
+
+    /// a target which stores messages with whatever id it likes -- here, always `id`
+    struct BadIdTarget {
+        inner: InMemoryTarget,
+        id: &'static str,
+    }
+
+    impl DeliveryTarget for BadIdTarget {
+        fn path(&self) -> &Path {
+            self.inner.path()
+        }
+
+        fn ensure_dirs(&self) -> std::io::Result<()> {
+            self.inner.ensure_dirs()
+        }
+
+        fn exists(&self, id: &str) -> bool {
+            self.inner.exists(id)
+        }
+
+        fn store(&self, _id: &str, data: &[u8]) -> std::result::Result<String, MaildirError> {
+            self.inner.store(self.id, data)
+        }
+
+        fn remove(&self, id: &str) -> std::io::Result<()> {
+            self.inner.remove(id)
+        }
+
+        fn set_mode(&self, id: &str, mode: u32) -> std::io::Result<()> {
+            self.inner.set_mode(id, mode)
+        }
+    }
+
+    /// if a target reports an invalid id, delivery fails with `EX_TEMPFAIL`, rather than
+    /// succeeding, and the message is removed -- where that's possible, which it isn't with an
+    /// empty id
+    #[test]
+    fn test_deliver_invalid_stored_id() {
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let config = Config::default();
+
+        for id in ["", "not a maildir name"] {
+            let targets = [BadIdTarget {
+                inner: InMemoryTarget::new("/bad"),
+                id,
+            }];
+            let mut input = Cursor::new(b"Subject: hi\n\nbody\n".to_vec());
+            let err =
+                deliver_to_maildirs(&mut input, &envelope, &targets, &config, None).unwrap_err();
+            assert_eq!(
+                exit_code_for(&err),
+                Sysexit::TempFail.code(),
+                "for {:?}",
+                id
+            );
+            assert_eq!(targets[0].inner.exists(id), id.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_hexdump() {
+        assert_eq!(hexdump(b"", 0), "");
+        assert_eq!(
+            hexdump(b"Subject: hi\n\nbody\n", 0x40),
+            "00000040  53 75 62 6a 65 63 74 3a 20 68 69 0a 0a 62 6f 64  |Subject: hi..bod|\n\
+             00000050  79 0a                                            |y.|\n"
+        );
+    }
+
+    /// a `MessageTracer` passes everything through, and keeps the first and last 64 bytes
+    #[test]
+    fn test_message_tracer() {
+        let data: Vec<u8> = (0..=199).collect();
+        let mut output = Vec::new();
+        let mut tracer = MessageTracer::new(&mut output);
+        for chunk in data.chunks(7) {
+            tracer.write_all(chunk).unwrap();
+        }
+        assert_eq!(tracer.size, 200);
+        assert_eq!(tracer.head, &data[..64]);
+        assert_eq!(tracer.tail, &data[136..]);
+        tracer.finish();
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn test_dedupe_critical_headers() {
+        let headers = b"From: first@example.com\nDate: Wed, 21 Oct 2020 07:28:00 GMT\n\
+            To: bob\nfrom: second@example.com\nDate: Thu, 22 Oct 2020\n  07:28:00 GMT\n\
+            Received: one\nReceived: two\nSubject: hi\nSubject: again\n";
+        assert_eq!(
+            String::from_utf8(dedupe_critical_headers(headers)).unwrap(),
+            "From: first@example.com\nDate: Wed, 21 Oct 2020 07:28:00 GMT\nTo: bob\n\
+             Received: one\nReceived: two\nSubject: hi\n"
+        );
+    }
+
+    /// with `dedupeCriticalHeaders`, only the first `From:` and `Date:` are delivered;
+    /// without it, duplicates are passed through
+    #[test]
+    fn test_deliver_dedupe_critical_headers() {
+        let time = test_received_time();
+        let envelope = test_envelope("bob", "alice", time);
+        let input = b"From: carol\nDate: Wed, 21 Oct 2020 07:28:00 GMT\nFrom: dave\n\
+            Date: Thu, 22 Oct 2020 07:28:00 GMT\n\nbody\n";
+        let deliver = |config: &Config| {
+            let mut output = Vec::new();
+            write_message_buffered(&mut Cursor::new(input), &mut output, &envelope, config)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        let config = Config {
+            dedupeCriticalHeaders: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            deliver(&config),
+            format!(
+                "Return-Path: <alice>\nDelivered-To: bob\n{}Message-ID: {TEST_MESSAGE_ID}\nFrom: carol\nDate: Wed, 21 Oct 2020 07:28:00 GMT\n\nbody\n",
+                make_received_header("bob", "alice", &time)
+            )
+        );
+
+        let passed_through = deliver(&Config::default());
+        assert!(passed_through.contains("From: carol\n"));
+        assert!(passed_through.contains("From: dave\n"));
+    }
+
+    /// each recognized name selects its default profile, unless overridden; other names get
+    /// the full one
+    #[test]
+    fn test_program_profile() {
+        for (name, expected) in [
+            ("rattomail", ProgramProfile::Full),
+            ("attomail", ProgramProfile::Full),
+            ("sendmail", ProgramProfile::Sendmail),
+            ("send-mail", ProgramProfile::Sendmail),
+            ("lmtp", ProgramProfile::Lmtp),
+        ] {
+            assert_eq!(program_profile(name, &[]), expected, "for {}", name);
+        }
+        assert!(DEFAULT_PROGRAM_PROFILES
+            .iter()
+            .all(|(name, _)| PROGRAM_NAMES.contains(name)));
+
+        let overrides = [
+            ("sendmail".to_string(), ProgramProfile::Full),
+            ("bogus_rattomail".to_string(), ProgramProfile::Sendmail),
+        ];
+        assert_eq!(
+            program_profile("sendmail", &overrides),
+            ProgramProfile::Full
+        );
+        assert_eq!(
+            program_profile("bogus_rattomail", &overrides),
+            ProgramProfile::Sendmail
+        );
+        assert_eq!(
+            program_profile("send-mail", &overrides),
+            ProgramProfile::Sendmail
+        );
+
+        assert_eq!(program_profile("mailx", &overrides), ProgramProfile::Full);
+    }
+
+    /// the probe succeeds (leaving nothing behind) in a writable Maildir, and fails with the
+    /// errno in one it can't write to
+    #[test]
+    fn test_probe_maildir_writable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        probe_maildir_writable(maildir.path()).unwrap();
+        assert_eq!(
+            std::fs::read_dir(maildir.path().join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+
+        let io_errno = |err: &anyhow::Error| {
+            err.chain()
+                .find_map(|e| e.downcast_ref::<std::io::Error>())
+                .and_then(|e| e.raw_os_error())
+                .map(Errno::from_raw)
+        };
+
+        // a tmp/ that isn't a directory at all
+        let broken = temp_dir.path().join("broken");
+        std::fs::create_dir(&broken).unwrap();
+        std::fs::write(broken.join("tmp"), b"").unwrap();
+        let err = probe_maildir_writable(&broken).unwrap_err();
+        assert_eq!(io_errno(&err), Some(Errno::ENOTDIR));
+        assert!(format!("{:#}", err).contains("ENOTDIR"), "{:#}", err);
+
+        // a read-only tmp/ -- which root can write to regardless
+        if !Uid::effective().is_root() {
+            let tmp = maildir.path().join("tmp");
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o555)).unwrap();
+            let err = probe_maildir_writable(maildir.path()).unwrap_err();
+            std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o755)).unwrap();
+            assert_eq!(io_errno(&err), Some(Errno::EACCES));
+        }
+    }
+
+    /// a quoted-printable message with a raw 8-bit body is re-declared `8bit` under
+    /// `fixQpMismatch`, and otherwise left alone (and just warned about); a correctly encoded
+    /// one is left alone either way
+    #[test]
+    fn test_fix_qp_mismatch() {
+        let time = test_received_time();
+        let envelope = test_envelope("bob", "alice", time);
+        let mismatched = "Subject: hi\nContent-Transfer-Encoding: Quoted-Printable\nTo: bob\n\n\
+            caf\u{e9} =3D\n";
+        let encoded = "Subject: hi\nContent-Transfer-Encoding: quoted-printable\n\ncaf=C3=A9\n";
+        let deliver = |input: &str, config: &Config, buffered: bool| {
+            let mut output = Vec::new();
+            let mut input = Cursor::new(input.as_bytes());
+            if buffered {
+                write_message_buffered(&mut input, &mut output, &envelope, config).unwrap();
+            } else {
+                write_message(&mut input, &mut output, &envelope, config).unwrap();
+            }
+            String::from_utf8(output).unwrap()
+        };
+        let with_added_headers = |message: &str| {
+            let (headers, body) = message.split_once("\n\n").unwrap();
+            format!(
+                "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nMessage-ID: {TEST_MESSAGE_ID}\n{}\n\n{}",
+                make_received_header("bob", "alice", &time),
+                time.to_rfc2822(),
+                headers,
+                body
+            )
+        };
+
+        let fixing = Config {
+            fixQpMismatch: true,
+            ..Config::default()
+        };
+        for buffered in [false, true] {
+            assert_eq!(
+                deliver(mismatched, &fixing, buffered),
+                with_added_headers(
+                    "Subject: hi\nContent-Transfer-Encoding: 8bit\nTo: bob\n\ncaf\u{e9} =3D\n"
+                )
+            );
+            assert_eq!(
+                deliver(mismatched, &Config::default(), buffered),
+                with_added_headers(mismatched)
+            );
+            assert_eq!(
+                deliver(encoded, &fixing, buffered),
+                with_added_headers(encoded)
+            );
+        }
+
+        let mut status_output = Vec::new();
+        let status = process_existing_headers(
+            &mut Cursor::new(mismatched.as_bytes()),
+            &mut status_output,
+            &Config::default(),
+        )
+        .unwrap();
+        assert!(status.quoted_printable);
+
+        // a folded header is unfolded before it's checked
+        let folded = "Subject: hi\nContent-Transfer-Encoding:\n quoted-printable\nTo: bob\n\n\
+            caf\u{e9} =3D\n";
+        let status = process_existing_headers(
+            &mut Cursor::new(folded.as_bytes()),
+            &mut Vec::new(),
+            &Config::default(),
+        )
+        .unwrap();
+        assert!(status.quoted_printable);
+        assert_eq!(
+            deliver(folded, &fixing, false),
+            with_added_headers(
+                "Subject: hi\nContent-Transfer-Encoding: 8bit\nTo: bob\n\ncaf\u{e9} =3D\n"
+            )
+        );
+
+        // CRLF line endings are kept
+        assert_eq!(
+            fix_qp_mismatch(b"Content-Transfer-Encoding: quoted-printable\r\n\r\n\xe9\r\n")
+                .unwrap(),
+            b"Content-Transfer-Encoding: 8bit\r\n\r\n\xe9\r\n"
+        );
+        assert_eq!(fix_qp_mismatch(b"Subject: hi\n\n\xe9\n"), None);
+    }
+
+    /// a process already running as the target UID and GID needs no privileges dropped; one
+    /// with any other real, effective or saved ID does
+    #[test]
+    fn test_already_running_as() {
+        use nix::unistd::{ResGid, ResUid};
+        let uid = Uid::from_raw(1000);
+        let gid = Gid::from_raw(1000);
+        let uids = |real, effective, saved| ResUid {
+            real: Uid::from_raw(real),
+            effective: Uid::from_raw(effective),
+            saved: Uid::from_raw(saved),
+        };
+        let gids = |real, effective, saved| ResGid {
+            real: Gid::from_raw(real),
+            effective: Gid::from_raw(effective),
+            saved: Gid::from_raw(saved),
+        };
+
+        assert!(already_running_as(
+            uids(1000, 1000, 1000),
+            gids(1000, 1000, 1000),
+            uid,
+            gid
+        ));
+        // e.g. a setuid-root binary
+        assert!(!already_running_as(
+            uids(1000, 0, 0),
+            gids(1000, 1000, 1000),
+            uid,
+            gid
+        ));
+        assert!(!already_running_as(
+            uids(1000, 1000, 0),
+            gids(1000, 1000, 1000),
+            uid,
+            gid
+        ));
+        assert!(!already_running_as(
+            uids(1000, 1000, 1000),
+            gids(1000, 1000, 50),
+            uid,
+            gid
+        ));
+        assert!(!already_running_as(
+            uids(1001, 1001, 1001),
+            gids(1000, 1000, 1000),
+            uid,
+            gid
+        ));
+    }
+
+    #[test]
+    fn test_read_address_fd() {
+        use std::os::fd::IntoRawFd;
+
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        let mut write_end = File::from(write_end);
+        write_end.write_all(b"alice@example.com\n").unwrap();
+        drop(write_end);
+        let fd_arg = read_end.into_raw_fd().to_string();
+        assert_eq!(
+            read_address_fd("--recipient-fd", &fd_arg).unwrap(),
+            "alice@example.com"
+        );
+
+        let err = read_address_fd("--recipient-fd", "x").unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::Usage.code());
+        assert!(err.to_string().starts_with("--recipient-fd: "), "{}", err);
+    }
+
+    /// a last header line with no line ending (because the input ends there) is still
+    /// terminated, rather than having the blank line after the headers run into it
+    #[test]
+    fn test_process_existing_headers_unterminated() {
+        let mut output = Vec::new();
+        let status = process_existing_headers(
+            &mut Cursor::new(&b"Subject: hi\nFrom: x"[..]),
+            &mut output,
+            &Config::default(),
+        )
+        .unwrap();
+        assert!(status.has_from);
+        assert_eq!(output, b"Subject: hi\nFrom: x\n");
+
+        let time = test_received_time();
+        let envelope = test_envelope("bob", "alice", time);
+        for input in [&b"From: x"[..], b"Subject: hi"] {
+            let mut output = Vec::new();
+            write_message(
+                &mut Cursor::new(input),
+                &mut output,
+                &envelope,
+                &Config::default(),
+            )
+            .unwrap();
+            let output = String::from_utf8(output).unwrap();
+            let input = std::str::from_utf8(input).unwrap();
+            assert!(
+                output.ends_with(&format!("\n{}\n\n", input)),
+                "{:?}",
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_logfile_allowed() {
+        let extra = vec!["/dev/fd/2".to_string()];
+        for path in ["-", "/dev/stderr"] {
+            assert!(check_logfile_allowed(path, &[]).is_ok(), "{}", path);
+            assert!(check_logfile_allowed(path, &extra).is_ok(), "{}", path);
+        }
+        assert!(check_logfile_allowed("/dev/fd/2", &extra).is_ok());
+        assert!(check_logfile_allowed("/dev/fd/2", &[]).is_err());
+
+        let err = check_logfile_allowed("/tmp/log", &extra).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"Invalid logfile path '/tmp/log'. Only ["-", "/dev/stderr", "/dev/fd/2"] are allowed."#
+        );
+    }
+
+    #[test]
+    fn test_parse_args_tolerantly() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|s| s.to_string()).collect() };
+        let cli = build_cli();
+
+        let matches = parse_args_tolerantly(
+            &cli,
+            &args(&[
+                "sendmail",
+                "--no-such-option",
+                "-f",
+                "alice",
+                "-Z",
+                "--foo=bar",
+                "bob",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(matches.get_one::<String>("sender_env").unwrap(), "alice");
+        assert_eq!(matches.get_one::<String>("to_address").unwrap(), "bob");
+
+        // other errors still are errors
+        let err = parse_args_tolerantly(&cli, &args(&["sendmail", "-f"])).unwrap_err();
+        assert_ne!(err.kind(), ErrorKind::UnknownArgument);
+    }
+
+    /// with `messageMode`, the delivered message gets exactly that mode
+    #[test]
+    fn test_deliver_message_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let deliver = |config: &Config| {
+            let input = b"Subject: hi\n\nsome body\n";
+            let report = deliver_to_maildirs(
+                &mut Cursor::new(input),
+                &envelope,
+                std::slice::from_ref(&maildir),
+                config,
+                None,
+            )
+            .unwrap();
+            let path = maildir.path().join("new").join(&report.deliveries[0].1);
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+        };
+
+        for mode in [0o600, 0o640, 0o444] {
+            let config = Config {
+                messageMode: Some(mode),
+                ..Config::default()
+            };
+            assert_eq!(deliver(&config), mode);
+        }
+    }
+
+    /// input of more than `inputLineLimit` lines is rejected with `EX_DATAERR`, leaving nothing
+    /// in the Maildir; input of exactly that many is delivered
+    #[test]
+    fn test_input_line_limit() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let config = Config::default();
+        let deliver = |input: &[u8], limit| {
+            let mut input = LineLimitReader::new(Cursor::new(input), Some(limit));
+            deliver_to_maildirs(
+                &mut input,
+                &envelope,
+                std::slice::from_ref(&maildir),
+                &config,
+                None,
+            )
+        };
+        let count = |subdir: &str| {
+            std::fs::read_dir(maildir.path().join(subdir))
+                .unwrap()
+                .count()
+        };
+
+        for input in [
+            &b"Subject: hi\n\none\ntwo\n"[..],
+            b"Subject: hi\n\none\ntwo",
+        ] {
+            let err = deliver(input, 3).unwrap_err();
+            assert_eq!(exit_code_for(&err), Sysexit::DataErr.code(), "{:#}", err);
+            assert_eq!(count("new"), 0);
+            assert_eq!(count("tmp"), 0);
+        }
+
+        deliver(b"Subject: hi\n\none\ntwo\n", 4).unwrap();
+        assert_eq!(count("new"), 1);
+
+        // no limit at all
+        let mut input = LineLimitReader::new(Cursor::new(&b"a\nb\nc\n"[..]), None);
+        let mut output = Vec::new();
+        std::io::copy(&mut input, &mut output).unwrap();
+        assert_eq!(output, b"a\nb\nc\n");
+    }
+
+    #[test]
+    fn test_parse_maildir_flags() {
+        assert_eq!(parse_maildir_flags("SD").unwrap(), "DS");
+        assert_eq!(parse_maildir_flags("TSRPFD").unwrap(), "DFPRST");
+        assert_eq!(parse_maildir_flags("SS").unwrap(), "S");
+        assert_eq!(parse_maildir_flags("").unwrap(), "");
+        for invalid in ["s", "X", "D,S", "D S"] {
+            assert!(parse_maildir_flags(invalid).is_err(), "for {:?}", invalid);
         }
     }
 
-    let maildir = maildir_new_path.parent().ok_or_else(||
-        // actually, if we are here, there is necessarily a parent, but the compiler doesn't
-        // know that
-        anyhow::anyhow!("mailDir path '{:?}' has no parent", maildir_new_path))?;
+    /// with `deliverFlags`, the message is delivered straight into `cur/`, with the flags (in
+    /// alphabetical order) in its filename's info section
+    #[test]
+    fn test_deliver_flags() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let config = Config {
+            deliverFlags: Some(parse_maildir_flags("SD").unwrap()),
+            messageMode: Some(0o600),
+            ..Config::default()
+        };
 
-    Ok(PathBuf::from(maildir))
-}
+        let input = b"Subject: hi\n\nsome body\n";
+        let report = deliver_to_maildirs(
+            &mut Cursor::new(input),
+            &envelope,
+            std::slice::from_ref(&maildir),
+            &config,
+            None,
+        )
+        .unwrap();
+        let id = &report.deliveries[0].1;
+        let (_, info) = id.split_once(':').unwrap();
+        assert_eq!(info, "2,DS");
+        assert!(is_valid_maildir_id(id), "{}", id);
 
-fn deliver_to_maildir<R: BufRead>(
-    input: &mut R,
-    from_address: String,
-    to_address: String,
-    maildir: Maildir,
-    received_time: &chrono::DateTime<Local>,
-) -> Result<()> {
-    let mut mail_mesg_bytes = Vec::<u8>::new();
-    write_message(
-        input,
-        &mut mail_mesg_bytes,
-        &to_address,
-        &from_address,
-        &received_time,
-    )
-    .context("Couldn't construct delivered message")?;
+        assert_eq!(
+            std::fs::read_dir(maildir.path().join("new"))
+                .unwrap()
+                .count(),
+            0
+        );
+        assert_eq!(
+            std::fs::read_dir(maildir.path().join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+        let path = message_path(maildir.path(), id);
+        assert_eq!(path, maildir.path().join("cur").join(id));
+        assert!(std::fs::read(&path).unwrap().ends_with(b"\n\nsome body\n"));
 
-    let message_id = maildir
-        .store_new(&mail_mesg_bytes)
-        .map_err(|e| anyhow::anyhow!("Couldn't store message in maildir: {}", e))?;
+        let entry = maildir.find(id.split_once(':').unwrap().0).unwrap();
+        assert!(entry.is_draft() && entry.is_seen() && !entry.is_flagged());
+    }
 
-    log::debug!("Message successfully delivered, with id: {}", message_id);
+    /// `maildirRelative` is resolved against the user's home directory, wherever that is --
+    /// not necessarily `/home/<name>`
+    #[test]
+    fn test_resolve_maildir_relative() {
+        let home = Path::new("/srv/mail/users/alice");
+        let resolved = resolve_maildir_relative(home, "Maildir/new").unwrap();
+        assert_eq!(resolved, PathBuf::from("/srv/mail/users/alice/Maildir/new"));
+        assert_eq!(
+            parse_maildir_new_path(&resolved).unwrap(),
+            PathBuf::from("/srv/mail/users/alice/Maildir")
+        );
 
-    Ok(())
-}
+        for relative in [
+            "",
+            "/var/mail/Maildir/new",
+            "../bob/Maildir/new",
+            "Maildir/../../bob",
+        ] {
+            let err = resolve_maildir_relative(home, relative).unwrap_err();
+            assert_eq!(
+                exit_code_for(&err),
+                Sysexit::Config.code(),
+                "for {:?}",
+                relative
+            );
+        }
+    }
 
-/// Check if a string is plausible as an email address, in the very loosest sense.
-/// We require only that it (a) not be empty and (b) consist only of "graphical" ASCII characters
-/// (basically, all letters and digits and punctuation, but not whitespace or control
-/// characters).
-pub fn is_plausible_string(s: &str) -> bool {
-    !s.is_empty() && s.chars().all(|c| c.is_ascii_graphic())
-}
+    /// an implausible sender is rejected, sanitized or replaced, as `onInvalidSender` says;
+    /// a plausible one is always used as-is
+    #[test]
+    fn test_check_sender() {
+        let check = |addr: &str, action| check_sender(addr, action, "carol");
 
-/// Main logic for the program. Various I/O-type values get injected here as arguments,
-/// for easy testing.
-///
-/// Arguments:
-/// - `allowable_program_names`: list of program names we expect to be invoked as (e.g.
-///   `sendmail`). We exit with an error if the program name is not one of these.
-/// - `ctx`: main context, containing arguments, config path, whether to drop privileges,
-///   time we were invoked, etc.
-/// - `input`: input stream to read from (stdin, in production)
-/// - `output`: optional output stream to write to. Should be `None` in production, but
-///    can be used for testing.
-///
-/// In production, we should _always_ drop privileges; for testing purposes,
-/// we might not.
-pub fn main<R: BufRead, W: Write>(
-    allowable_program_names: &[&str],
-    ctx: &MainContext,
-    input: &mut R,
-    output_opt: Option<&mut W>,
-) -> () {
-    let prog_name = match ctx.args.as_slice() {
-        [prog_name, ..] => prog_name,
-        _ => {
-            eprintln!("No program name provided.");
-            std::process::exit(1);
+        for action in [
+            InvalidSenderAction::Reject,
+            InvalidSenderAction::Sanitize,
+            InvalidSenderAction::Fallback,
+        ] {
+            assert_eq!(
+                check("alice@example.com", action).unwrap(),
+                "alice@example.com"
+            );
         }
-    };
 
-    // die if not one of the expected program names
-    let _prog_name = normalize_prog_name(allowable_program_names, prog_name);
+        let err = check("alice @example.com", InvalidSenderAction::Reject).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
 
-    let cli_options: Command = build_cli();
+        assert_eq!(
+            check("al\tice@exämple.com\n", InvalidSenderAction::Sanitize).unwrap(),
+            "alice@exmple.com"
+        );
+        // nothing plausible left
+        let err = check(" \t", InvalidSenderAction::Sanitize).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+
+        assert_eq!(
+            check("alice @example.com", InvalidSenderAction::Fallback).unwrap(),
+            "carol"
+        );
+        let err = check_sender("", InvalidSenderAction::Fallback, "").unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+    }
 
-    let cli_matches = cli_options.get_matches_from(ctx.args.iter());
+    #[test]
+    fn test_parse_address_list() {
+        assert_eq!(
+            parse_address_list(" bob@example.com, Carol <carol@example.com>").unwrap(),
+            vec!["bob@example.com", "carol@example.com"]
+        );
+        // commas in quoted names don't separate addresses
+        assert_eq!(
+            parse_address_list(r#""Doe, Dave" <dave@example.com>, "a \"quoted, name\"" <e@x>"#)
+                .unwrap(),
+            vec!["dave@example.com", "e@x"]
+        );
+        // groups' names are skipped
+        assert!(parse_address_list("undisclosed-recipients:;")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            parse_address_list("team: bob@x, carol@x;, dave@x").unwrap(),
+            vec!["bob@x", "carol@x", "dave@x"]
+        );
+        assert!(parse_address_list("  ").unwrap().is_empty());
 
-    // set up logging
-    let opt_logfile = cli_matches.get_one::<String>("logfile").cloned();
-    match opt_logfile {
-        Some(logfile_path) => {
-            init_logfile(logfile_path);
+        for bad in ["bob@x, , carol@x", "bob@x,", "Bob <>", "bob smith@x"] {
+            let err = parse_address_list(bad).unwrap_err();
+            assert_eq!(
+                exit_code_for(&err),
+                Sysexit::DataErr.code(),
+                "for {:?}",
+                bad
+            );
         }
-        None => {}
     }
 
-    // read config file to get maildir and user name to run as.
-    // We never run as root; permanently drop privileges to that user, and if the user
-    // _is_ root, fail with an error.
-    // Later on - if the specified user can't operate on the Maildir, we'll fail with an
-    // error then.
+    /// `To:`, `Cc:` and `Bcc:` addresses are all collected, including from folded headers and
+    /// in any case, and `Bcc:` headers are removed from what's put back
+    #[test]
+    fn test_take_header_recipients() {
+        let input = b"To: bob@example.com,\n carol@example.com\n\
+            Subject: hi\n\
+            bcc: Dave <dave@example.com>,\n\terin@example.com\n\
+            CC: frank@example.com\n\
+            \n\
+            To: not-a-header@example.com\n";
+        let mut cursor = Cursor::new(&input[..]);
+        let (block, recipients) = take_header_recipients(&mut cursor, 1000).unwrap();
 
-    let config_path = &ctx.config_path;
+        assert_eq!(
+            recipients,
+            vec![
+                "bob@example.com",
+                "carol@example.com",
+                "dave@example.com",
+                "erin@example.com",
+                "frank@example.com"
+            ]
+        );
+        assert_eq!(
+            String::from_utf8(block).unwrap(),
+            "To: bob@example.com,\n carol@example.com\nSubject: hi\nCC: frank@example.com\n\n"
+        );
+        // the body is left unread
+        let mut rest = String::new();
+        cursor.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "To: not-a-header@example.com\n");
 
-    log::debug!("Using config file: {:#?}", config_path);
+        // a message without headers gives its first line back
+        let (block, recipients) =
+            take_header_recipients(&mut Cursor::new(&b"just a body\n"[..]), 1000).unwrap();
+        assert_eq!(block, b"just a body\n");
+        assert!(recipients.is_empty());
 
-    let config = read_config_ini(config_path).unwrap_or_else(|e| {
-        eprintln!("Error reading config file '{}': {}", config_path, e);
-        std::process::exit(1);
-    });
+        let err = take_header_recipients(&mut Cursor::new(&input[..]), 20).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+        let err = take_header_recipients(&mut Cursor::new(&b"To: a b\n\n"[..]), 1000).unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+    }
 
-    log::debug!("Read config: {:?}", config);
+    /// under `addLinesHeader`, a `Lines:` header giving the body's line count is added at the
+    /// end of the header block; a final line lacking a line ending still counts
+    #[test]
+    fn test_add_lines_header() {
+        let time = test_received_time();
+        let envelope = test_envelope("bob", "alice", time);
+        let config = Config {
+            addLinesHeader: true,
+            ..Config::default()
+        };
+        let expected_headers = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nMessage-ID: {TEST_MESSAGE_ID}\nSubject: hi\n",
+            make_received_header("bob", "alice", &time),
+            time.to_rfc2822(),
+        );
 
-    if config.userName == "root" {
-        eprintln!("Error: Cannot run as root. Please specify a different user in the config file.");
-        std::process::exit(1);
+        for (body, lines) in [("", 0), ("one\n", 1), ("one\n\nthree\nfour", 4)] {
+            let input = format!("Subject: hi\n\n{}", body);
+            for buffered in [false, true] {
+                let mut output = Vec::new();
+                let mut input = Cursor::new(input.as_bytes());
+                if buffered {
+                    write_message_buffered(&mut input, &mut output, &envelope, &config).unwrap();
+                } else {
+                    write_message(&mut input, &mut output, &envelope, &config).unwrap();
+                }
+                let output = String::from_utf8(output).unwrap();
+                assert_eq!(
+                    output,
+                    format!("{}Lines: {}\n\n{}", expected_headers, lines, body)
+                );
+                let (_, delivered_body) = output.split_once("\n\n").unwrap();
+                assert_eq!(delivered_body.lines().count(), lines);
+            }
+        }
+
+        assert_eq!(
+            add_lines_header(b"Subject: hi\r\n\r\na\r\nb\r\n").unwrap(),
+            b"Subject: hi\r\nLines: 2\r\n\r\na\r\nb\r\n"
+        );
+        assert_eq!(add_lines_header(b"Subject: no body\n"), None);
     }
 
-    // drop privileges to the user specified in the config file
+    /// a generated `Message-ID:` is `<timestamp.pid.random@hostname>`
+    #[test]
+    fn test_make_message_id() {
+        let time = test_received_time();
+        assert_eq!(
+            make_message_id(&time, 1234, 0xdeadbeef, "myhost.example"),
+            format!(
+                "<{}.1234.00000000deadbeef@myhost.example>",
+                time.timestamp()
+            )
+        );
+    }
 
-    let new_user = User::from_name(&config.userName).map_or_else(
-        |err| {
-            eprintln!(
-                "Error: Couldn't get user '{}' specified in config file: errno was {}",
-                config.userName, err
+    /// an existing `Message-ID:` is detected whatever its capitalization, and then no other
+    /// is added
+    #[test]
+    fn test_write_headers_existing_message_id() {
+        let time = test_received_time();
+        for name in ["Message-ID", "Message-Id", "message-id"] {
+            let input = format!(
+                "{}: <abc@example.com>\nfrom: carol\ndate: 21 Oct 2020\n\n",
+                name
             );
-            std::process::exit(1);
-        },
-        |opt| {
-            opt.unwrap_or_else(|| {
-                eprintln!(
-                    "Error: User '{}' specified in config file is not a valid user",
-                    config.userName
-                );
-                std::process::exit(1);
-            })
-        },
-    );
+            let mut output = Vec::new();
+            let status = write_headers(
+                &mut Cursor::new(input.as_bytes()),
+                &mut output,
+                &test_envelope("bob", "alice", time),
+                &Config::default(),
+            )
+            .unwrap();
 
-    match ctx.should_drop_privs {
-        PrivilegeOption::NoDropPrivileges => {}
-        PrivilegeOption::DropPrivileges => {
-            drop_privileges(new_user);
+            assert!(status.has_message_id && status.has_from && status.has_date);
+            let expected_output = format!(
+                "Return-Path: <alice>\nDelivered-To: bob\n{}{}",
+                make_received_header("bob", "alice", &time),
+                input
+            );
+            assert_eq!(String::from_utf8(output).unwrap(), expected_output);
         }
     }
 
-    let from_address = cli_matches
-        .get_one::<String>("sender_env")
-        .cloned()
-        .unwrap_or_else(get_current_user);
+    /// the headers are stamped with the received time, or with `dateAtDelivery`, the time the
+    /// clock gives at delivery
+    #[test]
+    fn test_deliver_to_maildir_date_at_delivery() {
+        let received = test_received_time();
+        let delivered = received + chrono::Duration::minutes(5);
+        let envelope = test_envelope("bob", "alice", received);
+        for (date_at_delivery, expected_time) in [(false, received), (true, delivered)] {
+            let config = Config {
+                dateAtDelivery: date_at_delivery,
+                ..Config::default()
+            };
+            let target = InMemoryTarget::new("/nonexistent/Maildir");
+            let id = deliver_to_maildir(
+                &mut Cursor::new(b"From: carol\n\nBody\n"),
+                &envelope,
+                &target,
+                &config,
+                None,
+                &|| delivered,
+            )
+            .unwrap();
 
-    if !is_plausible_string(&from_address) {
-        eprintln!(
-            "From address '{}' contains non-ASCII, non-printable or whitespace characters, or is zero-length",
-            from_address
-        );
-        std::process::exit(1);
+            let expected = format!(
+                "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nMessage-ID: {TEST_MESSAGE_ID}\nFrom: carol\n\nBody\n",
+                make_received_header("bob", "alice", &expected_time),
+                expected_time.to_rfc2822()
+            );
+            assert_eq!(
+                String::from_utf8(target.message(&id).unwrap()).unwrap(),
+                expected
+            );
+        }
     }
 
-    log::debug!("Using from_address: {:#?}", from_address);
-
-    // if no recipient address is provided, we'll use the name from the config file
-    let to_address = cli_matches
-        .get_one::<String>("to_address")
-        .cloned()
-        .unwrap_or_else(|| config.userName.clone());
+    /// a `From:` folded over three lines counts as present, and is written unchanged
+    #[test]
+    fn test_process_existing_headers_folded_from() {
+        let input = b"Subject: hi\nFrom: Alice\n <alice@example.com>\n\t(the real one)\n\nBody\n";
+        let mut output = Vec::new();
+        let status =
+            process_existing_headers(&mut Cursor::new(input), &mut output, &Config::default())
+                .unwrap();
 
-    if !is_plausible_string(&to_address) {
-        eprintln!(
-            "Recipient address '{}' contains non-ASCII, non-printable or whitespace characters, or is zero-length",
-            to_address
+        assert!(status.has_from);
+        assert!(!status.has_date);
+        assert_eq!(
+            output,
+            b"Subject: hi\nFrom: Alice\n <alice@example.com>\n\t(the real one)\n"
         );
-        std::process::exit(1);
     }
 
-    log::debug!("Using to_address: {:#?}", to_address);
+    /// a continuation line that looks like a `Date:` or `From:` header is still just part of
+    /// the previous header's value
+    #[test]
+    fn test_process_existing_headers_continuation_like_header() {
+        let input = b"X-Foo: foo\n Date: 21 Oct 2020\n\tFrom: not-really\nTo: bob\n\nBody\n";
+        let mut output = Vec::new();
+        let status =
+            process_existing_headers(&mut Cursor::new(input), &mut output, &Config::default())
+                .unwrap();
 
-    let maildir_new_path = Path::new(&config.mailDir);
+        assert!(!status.has_date);
+        assert!(!status.has_from);
+        assert_eq!(
+            output,
+            b"X-Foo: foo\n Date: 21 Oct 2020\n\tFrom: not-really\nTo: bob\n"
+        );
+    }
 
-    let maildir_path = parse_maildir_new_path(maildir_new_path).unwrap_or_else(|err| {
-        eprintln!("Error getting path to maildir: {}", err);
-        std::process::exit(1);
-    });
+    /// with `requireHeaders`, a message with headers is accepted, but input without any is
+    /// rejected rather than treated as all body
+    #[test]
+    fn test_require_headers() {
+        let config = Config {
+            requireHeaders: true,
+            ..Config::default()
+        };
+        let process = |input: &[u8], config: &Config| {
+            process_existing_headers(&mut Cursor::new(input), &mut Vec::new(), config)
+        };
 
-    let maildir = Maildir::from(maildir_path.clone());
+        assert!(process(b"Subject: hi\n\nBody\n", &config).is_ok());
+        for input in [
+            &b"Just some prose, with no headers.\n\nMore prose.\n"[..],
+            b"\nBody after a blank line\n",
+            b"\x00\x01\x02binary\xff\n",
+        ] {
+            let err = process(input, &config).unwrap_err();
+            assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
 
-    match ctx.should_create_maildirs {
-        CreateMaildirsOption::CreateMaildirs => {
-            maildir.create_dirs().unwrap_or_else(|e| {
-                eprintln!(
-                    "Error creating Maildir directories at '{:?}': {}",
-                    maildir_path, e
-                );
-                std::process::exit(1);
-            });
+            let status = process(input, &Config::default()).unwrap();
+            assert!(!status.has_from, "for {:?}", input);
         }
-        CreateMaildirsOption::NoCreateMaildirs => {}
+        assert!(process(b"", &config).is_ok());
     }
 
-    match (ctx.message_destination, output_opt) {
-        (MessageDestination::Maildir, None) => {
-            deliver_to_maildir(input, from_address, to_address, maildir, &ctx.received_time)
-                .unwrap_or_else(|e| {
-                    eprintln!(
-                        "Error delivering message to maildir 'new' directiory {:?}: {}",
-                        maildir_new_path, e
-                    );
-                    std::process::exit(1);
-                });
-            log::debug!("Message successfully delivered to maildir");
+    /// a recipient with a section of their own is delivered to its Maildir, as its user;
+    /// anyone else gets the defaults
+    #[test]
+    fn test_route_for_recipient() {
+        let config = || Config {
+            mailDir: "/var/mail/Maildir/new".to_string(),
+            userName: "mail".to_string(),
+            maildirRelative: Some("Maildir/new".to_string()),
+            userRoutes: vec![UserRoute {
+                name: "alice".to_string(),
+                mailDir: "/home/alice/Maildir/new".to_string(),
+                userName: "alice".to_string(),
+            }],
+            ..Config::default()
+        };
+
+        for recipient in ["alice", "alice@example.com"] {
+            let routed = route_for_recipient(config(), Some(recipient));
+            assert_eq!(routed.mailDir, "/home/alice/Maildir/new");
+            assert_eq!(routed.userName, "alice");
+            assert_eq!(routed.maildirRelative, None);
         }
-        (MessageDestination::OutputStream, Some(output)) => {
-            write_message(
-                input,
-                output,
-                &to_address,
-                &from_address,
-                &ctx.received_time,
-            )
-            .unwrap_or_else(|e| {
-                eprintln!("Error writing message: {}", e);
-                std::process::exit(1);
-            });
-            log::debug!("Message successfully delivered to output stream");
+        for recipient in [Some("bob@example.com"), Some("Alice"), None] {
+            assert_eq!(route_for_recipient(config(), recipient), config());
         }
-        _ => {
-            eprintln!("Error: Invalid combination of message destination and output stream");
-            std::process::exit(1);
+    }
+
+    /// per-recipient sections are read as user routes, and must have just a mailDir and
+    /// userName
+    #[test]
+    fn test_read_user_routes() {
+        let read = |conts: &str| {
+            let config_file = tempfile::NamedTempFile::new().unwrap();
+            std::fs::write(config_file.path(), conts).unwrap();
+            read_config_ini(config_file.path())
+        };
+        let defaults = "mailDir = /var/mail/Maildir/new\nuserName = mail\n";
+
+        let config = read(&format!(
+            "{}[alice]\nmailDir = /home/alice/Maildir/new\nuserName = alice\n\n[bob]\nmailDir = /home/bob/Maildir/new\nuserName = bob\n",
+            defaults
+        ))
+        .unwrap();
+        assert_eq!(config.mailDir, "/var/mail/Maildir/new");
+        let names: Vec<_> = config.userRoutes.iter().map(|route| &route.name).collect();
+        assert_eq!(names, ["alice", "bob"]);
+        assert_eq!(config.userRoutes[1].mailDir, "/home/bob/Maildir/new");
+
+        assert!(read(defaults).unwrap().userRoutes.is_empty());
+        for section in [
+            "[alice]\nmailDir = /home/alice/Maildir/new\n",
+            "[alice]\nmailDir = /home/alice/Maildir/new\nuserName = alice\nstrictHeaders = true\n",
+            "[alice]\nmailDir = /a/Maildir/new\nuserName = alice\n[alice]\nmailDir = /b/Maildir/new\nuserName = alice\n",
+        ] {
+            assert!(read(&format!("{}{}", defaults, section)).is_err(), "for {:?}", section);
         }
     }
-}
 
-//pub fn bogus_main() {
-//    let input = br#"Subject: backupninja: ubuntu2004.localdomain
-//To: ggg
-//X-Mailer: mail (GNU Mailutils 3.7)
-//
-//success -- /etc/backup.d/example.sys
-//"#;
-//
-//    let message = MessageParser::default().parse(input).unwrap();
-//
-//    println!("message: {:#?}", message);
-//
-//    let new_message = message.clone();
-//}
+    /// with `maxMessageSize`, an oversized message is rejected as soon as it's known to be too
+    /// big, and nothing is left in the Maildir
+    #[test]
+    fn test_max_message_size() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let config = Config {
+            maxMessageSize: Some(1024 * 1024),
+            ..Config::default()
+        };
+        let envelope = test_envelope("bob", "alice", test_received_time());
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Cursor;
+        // a stream far bigger than the limit, whose end is never reached
+        let mut input = BufReader::new(
+            Cursor::new(&b"Subject: runaway\n\n"[..]).chain(std::io::repeat(b'\n').take(1 << 30)),
+        );
+        let err = deliver_to_maildir(&mut input, &envelope, &maildir, &config, None, &Local::now)
+            .unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+        assert!(
+            !input.fill_buf().unwrap().is_empty(),
+            "input was read to the end"
+        );
+        for subdir in ["new", "cur", "tmp"] {
+            let entries = std::fs::read_dir(maildir.path().join(subdir))
+                .unwrap()
+                .count();
+            assert_eq!(entries, 0, "expected {} to be empty", subdir);
+        }
 
-    /// helper func - standard control flow for all test cases with
-    /// `process_existing_headers` as subject under test.
-    fn test_headers_helper(input: &[u8], expected_status: HeaderStatus, expected_output: &str) {
+        // the limit counts the headers added, too
+        let small = b"Subject: hi\n\nsome body\n";
         let mut output = Vec::new();
-        let result = process_existing_headers(&mut Cursor::new(input), &mut output).unwrap();
-
-        assert_eq!(result, expected_status);
-        let output = String::from_utf8(output).unwrap();
-        assert_eq!(output, expected_output);
+        write_message_buffered(&mut Cursor::new(small), &mut output, &envelope, &config).unwrap();
+        let exact = Config {
+            maxMessageSize: Some(output.len() as u64),
+            ..Config::default()
+        };
+        deliver_to_maildir(
+            &mut Cursor::new(small),
+            &envelope,
+            &maildir,
+            &exact,
+            None,
+            &Local::now,
+        )
+        .unwrap();
+        let too_small = Config {
+            maxMessageSize: Some(output.len() as u64 - 1),
+            ..Config::default()
+        };
+        assert!(deliver_to_maildir(
+            &mut Cursor::new(small),
+            &envelope,
+            &maildir,
+            &too_small,
+            None,
+            &Local::now
+        )
+        .is_err());
+        assert_eq!(
+            std::fs::read_dir(maildir.path().join("new"))
+                .unwrap()
+                .count(),
+            1
+        );
     }
 
-    /// plausible-looking `From:` and `Date:`
+    /// when the message has to be buffered in full (here, for `addLinesHeader`), the
+    /// `maxMessageSize` limit still applies as it's read, rather than once it's all in memory
     #[test]
-    fn test_process_headers_with_from_and_date() {
-        let input = b"From: sender@example.com\nDate: Wed, 21 Oct 2020 07:28:00 GMT\n\nBody";
-        let expected_status = HeaderStatus {
-            has_from: true,
-            has_date: true,
+    fn test_max_message_size_buffered() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let config = Config {
+            maxMessageSize: Some(1024 * 1024),
+            addLinesHeader: true,
+            ..Config::default()
         };
-        let expected_output = "From: sender@example.com\nDate: Wed, 21 Oct 2020 07:28:00 GMT\n";
-        test_headers_helper(input, expected_status, expected_output);
+        let envelope = test_envelope("bob", "alice", test_received_time());
+
+        let mut input = BufReader::new(
+            Cursor::new(&b"Subject: runaway\n\n"[..]).chain(std::io::repeat(b'\n').take(1 << 30)),
+        );
+        let err = deliver_to_maildir(&mut input, &envelope, &maildir, &config, None, &Local::now)
+            .unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+        assert!(
+            format!("{:#}", err).contains("(maxMessageSize)"),
+            "unexpected error: {:#}",
+            err
+        );
+        assert!(
+            !input.fill_buf().unwrap().is_empty(),
+            "input was read to the end"
+        );
+        assert_eq!(
+            std::fs::read_dir(maildir.path().join("new"))
+                .unwrap()
+                .count(),
+            0
+        );
     }
 
-    /// implausible-looking `From:` and `Date:`
+    /// a `DeadlineReader` passes input through until its deadline, then fails with
+    /// `EX_TEMPFAIL`
     #[test]
-    fn test_process_headers_with_implausible_from_and_date() {
-        let input = b"From: :?\nDate: ,\n\nBody";
-        let expected_status = HeaderStatus {
-            has_from: true,
-            has_date: true,
-        };
-        let expected_output = "From: :?\nDate: ,\n";
-        test_headers_helper(input, expected_status, expected_output);
+    fn test_deadline_reader() {
+        let mut output = String::new();
+        DeadlineReader::new(Cursor::new("some input\n"), None)
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "some input\n");
+
+        let deadline = Some(Instant::now() + Duration::from_secs(60));
+        let mut reader = DeadlineReader::new(Cursor::new("some input\n"), deadline);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "some input\n");
+
+        let deadline = Some(Instant::now() - Duration::from_millis(1));
+        let mut reader = DeadlineReader::new(Cursor::new("some input\n"), deadline);
+        let err = reader.read_line(&mut line).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(exit_code_for(&input_error(err)), Sysexit::TempFail.code());
     }
 
-    /// `Date:` only
+    /// synthesized headers get their names' canonical capitalization, however they were
+    /// spelled; the message's own headers keep theirs
     #[test]
-    fn test_process_headers_without_from() {
-        let input = b"Date: 21 Oct 2020\n\nBody";
-        let expected_status = HeaderStatus {
-            has_from: false,
-            has_date: true,
-        };
-        let expected_output = "Date: 21 Oct 2020\n";
-        test_headers_helper(input, expected_status, expected_output);
+    fn test_write_headers_canonical_names() {
+        let time = test_received_time();
+        let mut envelope = test_envelope("bob", "alice", time);
+        envelope.extra_headers = vec![
+            ("mime-version".to_string(), "1.0".to_string()),
+            ("x-dsn-notify".to_string(), "never".to_string()),
+            ("X-custom".to_string(), "kept".to_string()),
+        ];
+        let mut output = Vec::new();
+        write_headers(
+            &mut Cursor::new(b"message-id: <abc@example.com>\nfrom: carol\n\nBody\n"),
+            &mut output,
+            &envelope,
+            &Config::default(),
+        )
+        .unwrap();
+
+        let expected_output = format!(
+            "Return-Path: <alice>\nDelivered-To: bob\n{}MIME-Version: 1.0\nX-DSN-Notify: never\nX-custom: kept\nDate: {}\nmessage-id: <abc@example.com>\nfrom: carol\n\n",
+            make_received_header("bob", "alice", &time),
+            time.to_rfc2822()
+        );
+        assert_eq!(String::from_utf8(output).unwrap(), expected_output);
+
+        let mut output = Vec::new();
+        write_header(&mut output, "message-id", TEST_MESSAGE_ID).unwrap();
+        assert_eq!(
+            output,
+            format!("Message-ID: {TEST_MESSAGE_ID}\n").as_bytes()
+        );
+        // including the ones added once the whole message has been written
+        assert_eq!(canonical_header_name("LINES"), "Lines");
+        assert_eq!(
+            canonical_header_name("content-transfer-encoding"),
+            "Content-Transfer-Encoding"
+        );
     }
 
-    /// `From:` only
+    /// a message which is rejected, or which fails to be stored, leaves nothing in the dump
     #[test]
-    fn test_process_headers_without_date() {
-        let input = b"From: sender@example.com\n\nBody";
-        let expected_status = HeaderStatus {
-            has_from: true,
-            has_date: false,
+    fn test_deliver_to_maildirs_dump_rejected() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let maildir = Maildir::from(temp_dir.path().join("Maildir"));
+        maildir.create_dirs().unwrap();
+        let envelope = test_envelope("bob", "alice", test_received_time());
+        let input = b"Subject: hi\n\nsome body which is rather too long\n";
+
+        let oversized = Config {
+            maxMessageSize: Some(64),
+            ..Config::default()
         };
-        let expected_output = "From: sender@example.com\n";
-        test_headers_helper(input, expected_status, expected_output);
+        let mut dump = Vec::new();
+        let err = deliver_to_maildirs(
+            &mut Cursor::new(input),
+            &envelope,
+            std::slice::from_ref(&maildir),
+            &oversized,
+            Some(&mut dump),
+        )
+        .unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::DataErr.code());
+        assert!(dump.is_empty(), "{:?}", String::from_utf8_lossy(&dump));
+
+        // nor does one which can't be stored, since its Maildir doesn't exist
+        let missing = Maildir::from(temp_dir.path().join("Missing"));
+        let mut dump = Vec::new();
+        deliver_to_maildirs(
+            &mut Cursor::new(input),
+            &envelope,
+            std::slice::from_ref(&missing),
+            &Config::default(),
+            Some(&mut dump),
+        )
+        .unwrap_err();
+        assert!(dump.is_empty(), "{:?}", String::from_utf8_lossy(&dump));
     }
 
-    /// empty headers
+    /// `truncateAtBytes` and `maxMessageSize` can each be set, but not both together
     #[test]
-    fn test_process_headers_empty() {
-        let input = b"\nBody";
-        let expected_status = HeaderStatus {
-            has_from: false,
-            has_date: false,
+    fn test_read_config_truncate_and_max_size() {
+        let read = |extra: &str| {
+            let config_file = tempfile::NamedTempFile::new().unwrap();
+            let conts = format!(
+                "mailDir = /var/mail/Maildir/new\nuserName = mail\n{}",
+                extra
+            );
+            std::fs::write(config_file.path(), conts).unwrap();
+            read_config_ini(config_file.path())
         };
-        let expected_output = "";
-        test_headers_helper(input, expected_status, expected_output);
+
+        assert_eq!(
+            read("truncateAtBytes = 1000\n").unwrap().truncateAtBytes,
+            Some(1000)
+        );
+        assert_eq!(
+            read("maxMessageSize = 5000\n").unwrap().maxMessageSize,
+            Some(5000)
+        );
+        let err = read("truncateAtBytes = 1000\nmaxMessageSize = 5000\n").unwrap_err();
+        assert_eq!(exit_code_for(&err), Sysexit::Config.code());
+        let msg = format!("{:#}", err);
+        assert!(
+            msg.contains("truncateAtBytes") && msg.contains("maxMessageSize"),
+            "{}",
+            msg
+        );
     }
 }
@@ -1,10 +1,11 @@
 use chrono::Local;
 
-use rattomail::{CreateMaildirsOption, MainContext, MessageDestination, PrivilegeOption};
+use rattomail::{
+    CreateMaildirsOption, MainContext, MessageDestination, PrivilegeOption, PROGRAM_NAMES,
+};
 
 fn main() {
-    // bizarrely, bsd-mailx sets argv[0] to "send-mail", for no good reason.
-    let valid_program_names = ["rattomail", "attomail", "sendmail", "send-mail"];
+    let valid_program_names = PROGRAM_NAMES;
     let cli_args: Vec<String> = std::env::args().collect();
     let config_path = env!("ATTOMAIL_CONFIG_PATH");
     let now: chrono::DateTime<Local> = Local::now();
@@ -23,5 +24,7 @@ fn main() {
 
     let output_opt: Option<&mut std::io::Stdout> = None;
 
-    rattomail::main(&valid_program_names, &ctx, &mut handle, output_opt);
+    if let Err(e) = rattomail::main(&valid_program_names, &ctx, &mut handle, output_opt) {
+        e.exit();
+    }
 }
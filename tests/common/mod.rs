@@ -0,0 +1,89 @@
+// Fixtures shared by the integration test binaries, each of which includes this module with
+// `mod common;` and uses whichever of them it needs.
+//
+// Tests which change state belonging to the whole process are kept in test binaries of their
+// own, so that they can't interfere with the tests running alongside them: test_quiet,
+// test_trace and test_compat_strict redirect its stdout and stderr (see `capture_fd`),
+// test_chdir changes its directory, test_deliver_timeout sets an alarm(2), and
+// test_delivery_group drops its privileges (which can't be undone).
+
+#![allow(dead_code)]
+
+use std::fs::write;
+use std::io::{Read, Seek};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+
+use chrono::Local;
+use tempfile::{tempfile, NamedTempFile};
+
+use rattomail::{
+                get_current_user,
+                CreateMaildirsOption,
+                MainContext,
+                MessageDestination,
+                PrivilegeOption,
+                UserCache,
+                DEFAULT_NSS_RETRIES,
+                USER_CACHE_TTL,
+               };
+
+/// user to put in the config file: the current user, unless that's root (which rattomail
+/// refuses to run as)
+pub fn test_user() -> String {
+  match get_current_user(&UserCache::new(USER_CACHE_TTL), DEFAULT_NSS_RETRIES).unwrap().as_str() {
+    "root" => "nobody".to_string(),
+    user => user.to_string(),
+  }
+}
+
+/// Contents for a config file delivering to `mail_dir` (a Maildir's `new` directory) as
+/// [`test_user`], followed by any `extra` lines.
+pub fn maildir_config(mail_dir: &Path, extra: &str) -> String {
+  format!("mailDir = {}\nuserName = {}\n{}", mail_dir.display(), test_user(), extra)
+}
+
+/// As for [`maildir_config`], but also trusting the invoking user, so `-f` doesn't add an
+/// `X-Authentication-Warning:` header.
+pub fn trusted_config(mail_dir: &Path, extra: &str) -> String {
+  maildir_config(mail_dir, &format!("trustedUsers = {}\n{}", get_current_user(&UserCache::new(USER_CACHE_TTL), DEFAULT_NSS_RETRIES).unwrap(), extra))
+}
+
+/// Write `conts` to a new temporary config file.
+pub fn config_file(conts: &str) -> NamedTempFile {
+  let config_file = NamedTempFile::new().unwrap();
+  write(config_file.path(), conts).unwrap();
+  config_file
+}
+
+/// A context for running `rattomail::main` with `args`, using `config_file`, and delivering
+/// to a Maildir (created if need be) received now. Privileges aren't dropped, so this can run
+/// as any user. Tests needing something else can override the fields they need.
+pub fn main_context(args: &[&str], config_file: &NamedTempFile) -> MainContext {
+  MainContext {
+    args: args.iter().map(|arg| arg.to_string()).collect(),
+    config_path: config_file.path().to_str().unwrap().to_string(),
+    should_drop_privs: PrivilegeOption::NoDropPrivileges,
+    should_create_maildirs: CreateMaildirsOption::CreateMaildirs,
+    message_destination: MessageDestination::Maildir,
+    received_time: Local::now(),
+  }
+}
+
+/// Run `f` with the process's file descriptor `fd` (1 for stdout, 2 for stderr) redirected to
+/// a temporary file, and return what `f` returned, along with whatever was written there.
+pub fn capture_fd<T>(fd: RawFd, f: impl FnOnce() -> T) -> (T, String) {
+  let mut captured = tempfile().unwrap();
+  let saved = nix::unistd::dup(fd).unwrap();
+  nix::unistd::dup2(captured.as_raw_fd(), fd).unwrap();
+
+  let res = f();
+
+  nix::unistd::dup2(saved, fd).unwrap();
+  nix::unistd::close(saved).unwrap();
+
+  let mut contents = String::new();
+  captured.rewind().unwrap();
+  captured.read_to_string(&mut contents).unwrap();
+  (res, contents)
+}
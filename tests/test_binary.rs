@@ -0,0 +1,94 @@
+
+// Runs the built `bogus_rattomail` executable as a subprocess, so as to exercise what the
+// library-level tests bypass: real argv handling, reading stdin and writing stdout, and exit
+// statuses. Only built with `--features test_env_executables`.
+
+mod common;
+
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+use tempfile::NamedTempFile;
+
+use common::{config_file, trusted_config};
+
+/// a minimal valid config file, trusting the invoking user (so `-f` doesn't add an
+/// `X-Authentication-Warning:` header)
+fn test_config_file() -> NamedTempFile {
+  config_file(&trusted_config(Path::new("/nonexistent/Maildir/new"), ""))
+}
+
+/// Run `bogus_rattomail` as `arg0`, with `args`, using `config_file`, and with `input` piped
+/// to its stdin.
+fn run_binary(arg0: &str, args: &[&str], config_file: &NamedTempFile, input: &[u8]) -> Output {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_bogus_rattomail"))
+    .arg0(arg0)
+    .args(args)
+    .env("ATTOMAIL_CONFIG_PATH", config_file.path())
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .unwrap();
+  // the process may exit without reading its input, so a broken pipe is fine
+  let _ = child.stdin.take().unwrap().write_all(input);
+  child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_binary_delivers_to_stdout() {
+  let config_file = test_config_file();
+  let output = run_binary("sendmail", &["-f", "alice", "bob"], &config_file,
+                          b"Subject: hello\n\nsome body\n");
+
+  assert_eq!(output.status.code(), Some(0), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+  let delivered = String::from_utf8(output.stdout).unwrap();
+  assert!(delivered.starts_with("Return-Path: <alice>\nDelivered-To: bob\nReceived: for bob with local (rattomail) (envelope-from alice); "),
+          "unexpected output: {}", delivered);
+  assert!(delivered.contains("\nFrom: alice\nMessage-ID: <"), "unexpected output: {}", delivered);
+  assert!(delivered.contains(">\nSubject: hello\n\nsome body\n"), "unexpected output: {}", delivered);
+  assert!(output.stderr.is_empty(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+}
+
+/// invoked under its own name (which has no profile of its own), `bogus_rattomail` delivers
+/// just as `rattomail` does
+#[test]
+fn test_binary_own_name() {
+  let config_file = test_config_file();
+  let output = run_binary("bogus_rattomail", &["-f", "alice", "bob"], &config_file,
+                          b"Subject: hello\n\nsome body\n");
+
+  assert_eq!(output.status.code(), Some(0), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+  let delivered = String::from_utf8(output.stdout).unwrap();
+  assert!(delivered.ends_with("Subject: hello\n\nsome body\n"), "unexpected output: {}", delivered);
+}
+
+#[test]
+fn test_binary_bad_program_name() {
+  let config_file = test_config_file();
+  let output = run_binary("not-sendmail", &["bob"], &config_file, b"Subject: hello\n\nbody\n");
+
+  assert_eq!(output.status.code(), Some(1));
+  assert!(output.stdout.is_empty());
+  assert!(String::from_utf8_lossy(&output.stderr).contains("not-sendmail"));
+}
+
+#[test]
+fn test_binary_bad_option() {
+  let config_file = test_config_file();
+  let output = run_binary("sendmail", &["--no-such-option", "bob"], &config_file,
+                          b"Subject: hello\n\nbody\n");
+
+  // clap's usual status for a usage error
+  assert_eq!(output.status.code(), Some(2));
+  assert!(output.stdout.is_empty());
+  assert!(String::from_utf8_lossy(&output.stderr).contains("--no-such-option"));
+
+  // but with --compat-strict, it's ignored
+  let output = run_binary("sendmail", &["--compat-strict", "--no-such-option", "-f", "alice", "bob"],
+                          &config_file, b"Subject: hello\n\nbody\n");
+  assert_eq!(output.status.code(), Some(0), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+  assert!(String::from_utf8(output.stdout).unwrap().starts_with("Return-Path: <alice>\nDelivered-To: bob\nReceived: for bob "));
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use std::io::Cursor;
+
+use tempfile::tempdir;
+
+use rattomail::PROGRAM_NAMES;
+
+use common::{config_file, main_context, maildir_config};
+
+#[test]
+fn test_main_chdir_delivery() {
+  let temp_dir = tempdir().unwrap();
+  let maildir_path = temp_dir.path().join("Maildir");
+  let config_file = config_file(&maildir_config(&maildir_path.join("new"), ""));
+  let ctx = main_context(&["sendmail", "--chdir", "-f", "alice", "bob"], &config_file);
+
+  let input = b"Subject: hello\n\nsome body\n";
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(input), None::<&mut Vec<u8>>).unwrap();
+
+  assert_eq!(
+    std::env::current_dir().unwrap().canonicalize().unwrap(),
+    temp_dir.path().canonicalize().unwrap()
+  );
+
+  let maildir = maildir::Maildir::from(maildir_path);
+  let entries: Vec<_> = maildir.list_new().collect();
+  assert_eq!(entries.len(), 1, "expected exactly one delivered message");
+  let delivered = std::fs::read_to_string(entries[0].as_ref().unwrap().path()).unwrap();
+  assert!(delivered.ends_with("Subject: hello\n\nsome body\n"), "unexpected message: {}", delivered);
+}
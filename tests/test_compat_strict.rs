@@ -0,0 +1,32 @@
+mod common;
+
+use std::io::Cursor;
+
+use tempfile::tempdir;
+
+use rattomail::PROGRAM_NAMES;
+
+use common::{capture_fd, config_file, main_context, maildir_config};
+
+/// invoked as `sendmail -t -i` with `--compat-strict`, a successful delivery succeeds silently
+/// -- even though the index command fails (which would normally give a warning), and an
+/// unknown option was given
+#[test]
+fn test_main_compat_strict() {
+  let temp_dir = tempdir().unwrap();
+  let maildir_path = temp_dir.path().join("Maildir");
+  let config_file = config_file(&maildir_config(&maildir_path.join("new"), "indexCommand = false\n"));
+  let ctx = main_context(&["sendmail", "--compat-strict", "-t", "-i", "--no-such-option", "-f", "alice"],
+                         &config_file);
+
+  let input = b"To: bob\nSubject: hello\n\nsome body\n";
+  let ((res, stderr), stdout) = capture_fd(1, || capture_fd(2, || {
+    rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(input), None::<&mut Vec<u8>>)
+  }));
+
+  res.unwrap();
+  let delivered = std::fs::read_dir(maildir_path.join("new")).unwrap().count();
+  assert_eq!(delivered, 1, "expected exactly one delivered message");
+  assert_eq!(stdout, "");
+  assert_eq!(stderr, "");
+}
@@ -5,8 +5,20 @@ use tempfile::NamedTempFile;
 
 
 use rattomail::{
+                check_config_file,
+                exit_code_for,
+                generate_config,
                 read_config_ini,
+                write_config_ini,
+                write_new_config_ini,
                 Config,
+                EmptyInputAction,
+                InvalidSenderAction,
+                ProgramProfile,
+                RawSubjectAction,
+                SelfAddressedAction,
+                TmpfileStrategy,
+                UserRoute,
                };
 
 
@@ -27,6 +39,7 @@ userName = user
   let expected = Config {
     mailDir: "/home/user/Maildir/new".to_string(),
     userName: "user".to_string(),
+    ..Config::default()
   };
 
   assert_eq!(expected, config, "config file conts does not equal what was written");
@@ -56,3 +69,352 @@ userName = user
 
   assert!(result.is_err(), "Expected an error, but got: {:?}", result);
 }
+
+/// with `maildirRelative`, `mailDir` can be left out
+#[test]
+fn test_read_config_ini_maildir_relative() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  write(file_path, "maildirRelative = Maildir/new\nuserName = user\n").unwrap();
+
+  let config = read_config_ini(file_path).unwrap();
+  assert_eq!(config.maildirRelative.as_deref(), Some("Maildir/new"));
+  assert_eq!(config.mailDir, "");
+}
+
+/// `--read-config-only`'s check: a valid config passes, and a malformed one fails with
+/// `EX_CONFIG`, giving the parse error
+#[test]
+fn test_check_config_file() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+
+  write(file_path, "mailDir = /home/user/Maildir/new\nuserName = user\n").unwrap();
+  check_config_file(file_path).unwrap();
+
+  write(file_path, "mailDir = /home/user/Maildir/new\nuserName = user\nmaxRecipients = lots\n").unwrap();
+  let err = check_config_file(file_path).unwrap_err();
+  assert_eq!(exit_code_for(&err), 78);
+  let msg = err.to_string();
+  assert!(msg.contains("maxRecipients"), "unexpected error: {}", msg);
+}
+
+#[test]
+fn test_read_config_ini_bounce_on_quota_full() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  let conts = r#"
+mailDir = /home/user/Maildir/new
+userName = user
+bounceOnQuotaFull = yes
+"#;
+
+  write(file_path, conts).unwrap();
+
+  let config = read_config_ini(file_path).unwrap();
+  assert!(config.bounceOnQuotaFull, "expected bounceOnQuotaFull to be set: {:?}", config);
+}
+
+#[test]
+fn test_read_config_ini_bad_bool() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  let conts = r#"
+mailDir = /home/user/Maildir/new
+userName = user
+bounceOnQuotaFull = perhaps
+"#;
+
+  write(file_path, conts).unwrap();
+
+  let result = read_config_ini(file_path);
+  assert!(result.is_err(), "Expected an error, but got: {:?}", result);
+}
+
+#[test]
+fn test_write_config_ini_round_trip() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+
+  for bounce in [false, true] {
+    let config = Config {
+      mailDir: "/home/user/Maildir/new".to_string(),
+      userName: "user".to_string(),
+      bounceOnQuotaFull: bounce,
+      strictHeaders: !bounce,
+      maxHeaderBytes: if bounce { 4096 } else { rattomail::DEFAULT_MAX_HEADER_BYTES },
+      defaultReplyTo: if bounce { Some("Tickets <tickets@example.com>".to_string()) } else { None },
+      addLinesHeader: !bounce,
+      normalizeDomainCase: bounce,
+      dateTimezone: if bounce { Some("Australia/Sydney".parse().unwrap()) } else { None },
+      filenameRetries: if bounce { 10 } else { rattomail::DEFAULT_FILENAME_RETRIES },
+      nssRetries: if bounce { 0 } else { rattomail::DEFAULT_NSS_RETRIES },
+      indexCommand: if bounce { Some("notmuch new".to_string()) } else { None },
+      indexTimeout: if bounce { 5 } else { rattomail::DEFAULT_INDEX_TIMEOUT },
+      maxRecipients: if bounce { 10 } else { rattomail::DEFAULT_MAX_RECIPIENTS },
+      reportTls: bounce,
+      rejectPartial: bounce,
+      validateRfc5322: bounce,
+      requireFromHeader: bounce,
+      requireHeaders: bounce,
+      dbusNotify: bounce,
+      receivedHideProduct: bounce,
+      deliveryGroup: if bounce { Some("mail".to_string()) } else { None },
+      truncateAtBytes: if bounce { Some(1000) } else { None },
+      maxMessageSize: if bounce { None } else { Some(10 * 1024 * 1024) },
+      receivedFoldWidth: if bounce { Some(78) } else { None },
+      messageMode: if bounce { Some(0o600) } else { None },
+      deliverFlags: if bounce { Some("DS".to_string()) } else { None },
+      maildirRelative: if bounce { Some("Maildir/new".to_string()) } else { None },
+      inputLineLimit: if bounce { Some(100000) } else { None },
+      deliveryTimeoutSeconds: if bounce { Some(300) } else { None },
+      normalizeMacLineEndings: bounce,
+      dedupeCriticalHeaders: bounce,
+      fixQpMismatch: bounce,
+      writeLastDelivery: bounce,
+      setSenderHeader: bounce,
+      dateAtDelivery: bounce,
+      programProfiles: if bounce { vec![("sendmail".to_string(), ProgramProfile::Full)] } else { Vec::new() },
+      onEmptyInput: if bounce { EmptyInputAction::Skip } else { EmptyInputAction::Deliver },
+      onInvalidSender: if bounce { InvalidSenderAction::Fallback } else { InvalidSenderAction::Reject },
+      tmpfileStrategy: if bounce { TmpfileStrategy::OTmpfile } else { TmpfileStrategy::Classic },
+      receiptKey: if bounce { Some("secret".to_string()) } else { None },
+      receiptKeyFile: if bounce { Some("/etc/rattomail.key".to_string()) } else { None },
+      warnRawSubject8bit: if bounce { RawSubjectAction::Encode } else { RawSubjectAction::Ignore },
+      datePartition: if bounce { Some("%Y/%m".to_string()) } else { None },
+      rejectSelfAddressed: if bounce { SelfAddressedAction::Drop } else { SelfAddressedAction::Deliver },
+      trustedUsers: if bounce { vec!["daemon".to_string(), "www-data".to_string()] } else { vec![] },
+      allowedLogfiles: if bounce { vec!["/dev/fd/2".to_string()] } else { vec![] },
+      programNames: if bounce { vec!["mailx".to_string(), "mail".to_string()] } else { vec![] },
+      userRoutes: if bounce {
+        vec![UserRoute {
+          name: "alice".to_string(),
+          mailDir: "/home/alice/Maildir/new".to_string(),
+          userName: "alice".to_string(),
+        }]
+      } else {
+        vec![]
+      },
+    };
+
+    write_config_ini(&config, file_path).unwrap();
+    let read_back = read_config_ini(file_path).unwrap();
+
+    assert_eq!(config, read_back, "config read back does not equal what was written");
+  }
+}
+
+/// header values in the config can't be used to inject extra headers
+#[test]
+fn test_read_config_ini_bad_reply_to() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  let conts = "mailDir = /home/user/Maildir/new\nuserName = user\ndefaultReplyTo = a@b\\r\\nBcc: c@d\n";
+
+  write(file_path, conts).unwrap();
+
+  let result = read_config_ini(file_path);
+  assert!(result.is_err(), "Expected an error, but got: {:?}", result);
+}
+
+#[test]
+fn test_read_config_ini_message_mode() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+
+  for (val, expected) in [("0600", 0o600), ("640", 0o640)] {
+    write(file_path, format!("mailDir = /home/user/Maildir/new\nuserName = user\nmessageMode = {}\n", val)).unwrap();
+    assert_eq!(read_config_ini(file_path).unwrap().messageMode, Some(expected));
+  }
+  for val in ["0800", "rw-------", "17777"] {
+    write(file_path, format!("mailDir = /home/user/Maildir/new\nuserName = user\nmessageMode = {}\n", val)).unwrap();
+    assert!(read_config_ini(file_path).is_err(), "for {}", val);
+  }
+}
+
+#[test]
+fn test_read_config_ini_deliver_flags() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+
+  write(file_path, "mailDir = /home/user/Maildir/new\nuserName = user\ndeliverFlags = SD\n").unwrap();
+  assert_eq!(read_config_ini(file_path).unwrap().deliverFlags.as_deref(), Some("DS"));
+
+  write(file_path, "mailDir = /home/user/Maildir/new\nuserName = user\ndeliverFlags = DX\n").unwrap();
+  assert!(read_config_ini(file_path).is_err());
+}
+
+#[test]
+fn test_read_config_ini_trusted_users() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  let conts = r#"
+mailDir = /home/user/Maildir/new
+userName = user
+trustedUsers = daemon, www-data  backup
+"#;
+
+  write(file_path, conts).unwrap();
+
+  let config = read_config_ini(file_path).unwrap();
+  assert_eq!(config.trustedUsers, vec!["daemon", "www-data", "backup"]);
+}
+
+#[test]
+fn test_read_config_ini_env_expansion() {
+  std::env::set_var("RATTOMAIL_TEST_MAILROOT", "/srv/mail");
+  std::env::remove_var("RATTOMAIL_TEST_UNSET");
+
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  let conts = r#"
+mailDir = ${RATTOMAIL_TEST_MAILROOT}/Maildir/new
+userName = ${RATTOMAIL_TEST_UNSET:-user}
+"#;
+  write(file_path, conts).unwrap();
+
+  let config = read_config_ini(file_path).unwrap();
+  assert_eq!(config.mailDir, "/srv/mail/Maildir/new");
+  assert_eq!(config.userName, "user");
+
+  let conts = r#"
+mailDir = ${RATTOMAIL_TEST_UNSET}/Maildir/new
+userName = user
+"#;
+  write(file_path, conts).unwrap();
+
+  let err = read_config_ini(file_path).unwrap_err();
+  assert!(err.to_string().contains("RATTOMAIL_TEST_UNSET"));
+}
+
+#[test]
+fn test_read_config_ini_bom() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  write(file_path, b"\xEF\xBB\xBFmailDir = /home/user/Maildir/new\nuserName = user\n").unwrap();
+
+  let config = read_config_ini(file_path).unwrap();
+  assert_eq!(config.mailDir, "/home/user/Maildir/new");
+  assert_eq!(config.userName, "user");
+}
+
+#[test]
+fn test_read_config_ini_invalid_utf8() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  // Latin-1 "é"
+  write(file_path, b"mailDir = /home/user/Maildir/new\nuserName = ren\xE9\n").unwrap();
+
+  let err = read_config_ini(file_path).unwrap_err();
+  assert_eq!(exit_code_for(&err), 78);
+  assert_eq!(err.to_string(),
+             format!("Error reading config file {}: invalid UTF-8 at line 2, variable userName", file_path.display()));
+}
+
+#[test]
+fn test_generate_config_reads_back() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let file_path = temp_dir.path().join("rattomail.conf");
+
+  // "nobody"'s home directory is normally /nonexistent
+  let config = generate_config("nobody", None).unwrap();
+  let home = nix::unistd::User::from_name("nobody").unwrap().unwrap().dir;
+  let expected = Config {
+    mailDir: home.join("Maildir/new").display().to_string(),
+    userName: "nobody".to_string(),
+    ..Config::default()
+  };
+  assert_eq!(config, expected);
+
+  write_new_config_ini(&config, &file_path, false).unwrap();
+  assert_eq!(read_config_ini(&file_path).unwrap(), expected);
+
+  // won't overwrite without `force`
+  let other = generate_config("nobody", Some("/srv/mail/Maildir/new")).unwrap();
+  assert!(write_new_config_ini(&other, &file_path, false).is_err());
+  assert_eq!(read_config_ini(&file_path).unwrap(), expected);
+  write_new_config_ini(&other, &file_path, true).unwrap();
+  assert_eq!(read_config_ini(&file_path).unwrap().mailDir, "/srv/mail/Maildir/new");
+}
+
+#[test]
+fn test_generate_config_invalid() {
+  assert!(generate_config("root", None).is_err());
+  assert!(generate_config("no-such-user-rattomail", None).is_err());
+  assert!(generate_config("nobody", Some("relative/Maildir/new")).is_err());
+}
+
+#[test]
+fn test_read_config_ini_reject_self_addressed() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  for (val, expected) in [("true", Some(SelfAddressedAction::Reject)),
+                          ("no", Some(SelfAddressedAction::Deliver)),
+                          ("drop", Some(SelfAddressedAction::Drop)),
+                          ("reject", Some(SelfAddressedAction::Reject)),
+                          ("bounce", None)] {
+    let conts = format!("mailDir = /home/user/Maildir/new\nuserName = user\nrejectSelfAddressed = {}\n", val);
+    write(file_path, conts).unwrap();
+    assert_eq!(read_config_ini(file_path).ok().map(|config| config.rejectSelfAddressed), expected);
+  }
+}
+
+#[test]
+fn test_read_config_ini_program_profiles() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  let conts = "mailDir = /home/user/Maildir/new\nuserName = user\nprogramProfiles = sendmail:full, send-mail:lmtp\n";
+  write(file_path, conts).unwrap();
+  assert_eq!(read_config_ini(file_path).unwrap().programProfiles,
+             vec![("sendmail".to_string(), ProgramProfile::Full),
+                  ("send-mail".to_string(), ProgramProfile::Lmtp)]);
+
+  for bad in ["sendmail", "sendmail:smtp"] {
+    let conts = format!("mailDir = /home/user/Maildir/new\nuserName = user\nprogramProfiles = {}\n", bad);
+    write(file_path, conts).unwrap();
+    assert!(read_config_ini(file_path).is_err(), "{} should be rejected", bad);
+  }
+}
+
+#[test]
+fn test_read_config_ini_on_empty_input() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  for (val, expected) in [("deliver", Some(EmptyInputAction::Deliver)),
+                          ("reject", Some(EmptyInputAction::Reject)),
+                          ("skip", Some(EmptyInputAction::Skip)),
+                          ("true", None)] {
+    let conts = format!("mailDir = /home/user/Maildir/new\nuserName = user\nonEmptyInput = {}\n", val);
+    write(file_path, conts).unwrap();
+    assert_eq!(read_config_ini(file_path).ok().map(|config| config.onEmptyInput), expected);
+  }
+}
+
+#[test]
+fn test_read_config_ini_on_invalid_sender() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  for (val, expected) in [("reject", Some(InvalidSenderAction::Reject)),
+                          ("sanitize", Some(InvalidSenderAction::Sanitize)),
+                          ("fallback", Some(InvalidSenderAction::Fallback)),
+                          ("true", None)] {
+    let conts = format!("mailDir = /home/user/Maildir/new\nuserName = user\nonInvalidSender = {}\n", val);
+    write(file_path, conts).unwrap();
+    assert_eq!(read_config_ini(file_path).ok().map(|config| config.onInvalidSender), expected);
+  }
+}
+
+#[test]
+fn test_read_config_ini_warn_raw_subject_8bit() {
+  let temp_file = NamedTempFile::new().unwrap();
+  let file_path = temp_file.path();
+  for (val, expected) in [("true", Some(RawSubjectAction::Warn)),
+                          ("warn", Some(RawSubjectAction::Warn)),
+                          ("false", Some(RawSubjectAction::Ignore)),
+                          ("encode", Some(RawSubjectAction::Encode)),
+                          ("strict", None)] {
+    let conts = format!("mailDir = /home/user/Maildir/new\nuserName = user\nwarnRawSubject8bit = {}\n", val);
+    write(file_path, conts).unwrap();
+    assert_eq!(read_config_ini(file_path).ok().map(|config| config.warnRawSubject8bit), expected);
+  }
+}
@@ -0,0 +1,105 @@
+
+mod common;
+
+use std::io::{BufReader, Cursor, Read};
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use tempfile::tempdir;
+
+use rattomail::PROGRAM_NAMES;
+
+use common::{config_file, main_context, maildir_config};
+
+/// Held by each test for as long as it runs, since there's only one alarm per process.
+static ALARM_LOCK: Mutex<()> = Mutex::new(());
+
+/// A reader which gives out a line at a time, pausing before each one -- like a program that's
+/// slow to produce its output.
+struct SlowReader {
+  lines_left: u32,
+  pause: Duration,
+}
+
+impl Read for SlowReader {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let line: &[u8] = match self.lines_left {
+      0 => return Ok(0),
+      n if n % 10 == 0 => b"Subject: slow\n\n",
+      _ => b"another line\n",
+    };
+    sleep(self.pause);
+    self.lines_left -= 1;
+    let n = line.len().min(buf.len());
+    buf[..n].copy_from_slice(&line[..n]);
+    Ok(n)
+  }
+}
+
+/// with `deliveryTimeoutSeconds`, an input that takes too long to arrive is given up on with
+/// `EX_TEMPFAIL`, and nothing is left in the Maildir
+#[test]
+fn test_main_delivery_timeout() {
+  let _lock = ALARM_LOCK.lock().unwrap();
+  let temp_dir = tempdir().unwrap();
+  let maildir_path = temp_dir.path().join("Maildir");
+  let config_file = config_file(&maildir_config(&maildir_path.join("new"), "deliveryTimeoutSeconds = 1\n"));
+  let ctx = main_context(&["sendmail", "-f", "alice", "bob"], &config_file);
+
+  let started = Instant::now();
+  let mut input = BufReader::new(SlowReader { lines_left: 20, pause: Duration::from_millis(200) });
+  let err = rattomail::main(&PROGRAM_NAMES, &ctx, &mut input, None::<&mut Vec<u8>>).unwrap_err();
+
+  assert_eq!(err.exit_code(), 75, "expected EX_TEMPFAIL, got: {}", err);
+  assert!(started.elapsed() < Duration::from_secs(3), "delivery wasn't cut short");
+  for subdir in ["new", "cur", "tmp"] {
+    if let Ok(entries) = std::fs::read_dir(maildir_path.join(subdir)) {
+      assert_eq!(entries.count(), 0, "expected {} to be empty", subdir);
+    }
+  }
+}
+
+/// with several recipients, a delivery which only runs out of time after some of the messages
+/// have been stored (here, because indexing them is slow) removes every one of them, not just
+/// the last recipient's
+#[test]
+fn test_main_delivery_timeout_several_recipients() {
+  let _lock = ALARM_LOCK.lock().unwrap();
+  let temp_dir = tempdir().unwrap();
+  let maildir_path = temp_dir.path().join("Maildir");
+  let config_file = config_file(&maildir_config(&maildir_path.join("new"),
+                                                "deliveryTimeoutSeconds = 1\nindexCommand = sleep 2\n"));
+  let ctx = main_context(&["sendmail", "-t", "-f", "alice"], &config_file);
+
+  let mut input = Cursor::new(b"To: bob, carol\nSubject: hello\n\nsome body\n");
+  let err = rattomail::main(&PROGRAM_NAMES, &ctx, &mut input, None::<&mut Vec<u8>>).unwrap_err();
+
+  assert_eq!(err.exit_code(), 75, "expected EX_TEMPFAIL, got: {}", err);
+  for subdir in ["new", "cur", "tmp"] {
+    if let Ok(entries) = std::fs::read_dir(maildir_path.join(subdir)) {
+      assert_eq!(entries.count(), 0, "expected {} to be empty", subdir);
+    }
+  }
+}
+
+/// a `DeliveryAlarm` puts back whatever `SIGALRM` action was installed before it, once it's
+/// dropped
+#[test]
+fn test_delivery_alarm_restores_previous_action() {
+  use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+  let _lock = ALARM_LOCK.lock().unwrap();
+  let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+  let original = unsafe { sigaction(Signal::SIGALRM, &ignore) }.unwrap();
+
+  let alarm = rattomail::DeliveryAlarm::arm(Duration::from_secs(60)).unwrap();
+  let during = unsafe { sigaction(Signal::SIGALRM, &ignore) }.unwrap();
+  // putting the handler back, so that dropping the alarm has something to replace
+  unsafe { sigaction(Signal::SIGALRM, &during) }.unwrap();
+  assert!(matches!(during.handler(), SigHandler::Handler(_)));
+  drop(alarm);
+
+  let after = unsafe { sigaction(Signal::SIGALRM, &original) }.unwrap();
+  assert_eq!(after.handler(), SigHandler::SigIgn);
+}
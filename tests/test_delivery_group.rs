@@ -0,0 +1,48 @@
+
+mod common;
+
+use std::io::Cursor;
+
+use nix::unistd::{getegid, getgid, getgroups, Group, Uid};
+
+use rattomail::{
+                CreateMaildirsOption,
+                MainContext,
+                MessageDestination,
+                PrivilegeOption,
+                PROGRAM_NAMES,
+               };
+
+use common::{config_file, main_context};
+
+/// when privileges are dropped, the process ends up with the `deliveryGroup` gid, rather than
+/// the delivery user's primary group. Only runs as root, since otherwise privileges can't be
+/// dropped.
+#[test]
+fn test_main_delivery_group() {
+  if !Uid::effective().is_root() {
+    eprintln!("skipping test_main_delivery_group: must be run as root");
+    return;
+  }
+  let group = ["mail", "daemon"].iter()
+    .find_map(|name| Group::from_name(name).unwrap())
+    .expect("expected a 'mail' or 'daemon' group to exist");
+
+  let config_file = config_file(&format!(
+    "mailDir = /nonexistent/Maildir/new\nuserName = nobody\ndeliveryGroup = {}\n", group.name));
+  let ctx = MainContext {
+    should_drop_privs: PrivilegeOption::DropPrivileges,
+    should_create_maildirs: CreateMaildirsOption::NoCreateMaildirs,
+    message_destination: MessageDestination::OutputStream,
+    ..main_context(&["sendmail", "-f", "alice", "bob"], &config_file)
+  };
+
+  let mut output = Vec::new();
+  let input = b"Subject: hello\n\nsome body\n";
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), Some(&mut output)).unwrap();
+
+  assert!(output.ends_with(b"Subject: hello\n\nsome body\n"));
+  assert_eq!(getegid(), group.gid);
+  assert_eq!(getgid(), group.gid);
+  assert_eq!(getgroups().unwrap(), vec![group.gid]);
+}
@@ -0,0 +1,714 @@
+
+mod common;
+
+use std::io::Cursor;
+use std::path::Path;
+
+use chrono::{Local, TimeZone};
+use tempfile::NamedTempFile;
+
+use rattomail::{
+                make_received_header,
+                pid_header_value,
+                CreateMaildirsOption,
+                LAST_DELIVERY_FILE,
+                MAILTO_VAR,
+                MainContext,
+                MainError,
+                MessageDestination,
+                PROGRAM_NAMES,
+               };
+
+use common::{config_file, main_context, maildir_config, test_user, trusted_config};
+
+/// Run `rattomail::main` on `input`, with the given command-line args and config file
+/// contents, delivering to an output stream; and return what was delivered.
+///
+/// Privileges aren't dropped and no Maildirs are created, so this can run as any user. The
+/// args and config must be valid, since any error from `main` fails the test.
+fn run_main(args: &[&str], config_conts: &str, input: &[u8]) -> String {
+  let config_file = config_file(config_conts);
+  let ctx = MainContext {
+    should_create_maildirs: CreateMaildirsOption::NoCreateMaildirs,
+    message_destination: MessageDestination::OutputStream,
+    ..test_context(args, &config_file)
+  };
+
+  let mut output = Vec::new();
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(input), Some(&mut output)).unwrap();
+  strip_generated_message_ids(&String::from_utf8(output).unwrap())
+}
+
+/// whether `line` is a `Message-ID:` header generated by rattomail, i.e.
+/// `Message-ID: <timestamp.pid.random@hostname>\n`
+fn is_generated_message_id(line: &str) -> bool {
+  let Some(id) = line.strip_prefix("Message-ID: <").and_then(|rest| rest.strip_suffix(">\n")) else {
+    return false;
+  };
+  let Some((local, host)) = id.split_once('@') else {
+    return false;
+  };
+  let parts: Vec<&str> = local.split('.').collect();
+  !host.is_empty()
+    && parts.len() == 3
+    && parts[0].chars().all(|c| c.is_ascii_digit())
+    && parts[1].chars().all(|c| c.is_ascii_digit())
+    && parts[2].len() == 16
+    && parts[2].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Remove the `Message-ID:` headers rattomail generates (which differ on every run) from
+/// delivered output, so it can be compared against a fixed expected value.
+fn strip_generated_message_ids(output: &str) -> String {
+  output.split_inclusive('\n').filter(|line| !is_generated_message_id(line)).collect()
+}
+
+/// Run `rattomail::main` as for [`run_main`], but expecting it to fail, and return the error.
+fn run_main_err(args: &[&str], config_conts: &str, input: &[u8]) -> MainError {
+  let config_file = config_file(config_conts);
+  let ctx = MainContext {
+    should_create_maildirs: CreateMaildirsOption::NoCreateMaildirs,
+    message_destination: MessageDestination::OutputStream,
+    ..test_context(args, &config_file)
+  };
+
+  let mut output = Vec::new();
+  let err = rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(input), Some(&mut output)).unwrap_err();
+  assert!(output.is_empty(), "nothing should have been delivered");
+  err
+}
+
+fn test_received_time() -> chrono::DateTime<Local> {
+  Local.with_ymd_and_hms(2024, 12, 31, 10, 30, 0).unwrap()
+}
+
+/// a context for running `rattomail::main` with `args` and `config_file`, delivering to a
+/// Maildir (see [`main_context`]), received at [`test_received_time`]
+fn test_context(args: &[&str], config_file: &NamedTempFile) -> MainContext {
+  MainContext { received_time: test_received_time(), ..main_context(args, config_file) }
+}
+
+/// a minimal valid config file. The invoking user is trusted, so using `-f` doesn't add an
+/// `X-Authentication-Warning:` header.
+fn test_config() -> String {
+  trusted_config(Path::new("/nonexistent/Maildir/new"), "")
+}
+
+#[test]
+fn test_main_synthesizes_missing_headers() {
+  let input = b"Subject: hello\nTo: bob\n\nsome body\nmore body\n";
+  let output = run_main(&["sendmail", "-f", "alice", "bob"], &test_config(), input);
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nSubject: hello\nTo: bob\n\nsome body\nmore body\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+#[test]
+fn test_main_preserves_existing_headers() {
+  let input = b"From: carol@example.com\nDate: Wed, 21 Oct 2020 07:28:00 GMT\nSubject: hello\n\nsome body\n";
+  let output = run_main(&["/usr/sbin/sendmail", "-i", "-f", "alice", "bob@example.com"], &test_config(), input);
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob@example.com\n{}From: carol@example.com\nDate: Wed, 21 Oct 2020 07:28:00 GMT\nSubject: hello\n\nsome body\n",
+    make_received_header("bob@example.com", "alice", &time),
+  );
+  assert_eq!(output, expected);
+}
+
+/// with no recipient, the user in the config file is the recipient
+#[test]
+fn test_main_default_recipient() {
+  let input = b"Subject: hello\n\nsome body\n";
+  let output = run_main(&["send-mail", "-f", "alice"], &test_config(), input);
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: {}\n{}Date: {}\nFrom: alice\nSubject: hello\n\nsome body\n",
+    test_user(),
+    make_received_header(&test_user(), "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// empty input still gets a full set of headers
+#[test]
+fn test_main_empty_input() {
+  let output = run_main(&["rattomail", "-f", "alice", "bob"], &test_config(), b"");
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\n\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// config settings are honoured
+#[test]
+fn test_main_default_reply_to() {
+  let config = format!("{}defaultReplyTo = tickets@example.com\n", test_config());
+  let output = run_main(&["sendmail", "-f", "alice", "bob"], &config, b"Subject: hello\n\nsome body\n");
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nReply-To: tickets@example.com\nSubject: hello\n\nsome body\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// sendmail's `-o`/`-O` options are accepted, repeatedly, and don't affect delivery
+#[test]
+fn test_main_sendmail_options() {
+  let input = b"Subject: hello\n\nsome body\n.\nmore body\n";
+  let args = ["sendmail", "-oi", "-odq", "-O", "DeliveryMode=b", "-oem", "-f", "alice", "bob"];
+  let output = run_main(&args, &test_config(), input);
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nSubject: hello\n\nsome body\n.\nmore body\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// `-N` conditions are recorded in an `X-DSN-Notify:` header
+#[test]
+fn test_main_dsn_notify() {
+  let input = b"Subject: hello\n\nsome body\n";
+  let output = run_main(&["sendmail", "-N", "success,failure", "-f", "alice", "bob"], &test_config(), input);
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}X-DSN-Notify: success,failure\nDate: {}\nFrom: alice\nSubject: hello\n\nsome body\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// with `--envelope-from-fd` and `--recipient-fd`, the addresses are read from the given file
+/// descriptors, and used just as if they'd been given on the command line
+#[test]
+fn test_main_address_fds() {
+  use std::io::Write;
+  use std::os::fd::IntoRawFd;
+
+  // rattomail takes ownership of (and closes) the fds it's given
+  let address_fd = |address: &str| {
+    let (read_end, write_end) = nix::unistd::pipe().unwrap();
+    let mut write_end = std::fs::File::from(write_end);
+    writeln!(write_end, "{}", address).unwrap();
+    read_end.into_raw_fd().to_string()
+  };
+  let from_fd = address_fd("alice");
+  let to_fd = address_fd("bob");
+  let args = ["sendmail", "--envelope-from-fd", &from_fd, "--recipient-fd", &to_fd];
+  assert!(!args.iter().any(|arg| arg.contains("alice") || arg.contains("bob")));
+
+  let input = b"Subject: hello\n\nsome body\n";
+  let output = run_main(&args, &test_config(), input);
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nSubject: hello\n\nsome body\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// a recipient given as `Name <addr>` is delivered to the bare address, with the full form
+/// recorded in an `X-Original-To:` header; a bare address gets no such header
+#[test]
+fn test_main_recipient_with_name() {
+  let input = b"Subject: hello\n\nsome body\n";
+  let time = test_received_time();
+
+  let output = run_main(&["sendmail", "-f", "alice", "Bob <bob@example.com>"], &test_config(), input);
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob@example.com\n{}X-Original-To: Bob <bob@example.com>\nDate: {}\nFrom: alice\nSubject: hello\n\nsome body\n",
+    make_received_header("bob@example.com", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+
+  let output = run_main(&["sendmail", "-f", "alice", "bob@example.com"], &test_config(), input);
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob@example.com\n{}Date: {}\nFrom: alice\nSubject: hello\n\nsome body\n",
+    make_received_header("bob@example.com", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// with `--recipients-from-mailto`, each address in a comma-separated `MAILTO` gets a
+/// delivery of its own; and an empty `MAILTO` means nothing is delivered at all. (One test, since
+/// the environment is shared by every test.)
+#[test]
+fn test_main_recipients_from_mailto() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let maildir_new = temp_dir.path().join("Maildir").join("new");
+  let config_file = config_file(&trusted_config(&maildir_new, ""));
+
+  let ctx = test_context(&["sendmail", "--recipients-from-mailto", "-f", "alice"], &config_file);
+  let input = b"Subject: hello\n\nsome body\n";
+
+  std::env::set_var(MAILTO_VAR, "bob@example.com, carol@example.com");
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), None::<&mut Vec<u8>>).unwrap();
+
+  let mut delivered: Vec<String> = std::fs::read_dir(&maildir_new).unwrap()
+    .map(|entry| strip_generated_message_ids(&std::fs::read_to_string(entry.unwrap().path()).unwrap()))
+    .collect();
+  delivered.sort();
+  let time = test_received_time();
+  let expected: Vec<String> = ["bob@example.com", "carol@example.com"].iter()
+    .map(|to| format!("Return-Path: <alice>\nDelivered-To: {}\n{}Date: {}\nFrom: alice\nSubject: hello\n\nsome body\n",
+                      to, make_received_header(to, "alice", &time), time.to_rfc2822()))
+    .collect();
+  assert_eq!(delivered, expected);
+
+  // an empty MAILTO suppresses delivery
+  std::fs::remove_dir_all(&maildir_new).unwrap();
+  std::env::set_var(MAILTO_VAR, "");
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), None::<&mut Vec<u8>>).unwrap();
+  std::env::remove_var(MAILTO_VAR);
+  assert!(!maildir_new.exists(), "nothing should have been delivered");
+}
+
+/// with `-t`, each recipient in the `To:`, `Cc:` and `Bcc:` headers, and any given as an
+/// argument, gets a delivery, and the `Bcc:` header isn't delivered
+#[test]
+fn test_main_recipients_from_headers() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let maildir_new = temp_dir.path().join("Maildir").join("new");
+  let config_file = config_file(&trusted_config(&maildir_new, ""));
+
+  let ctx = test_context(&["sendmail", "-t", "-f", "alice", "erin@example.com"], &config_file);
+  let input = b"To: Bob <bob@example.com>,\n carol@example.com\nBcc: dave@example.com\nSubject: hello\n\nsome body\n";
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), None::<&mut Vec<u8>>).unwrap();
+
+  let mut delivered: Vec<String> = std::fs::read_dir(&maildir_new).unwrap()
+    .map(|entry| strip_generated_message_ids(&std::fs::read_to_string(entry.unwrap().path()).unwrap()))
+    .collect();
+  delivered.sort();
+  let time = test_received_time();
+  let expected: Vec<String> = ["bob@example.com", "carol@example.com", "dave@example.com", "erin@example.com"]
+    .iter()
+    .map(|to| format!("Return-Path: <alice>\nDelivered-To: {}\n{}Date: {}\nFrom: alice\nTo: Bob <bob@example.com>,\n carol@example.com\nSubject: hello\n\nsome body\n",
+                      to, make_received_header(to, "alice", &time), time.to_rfc2822()))
+    .collect();
+  assert_eq!(delivered, expected);
+}
+
+/// `--pid-tag` records the delivering process's PID in an `X-Rattomail-Pid:` header
+#[test]
+fn test_main_pid_tag() {
+  let input = b"Subject: hello\n\nsome body\n";
+  let output = run_main(&["sendmail", "--pid-tag", "-f", "alice", "bob"], &test_config(), input);
+
+  let pid_line = output
+    .lines()
+    .find_map(|line| line.strip_prefix("X-Rattomail-Pid: "))
+    .expect("no X-Rattomail-Pid header");
+  let pid = pid_line.split_whitespace().next().unwrap();
+  assert_eq!(pid, std::process::id().to_string());
+  assert_eq!(pid_line, pid_header_value(std::process::id(), std::os::unix::process::parent_id()));
+
+  // and isn't added by default
+  let output = run_main(&["sendmail", "-f", "alice", "bob"], &test_config(), input);
+  assert!(!output.contains("X-Rattomail-Pid"));
+}
+
+#[test]
+fn test_main_normalize_domain_case() {
+  let input = b"Subject: hello\n\nsome body\n";
+  let time = test_received_time();
+
+  // by default, addresses are left as-is
+  let output = run_main(&["sendmail", "-f", "Alice@Example.COM", "Bob@Example.ORG"], &test_config(), input);
+  let expected = format!(
+    "Return-Path: <Alice@Example.COM>\nDelivered-To: Bob@Example.ORG\n{}Date: {}\nFrom: Alice@Example.COM\nSubject: hello\n\nsome body\n",
+    make_received_header("Bob@Example.ORG", "Alice@Example.COM", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+
+  // with normalizeDomainCase, only the domains are lowercased
+  let config = format!("{}normalizeDomainCase = true\n", test_config());
+  let output = run_main(&["sendmail", "-f", "Alice@Example.COM", "Bob@Example.ORG"], &config, input);
+  let expected = format!(
+    "Return-Path: <Alice@example.com>\nDelivered-To: Bob@example.org\n{}Date: {}\nFrom: Alice@example.com\nSubject: hello\n\nsome body\n",
+    make_received_header("Bob@example.org", "Alice@example.com", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// like `echo "just text" | sendmail bob`
+#[test]
+fn test_main_headerless_input() {
+  let output = run_main(&["sendmail", "-f", "alice", "bob"], &test_config(), b"just text\n");
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\n\njust text\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// with `--discard`, the whole message is read, but nothing is written anywhere
+#[test]
+fn test_main_discard() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let maildir_new = temp_dir.path().join("Maildir").join("new");
+  let config_file = config_file(&trusted_config(&maildir_new, ""));
+
+  let ctx = test_context(&["sendmail", "--discard", "-f", "alice", "bob"], &config_file);
+
+  let input = b"Subject: hello\n\nsome body\nmore body\n";
+  let mut input = Cursor::new(&input[..]);
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut input, None::<&mut Vec<u8>>).unwrap();
+
+  assert_eq!(input.position() as usize, input.get_ref().len(), "all input should be consumed");
+  assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 0, "no files should be created");
+}
+
+/// with `--headers-only`, output ends at the blank line after the headers
+#[test]
+fn test_main_headers_only() {
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nSubject: hello\n\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+
+  let output = run_main(&["sendmail", "--headers-only", "-f", "alice", "bob"], &test_config(), b"Subject: hello\n");
+  assert_eq!(output, expected);
+
+  // any body is skipped
+  let output = run_main(&["sendmail", "--headers-only", "-f", "alice", "bob"], &test_config(),
+                        b"Subject: hello\n\nsome body\n");
+  assert_eq!(output, expected);
+}
+
+/// with `--stdout-eml`, output is just the message, ending in exactly one newline -- even if
+/// the input didn't end in one
+#[test]
+fn test_main_stdout_eml() {
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nSubject: hello\n\nsome body\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+
+  for input in [&b"Subject: hello\n\nsome body"[..], b"Subject: hello\n\nsome body\n"] {
+    let output = run_main(&["sendmail", "--stdout-eml", "-f", "alice", "bob"], &test_config(), input);
+    assert_eq!(output, expected);
+    assert!(!output.starts_with("From "), "there should be no mbox separator");
+  }
+}
+
+/// with `reportTls`, a TLS cipher passed by the MTA is noted in the `Received:` header
+#[test]
+fn test_main_report_tls() {
+  std::env::set_var(rattomail::TLS_CIPHER_VAR, "TLS_AES_256_GCM_SHA384");
+  let config = format!("{}reportTls = true\n", test_config());
+  let output = run_main(&["sendmail", "-f", "alice", "bob"], &config, b"Subject: hello\n\nsome body\n");
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\nReceived: for bob with local (rattomail) (TLS TLS_AES_256_GCM_SHA384) (envelope-from alice); {}\nDate: {}\nFrom: alice\nSubject: hello\n\nsome body\n",
+    time.to_rfc2822(),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+}
+
+/// a self-addressed message is delivered by default, but can be dropped
+#[test]
+fn test_main_self_addressed() {
+  let input = b"Subject: hello\n\nsome body\n";
+  let output = run_main(&["sendmail", "-f", "alice", "alice"], &test_config(), input);
+  assert!(output.ends_with("Subject: hello\n\nsome body\n"));
+
+  let config = format!("{}rejectSelfAddressed = drop\n", test_config());
+  let output = run_main(&["sendmail", "-f", "alice", "alice"], &config, input);
+  assert_eq!(output, "");
+}
+
+/// `onEmptyInput = deliver` is the same as the default (see `test_main_empty_input`), while
+/// `skip` delivers nothing. (`reject` makes `main` exit, so is tested in the library.)
+#[test]
+fn test_main_on_empty_input() {
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\n\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+  let config = format!("{}onEmptyInput = deliver\n", test_config());
+  let output = run_main(&["sendmail", "-f", "alice", "bob"], &config, b"");
+  assert_eq!(output, expected);
+
+  let config = format!("{}onEmptyInput = skip\n", test_config());
+  let output = run_main(&["sendmail", "-f", "alice", "bob"], &config, b"");
+  assert_eq!(output, "");
+}
+
+/// with `--receipt-fd`, a receipt for the delivered message is written to the given file
+/// descriptor, signed with the configured key
+#[test]
+fn test_main_receipt_fd() {
+  use hmac::{Hmac, Mac};
+  use std::io::{Read, Seek};
+  use std::os::fd::AsRawFd;
+
+  let temp_dir = tempfile::tempdir().unwrap();
+  let maildir_new = temp_dir.path().join("Maildir").join("new");
+  let config_file = config_file(&maildir_config(&maildir_new, "receiptKey = sekrit\n"));
+
+  // rattomail takes ownership of (and closes) the fd it's given, so give it a duplicate
+  let mut receipt_file = tempfile::tempfile().unwrap();
+  let fd = nix::unistd::dup(receipt_file.as_raw_fd()).unwrap().to_string();
+
+  let ctx = test_context(&["sendmail", "--receipt-fd", &fd, "-f", "alice", "bob"], &config_file);
+  let input = b"Subject: hello\n\nsome body\n";
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), None::<&mut Vec<u8>>).unwrap();
+
+  let delivered: Vec<_> = std::fs::read_dir(&maildir_new).unwrap().map(|entry| entry.unwrap()).collect();
+  assert_eq!(delivered.len(), 1);
+  let message_id = delivered[0].file_name().into_string().unwrap();
+  let size = delivered[0].metadata().unwrap().len();
+
+  let mut receipt = String::new();
+  receipt_file.rewind().unwrap();
+  receipt_file.read_to_string(&mut receipt).unwrap();
+
+  let (fields, hmac_line) = receipt.trim_end().rsplit_once('\n').unwrap();
+  let fields = format!("{}\n", fields);
+  assert_eq!(fields, format!("message-id: {}\nrecipient: bob\nsize: {}\ntimestamp: {}\n",
+                             message_id, size, test_received_time().to_rfc3339()));
+
+  let hmac_hex = hmac_line.strip_prefix("hmac-sha256: ").unwrap();
+  let hmac_bytes: Vec<u8> = (0..hmac_hex.len()).step_by(2)
+    .map(|i| u8::from_str_radix(&hmac_hex[i..i + 2], 16).unwrap())
+    .collect();
+  let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"sekrit").unwrap();
+  mac.update(fields.as_bytes());
+  mac.verify_slice(&hmac_bytes).expect("receipt HMAC should verify");
+}
+
+/// `--maildir-root` overrides the config file's `mailDir`
+#[test]
+fn test_main_maildir_root() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let maildir = temp_dir.path().join("scratch");
+  let config_file = config_file(&test_config());
+
+  let maildir_arg = maildir.to_str().unwrap();
+  let ctx = test_context(&["sendmail", "--maildir-root", maildir_arg, "-f", "alice", "bob"], &config_file);
+
+  let input = b"Subject: hello\n\nsome body\n";
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), None::<&mut Vec<u8>>).unwrap();
+
+  let delivered: Vec<_> = std::fs::read_dir(maildir.join("new")).unwrap().collect();
+  assert_eq!(delivered.len(), 1);
+  let conts = std::fs::read(delivered[0].as_ref().unwrap().path()).unwrap();
+  assert!(conts.ends_with(b"Subject: hello\n\nsome body\n"));
+  assert!(!std::path::Path::new("/nonexistent/Maildir").exists());
+}
+
+/// with `datePartition`, mail is delivered to a subfolder named after the received time
+#[test]
+fn test_main_date_partition() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let maildir = temp_dir.path().join("Maildir");
+  let config_file = config_file(&format!("{}datePartition = %Y/%m\n", test_config()));
+
+  let maildir_arg = maildir.to_str().unwrap();
+  let ctx = test_context(&["sendmail", "--maildir-root", maildir_arg, "-f", "alice", "bob"], &config_file);
+
+  let input = b"Subject: hello\n\nsome body\n";
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), None::<&mut Vec<u8>>).unwrap();
+
+  let delivered: Vec<_> = std::fs::read_dir(maildir.join(".2024.12").join("new")).unwrap().collect();
+  assert_eq!(delivered.len(), 1);
+  let conts = std::fs::read(delivered[0].as_ref().unwrap().path()).unwrap();
+  assert!(conts.ends_with(b"Subject: hello\n\nsome body\n"));
+  assert!(!maildir.join("new").exists());
+}
+
+/// `--assume-user` gives the default sender and recipient, and is substituted for `%u`
+#[test]
+fn test_main_assume_user() {
+  let input = b"Subject: hello\n\nsome body\n";
+  let output = run_main(&["sendmail", "--assume-user", "carol"], &test_config(), input);
+
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <carol>\nDelivered-To: carol\n{}Date: {}\nFrom: carol\nSubject: hello\n\nsome body\n",
+    make_received_header("carol", "carol", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+
+  let temp_dir = tempfile::tempdir().unwrap();
+  let config_file = config_file(&maildir_config(&temp_dir.path().join("%u/Maildir/new"), ""));
+  let ctx = test_context(&["sendmail", "--assume-user", "carol"], &config_file);
+  rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), None::<&mut Vec<u8>>).unwrap();
+
+  let delivered: Vec<_> = std::fs::read_dir(temp_dir.path().join("carol/Maildir/new")).unwrap().collect();
+  assert_eq!(delivered.len(), 1);
+}
+
+/// a recipient with a section of their own in the config file is delivered to its Maildir;
+/// anyone else, to the default one
+#[test]
+fn test_main_user_routes() {
+  let temp_dir = tempfile::tempdir().unwrap();
+  let config_file = config_file(&maildir_config(
+    &temp_dir.path().join("default/Maildir/new"),
+    &format!("\n[alice]\nmailDir = {}/alice/Maildir/new\nuserName = {}\n", temp_dir.path().display(), test_user())));
+
+  for recipient in ["alice@example.com", "bob"] {
+    let ctx = test_context(&["sendmail", "-f", "carol", recipient], &config_file);
+    let input = b"Subject: hello\n\nsome body\n";
+    rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), None::<&mut Vec<u8>>).unwrap();
+  }
+
+  for folder in ["alice", "default"] {
+    let delivered = std::fs::read_dir(temp_dir.path().join(folder).join("Maildir/new")).unwrap().count();
+    assert_eq!(delivered, 1, "expected one message in {}", folder);
+  }
+}
+
+/// a message that's already been delivered to the recipient is rejected as a mail loop, with
+/// `EX_NOPERM`, so the sender bounces it
+#[test]
+fn test_main_mail_loop() {
+  let input = b"Delivered-To: Bob@Example.com\nSubject: hello\n\nsome body\n";
+  let err = run_main_err(&["sendmail", "-f", "alice", "bob@example.com"], &test_config(), input);
+  assert_eq!(err.exit_code(), 77);
+  assert!(err.to_string().contains("Mail loop detected"), "{}", err);
+
+  // but not if it's for someone else
+  let output = run_main(&["sendmail", "-f", "alice", "carol@example.com"], &test_config(), input);
+  assert!(output.starts_with("Return-Path: <alice>\nDelivered-To: carol@example.com\n"));
+}
+
+/// with `writeLastDelivery`, the Maildir's status file records the most recent delivery only
+#[test]
+fn test_main_write_last_delivery() {
+  use std::collections::HashSet;
+
+  let temp_dir = tempfile::tempdir().unwrap();
+  let maildir_new = temp_dir.path().join("Maildir").join("new");
+  let config_file = config_file(&trusted_config(&maildir_new, "writeLastDelivery = true\n"));
+
+  let delivered = || -> HashSet<String> {
+    std::fs::read_dir(&maildir_new).unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect()
+  };
+  let deliver = |sender: &str| {
+    let ctx = test_context(&["sendmail", "-f", sender, "bob"], &config_file);
+    let input = b"Subject: hello\n\nsome body\n";
+    rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(&input[..]), None::<&mut Vec<u8>>).unwrap();
+  };
+
+  deliver("alice");
+  let first = delivered();
+  deliver("carol");
+  let latest: Vec<String> = delivered().difference(&first).cloned().collect();
+  assert_eq!(latest.len(), 1);
+
+  let status = std::fs::read_to_string(temp_dir.path().join("Maildir").join(LAST_DELIVERY_FILE)).unwrap();
+  assert_eq!(status, format!("timestamp: {}\nsender: carol\nid: {}\n",
+                             test_received_time().to_rfc3339(), latest[0]));
+
+  // and no temporary files are left behind
+  let leftovers: Vec<_> = std::fs::read_dir(temp_dir.path().join("Maildir")).unwrap()
+    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+    .filter(|name| name.starts_with(LAST_DELIVERY_FILE) && name != LAST_DELIVERY_FILE)
+    .collect();
+  assert_eq!(leftovers, Vec::<String>::new());
+}
+
+/// failures are returned, with the message that would be printed and the status to exit with
+#[test]
+fn test_main_returns_errors() {
+  let input = b"Subject: hello\n\nsome body\n";
+
+  let err = run_main_err(&["not-sendmail", "bob"], &test_config(), input);
+  assert_eq!(err.exit_code(), 1);
+  assert_eq!(err.to_string(),
+             format!("Error: Invalid program name 'not-sendmail'. Only {:?} are allowed.", PROGRAM_NAMES));
+
+  let err = run_main_err(&["sendmail", "bob"], "userName = nobody\n", input);
+  assert_eq!(err.exit_code(), 1);
+  assert!(err.to_string().starts_with("Error reading config file '"), "unexpected error: {}", err);
+
+  let err = run_main_err(&["sendmail", "--no-such-option", "bob"], &test_config(), input);
+  assert!(matches!(err, MainError::Cli { .. }), "unexpected error: {:?}", err);
+  assert_eq!(err.exit_code(), 2);
+
+  let err = run_main_err(&["sendmail", "--input-limit-lines", "lots", "bob"], &test_config(), input);
+  assert_eq!(err.exit_code(), 64);
+  assert_eq!(err.to_string(), "Error: --input-limit-lines: 'lots' is not a number of lines");
+}
+
+/// names added by the config file's `programNames` are accepted as well as the built-in ones;
+/// others are still rejected
+#[test]
+fn test_main_configured_program_names() {
+  let config = format!("{}programNames = mailx\n", test_config());
+  let input = b"Subject: hello\n\nsome body\n";
+
+  let output = run_main(&["/usr/bin/mailx", "-f", "alice", "bob"], &config, input);
+  let time = test_received_time();
+  let expected = format!(
+    "Return-Path: <alice>\nDelivered-To: bob\n{}Date: {}\nFrom: alice\nSubject: hello\n\nsome body\n",
+    make_received_header("bob", "alice", &time),
+    time.to_rfc2822()
+  );
+  assert_eq!(output, expected);
+
+  let err = run_main_err(&["mail", "-f", "alice", "bob"], &config, input);
+  assert_eq!(err.exit_code(), 1);
+  let mut allowed: Vec<&str> = PROGRAM_NAMES.to_vec();
+  allowed.push("mailx");
+  assert_eq!(err.to_string(), format!("Error: Invalid program name 'mail'. Only {:?} are allowed.", allowed));
+}
+
+/// invoked as `sendmail`, a failure without a more specific code exits with `EX_TEMPFAIL`, so
+/// the MTA retries the message; under the other profiles, it exits with 1
+#[test]
+fn test_main_sendmail_profile_tempfail() {
+  let input = b"Subject: hello\n\nsome body\n";
+
+  let err = run_main_err(&["sendmail", "-f", "alice", "bob smith"], &test_config(), input);
+  assert_eq!(err.exit_code(), 75);
+  assert!(err.to_string().starts_with("Recipient address 'bob smith'"), "unexpected error: {}", err);
+
+  let err = run_main_err(&["rattomail", "-f", "alice", "bob smith"], &test_config(), input);
+  assert_eq!(err.exit_code(), 1);
+
+  // errors which already have a sysexits code keep it
+  let err = run_main_err(&["sendmail", "--input-limit-lines", "lots", "bob"], &test_config(), input);
+  assert_eq!(err.exit_code(), 64);
+}
@@ -0,0 +1,43 @@
+mod common;
+
+use std::io::Cursor;
+
+use tempfile::tempdir;
+
+use rattomail::PROGRAM_NAMES;
+
+use common::{capture_fd, config_file, main_context, maildir_config};
+
+/// Deliver a message with `rattomail::main`, using a config whose index command fails (which
+/// gives a warning), and return whatever was written to stderr.
+fn deliver_capturing_stderr(extra_args: &[&str]) -> String {
+  let temp_dir = tempdir().unwrap();
+  let maildir_path = temp_dir.path().join("Maildir");
+  let config_file = config_file(&maildir_config(&maildir_path.join("new"), "indexCommand = false\n"));
+
+  let mut args = vec!["sendmail"];
+  args.extend_from_slice(extra_args);
+  args.extend_from_slice(&["-f", "alice", "bob"]);
+  let ctx = main_context(&args, &config_file);
+
+  let input = b"Subject: hello\n\nsome body\n";
+  let (res, stderr) = capture_fd(2, || {
+    rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(input), None::<&mut Vec<u8>>)
+  });
+
+  res.unwrap();
+  let delivered = std::fs::read_dir(maildir_path.join("new")).unwrap().count();
+  assert_eq!(delivered, 1, "expected exactly one delivered message");
+  stderr
+}
+
+/// a failing index command produces a warning on stderr -- but not with `--quiet`, though the
+/// message is still delivered
+#[test]
+fn test_main_quiet() {
+  let stderr = deliver_capturing_stderr(&[]);
+  assert!(stderr.contains("Warning: couldn't index message"), "unexpected stderr: {}", stderr);
+
+  let stderr = deliver_capturing_stderr(&["--quiet"]);
+  assert_eq!(stderr, "");
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use std::io::Cursor;
+use std::path::Path;
+
+use chrono::{Local, TimeZone};
+
+use rattomail::{CreateMaildirsOption, MainContext, MessageDestination, PROGRAM_NAMES};
+
+use common::{capture_fd, config_file, main_context, trusted_config};
+
+/// Run `rattomail::main` on `input` with the given args, writing to an output stream, and
+/// return what was written there and to stderr.
+fn run_main_capturing_stderr(args: &[&str], input: &[u8]) -> (String, String) {
+  let config_file = config_file(&trusted_config(Path::new("/nonexistent/Maildir/new"), ""));
+  let ctx = MainContext {
+    should_create_maildirs: CreateMaildirsOption::NoCreateMaildirs,
+    message_destination: MessageDestination::OutputStream,
+    received_time: Local.with_ymd_and_hms(2024, 12, 31, 10, 30, 0).unwrap(),
+    ..main_context(args, &config_file)
+  };
+
+  let mut output = Vec::new();
+  let (res, stderr) = capture_fd(2, || {
+    rattomail::main(&PROGRAM_NAMES, &ctx, &mut Cursor::new(input), Some(&mut output))
+  });
+
+  res.unwrap();
+  (String::from_utf8(output).unwrap(), stderr)
+}
+
+/// with `--trace`, each stage's marker appears on stderr, in order, and the delivered message
+/// is the same as without it. (The input has a `Message-ID:`, so that none is generated, which
+/// would differ between the two runs.)
+#[test]
+fn test_main_trace() {
+  let input = b"Subject: hello\nTo: bob\nMessage-ID: <1@example.com>\n\nsome body\n";
+  let (untraced, stderr) = run_main_capturing_stderr(&["sendmail", "-f", "alice", "bob"], input);
+  assert_eq!(stderr, "");
+
+  let (traced, stderr) = run_main_capturing_stderr(&["sendmail", "--trace", "-f", "alice", "bob"], input);
+  assert_eq!(traced, untraced);
+
+  let stages = ["addresses", "destination", "headers", "message"];
+  let positions: Vec<usize> = stages.iter()
+    .map(|stage| {
+      stderr.find(&format!("rattomail trace: [{}]\n", stage))
+        .unwrap_or_else(|| panic!("no {} stage in trace: {}", stage, stderr))
+    })
+    .collect();
+  assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "stages out of order: {}", stderr);
+
+  assert!(stderr.contains("    from: alice\n    to: bob\n"), "unexpected trace: {}", stderr);
+  assert!(stderr.contains("    Subject: hello\n    To: bob\n"), "unexpected trace: {}", stderr);
+  assert!(stderr.contains(&format!("    {} bytes\n", traced.len())), "unexpected trace: {}", stderr);
+}